@@ -0,0 +1,134 @@
+use crate::utils::format_size;
+use thiserror::Error;
+
+/// 对外接口的错误类型, 调用方可按失败原因分别处理
+///
+/// 内部实现仍使用 `anyhow`, 在公开接口处转换为该类型
+#[derive(Debug, Error)]
+pub enum LlmError {
+    #[error("模型 '{0}' 不存在")]
+    ModelNotFound(String),
+
+    #[error("不支持的模型架构: {0}")]
+    ArchUnsupported(String),
+
+    #[error("配置无效: {0}")]
+    InvalidConfig(#[source] anyhow::Error),
+
+    #[error("下载失败: {0}")]
+    Download(#[source] anyhow::Error),
+
+    #[error("加载 tokenizer 失败: {0}")]
+    TokenizerLoad(#[source] anyhow::Error),
+
+    #[error("加载模型失败: {0}")]
+    ModelLoad(#[source] anyhow::Error),
+
+    #[error("上下文长度 {len} 超出模型上限 {max}")]
+    ContextOverflow { len: usize, max: usize },
+
+    #[error("推理失败: {0}")]
+    Inference(#[source] anyhow::Error),
+
+    /// 前向计算时设备内存不足, `requested` 为按模型维度估算的 KV 缓存大小,
+    /// `available` 为出错后查询到的可用内存, 无法获取时为 `None`
+    #[error(
+        "设备内存不足{}, 可换用更小的量化文件或减小 max_context",
+        oom_detail(.requested, .available)
+    )]
+    OutOfMemory {
+        requested: Option<usize>,
+        available: Option<usize>,
+    },
+
+    /// 下载确认回调拒绝了下载, 见 [`HubClientBuilder::confirm_download`](crate::utils::load::HubClientBuilder::confirm_download)
+    #[error("已取消下载")]
+    Cancelled,
+
+    /// 生成过程中模型被卸载或重新加载, 回答不完整, 可重新提交
+    #[error("生成被中断: 模型已卸载或重新加载")]
+    Interrupted,
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl LlmError {
+    /// 将内部的 `anyhow` 错误转换为对外错误
+    ///
+    /// 内部已构造的 `LlmError` 原样取出, Hub 请求错误归为 [`Download`](Self::Download),
+    /// 其余交由 `fallback` 归类
+    pub(crate) fn from_anyhow(
+        err: anyhow::Error,
+        fallback: impl FnOnce(anyhow::Error) -> Self,
+    ) -> Self {
+        match err.downcast::<Self>() {
+            Ok(err) => err,
+            Err(err) if err.chain().any(|e| e.is::<reqwest::Error>()) => Self::Download(err),
+            Err(err) => fallback(err),
+        }
+    }
+}
+
+/// 设备内存不足的错误, 各后端只提供错误信息, 按信息内容识别
+pub(crate) fn is_out_of_memory(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        let msg = e.to_string().to_lowercase();
+        msg.contains("out of memory") || msg.contains("out_of_memory")
+    })
+}
+
+fn oom_detail(requested: &Option<usize>, available: &Option<usize>) -> String {
+    let detail: Vec<_> = [("KV 缓存约需", requested), ("可用", available)]
+        .into_iter()
+        .filter_map(|(label, size)| size.map(|size| format!("{label} {}", format_size(size))))
+        .collect();
+    if detail.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", detail.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_from_anyhow() -> Result<()> {
+        // 内部构造的错误原样取出
+        let err = anyhow::Error::from(LlmError::ArchUnsupported("gemma gguf".into()));
+        let err = LlmError::from_anyhow(err, LlmError::ModelLoad);
+        assert!(matches!(err, LlmError::ArchUnsupported(arch) if arch == "gemma gguf"));
+
+        // Hub 请求错误归为下载失败, 即使带有上下文
+        let request = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let err = anyhow::Error::from(request).context("fetch config.json");
+        let err = LlmError::from_anyhow(err, LlmError::ModelLoad);
+        assert!(matches!(err, LlmError::Download(_)));
+
+        // 其余错误交由 fallback
+        let err = LlmError::from_anyhow(anyhow!("bad weights"), LlmError::ModelLoad);
+        assert!(matches!(err, LlmError::ModelLoad(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        let err =
+            anyhow!("DriverError(CUDA_ERROR_OUT_OF_MEMORY, \"out of memory\")").context("forward");
+        assert!(is_out_of_memory(&err));
+        assert!(!is_out_of_memory(&anyhow!("shape mismatch")));
+
+        let err = LlmError::OutOfMemory {
+            requested: Some(2_000_000_000),
+            available: None,
+        };
+        assert_eq!(
+            err.to_string(),
+            "设备内存不足 (KV 缓存约需 2.00GB), 可换用更小的量化文件或减小 max_context"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! 在后台线程常驻模型, 通过通道提交请求
+//!
+//! [`TextGeneration`] 的接口需要 `&mut self`, 句柄将其移入独立线程独占, 调用方只持有发送端,
+//! 适用于启动时预先加载模型、之后随时提问的桌面应用
+
+use crate::error::LlmError;
+use crate::model::config::InferenceConfig;
+use crate::pipe::TextGeneration;
+use anyhow::Result;
+use async_stream::try_stream;
+use futures_core::stream::Stream;
+use futures_util::{StreamExt, pin_mut};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+
+type LoadFuture = Pin<Box<dyn Future<Output = Result<TextGeneration, LlmError>>>>;
+
+enum Command {
+    Chat {
+        prompt: String,
+        /// 提交时的模型代数, 与当前代数不同说明其间模型被卸载或重新加载
+        epoch: u64,
+        chunks: UnboundedSender<Result<String>>,
+    },
+    Reload(Box<dyn FnOnce() -> LoadFuture + Send>),
+}
+
+/// 后台模型线程的句柄, 请求按提交顺序依次处理, 共享同一段对话历史
+pub struct TextGenerationHandle {
+    commands: UnboundedSender<Command>,
+    thread: JoinHandle<()>,
+    epoch: Arc<AtomicU64>,
+}
+
+impl TextGeneration {
+    /// 在后台线程加载模型并常驻, 立即返回句柄
+    ///
+    /// 加载完成前提交的请求排队等待, 加载失败时每个请求都返回该错误
+    pub fn spawn(model_id: &str, config: InferenceConfig) -> TextGenerationHandle {
+        let model_id = model_id.to_string();
+        TextGenerationHandle::spawn(move || async move { Self::new(&model_id, config).await })
+    }
+
+    /// 将已加载的实例移入后台线程
+    pub fn into_handle(self) -> TextGenerationHandle {
+        TextGenerationHandle::spawn(move || async move { Ok(self) })
+    }
+}
+
+impl TextGenerationHandle {
+    /// 在新线程的单线程运行时中执行 `load` 返回的加载过程, 之后在该线程处理所有请求
+    pub fn spawn<F, Fut>(load: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<TextGeneration, LlmError>>,
+    {
+        let (commands, mut rx) = unbounded_channel();
+        let epoch = Arc::new(AtomicU64::new(0));
+        let current = epoch.clone();
+        let thread = std::thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(e) => {
+                    error!("failed to start the model thread: {e}");
+                    return;
+                }
+            };
+
+            rt.block_on(async move {
+                let mut text_gen = load().await;
+                if let Err(e) = &text_gen {
+                    error!("failed to load the model: {e}");
+                }
+
+                while let Some(command) = rx.recv().await {
+                    match command {
+                        Command::Chat {
+                            prompt,
+                            epoch,
+                            chunks,
+                        } => {
+                            let interrupted = || current.load(Ordering::Acquire) != epoch;
+                            if interrupted() {
+                                let _ = chunks.send(Err(LlmError::Interrupted.into()));
+                                continue;
+                            }
+                            let text_gen = match &mut text_gen {
+                                Ok(text_gen) => text_gen,
+                                Err(e) => {
+                                    let _ = chunks.send(Err(anyhow!("model failed to load: {e}")));
+                                    continue;
+                                }
+                            };
+
+                            let stream = text_gen.chat(&prompt);
+                            pin_mut!(stream);
+                            while let Some(chunk) = stream.next().await {
+                                // 调用方丢弃了回答流, 停止生成
+                                if chunks.send(chunk).is_err() {
+                                    break;
+                                }
+                                if interrupted() {
+                                    let _ = chunks.send(Err(LlmError::Interrupted.into()));
+                                    break;
+                                }
+                            }
+                        }
+                        Command::Reload(load) => {
+                            // 先释放旧模型, 避免两份权重同时占用内存
+                            drop(text_gen);
+                            text_gen = load().await;
+                            if let Err(e) = &text_gen {
+                                error!("failed to reload the model: {e}");
+                            }
+                        }
+                    }
+                }
+            });
+        });
+
+        Self {
+            commands,
+            thread,
+            epoch,
+        }
+    }
+
+    /// 提交一轮对话, 返回回答片段流
+    ///
+    /// 流不借用句柄, 可移到其他任务中读取; 丢弃流后后台在下一个 token 前停止生成
+    pub fn chat(&self, prompt: &str) -> impl Stream<Item = Result<String>> + use<> {
+        let (chunks, mut rx) = unbounded_channel();
+        let sent = self.commands.send(Command::Chat {
+            prompt: prompt.to_string(),
+            epoch: self.epoch.load(Ordering::Acquire),
+            chunks,
+        });
+
+        try_stream!({
+            sent.map_err(|_| LlmError::Interrupted)?;
+            while let Some(chunk) = rx.recv().await {
+                yield chunk?;
+            }
+        })
+    }
+
+    /// 中断正在生成与排队中的请求, 它们的回答流以 [`LlmError::Interrupted`] 结束
+    ///
+    /// 生成在下一个 token 前停止; 之后提交的请求不受影响
+    pub fn interrupt(&self) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// 中断已提交的请求并换用 `load` 加载的模型, 对话历史随旧模型一起丢弃
+    ///
+    /// 加载完成前提交的请求排队等待新模型
+    pub fn reload<F, Fut>(&self, load: F) -> Result<()>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<TextGeneration, LlmError>> + 'static,
+    {
+        self.interrupt();
+        self.commands
+            .send(Command::Reload(Box::new(move || -> LoadFuture {
+                Box::pin(load())
+            })))
+            .map_err(|_| LlmError::Interrupted.into())
+    }
+
+    /// 中断已提交的请求后结束后台线程并释放模型, 用于从模型池中驱逐
+    pub async fn evict(self) -> Result<()> {
+        self.interrupt();
+        self.shutdown().await
+    }
+
+    /// 处理完已提交的请求后结束后台线程并释放模型
+    pub async fn shutdown(self) -> Result<()> {
+        let Self {
+            commands, thread, ..
+        } = self;
+        drop(commands);
+        tokio::task::spawn_blocking(move || thread.join())
+            .await?
+            .map_err(|_| anyhow!("model thread panicked"))
+    }
+}
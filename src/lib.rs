@@ -5,6 +5,10 @@ extern crate tracing;
 #[macro_use]
 extern crate serde_default_utils;
 
+pub mod error;
+pub mod handle;
 pub mod model;
+pub mod openai;
 pub mod pipe;
+pub mod sse;
 pub mod utils;
@@ -1,43 +1,290 @@
-use crate::model::ModelInference;
+use crate::error::LlmError;
 use crate::model::hub::{HubInfo, ModelArch, ModelType};
+use crate::model::lora::LoraAdapter;
 use crate::model::registry::ModelRegistry;
-use crate::utils::load::ApiRepoExt;
-use crate::utils::load::{download_gguf, load_tokenizer};
+use crate::model::rope::RopeScaling;
+use crate::model::sharded_qwen3::ModelForCausalLM as ShardedQwen3Model;
+use crate::model::{LoadedModel, ModelInference};
+use crate::utils::load::{
+    HubClient, confirm_gguf_download, confirm_safetensors_download, download_gguf,
+    download_gguf_files, gguf_candidates, load_config, load_tokenizer, read_gguf, read_gguf_header,
+    resolve_gguf_pattern,
+};
+use crate::utils::memory::{
+    KvCacheDims, device_free_bytes, gguf_bytes, safetensors_bytes, select_quant,
+};
+use crate::utils::stop::StopRegex;
+use crate::utils::{Secret, format_size};
 use anyhow::{Result, anyhow};
 use candle::quantized::gguf_file::Content;
-use candle::{DType, Device};
+use candle::{DType, Device, DeviceLocation};
 use candle_nn::VarBuilder;
 use candle_transformers::models::{
-    quantized_llama, quantized_qwen3,
+    gemma2::{Config as Gemma2Config, Model as Gemma2Model},
+    mistral::{Config as MistralConfig, Model as MistralModel},
+    phi3::{Config as Phi3Config, Model as Phi3Model},
+    quantized_llama,
+    quantized_mistral::{Model as QMistralModel, VarBuilder as QVarBuilder},
+    quantized_phi3, quantized_qwen2, quantized_qwen3,
+    qwen2::{Config as Qwen2Config, ModelForCausalLM as Qwen2Model},
     qwen3::{Config as Qwen3Config, ModelForCausalLM as Qwen3Model},
 };
-use hf_hub::api::tokio::{Api, ApiBuilder};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::fs::File;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use tokenizers::Tokenizer;
 
+/// 可序列化的设备描述, 形如 `cpu`、`cuda:0`、`metal:0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceConfig {
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl DeviceConfig {
+    pub fn to_device(self) -> Result<Device> {
+        Ok(match self {
+            Self::Cpu => Device::Cpu,
+            Self::Cuda(ordinal) => Device::new_cuda(ordinal)?,
+            Self::Metal(ordinal) => Device::new_metal(ordinal)?,
+        })
+    }
+}
+
+impl From<&Device> for DeviceConfig {
+    fn from(device: &Device) -> Self {
+        match device.location() {
+            DeviceLocation::Cpu => Self::Cpu,
+            DeviceLocation::Cuda { gpu_id } => Self::Cuda(gpu_id),
+            DeviceLocation::Metal { gpu_id } => Self::Metal(gpu_id),
+        }
+    }
+}
+
+impl fmt::Display for DeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+impl FromStr for DeviceConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, ordinal) = match s.split_once(':') {
+            Some((kind, ordinal)) => (kind, ordinal.parse()?),
+            None => (s, 0),
+        };
+        match kind.to_lowercase().as_str() {
+            "cpu" => Ok(Self::Cpu),
+            "cuda" => Ok(Self::Cuda(ordinal)),
+            "metal" => Ok(Self::Metal(ordinal)),
+            _ => bail!("unknown device: {s}"),
+        }
+    }
+}
+
+/// 以 [`DeviceConfig`] 字符串形式 (反)序列化 [`Device`]
+mod device_serde {
+    use super::DeviceConfig;
+    use candle::Device;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(device: &Device, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&DeviceConfig::from(device))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Device, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse::<DeviceConfig>()
+            .and_then(DeviceConfig::to_device)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// 解码策略
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodeStrategy {
+    /// 按温度与 top_p 逐个采样, 可流式输出
+    #[default]
+    Sampling,
+    /// 束搜索, 保留累计对数概率最高的 `width` 条候选, 完成后输出最优候选
+    ///
+    /// 需要模型支持 KV 缓存快照, 不支持的模型在加载时报错
+    Beam { width: usize },
+    /// Mirostat v2 采样, 动态截断候选使平均惊奇度 (比特) 趋近 `tau`, `eta` 为调整速率
+    Mirostat { tau: f64, eta: f64 },
+}
+
+/// 随生成步数变化的温度
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureSchedule {
+    /// 固定温度
+    Constant(f64),
+    /// 从 `start` 线性变化, 在第 `sample_len` 个 token 时达到 `end`
+    Linear { start: f64, end: f64 },
+}
+
+impl TemperatureSchedule {
+    /// 第 `step` 个生成的 token (从 0 开始) 使用的温度
+    pub fn at(&self, step: usize, sample_len: usize) -> f64 {
+        match *self {
+            Self::Constant(temperature) => temperature,
+            Self::Linear { start, end } => {
+                let last = sample_len.saturating_sub(1).max(1);
+                start + (end - start) * (step.min(last) as f64 / last as f64)
+            }
+        }
+    }
+
+    fn endpoints(&self) -> [f64; 2] {
+        match *self {
+            Self::Constant(temperature) => [temperature; 2],
+            Self::Linear { start, end } => [start, end],
+        }
+    }
+}
+
 /// 推理参数配置
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct InferenceConfig {
     /// The length of the sample to generate (in tokens).
     pub sample_len: usize,
 
+    /// The minimum number of tokens to generate, EOS is suppressed until it is reached.
+    pub min_new_tokens: usize,
+
     /// The temperature used to generate samples, use 0 for greedy sampling.
     pub temperature: f64,
 
+    /// Varies the temperature with the generation step, overrides `temperature` when set.
+    ///
+    /// 例如 `Linear { start: 1.2, end: 0.4 }` 先发散后收敛; 束搜索忽略温度, 同样不受影响
+    pub temperature_schedule: Option<TemperatureSchedule>,
+
     /// Nucleus sampling probability cutoff.
     pub top_p: Option<f64>,
 
+    /// Locally typical sampling cutoff, keeps the tokens whose information content is closest
+    /// to the entropy of the distribution until their probability mass reaches it.
+    pub typical_p: Option<f64>,
+
     /// The seed to use when generating random samples.
+    ///
+    /// 采样器在每轮生成开始时以该种子重置, 相同提示词在全新上下文中的输出可复现
     pub seed: u64,
 
     /// Penalty to be applied for repeating tokens, 1. means no penalty.
     pub repeat_penalty: f32,
 
+    /// Whether to apply `repeat_penalty`, false skips it whatever its value.
+    ///
+    /// 关闭后保留 `repeat_penalty` 的值, 重新开启时沿用; `repeat_penalty` 为 1 时同样不惩罚
+    pub repeat_penalty_enabled: bool,
+
     /// The context size to consider for the repeat penalty.
     pub repeat_last_n: usize,
 
+    /// Additive penalty scaled by how often a token has been generated, 0. means no penalty.
+    pub frequency_penalty: f32,
+
+    /// Additive penalty for any token that has already been generated, 0. means no penalty.
+    pub presence_penalty: f32,
+
+    /// How to pick the next token, beam search ignores temperature, top_p and penalties.
+    pub decode_strategy: DecodeStrategy,
+
+    /// Record the logprob of each generated token together with this many top alternatives,
+    /// read back with `TextGeneration::last_logprobs`; None skips the extra softmax.
+    ///
+    /// 取自惩罚与截断之后、温度缩放之前的分布; 束搜索生成的回答不记录.
+    /// 开启 trace 级别日志时每个采样的 token 连同其对数概率另记一条事件, 此时总会计算
+    pub top_logprobs: Option<usize>,
+
+    /// Cap on the context length in tokens, overrides the model's `max_position_embeddings`
+    /// for the overflow check and must not exceed it; the KV cache grows up to this length.
+    pub max_context: Option<usize>,
+
+    /// RoPE scaling that extends the context beyond the training length, overrides the
+    /// `rope_scaling` in the model's config.json; only applies to safetensors models,
+    /// loading a GGUF model with it set fails.
+    ///
+    /// 模型上限随之扩大为训练长度的 `factor` 倍; 线性内插与 YaRN 目前仅支持 Qwen3
+    pub rope_scaling: Option<RopeScaling>,
+
+    /// The maximum time to wait for a single token, None means no limit.
+    pub token_timeout: Option<Duration>,
+
+    /// The maximum wall-clock time for a whole answer, None means no limit.
+    pub max_duration: Option<Duration>,
+
+    /// Number of CPU threads for tensor ops when running on CPU, None uses all cores.
+    ///
+    /// 前向计算在该数量线程的专用 rayon 线程池中执行, 不修改环境变量与全局线程池;
+    /// candle 切分出的并行任务都由这些线程完成. 分词器的并行编码仍使用 rayon 全局线程池,
+    /// 需要限制时在进程启动前设置 `RAYON_NUM_THREADS`
+    pub cpu_threads: Option<usize>,
+
+    /// Use flash-attention where the model supports it, requires the `flash-attn` feature.
+    pub use_flash_attn: bool,
+
+    /// Pick the largest GGUF file in the model repo that fits in free device memory,
+    /// falls back to the configured file when free memory or file sizes are unknown.
+    pub auto_quant: bool,
+
+    /// Whether the tokenizer adds special tokens such as BOS to the rendered prompt,
+    /// None adds them unless the chat template already starts with the same token.
+    pub add_special_tokens: Option<bool>,
+
+    /// Drop special-token text such as `<|im_start|>` that leaks into the streamed answer.
+    pub skip_special_tokens: bool,
+
+    /// Stop once the accumulated answer matches this regex, the match and anything after it
+    /// are dropped from the answer. Only checked after `min_new_tokens` tokens; streamed text
+    /// that could still start a match is held back (up to 256 bytes) until it can no longer match.
+    pub stop_regex: Option<String>,
+
+    /// Abort the answer when a generated token fails to decode, otherwise the token is
+    /// logged and skipped so one bad token doesn't end the response.
+    pub strict_decode: bool,
+
+    /// Extra tokens that end the answer like EOS, given by name such as `<|im_end|>`;
+    /// names missing from the vocab are ignored with a warning.
+    pub extra_stop_tokens: Vec<String>,
+
+    /// In completion mode, drop the last prompt token and constrain the first generated token
+    /// to those starting with it, so a prompt ending mid-word is completed naturally.
+    pub token_healing: bool,
+
+    /// Jinja chat template overriding the one shipped with the tokenizer,
+    /// required for base models without a template.
+    pub chat_template: Option<String>,
+
+    /// HuggingFace token for gated or private repos, overrides `HF_TOKEN` and the cached token.
+    #[serde(skip_serializing)]
+    pub hf_token: Option<Secret>,
+
+    /// HuggingFace cache directory, overrides `HF_HOME`.
+    pub cache_dir: Option<PathBuf>,
+
+    /// Proxy for Hub downloads such as `http://proxy.corp:8080`, overrides `HTTPS_PROXY`.
+    pub proxy: Option<String>,
+
     /// The device to use for inference.
+    #[serde(with = "device_serde")]
     pub device: Device,
 }
 
@@ -45,119 +292,1797 @@ impl Default for InferenceConfig {
     fn default() -> Self {
         Self {
             sample_len: 1000,
+            min_new_tokens: 0,
             temperature: 0.8,
+            temperature_schedule: None,
             top_p: None,
+            typical_p: None,
             seed: 299792458,
             repeat_penalty: 1.1,
+            repeat_penalty_enabled: true,
             repeat_last_n: 64,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            decode_strategy: DecodeStrategy::Sampling,
+            top_logprobs: None,
+            max_context: None,
+            rope_scaling: None,
+            token_timeout: None,
+            max_duration: None,
+            cpu_threads: None,
+            use_flash_attn: false,
+            auto_quant: false,
+            add_special_tokens: None,
+            skip_special_tokens: true,
+            stop_regex: None,
+            strict_decode: false,
+            extra_stop_tokens: vec![],
+            token_healing: false,
+            chat_template: None,
+            hf_token: None,
+            cache_dir: None,
+            proxy: None,
             device: candle::Device::cuda_if_available(0).unwrap(),
         }
     }
 }
 
+impl InferenceConfig {
+    /// 以默认配置为基础构建
+    pub fn builder() -> InferenceConfigBuilder {
+        InferenceConfigBuilder::default()
+    }
+
+    /// 从 TOML 文件加载, 未配置的字段使用默认值
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self> {
+        let config: Self = toml::from_str(&std::fs::read_to_string(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 第 `step` 个生成的 token (从 0 开始) 使用的温度
+    pub fn temperature_at(&self, step: usize) -> f64 {
+        match self.temperature_schedule {
+            Some(schedule) => schedule.at(step, self.sample_len),
+            None => self.temperature,
+        }
+    }
+
+    /// 是否每一步都贪心解码, 此时相同上下文的回答是确定的
+    pub fn is_greedy(&self) -> bool {
+        match self.temperature_schedule {
+            Some(schedule) => schedule.endpoints().iter().all(|t| *t <= 0.),
+            None => self.temperature <= 0.,
+        }
+    }
+
+    /// 是否对回答中重复的 token 应用 `repeat_penalty`
+    pub fn applies_repeat_penalty(&self) -> bool {
+        self.repeat_penalty_enabled && self.repeat_penalty != 1.
+    }
+
+    /// 在 CPU 上推理且设置了 `cpu_threads` 时, 创建执行前向计算的专用线程池
+    pub fn build_cpu_pool(&self) -> Result<Option<ThreadPool>> {
+        match self.cpu_threads {
+            Some(threads) if self.device.is_cpu() => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|i| format!("llm-cpu-{i}"))
+                    .build()?;
+                Ok(Some(pool))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 实际是否启用 flash-attention, 未编译 `flash-attn` feature 时回退到标准注意力
+    pub fn flash_attn_enabled(&self) -> bool {
+        if self.use_flash_attn && !cfg!(feature = "flash-attn") {
+            warn!("flash-attn feature is not enabled, falling back to standard attention");
+            return false;
+        }
+        self.use_flash_attn
+    }
+
+    /// 校验参数范围
+    pub fn validate(&self) -> Result<()> {
+        if self.sample_len == 0 {
+            bail!("sample_len must be greater than 0");
+        }
+        if self.temperature.is_nan() || self.temperature < 0. {
+            bail!("temperature must be >= 0, got {}", self.temperature);
+        }
+        if let Some(schedule) = self.temperature_schedule
+            && schedule.endpoints().iter().any(|t| t.is_nan() || *t < 0.)
+        {
+            bail!("temperature schedule must stay >= 0, got {schedule:?}");
+        }
+        if let Some(top_p) = self.top_p
+            && (top_p.is_nan() || top_p <= 0. || top_p > 1.)
+        {
+            bail!("top_p must be in (0, 1], got {top_p}");
+        }
+        if let Some(typical_p) = self.typical_p
+            && (typical_p.is_nan() || typical_p <= 0. || typical_p > 1.)
+        {
+            bail!("typical_p must be in (0, 1], got {typical_p}");
+        }
+        if self.repeat_penalty.is_nan() || self.repeat_penalty <= 0. {
+            bail!("repeat_penalty must be > 0, got {}", self.repeat_penalty);
+        }
+        if self.cpu_threads == Some(0) {
+            bail!("cpu_threads must be greater than 0");
+        }
+        if self.max_context == Some(0) {
+            bail!("max_context must be greater than 0");
+        }
+        if let Some(scaling) = self.rope_scaling
+            && (scaling.factor.is_nan() || scaling.factor < 1.)
+        {
+            bail!("rope scaling factor must be >= 1, got {}", scaling.factor);
+        }
+        if let Some(stop_regex) = &self.stop_regex {
+            StopRegex::new(stop_regex)?;
+        }
+        match self.decode_strategy {
+            DecodeStrategy::Beam { width: 0 } => bail!("beam width must be greater than 0"),
+            DecodeStrategy::Mirostat { tau, eta } if !(tau > 0. && eta > 0.) => {
+                bail!("mirostat tau and eta must be > 0, got tau={tau}, eta={eta}")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// [`InferenceConfig`] 构建器, 未设置的字段使用默认值
+#[derive(Debug, Clone, Default)]
+pub struct InferenceConfigBuilder {
+    config: InferenceConfig,
+}
+
+impl InferenceConfigBuilder {
+    pub fn sample_len(mut self, sample_len: usize) -> Self {
+        self.config.sample_len = sample_len;
+        self
+    }
+
+    pub fn min_new_tokens(mut self, min_new_tokens: usize) -> Self {
+        self.config.min_new_tokens = min_new_tokens;
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.config.temperature = temperature;
+        self
+    }
+
+    pub fn temperature_schedule(mut self, temperature_schedule: TemperatureSchedule) -> Self {
+        self.config.temperature_schedule = Some(temperature_schedule);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.config.top_p = Some(top_p);
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f64) -> Self {
+        self.config.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = seed;
+        self
+    }
+
+    pub fn repeat_penalty(mut self, repeat_penalty: f32) -> Self {
+        self.config.repeat_penalty = repeat_penalty;
+        self
+    }
+
+    pub fn repeat_penalty_enabled(mut self, repeat_penalty_enabled: bool) -> Self {
+        self.config.repeat_penalty_enabled = repeat_penalty_enabled;
+        self
+    }
+
+    pub fn repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.config.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.config.frequency_penalty = frequency_penalty;
+        self
+    }
+
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.config.presence_penalty = presence_penalty;
+        self
+    }
+
+    pub fn decode_strategy(mut self, decode_strategy: DecodeStrategy) -> Self {
+        self.config.decode_strategy = decode_strategy;
+        self
+    }
+
+    pub fn top_logprobs(mut self, top_logprobs: usize) -> Self {
+        self.config.top_logprobs = Some(top_logprobs);
+        self
+    }
+
+    pub fn max_context(mut self, max_context: usize) -> Self {
+        self.config.max_context = Some(max_context);
+        self
+    }
+
+    pub fn rope_scaling(mut self, rope_scaling: RopeScaling) -> Self {
+        self.config.rope_scaling = Some(rope_scaling);
+        self
+    }
+
+    pub fn token_timeout(mut self, token_timeout: Duration) -> Self {
+        self.config.token_timeout = Some(token_timeout);
+        self
+    }
+
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.config.max_duration = Some(max_duration);
+        self
+    }
+
+    pub fn cpu_threads(mut self, cpu_threads: usize) -> Self {
+        self.config.cpu_threads = Some(cpu_threads);
+        self
+    }
+
+    pub fn use_flash_attn(mut self, use_flash_attn: bool) -> Self {
+        self.config.use_flash_attn = use_flash_attn;
+        self
+    }
+
+    pub fn auto_quant(mut self, auto_quant: bool) -> Self {
+        self.config.auto_quant = auto_quant;
+        self
+    }
+
+    pub fn add_special_tokens(mut self, add_special_tokens: bool) -> Self {
+        self.config.add_special_tokens = Some(add_special_tokens);
+        self
+    }
+
+    pub fn skip_special_tokens(mut self, skip_special_tokens: bool) -> Self {
+        self.config.skip_special_tokens = skip_special_tokens;
+        self
+    }
+
+    pub fn stop_regex(mut self, stop_regex: impl Into<String>) -> Self {
+        self.config.stop_regex = Some(stop_regex.into());
+        self
+    }
+
+    pub fn strict_decode(mut self, strict_decode: bool) -> Self {
+        self.config.strict_decode = strict_decode;
+        self
+    }
+
+    pub fn extra_stop_tokens(
+        mut self,
+        tokens: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.extra_stop_tokens = tokens.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn token_healing(mut self, token_healing: bool) -> Self {
+        self.config.token_healing = token_healing;
+        self
+    }
+
+    pub fn chat_template(mut self, chat_template: impl Into<String>) -> Self {
+        self.config.chat_template = Some(chat_template.into());
+        self
+    }
+
+    pub fn hf_token(mut self, hf_token: impl Into<String>) -> Self {
+        self.config.hf_token = Some(Secret::from(hf_token.into()));
+        self
+    }
+
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.config.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.config.proxy = Some(proxy.into());
+        self
+    }
+
+    pub fn device(mut self, device: Device) -> Self {
+        self.config.device = device;
+        self
+    }
+
+    /// 校验参数后生成配置
+    pub fn build(self) -> Result<InferenceConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// GGUF 文件头中的模型信息, 读取时不加载权重
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GgufInfo {
+    /// `general.architecture`, candle 导出的文件可能缺失
+    pub architecture: Option<String>,
+    /// 占用字节最多的量化类型, 如 `Q4K`
+    pub quantization: Option<String>,
+    pub tensor_count: usize,
+    /// 张量的元素总数, 即参数量
+    pub parameter_count: usize,
+    /// 量化张量的总字节数
+    pub total_bytes: usize,
+    /// `{architecture}.context_length`
+    pub context_length: Option<usize>,
+    /// 内嵌的 `tokenizer.chat_template`
+    pub chat_template: Option<String>,
+    /// 层数与注意力维度, 元数据不全时为 `None`
+    pub kv_dims: Option<KvCacheDims>,
+}
+
+impl GgufInfo {
+    pub fn from_content(ct: &Content) -> Self {
+        let metadata_str = |key: &str| {
+            ct.metadata
+                .get(key)
+                .and_then(|v| v.to_string().ok())
+                .cloned()
+        };
+        let architecture = metadata_str("general.architecture");
+
+        let context_length = architecture.as_ref().and_then(|arch| {
+            let v = ct.metadata.get(&format!("{arch}.context_length"))?;
+            v.to_u32()
+                .map(|x| x as usize)
+                .or_else(|_| v.to_u64().map(|x| x as usize))
+                .ok()
+        });
+
+        let mut dtype_bytes = HashMap::new();
+        for tensor in ct.tensor_infos.values() {
+            let dtype = tensor.ggml_dtype;
+            *dtype_bytes.entry(format!("{dtype:?}")).or_insert(0) +=
+                tensor.shape.elem_count() * dtype.type_size() / dtype.block_size();
+        }
+        let quantization = dtype_bytes
+            .into_iter()
+            .max_by_key(|(_, bytes)| *bytes)
+            .map(|(dtype, _)| dtype);
+
+        Self {
+            architecture,
+            quantization,
+            tensor_count: ct.tensor_infos.len(),
+            parameter_count: ct
+                .tensor_infos
+                .values()
+                .map(|tensor| tensor.shape.elem_count())
+                .sum(),
+            total_bytes: gguf_bytes(ct),
+            context_length,
+            chat_template: metadata_str("tokenizer.chat_template"),
+            kv_dims: KvCacheDims::from_gguf(ct),
+        }
+    }
+}
+
+/// 模型权重的存储类型
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantization {
+    /// 占用字节最多的类型, 如 GGUF 的 `Q4K`, 完整模型为加载后的 `BF16`
+    pub dtype: String,
+    /// 平均每个权重占用的比特数, 包含量化块的缩放系数与未量化的张量
+    pub bits_per_weight: f64,
+}
+
+impl Quantization {
+    /// 由 GGUF 信息得出, 没有张量时为 `None`
+    pub fn from_gguf(info: &GgufInfo) -> Option<Self> {
+        Some(Self {
+            dtype: info.quantization.clone()?,
+            bits_per_weight: (info.total_bytes * 8) as f64 / info.parameter_count as f64,
+        })
+    }
+
+    /// 以 `dtype` 加载的未量化权重
+    pub fn from_dtype(dtype: DType) -> Self {
+        Self {
+            dtype: format!("{dtype:?}"),
+            bits_per_weight: (dtype.size_in_bytes() * 8) as f64,
+        }
+    }
+
+    /// 是否为量化类型, `F16`、`BF16` 等浮点类型不算
+    pub fn is_quantized(&self) -> bool {
+        !matches!(self.dtype.as_str(), "F64" | "F32" | "F16" | "BF16")
+    }
+}
+
+/// 已加载模型的基本信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    /// 注册表中的模型标识符, 由组件直接构造时为 `None`
+    pub id: Option<String>,
+    /// 模型架构, 如 `qwen3`
+    pub arch: Option<String>,
+    /// 模型支持的最大上下文长度
+    pub context_length: Option<usize>,
+    /// 包含 added tokens 的词表大小
+    pub vocab_size: usize,
+    /// 权重的量化类型与每个权重的平均比特数
+    pub quantization: Option<Quantization>,
+    /// 计算使用的数据类型, 量化模型为 `F32`
+    pub dtype: Option<DType>,
+    /// 估算 KV 缓存的模型维度, 来自 GGUF 元数据或 config.json
+    pub kv_dims: Option<KvCacheDims>,
+}
+
+impl ModelInfo {
+    /// 仅包含分词器信息, 供由组件直接构造时使用
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Self {
+        Self {
+            id: None,
+            arch: None,
+            context_length: None,
+            vocab_size: tokenizer.get_vocab_size(true),
+            quantization: None,
+            dtype: None,
+            kv_dims: None,
+        }
+    }
+
+    /// 上下文长度为 `context_len` 时 KV 缓存占用的字节数, 缺少模型维度或数据类型时为 `None`
+    pub fn estimate_kv_cache_bytes(&self, context_len: usize) -> Option<u64> {
+        Some(self.kv_dims?.bytes(context_len, self.dtype?))
+    }
+}
+
 /// 模型加载器 - 专门负责模型相关操作
 pub struct ModelLoader;
 
 impl ModelLoader {
     /// 一次性加载所有需要的组件
     pub async fn load(
+        hub: &HubClient,
         hub_info: &HubInfo,
         device: &Device,
-    ) -> Result<(Box<dyn ModelInference>, Tokenizer)> {
-        if hub_info.model_repo.to_lowercase().contains("gguf") {
-            Self::load_gguf(hub_info, device).await
+    ) -> Result<(Box<dyn ModelInference>, Tokenizer), LlmError> {
+        Self::load_with_flash_attn(hub, hub_info, device, false).await
+    }
+
+    /// 同 [`load`](Self::load), 可为支持的模型启用 flash-attention
+    ///
+    /// 支持 flash-attention 的模型: Gemma-2, Mistral (含 candle 导出的 GGUF), Phi-3 GGUF;
+    /// candle 的 Qwen2/Qwen3/Llama 实现不支持, 将使用标准注意力.
+    /// llama.cpp 导出的 Mistral GGUF 按 llama 架构加载, 同样不支持
+    pub async fn load_with_flash_attn(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        device: &Device,
+        use_flash_attn: bool,
+    ) -> Result<(Box<dyn ModelInference>, Tokenizer), LlmError> {
+        Self::load_model(hub, hub_info, device, use_flash_attn, None).await
+    }
+
+    /// 按推理配置加载: 使用其中的设备、flash-attention 开关与 RoPE 缩放
+    pub async fn load_with_config(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        config: &InferenceConfig,
+    ) -> Result<(Box<dyn ModelInference>, Tokenizer), LlmError> {
+        let use_flash_attn = config.flash_attn_enabled();
+        Self::load_model(
+            hub,
+            hub_info,
+            &config.device,
+            use_flash_attn,
+            config.rope_scaling,
+        )
+        .await
+    }
+
+    async fn load_model(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        device: &Device,
+        use_flash_attn: bool,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<(Box<dyn ModelInference>, Tokenizer), LlmError> {
+        let hub_info = &Self::resolve_model_file(hub, hub_info).await?;
+        let loaded = if Self::is_gguf(hub_info) {
+            // 忽略缩放会以错误的上下文长度运行
+            if let Some(scaling) = rope_scaling {
+                return Err(LlmError::InvalidConfig(anyhow!(
+                    "{} rope scaling is not supported for gguf models",
+                    scaling.rope_type
+                )));
+            }
+            Self::load_gguf(hub, hub_info, device, use_flash_attn).await
         } else {
-            Self::load_safetensors(hub_info, device).await
+            Self::load_safetensors(hub, hub_info, device, use_flash_attn, rope_scaling).await
+        };
+        loaded.map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))
+    }
+
+    /// 将 RoPE 缩放写入 config.json, `rope_scaling` 为 `None` 时使用 config.json 自带的配置
+    ///
+    /// 返回实际生效的缩放, 见 [`RopeScaling::apply_to_config`]
+    pub fn apply_rope_scaling(
+        config: &mut Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Option<RopeScaling>> {
+        rope_scaling
+            .or_else(|| RopeScaling::from_config(config))
+            .map(|scaling| scaling.apply_to_config(config))
+            .transpose()
+    }
+
+    /// 是否为 GGUF 量化模型
+    pub fn is_gguf(hub_info: &HubInfo) -> bool {
+        hub_info.model_repo.to_lowercase().contains("gguf")
+            || hub_info.model_file.ends_with(".gguf")
+            || hub_info
+                .model_file_pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.ends_with(".gguf"))
+    }
+
+    /// 将 `model_file_pattern` 解析为仓库中具体的 `model_file`, 未设置通配符时原样返回
+    ///
+    /// 加载与查询模型的入口都会先解析, 预先解析可避免每次重复查询仓库文件列表
+    pub async fn resolve_model_file(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+    ) -> Result<HubInfo, LlmError> {
+        let Some(pattern) = &hub_info.model_file_pattern else {
+            return Ok(hub_info.clone());
+        };
+        let model_file = resolve_gguf_pattern(hub, &hub_info.model_repo, pattern)
+            .await
+            .map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))?;
+        debug!("resolved {pattern} to {model_file}");
+        Ok(HubInfo {
+            model_file,
+            model_file_pattern: None,
+            ..hub_info.clone()
+        })
+    }
+
+    /// 模型权重加载到设备后占用的字节数, 由已下载的权重文件计算
+    pub async fn weights_bytes(hub: &HubClient, hub_info: &HubInfo) -> Result<usize, LlmError> {
+        let hub_info = &Self::resolve_model_file(hub, hub_info).await?;
+        let bytes = if Self::is_gguf(hub_info) {
+            Self::gguf_weights_bytes(hub, hub_info).await
+        } else {
+            Self::safetensors_weights_bytes(hub, hub_info).await
+        };
+        bytes.map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))
+    }
+
+    /// 按设备可用内存选出仓库中放得下的最大 GGUF 文件
+    ///
+    /// 查询失败或没有放得下的文件时使用注册表中配置的文件
+    pub async fn auto_quant(hub: &HubClient, hub_info: &HubInfo, device: &Device) -> HubInfo {
+        Self::auto_quant_with_free(hub, hub_info, device_free_bytes(device)).await
+    }
+
+    /// 按给定的可用内存选择, `free` 为 `None` 表示设备不支持查询
+    async fn auto_quant_with_free(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        free: Option<usize>,
+    ) -> HubInfo {
+        match Self::select_gguf(hub, hub_info, free).await {
+            Ok(model_file) => {
+                info!("auto-selected {model_file} from {}", hub_info.model_repo);
+                HubInfo {
+                    model_file,
+                    model_file_pattern: None,
+                    ..hub_info.clone()
+                }
+            }
+            Err(e) => {
+                warn!("auto quant failed, using {}: {e:#}", hub_info.model_file);
+                hub_info.clone()
+            }
+        }
+    }
+
+    async fn select_gguf(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        free: Option<usize>,
+    ) -> Result<String> {
+        if !Self::is_gguf(hub_info) {
+            bail!("{} is not a GGUF repo", hub_info.model_repo);
+        }
+        let free = free.ok_or_else(|| anyhow!("free device memory is unknown"))?;
+        let candidates = gguf_candidates(hub, &hub_info.model_repo).await?;
+        select_quant(&candidates, free)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("no GGUF file fits in {} free memory", format_size(free)))
+    }
+
+    /// 读取 GGUF 模型的元数据, 不构建模型权重, 便于加载前展示模型信息
+    ///
+    /// 文件未缓存时只以 Range 请求下载文件头, 不下载权重
+    pub async fn inspect_gguf(
+        hub: &HubClient,
+        repo: &str,
+        file: &str,
+    ) -> Result<GgufInfo, LlmError> {
+        Self::gguf_info(hub, repo, file)
+            .await
+            .map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))
+    }
+
+    /// 由 GGUF 元数据或 config.json 汇总模型信息, 不含模型标识符
+    pub async fn model_info(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        config: &Value,
+        tokenizer: &Tokenizer,
+    ) -> Result<ModelInfo, LlmError> {
+        let hub_info = &Self::resolve_model_file(hub, hub_info).await?;
+        let gguf = if Self::is_gguf(hub_info) {
+            Some(Self::inspect_gguf(hub, &hub_info.model_repo, &hub_info.model_file).await?)
+        } else {
+            None
+        };
+
+        // candle 导出的 GGUF 可能缺少架构信息, 回退到 config.json
+        let arch = gguf
+            .as_ref()
+            .and_then(|info| info.architecture.as_deref())
+            .and_then(|arch| ModelArch::from_str(arch).ok())
+            .or_else(|| ModelArch::from_config(config).ok());
+        let max_position_embeddings = config
+            .get("max_position_embeddings")
+            .and_then(|x| x.as_u64())
+            .map(|x| x as usize);
+        let context_length = gguf
+            .as_ref()
+            .and_then(|info| info.context_length)
+            .or(max_position_embeddings);
+
+        // 量化模型以 F32 计算, 完整模型以 BF16 加载
+        let quantization = match &gguf {
+            Some(info) => Quantization::from_gguf(info),
+            None => Some(Quantization::from_dtype(DType::BF16)),
+        };
+
+        Ok(ModelInfo {
+            arch: arch.map(|arch| arch.to_string()),
+            context_length,
+            quantization,
+            dtype: Some(if gguf.is_some() {
+                DType::F32
+            } else {
+                DType::BF16
+            }),
+            kv_dims: gguf
+                .and_then(|info| info.kv_dims)
+                .or_else(|| KvCacheDims::from_config(config)),
+            ..ModelInfo::from_tokenizer(tokenizer)
+        })
+    }
+
+    async fn gguf_info(hub: &HubClient, repo: &str, file: &str) -> Result<GgufInfo> {
+        let ct = read_gguf_header(hub, repo, file).await?;
+        Ok(GgufInfo::from_content(&ct))
+    }
+
+    async fn gguf_weights_bytes(hub: &HubClient, hub_info: &HubInfo) -> Result<usize> {
+        let ct = read_gguf_header(hub, &hub_info.model_repo, &hub_info.model_file).await?;
+        Ok(gguf_bytes(&ct))
+    }
+
+    async fn safetensors_weights_bytes(hub: &HubClient, hub_info: &HubInfo) -> Result<usize> {
+        let model_files = Self::safetensors_files(hub, hub_info).await?;
+        safetensors_bytes(&model_files, DType::BF16)
+    }
+
+    /// 获取 safetensors 权重文件, 单文件不存在时按 index.json 获取分片文件
+    async fn safetensors_files(hub: &HubClient, hub_info: &HubInfo) -> Result<Vec<PathBuf>> {
+        match hub.get(&hub_info.model_repo, &hub_info.model_file).await {
+            Ok(single_file) => Ok(vec![single_file]),
+            Err(_) => hub.get_safetensors(&hub_info.model_repo).await,
         }
     }
 
     /// 加载 GGUF 量化模型
     async fn load_gguf(
+        hub: &HubClient,
         hub_info: &HubInfo,
         device: &Device,
+        use_flash_attn: bool,
     ) -> Result<(Box<dyn ModelInference>, Tokenizer)> {
-        let model_pth = download_gguf(&hub_info.model_repo, &hub_info.model_file).await?;
-
-        let mut file = File::open(model_pth)?;
-        let ct = Content::read(&mut file)?;
-
-        let repo = hub_info.model_repo.to_lowercase();
-        let model = if repo.contains("qwen3") {
-            let model = quantized_qwen3::ModelWeights::from_gguf(ct, &mut file, device)?;
-            Box::new(model) as Box<dyn ModelInference>
-        } else if repo.contains("llama") {
-            // let model = quantized_llama::ModelWeights::from_gguf(ct, &mut file, device)?;
-            // Box::new(model) as Box<dyn ModelInference>
-            bail!("Llama gguf support not yet implemented");
+        // 量化权重无法合并 LoRA, 不能在忽略适配器后继续使用基础模型
+        if let Some(repo) = &hub_info.adapter_repo {
+            bail!("LoRA adapter {repo} cannot be applied to gguf models");
+        }
+        confirm_gguf_download(hub, &hub_info.model_repo, &hub_info.model_file).await?;
+        // 分片模型直接拼接读取, 无需先合并
+        let model_files =
+            download_gguf_files(hub, &hub_info.model_repo, &hub_info.model_file).await?;
+        let (ct, mut file) = read_gguf(&model_files)?;
+
+        // llama.cpp 导出的 GGUF 带有架构元数据, candle 导出的需读取原仓库 config.json
+        let config = if ct.metadata.contains_key("general.architecture") {
+            None
         } else {
-            bail!("Unsupported model type");
+            Some(load_config(&hub.tokenizer(), &hub_info.tokenizer_repo).await?)
+        };
+        let arch = match &config {
+            None => ModelArch::from_gguf(&ct)?,
+            Some(config) => ModelArch::from_config(config)?,
+        };
+        if use_flash_attn && !matches!(arch, ModelArch::Phi3 | ModelArch::Mistral) {
+            warn!("flash-attn is not supported for {arch} gguf, using standard attention");
+        }
+        let num_layers = match &config {
+            None => gguf_num_layers(&ct)?,
+            Some(config) => config
+                .get("num_hidden_layers")
+                .and_then(|x| x.as_u64())
+                .ok_or_else(|| anyhow!("num_hidden_layers not found in config.json"))?
+                as usize,
         };
 
-        let tokenizer = load_tokenizer(&hub_info.tokenizer_repo)?;
+        let model: Box<dyn ModelInference> = match arch {
+            ModelArch::Qwen2 => {
+                let model = quantized_qwen2::ModelWeights::from_gguf(ct, &mut file, device)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+            ModelArch::Qwen3 => {
+                let model = quantized_qwen3::ModelWeights::from_gguf(ct, &mut file, device)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+            ModelArch::Gemma => {
+                Err(LlmError::ArchUnsupported("gemma (gguf)".to_string()))?
+            }
+            // Mistral 等 llama.cpp 导出的 GGUF 架构元数据同样为 llama
+            ModelArch::Llama => {
+                let model = quantized_llama::ModelWeights::from_gguf(ct, &mut file, device)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+            ModelArch::Phi3 => {
+                let model = quantized_phi3::ModelWeights::from_gguf(use_flash_attn, ct, &mut file, device)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+            // candle 导出的 Mistral GGUF 没有架构元数据, 由 config.json 识别, 张量沿用 safetensors 的命名
+            ModelArch::Mistral => {
+                let config = match config {
+                    Some(config) => config,
+                    None => load_config(&hub.tokenizer(), &hub_info.tokenizer_repo).await?,
+                };
+                let config = MistralConfig {
+                    use_flash_attn,
+                    ..serde_json::from_value(config)?
+                };
+                // QVarBuilder 只能从单个文件读取, 分片模型需先合并
+                let model_pth = match model_files.as_slice() {
+                    [path] => path.clone(),
+                    _ => download_gguf(hub, &hub_info.model_repo, &hub_info.model_file).await?,
+                };
+                let vb = QVarBuilder::from_gguf(&model_pth, device)?;
+                let model = QMistralModel::new(&config, vb)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+        };
+
+        let tokenizer = load_tokenizer(
+            &hub.tokenizer(),
+            &hub_info.tokenizer_repo,
+            hub_info.tokenizer_file.as_deref(),
+        )
+        .await?;
 
         Ok((model, tokenizer))
     }
 
-    /// 加载 Safetensors 完整模型 暂时支持qwen
+    /// 加载 Safetensors 完整模型
     async fn load_safetensors(
+        hub: &HubClient,
         hub_info: &HubInfo,
         device: &Device,
+        use_flash_attn: bool,
+        rope_scaling: Option<RopeScaling>,
     ) -> Result<(Box<dyn ModelInference>, Tokenizer)> {
-        let api = ApiBuilder::from_env().build()?;
-        let repo = api.model(hub_info.model_repo.clone());
-
         // 加载模型权重文件
-        let model_files = match repo.get(&hub_info.model_file).await {
-            Ok(single_file) => vec![single_file],
-            Err(_) => {
-                // 单文件不存在，尝试获取分片文件
-                repo.get_safetensors().await?
+        confirm_safetensors_download(hub, &hub_info.model_repo, &hub_info.model_file).await?;
+        let model_files = Self::safetensors_files(hub, hub_info).await?;
+
+        let vb = match &hub_info.adapter_repo {
+            Some(repo) => {
+                info!("merging LoRA adapter {repo}");
+                let adapter = LoraAdapter::load(hub, repo).await?;
+                adapter.var_builder(&model_files, DType::BF16, device)?
             }
+            None => unsafe {
+                VarBuilder::from_mmaped_safetensors(&model_files, DType::BF16, device)?
+            },
         };
 
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&model_files, DType::BF16, device)? };
-
-        let arch = ModelArch::Qwen3;
+        // 加载配置文件, 根据 model_type 确定架构
+        let mut config = load_config(hub, &hub_info.model_repo).await?;
+        Self::resolve_tied_embeddings(&mut config, &vb)?;
+        let rope_scaling = Self::apply_rope_scaling(&mut config, rope_scaling)?;
 
-        // 加载配置文件
-        let config_path = repo.get("config.json").await?;
-        let config_content = std::fs::read(&config_path)?;
+        let arch = ModelArch::from_config(&config)?;
+        if use_flash_attn && !matches!(arch, ModelArch::Gemma | ModelArch::Mistral) {
+            warn!("flash-attn is not supported for {arch}, using standard attention");
+        }
+        // 线性内插与 YaRN 需改写频率, candle 的模型只按 rope_theta 计算
+        let rope_scaling = rope_scaling.filter(RopeScaling::custom_rope);
+        if let Some(scaling) = &rope_scaling
+            && !matches!(arch, ModelArch::Qwen3)
+        {
+            Err(LlmError::ArchUnsupported(format!(
+                "{arch} with {} rope scaling",
+                scaling.rope_type
+            )))?
+        }
+        let num_layers = config
+            .get("num_hidden_layers")
+            .and_then(|x| x.as_u64())
+            .ok_or_else(|| anyhow!("num_hidden_layers not found in config.json"))?
+            as usize;
 
         let model: Box<dyn ModelInference> = match arch {
+            ModelArch::Qwen2 => {
+                let config: Qwen2Config = serde_json::from_value(config)?;
+                let model = Qwen2Model::new(&config, vb)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
             ModelArch::Qwen3 => {
-                let config: Qwen3Config = serde_json::from_slice(&config_content)?;
-                let model = Qwen3Model::new(&config, vb)?;
-                Box::new(model)
+                let config: Qwen3Config = serde_json::from_value(config)?;
+                match rope_scaling {
+                    // 单设备加载本仓库的实现
+                    Some(scaling) => {
+                        let model =
+                            ShardedQwen3Model::with_rope_scaling(&config, &[vb], Some(&scaling))?;
+                        Box::new(LoadedModel::new(model, num_layers))
+                    }
+                    None => {
+                        let model = Qwen3Model::new(&config, vb)?;
+                        Box::new(LoadedModel::new(model, num_layers))
+                    }
+                }
+            }
+            ModelArch::Gemma => {
+                let config: Gemma2Config = serde_json::from_value(config)?;
+                let model = Gemma2Model::new(use_flash_attn, &config, vb)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+            ModelArch::Mistral => {
+                let config = MistralConfig {
+                    use_flash_attn,
+                    ..serde_json::from_value(config)?
+                };
+                let model = MistralModel::new(&config, vb)?;
+                Box::new(LoadedModel::new(model, num_layers))
+            }
+            ModelArch::Phi3 => {
+                let config: Phi3Config = serde_json::from_value(config)?;
+                let model = Phi3Model::new(&config, vb)?;
+                Box::new(LoadedModel::new(model, num_layers))
             }
             ModelArch::Llama => {
-                bail!("Llama safetensors support not yet implemented");
+                Err(LlmError::ArchUnsupported("llama (safetensors)".to_string()))?
             }
         };
 
-        let tokenizer = load_tokenizer(&hub_info.tokenizer_repo)?;
+        let tokenizer = load_tokenizer(
+            &hub.tokenizer(),
+            &hub_info.tokenizer_repo,
+            hub_info.tokenizer_file.as_deref(),
+        )
+        .await?;
 
         Ok((model, tokenizer))
     }
+
+    /// 将 Safetensors 模型按层切分到多个设备加载, 用于单卡放不下的大模型
+    ///
+    /// 目前仅支持 Qwen3, 解码层按顺序平均分配到 `devices`
+    pub async fn load_sharded(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        devices: &[Device],
+    ) -> Result<(Box<dyn ModelInference>, Tokenizer), LlmError> {
+        let hub_info = &Self::resolve_model_file(hub, hub_info).await?;
+        Self::load_sharded_safetensors(hub, hub_info, devices)
+            .await
+            .map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))
+    }
+
+    async fn load_sharded_safetensors(
+        hub: &HubClient,
+        hub_info: &HubInfo,
+        devices: &[Device],
+    ) -> Result<(Box<dyn ModelInference>, Tokenizer)> {
+        if devices.is_empty() {
+            bail!("at least one device is required");
+        }
+        if let Some(repo) = &hub_info.adapter_repo {
+            bail!("LoRA adapter {repo} cannot be applied when loading across devices");
+        }
+
+        confirm_safetensors_download(hub, &hub_info.model_repo, &hub_info.model_file).await?;
+        let model_files = Self::safetensors_files(hub, hub_info).await?;
+
+        let mut config = load_config(hub, &hub_info.model_repo).await?;
+        let arch = ModelArch::from_config(&config)?;
+        if !matches!(arch, ModelArch::Qwen3) {
+            Err(LlmError::ArchUnsupported(format!("{arch} (multi-device)")))?
+        }
+        let rope_scaling = Self::apply_rope_scaling(&mut config, None)?;
+
+        // 每个设备各自映射一份权重文件, 只读取分配到该设备的张量
+        let vbs = devices
+            .iter()
+            .map(|device| unsafe {
+                VarBuilder::from_mmaped_safetensors(&model_files, DType::BF16, device)
+            })
+            .collect::<candle::Result<Vec<_>>>()?;
+
+        let config: Qwen3Config = serde_json::from_value(config)?;
+        let model = LoadedModel::new(
+            ShardedQwen3Model::with_rope_scaling(&config, &vbs, rope_scaling.as_ref())?,
+            config.num_hidden_layers,
+        );
+
+        let tokenizer = load_tokenizer(
+            &hub.tokenizer(),
+            &hub_info.tokenizer_repo,
+            hub_info.tokenizer_file.as_deref(),
+        )
+        .await?;
+
+        Ok((Box::new(model), tokenizer))
+    }
+
+    /// 处理共享词嵌入: 权重中缺少独立的 lm_head 时回退到词嵌入
+    fn resolve_tied_embeddings(config: &mut Value, vb: &VarBuilder) -> Result<()> {
+        if vb.contains_tensor("lm_head.weight") {
+            return Ok(());
+        }
+        if !vb.contains_tensor("model.embed_tokens.weight") {
+            bail!("neither lm_head.weight nor model.embed_tokens.weight found in safetensors");
+        }
+
+        if config.get("tie_word_embeddings").and_then(|x| x.as_bool()) != Some(true) {
+            warn!("lm_head.weight not found, falling back to tied word embeddings");
+            config["tie_word_embeddings"] = Value::Bool(true);
+        }
+
+        Ok(())
+    }
+}
+
+/// GGUF 中的解码层数, 读取元数据 `{architecture}.block_count`
+fn gguf_num_layers(ct: &Content) -> Result<usize> {
+    let arch = ct
+        .metadata
+        .get("general.architecture")
+        .ok_or_else(|| anyhow!("general.architecture not found in gguf metadata"))?
+        .to_string()?;
+    let key = format!("{arch}.block_count");
+    let value = ct
+        .metadata
+        .get(&key)
+        .ok_or_else(|| anyhow!("{key} not found in gguf metadata"))?;
+    let layers = match value.to_u32() {
+        Ok(layers) => layers as usize,
+        Err(_) => value.to_u64()? as usize,
+    };
+    Ok(layers)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::test_hub::{serve, write_response};
+    use candle::Tensor;
+    use candle::quantized::{GgmlDType, QTensor, gguf_file};
+    use serde_json::json;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        InferenceConfig::default().validate()?;
+
+        let invalid = [
+            InferenceConfig {
+                sample_len: 0,
+                ..Default::default()
+            },
+            InferenceConfig {
+                temperature: -1.,
+                ..Default::default()
+            },
+            InferenceConfig {
+                temperature: f64::NAN,
+                ..Default::default()
+            },
+            InferenceConfig {
+                top_p: Some(1.5),
+                ..Default::default()
+            },
+            InferenceConfig {
+                top_p: Some(0.),
+                ..Default::default()
+            },
+            InferenceConfig {
+                typical_p: Some(1.5),
+                ..Default::default()
+            },
+            InferenceConfig {
+                repeat_penalty: 0.,
+                ..Default::default()
+            },
+            InferenceConfig {
+                max_context: Some(0),
+                ..Default::default()
+            },
+            InferenceConfig {
+                cpu_threads: Some(0),
+                ..Default::default()
+            },
+            InferenceConfig {
+                rope_scaling: Some(RopeScaling {
+                    rope_type: crate::model::rope::RopeType::Linear,
+                    factor: 0.5,
+                    original_max_position_embeddings: None,
+                }),
+                ..Default::default()
+            },
+            InferenceConfig {
+                stop_regex: Some("(".to_string()),
+                ..Default::default()
+            },
+            InferenceConfig {
+                decode_strategy: DecodeStrategy::Beam { width: 0 },
+                ..Default::default()
+            },
+            InferenceConfig {
+                decode_strategy: DecodeStrategy::Mirostat { tau: 5., eta: 0. },
+                ..Default::default()
+            },
+            InferenceConfig {
+                temperature_schedule: Some(TemperatureSchedule::Linear {
+                    start: 1.,
+                    end: -0.5,
+                }),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_info() -> Result<()> {
+        let arch = gguf_file::Value::String("qwen3".to_string());
+        let context_length = gguf_file::Value::U32(40960);
+        let template = gguf_file::Value::String("{{ messages }}".to_string());
+        let block_count = gguf_file::Value::U32(36);
+        let head_count = gguf_file::Value::U32(32);
+        let head_count_kv = gguf_file::Value::U32(8);
+        let embedding_length = gguf_file::Value::U32(4096);
+        let metadata = [
+            ("general.architecture", &arch),
+            ("qwen3.context_length", &context_length),
+            ("tokenizer.chat_template", &template),
+            ("qwen3.block_count", &block_count),
+            ("qwen3.attention.head_count", &head_count),
+            ("qwen3.attention.head_count_kv", &head_count_kv),
+            ("qwen3.embedding_length", &embedding_length),
+        ];
+
+        let weight = Tensor::zeros((4, 256), DType::F32, &Device::Cpu)?;
+        let attn_q = QTensor::quantize(&weight, GgmlDType::Q4K)?;
+        let attn_k = QTensor::quantize(&weight, GgmlDType::Q4K)?;
+        let norm = QTensor::quantize(&weight.narrow(0, 0, 1)?, GgmlDType::F32)?;
+        let tensors = [
+            ("blk.0.attn_q.weight", &attn_q),
+            ("blk.0.attn_k.weight", &attn_k),
+            ("output_norm.weight", &norm),
+        ];
+
+        let mut buf = Cursor::new(vec![]);
+        gguf_file::write(&mut buf, &metadata, &tensors)?;
+        buf.set_position(0);
+        let ct = Content::read(&mut buf)?;
+        let info = GgufInfo::from_content(&ct);
+        assert_eq!(gguf_num_layers(&ct)?, 36);
+        // 缺少元数据时报错, 不按张量名猜测
+        let mut buf = Cursor::new(vec![]);
+        gguf_file::write(&mut buf, &[("general.architecture", &arch)], &tensors)?;
+        buf.set_position(0);
+        assert!(gguf_num_layers(&Content::read(&mut buf)?).is_err());
+
+        assert_eq!(info.architecture.as_deref(), Some("qwen3"));
+        assert_eq!(info.quantization.as_deref(), Some("Q4K"));
+        assert_eq!(info.tensor_count, 3);
+        assert_eq!(info.parameter_count, 2 * 4 * 256 + 256);
+        // Q4K 每 256 个元素 144 字节, F32 每个元素 4 字节
+        assert_eq!(info.total_bytes, 2 * 4 * 144 + 256 * 4);
+
+        let quant = Quantization::from_gguf(&info).unwrap();
+        assert_eq!(quant.dtype, "Q4K");
+        assert!(quant.is_quantized());
+        // 4.5 比特的 Q4K 权重与少量 32 比特的 F32 张量
+        let expected = (2 * 4 * 144 + 256 * 4) as f64 * 8. / (2 * 4 * 256 + 256) as f64;
+        assert!((quant.bits_per_weight - expected).abs() < 1e-9);
+        assert!(!Quantization::from_dtype(DType::BF16).is_quantized());
+        assert_eq!(info.context_length, Some(40960));
+        assert_eq!(info.chat_template.as_deref(), Some("{{ messages }}"));
+
+        // 缺少 key_length 时 head_dim 由 embedding_length 推出
+        let mut model_info = ModelInfo {
+            id: None,
+            arch: None,
+            context_length: None,
+            vocab_size: 0,
+            quantization: None,
+            dtype: Some(DType::F32),
+            kv_dims: info.kv_dims,
+        };
+        assert_eq!(
+            model_info.estimate_kv_cache_bytes(1024),
+            Some(2 * 36 * 8 * 128 * 1024 * 4)
+        );
+        model_info.dtype = None;
+        assert_eq!(model_info.estimate_kv_cache_bytes(1024), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_introspection() -> Result<()> {
+        let config: Qwen3Config = serde_json::from_value(json!({
+            "vocab_size": 16,
+            "hidden_size": 8,
+            "intermediate_size": 16,
+            "num_hidden_layers": 3,
+            "num_attention_heads": 2,
+            "head_dim": 4,
+            "attention_bias": false,
+            "num_key_value_heads": 1,
+            "max_position_embeddings": 32,
+            "sliding_window": null,
+            "max_window_layers": 3,
+            "tie_word_embeddings": false,
+            "rope_theta": 10000.0,
+            "rms_norm_eps": 1e-6,
+            "use_sliding_window": false,
+            "hidden_act": "silu",
+        }))?;
+        let vb = VarBuilder::zeros(DType::F32, &Device::Cpu);
+        let model: Box<dyn ModelInference> = Box::new(LoadedModel::new(
+            Qwen3Model::new(&config, vb)?,
+            config.num_hidden_layers,
+        ));
+
+        assert_eq!(model.arch_name(), "qwen3");
+        assert_eq!(model.num_layers(), 3);
+        // 复制出的实例保留结构信息
+        assert_eq!(model.fork()?.num_layers(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let config = InferenceConfig::builder()
+            .temperature(0.7)
+            .top_p(0.9)
+            .sample_len(512)
+            .chat_template("{{ messages }}")
+            .device(Device::Cpu)
+            .build()?;
+
+        assert_eq!(config.temperature, 0.7);
+        assert_eq!(config.top_p, Some(0.9));
+        assert_eq!(config.sample_len, 512);
+        assert!(config.device.is_cpu());
+        assert_eq!(config.chat_template.as_deref(), Some("{{ messages }}"));
+        // 未设置的字段保持默认值
+        assert_eq!(config.seed, InferenceConfig::default().seed);
+
+        assert!(InferenceConfig::builder().top_p(1.5).build().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_temperature_schedule() -> Result<()> {
+        let config = InferenceConfig::builder()
+            .temperature(0.8)
+            .temperature_schedule(TemperatureSchedule::Linear {
+                start: 1.2,
+                end: 0.4,
+            })
+            .sample_len(5)
+            .build()?;
+
+        assert_eq!(config.temperature_at(0), 1.2);
+        assert!((config.temperature_at(2) - 0.8).abs() < 1e-9);
+        assert_eq!(config.temperature_at(4), 0.4);
+        // 超出 sample_len 时保持终点温度
+        assert_eq!(config.temperature_at(10), 0.4);
+
+        let config = InferenceConfig {
+            temperature_schedule: Some(TemperatureSchedule::Constant(0.5)),
+            ..config
+        };
+        assert_eq!(config.temperature_at(3), 0.5);
+        assert_eq!(InferenceConfig::default().temperature_at(3), 0.8);
+
+        let parsed: InferenceConfig =
+            toml::from_str("temperature_schedule = { linear = { start = 1.0, end = 0.2 } }")?;
+        assert_eq!(
+            parsed.temperature_schedule,
+            Some(TemperatureSchedule::Linear {
+                start: 1.,
+                end: 0.2
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_serde() -> Result<()> {
+        let config = InferenceConfig::builder()
+            .temperature(0.3)
+            .top_p(0.9)
+            .token_timeout(Duration::from_secs(5))
+            .device(Device::Cpu)
+            .build()?;
+
+        let toml_str = toml::to_string(&config)?;
+        assert!(toml_str.contains(r#"device = "cpu""#));
+
+        let parsed: InferenceConfig = toml::from_str(&toml_str)?;
+        assert_eq!(parsed.temperature, 0.3);
+        assert_eq!(parsed.top_p, Some(0.9));
+        assert_eq!(parsed.token_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(parsed.sample_len, config.sample_len);
+        assert!(parsed.device.is_cpu());
+
+        // 未配置的字段使用默认值
+        let parsed: InferenceConfig = toml::from_str(r#"temperature = 0.0"#)?;
+        assert_eq!(parsed.temperature, 0.);
+        assert_eq!(parsed.seed, InferenceConfig::default().seed);
+        assert_eq!(parsed.decode_strategy, DecodeStrategy::Sampling);
+
+        let parsed: InferenceConfig =
+            toml::from_str(r#"decode_strategy = { beam = { width = 4 } }"#)?;
+        assert_eq!(parsed.decode_strategy, DecodeStrategy::Beam { width: 4 });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_token_redacted() -> Result<()> {
+        let parsed: InferenceConfig = toml::from_str(r#"hf_token = "hf_secret""#)?;
+        assert_eq!(
+            parsed.hf_token.as_ref().map(Secret::expose),
+            Some("hf_secret")
+        );
+        assert!(!format!("{parsed:?}").contains("hf_secret"));
+        assert!(!toml::to_string(&parsed)?.contains("hf_secret"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_config() -> Result<()> {
+        assert_eq!("cpu".parse::<DeviceConfig>()?, DeviceConfig::Cpu);
+        assert_eq!("cuda:1".parse::<DeviceConfig>()?, DeviceConfig::Cuda(1));
+        assert_eq!("metal".parse::<DeviceConfig>()?, DeviceConfig::Metal(0));
+        assert!("tpu:0".parse::<DeviceConfig>().is_err());
+        assert_eq!(DeviceConfig::Cuda(0).to_string(), "cuda:0");
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "flash-attn"))]
+    #[test]
+    fn test_flash_attn_fallback() {
+        let config = InferenceConfig {
+            use_flash_attn: true,
+            device: Device::Cpu,
+            ..Default::default()
+        };
+        assert!(!config.flash_attn_enabled());
+    }
+
+    #[cfg(feature = "flash-attn")]
+    #[tokio::test]
+    async fn test_flash_attn_load() -> Result<()> {
+        let config = InferenceConfig::builder()
+            .use_flash_attn(true)
+            .device(Device::new_cuda(0)?)
+            .build()?;
+        assert!(config.flash_attn_enabled());
+
+        let registry = ModelRegistry::new()?;
+        let hub_info = registry.get("gemma.2b_base")?;
+        ModelLoader::load_with_config(&HubClient::from_env()?, hub_info, &config).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_tied_embeddings() -> Result<()> {
+        let device = Device::Cpu;
+        let tensor = |name: &str| -> Result<(String, Tensor)> {
+            Ok((name.to_string(), Tensor::zeros((4, 2), DType::F32, &device)?))
+        };
+
+        // 仅有词嵌入时回退为共享权重
+        let tensors = HashMap::from([tensor("model.embed_tokens.weight")?]);
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+        let mut config = json!({"tie_word_embeddings": false});
+        ModelLoader::resolve_tied_embeddings(&mut config, &vb)?;
+        assert_eq!(config["tie_word_embeddings"], true);
+
+        // 存在独立 lm_head 时保持原配置
+        let tensors = HashMap::from([
+            tensor("model.embed_tokens.weight")?,
+            tensor("lm_head.weight")?,
+        ]);
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+        let mut config = json!({"tie_word_embeddings": false});
+        ModelLoader::resolve_tied_embeddings(&mut config, &vb)?;
+        assert_eq!(config["tie_word_embeddings"], false);
+
+        // 两者都不存在时报错
+        let vb = VarBuilder::from_tensors(HashMap::new(), DType::F32, &device);
+        assert!(ModelLoader::resolve_tied_embeddings(&mut config, &vb).is_err());
+
+        Ok(())
+    }
+
+    /// 只响应仓库信息请求的本地 Hub, 每个请求的请求行通过通道发出
+    fn mock_info_hub(siblings: Value) -> Result<(String, std::sync::mpsc::Receiver<String>)> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let endpoint = serve(move |headers, stream| {
+            let _ = tx.send(headers[0].clone());
+            if headers[0].contains("/api/models/") {
+                let info = json!({"sha": "0000000", "siblings": siblings});
+                write_response(stream, "200 OK", info.to_string().as_bytes());
+            } else {
+                write_response(stream, "404 Not Found", b"");
+            }
+        })?;
+        Ok((endpoint, rx))
+    }
+
+    #[tokio::test]
+    async fn test_confirm_download() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-confirm-download");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let hub_info = |model_repo: &str, model_file: &str| HubInfo {
+            model_repo: model_repo.to_string(),
+            model_file: model_file.to_string(),
+            model_file_pattern: None,
+            tokenizer_repo: "Mock/Tokenizer".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
+            default: false,
+        };
+
+        for (siblings, hub_info, expected) in [
+            // GGUF 分片按合并后的文件统计, 其他量化文件不计入
+            (
+                json!([
+                    {"rfilename": "model-Q4_K_M-00001-of-00002.gguf", "size": 100},
+                    {"rfilename": "model-Q4_K_M-00002-of-00002.gguf", "size": 50},
+                    {"rfilename": "model-Q8_0.gguf", "size": 300},
+                ]),
+                hub_info("Mock/Model-GGUF", "model-Q4_K_M.gguf"),
+                150,
+            ),
+            (
+                json!([
+                    {"rfilename": "config.json", "size": 1},
+                    {"rfilename": "model.safetensors", "size": 70},
+                ]),
+                hub_info("Mock/Model", "model.safetensors"),
+                70,
+            ),
+        ] {
+            let (endpoint, requests) = mock_info_hub(siblings)?;
+            let asked = Arc::new(Mutex::new(vec![]));
+            let hub = HubClient::builder()
+                .endpoint(endpoint)
+                .cache_dir(&cache_dir)
+                .progress(false)
+                .confirm_download({
+                    let asked = asked.clone();
+                    move |bytes| {
+                        asked.lock().unwrap().push(bytes);
+                        false
+                    }
+                })
+                .build()?;
+
+            let loaded = ModelLoader::load(&hub, &hub_info, &Device::Cpu).await;
+            assert!(matches!(loaded.err(), Some(LlmError::Cancelled)));
+            assert_eq!(*asked.lock().unwrap(), [expected]);
+            // 多设备加载同样询问
+            if !ModelLoader::is_gguf(&hub_info) {
+                let loaded = ModelLoader::load_sharded(&hub, &hub_info, &[Device::Cpu]).await;
+                assert!(matches!(loaded.err(), Some(LlmError::Cancelled)));
+                assert_eq!(*asked.lock().unwrap(), [expected, expected]);
+            }
+            // 只查询了文件列表, 没有下载任何文件
+            let requests: Vec<_> = requests.try_iter().collect();
+            assert!(
+                requests.iter().all(|r| !r.contains("/resolve/")),
+                "{requests:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_candle_mistral_gguf() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join(format!("mistral-gguf-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let snapshot = |repo: &str| -> Result<PathBuf> {
+            let repo = hf_hub::Cache::new(cache_dir.clone()).model(repo.to_string());
+            repo.create_ref("0000000")?;
+            let snapshot = repo.pointer_path("0000000");
+            std::fs::create_dir_all(&snapshot)?;
+            Ok(snapshot)
+        };
+
+        // 原仓库提供 config.json 与分词器
+        let (vocab, hidden, intermediate) = (4, 8, 16);
+        let tokenizer_dir = snapshot("Mock/Mistral")?;
+        let config = json!({
+            "model_type": "mistral",
+            "vocab_size": vocab,
+            "hidden_size": hidden,
+            "intermediate_size": intermediate,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "num_key_value_heads": 1,
+            "hidden_act": "silu",
+            "max_position_embeddings": 32,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.,
+            "sliding_window": null,
+        });
+        std::fs::write(tokenizer_dir.join("config.json"), config.to_string())?;
+        let tokenizer = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"<unk>": 0, "a": 1, "b": 2, "</s>": 3},
+                "unk_token": "<unk>",
+            },
+        });
+        std::fs::write(tokenizer_dir.join("tokenizer.json"), tokenizer.to_string())?;
+
+        // candle 导出的 GGUF 没有 general.architecture, 张量名与 safetensors 相同
+        let device = Device::Cpu;
+        let q = |shape: &[usize]| -> Result<QTensor> {
+            let t = Tensor::ones(shape, DType::F32, &device)?.affine(0.01, 0.)?;
+            Ok(QTensor::quantize(&t, GgmlDType::F32)?)
+        };
+        let head_dim = hidden / 2;
+        let layer = [
+            ("input_layernorm", vec![hidden]),
+            ("post_attention_layernorm", vec![hidden]),
+            ("self_attn.q_proj", vec![hidden, hidden]),
+            ("self_attn.k_proj", vec![head_dim, hidden]),
+            ("self_attn.v_proj", vec![head_dim, hidden]),
+            ("self_attn.o_proj", vec![hidden, hidden]),
+            ("mlp.gate_proj", vec![intermediate, hidden]),
+            ("mlp.up_proj", vec![intermediate, hidden]),
+            ("mlp.down_proj", vec![hidden, intermediate]),
+        ]
+        .map(|(name, shape)| (format!("model.layers.0.{name}"), shape));
+        let shapes = [
+            ("model.embed_tokens".to_string(), vec![vocab, hidden]),
+            ("model.norm".to_string(), vec![hidden]),
+            ("lm_head".to_string(), vec![vocab, hidden]),
+        ];
+        let tensors = shapes
+            .into_iter()
+            .chain(layer)
+            .map(|(name, shape)| Ok((format!("{name}.weight"), q(&shape)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let tensors: Vec<_> = tensors.iter().map(|(name, t)| (name.as_str(), t)).collect();
+        let model_dir = snapshot("Mock/Mistral-GGUF")?;
+        let mut file = std::fs::File::create(model_dir.join("model-Q4_K_M.gguf"))?;
+        gguf_file::write(&mut file, &[], &tensors)?;
+
+        let hub = HubClient::builder()
+            .cache_dir(&cache_dir)
+            .offline(true)
+            .progress(false)
+            .build()?;
+        let hub_info = HubInfo {
+            model_repo: "Mock/Mistral-GGUF".to_string(),
+            model_file: "model-Q4_K_M.gguf".to_string(),
+            model_file_pattern: None,
+            tokenizer_repo: "Mock/Mistral".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
+            default: false,
+        };
+        let (mut model, tokenizer) = ModelLoader::load(&hub, &hub_info, &device).await?;
+        assert_eq!(model.arch_name(), "mistral");
+        assert_eq!(model.num_layers(), 1);
+        assert_eq!(tokenizer.get_vocab_size(false), vocab);
+
+        // 生成一个 token
+        let input = Tensor::new(&[1u32, 2], &device)?.unsqueeze(0)?;
+        let logits = model.forward(&input, 0)?.squeeze(0)?;
+        assert_eq!(logits.dims(), [vocab]);
+        let next = logits.argmax(0)?.to_scalar::<u32>()?;
+        assert!((next as usize) < vocab);
+
+        std::fs::remove_dir_all(cache_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gguf_rope_scaling() -> Result<()> {
+        let hub = HubClient::builder()
+            .cache_dir(std::env::temp_dir().join("candle-llm-chat-gguf-rope-scaling"))
+            .offline(true)
+            .progress(false)
+            .build()?;
+        let hub_info = HubInfo {
+            model_repo: "Mock/Model-GGUF".to_string(),
+            model_file: "model-Q4_K_M.gguf".to_string(),
+            model_file_pattern: None,
+            tokenizer_repo: "Mock/Tokenizer".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
+            default: false,
+        };
+        let config = InferenceConfig {
+            rope_scaling: Some(RopeScaling {
+                rope_type: crate::model::rope::RopeType::Linear,
+                factor: 2.,
+                original_max_position_embeddings: None,
+            }),
+            ..Default::default()
+        };
+
+        // 报错而不是忽略缩放继续加载
+        let err = ModelLoader::load_with_config(&hub, &hub_info, &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, LlmError::InvalidConfig(_)), "{err:#}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_adapter_unsupported() -> Result<()> {
+        let hub = HubClient::builder()
+            .cache_dir(std::env::temp_dir().join("candle-llm-chat-adapter-unsupported"))
+            .offline(true)
+            .progress(false)
+            .build()?;
+        let hub_info = |model_repo: &str, model_file: &str| HubInfo {
+            model_repo: model_repo.to_string(),
+            model_file: model_file.to_string(),
+            model_file_pattern: None,
+            tokenizer_repo: "Mock/Tokenizer".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: Some("Mock/Adapter".to_string()),
+            alias: vec![],
+            default: false,
+        };
+
+        // 无法应用适配器时报错, 而不是加载基础模型
+        let gguf = hub_info("Mock/Model-GGUF", "model-Q4_K_M.gguf");
+        let err = ModelLoader::load(&hub, &gguf, &Device::Cpu)
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("Mock/Adapter"), "{err:#}");
+
+        let safetensors = hub_info("Mock/Model", "model.safetensors");
+        let err = ModelLoader::load_sharded(&hub, &safetensors, &[Device::Cpu])
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("Mock/Adapter"), "{err:#}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_quant() -> Result<()> {
+        const GB: usize = 1_000_000_000;
+        let (endpoint, _) = mock_info_hub(json!([
+            {"rfilename": "model-Q4_K_M.gguf", "size": 5 * GB},
+            {"rfilename": "model-Q8_0-00001-of-00002.gguf", "size": 4 * GB},
+            {"rfilename": "model-Q8_0-00002-of-00002.gguf", "size": 4 * GB + GB / 2},
+            {"rfilename": "model-BF16.gguf", "size": 16 * GB},
+        ]))?;
+        let hub = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(std::env::temp_dir().join("candle-llm-chat-auto-quant"))
+            .progress(false)
+            .build()?;
+        let hub_info = HubInfo {
+            model_repo: "Mock/Model-GGUF".to_string(),
+            model_file: "model-Q4_K_M.gguf".to_string(),
+            model_file_pattern: None,
+            tokenizer_repo: "Mock/Tokenizer".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
+            default: false,
+        };
+        let select = |free| ModelLoader::auto_quant_with_free(&hub, &hub_info, free);
+
+        // 分片按合并后的文件计, 8.5GB 放得进 12GB 并留有余量
+        let selected = select(Some(12 * GB)).await;
+        assert_eq!(selected.model_file, "model-Q8_0.gguf");
+        assert_eq!(selected.model_repo, hub_info.model_repo);
+        assert_eq!(select(Some(24 * GB)).await.model_file, "model-BF16.gguf");
+
+        // 放不下或无法查询时使用配置的文件
+        assert_eq!(select(Some(GB)).await.model_file, hub_info.model_file);
+        assert_eq!(select(None).await.model_file, hub_info.model_file);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_model_file() -> Result<()> {
+        let (endpoint, requests) = mock_info_hub(json!([
+            {"rfilename": "model-Q4_K_M.gguf", "size": 100},
+            {"rfilename": "model-Q8_0.gguf", "size": 200},
+        ]))?;
+        let asked = Arc::new(Mutex::new(vec![]));
+        let hub = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(std::env::temp_dir().join("candle-llm-chat-resolve-model-file"))
+            .progress(false)
+            .confirm_download({
+                let asked = asked.clone();
+                move |bytes| {
+                    asked.lock().unwrap().push(bytes);
+                    false
+                }
+            })
+            .build()?;
+        let hub_info = HubInfo {
+            model_repo: "Mock/Model-GGUF".to_string(),
+            model_file: "model.gguf".to_string(),
+            model_file_pattern: Some("*Q8_0*".to_string()),
+            tokenizer_repo: "Mock/Tokenizer".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
+            default: false,
+        };
+
+        // 加载与查询都使用通配符匹配到的文件
+        let loaded = ModelLoader::load(&hub, &hub_info, &Device::Cpu).await;
+        assert!(matches!(loaded.err(), Some(LlmError::Cancelled)));
+        assert_eq!(*asked.lock().unwrap(), [200]);
+        assert!(ModelLoader::weights_bytes(&hub, &hub_info).await.is_err());
+        let requests: Vec<_> = requests.try_iter().collect();
+        assert!(
+            requests.iter().any(|r| r.contains("model-Q8_0.gguf")),
+            "{requests:?}"
+        );
+        assert!(
+            requests.iter().all(|r| !r.contains("/model.gguf")),
+            "{requests:?}"
+        );
+
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_model_loader_load() -> Result<()> {
         let device = Device::cuda_if_available(0)?;
         let registry = ModelRegistry::new()?;
+        let hub = HubClient::from_env()?;
 
         // 测试加载 GGUF 量化模型
-        assert!(ModelLoader::load(registry.get("qwen3.4b_q4")?, &device).await.is_ok());
+        assert!(
+            ModelLoader::load(&hub, registry.get("qwen3.4b_q4")?, &device)
+                .await
+                .is_ok()
+        );
 
         // 测试加载 Safetensors 完整模型
-        assert!(ModelLoader::load(registry.get("qwen3.4b_base")?, &device).await.is_ok());
+        assert!(
+            ModelLoader::load(&hub, registry.get("qwen3.4b_base")?, &device)
+                .await
+                .is_ok()
+        );
+
+        // 测试加载 Qwen2 GGUF 量化模型
+        assert!(
+            ModelLoader::load(&hub, registry.get("qwen2.3b_q4")?, &device)
+                .await
+                .is_ok()
+        );
+
+        // 测试加载 Gemma-2 Safetensors 模型
+        assert!(
+            ModelLoader::load(&hub, registry.get("gemma.2b_base")?, &device)
+                .await
+                .is_ok()
+        );
+
+        // 测试加载 Mistral GGUF 量化模型
+        assert!(
+            ModelLoader::load(&hub, registry.get("mistral.7b_q4")?, &device)
+                .await
+                .is_ok()
+        );
+
+        // 测试加载 Phi-3 GGUF 量化模型
+        assert!(
+            ModelLoader::load(&hub, registry.get("phi3.mini_q4")?, &device)
+                .await
+                .is_ok()
+        );
 
         // 测试加载不存在的模型
         assert!(
-            ModelLoader::load(registry.get("nonexistent_model")?, &device)
+            ModelLoader::load(&hub, registry.get("nonexistent_model")?, &device)
                 .await
                 .is_err()
         );
@@ -1,9 +1,11 @@
+use crate::error::LlmError;
+use crate::utils::load::DEFAULT_REVISION;
 use anyhow::Result;
 use candle::quantized::gguf_file::Content;
 use derive_new::new;
-use hf_hub::api::tokio::ApiBuilder;
 use serde::Deserialize;
-use std::{default, path::PathBuf};
+use serde_json::Value;
+use std::{default, path::PathBuf, str::FromStr};
 use strum::{Display, EnumString};
 use tokenizers::Tokenizer;
 
@@ -21,7 +23,20 @@ pub struct HubInfoRaw {
     pub model_repo: String,
     #[serde_inline_default("model.safetensors".to_string())]
     pub model_file: String,
+    /// 文件名通配符, 如 `"Qwen3-4B-Q4_K_M*.gguf"`, 加载前在仓库文件中解析为唯一的 `model_file`
+    pub model_file_pattern: Option<String>,
     pub tokenizer_repo: Option<String>,
+    /// 模型仓库的版本 (分支、标签或 commit), 未设置时为 main
+    pub revision: Option<String>,
+    /// 分词器仓库的版本, 未设置时沿用提供 tokenizer_repo 的变体的版本
+    pub tokenizer_revision: Option<String>,
+    /// 分词器文件名, 未设置时依次尝试 tokenizer.json 与 tokenizer.model
+    pub tokenizer_file: Option<String>,
+    /// PEFT 格式的 LoRA 适配器仓库, 加载 Safetensors 模型时合并到基座权重
+    pub adapter_repo: Option<String>,
+    /// 变体的别名, 如 `alias = ["latest", "4b"]`, 查找时与变体名一样不区分大小写
+    #[serde(default)]
+    pub alias: Vec<String>,
     #[serde(default)]
     pub default: bool,
 }
@@ -31,16 +46,39 @@ pub struct HubInfoRaw {
 pub struct HubInfo {
     pub model_repo: String,
     pub model_file: String,
+    pub model_file_pattern: Option<String>,
     pub tokenizer_repo: String,
+    pub revision: String,
+    pub tokenizer_revision: String,
+    pub tokenizer_file: Option<String>,
+    pub adapter_repo: Option<String>,
+    pub alias: Vec<String>,
     pub default: bool,
 }
 
 impl From<HubInfoRaw> for HubInfo {
     fn from(raw: HubInfoRaw) -> Self {
+        let revision = raw.revision.unwrap_or_else(|| DEFAULT_REVISION.to_string());
+        let same_repo = raw
+            .tokenizer_repo
+            .as_ref()
+            .is_none_or(|repo| *repo == raw.model_repo);
+        let tokenizer_revision = match raw.tokenizer_revision {
+            Some(revision) => revision,
+            // 分词器来自模型仓库时与模型使用同一版本
+            None if same_repo => revision.clone(),
+            None => DEFAULT_REVISION.to_string(),
+        };
         Self {
             model_repo: raw.model_repo.clone(),
             model_file: raw.model_file,
+            model_file_pattern: raw.model_file_pattern,
             tokenizer_repo: raw.tokenizer_repo.unwrap_or(raw.model_repo),
+            revision,
+            tokenizer_revision,
+            tokenizer_file: raw.tokenizer_file,
+            adapter_repo: raw.adapter_repo,
+            alias: raw.alias,
             default: raw.default,
         }
     }
@@ -49,8 +87,35 @@ impl From<HubInfoRaw> for HubInfo {
 #[derive(Debug, Clone, EnumString, Display)]
 #[strum(serialize_all = "lowercase")]
 pub enum ModelArch {
+    Qwen2,
     Qwen3,
     Llama,
+    /// Gemma-2, config.json 中 model_type 为 gemma2
+    #[strum(to_string = "gemma", serialize = "gemma2")]
+    Gemma,
+    Mistral,
+    Phi3,
+}
+
+impl ModelArch {
+    /// 从 GGUF 元数据 `general.architecture` 识别模型架构
+    pub fn from_gguf(ct: &Content) -> Result<Self> {
+        let arch = ct
+            .metadata
+            .get("general.architecture")
+            .ok_or_else(|| anyhow!("general.architecture not found in gguf metadata"))?
+            .to_string()?;
+        Ok(Self::from_str(arch).map_err(|_| LlmError::ArchUnsupported(arch.to_string()))?)
+    }
+
+    /// 从 config.json 的 `model_type` 字段识别模型架构
+    pub fn from_config(config: &Value) -> Result<Self> {
+        let arch = config
+            .get("model_type")
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| anyhow!("model_type not found in config.json"))?;
+        Ok(Self::from_str(arch).map_err(|_| LlmError::ArchUnsupported(arch.to_string()))?)
+    }
 }
 
 #[cfg(test)]
@@ -82,13 +147,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_arch_from_config() -> Result<()> {
+        let config: Value = serde_json::from_str(r#"{"model_type": "qwen2"}"#)?;
+        assert!(matches!(ModelArch::from_config(&config)?, ModelArch::Qwen2));
+
+        let config: Value = serde_json::from_str(r#"{"model_type": "gemma2"}"#)?;
+        assert!(matches!(ModelArch::from_config(&config)?, ModelArch::Gemma));
+
+        let config: Value = serde_json::from_str(r#"{"model_type": "unknown"}"#)?;
+        assert!(ModelArch::from_config(&config).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_hub_info_conversion() -> Result<()> {
         // 测试 HubInfoRaw 到 HubInfo 的转换
         let raw = HubInfoRaw {
             model_repo: "Qwen/Qwen3-8B".to_string(),
             model_file: "model.safetensors".to_string(),
+            model_file_pattern: None,
             tokenizer_repo: None, // 测试自动填充
+            revision: Some("abc1234".to_string()),
+            tokenizer_revision: None,
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
             default: true,
         };
 
@@ -97,6 +182,9 @@ mod tests {
         assert_eq!(hub_info.model_repo, "Qwen/Qwen3-8B");
         assert_eq!(hub_info.model_file, "model.safetensors");
         assert_eq!(hub_info.tokenizer_repo, "Qwen/Qwen3-8B"); // 自动填充
+        // 分词器与模型同仓库, 沿用模型的版本
+        assert_eq!(hub_info.revision, "abc1234");
+        assert_eq!(hub_info.tokenizer_revision, "abc1234");
         assert!(hub_info.default);
 
         Ok(())
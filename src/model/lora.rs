@@ -0,0 +1,191 @@
+//! 加载 PEFT 格式的 LoRA 适配器, 并在加载时合并到基座模型的 Safetensors 权重
+//!
+//! 合并后的权重为 `W + scale * B @ A`, 推理时与普通模型没有区别
+
+use crate::utils::load::{HubClient, confirm_safetensors_download};
+use anyhow::{Result, bail};
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// adapter_config.json 中合并需要的字段
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    #[serde(default)]
+    pub use_rslora: bool,
+}
+
+impl LoraConfig {
+    /// 增量的缩放系数 `alpha / r`, rsLoRA 为 `alpha / sqrt(r)`
+    pub fn scale(&self) -> f64 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f64).sqrt()
+        } else {
+            self.lora_alpha / self.r as f64
+        }
+    }
+}
+
+/// LoRA 适配器, 按基座权重名保存对应的 `(A, B)`
+pub struct LoraAdapter {
+    config: LoraConfig,
+    weights: HashMap<String, (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    /// 从仓库下载 adapter_config.json 与 adapter_model.safetensors
+    pub async fn load(hub: &HubClient, repo: &str) -> Result<Self> {
+        let config = hub.get(repo, "adapter_config.json").await?;
+        let config: LoraConfig = serde_json::from_reader(BufReader::new(File::open(config)?))?;
+        confirm_safetensors_download(hub, repo, "adapter_model.safetensors").await?;
+        let weights = hub.get(repo, "adapter_model.safetensors").await?;
+        Self::new(config, candle::safetensors::load(weights, &Device::Cpu)?)
+    }
+
+    /// 权重名形如 `base_model.model.{name}.lora_A.weight`, 对应基座权重 `{name}.weight`
+    pub fn new(config: LoraConfig, tensors: HashMap<String, Tensor>) -> Result<Self> {
+        let mut lora_a = HashMap::new();
+        let mut lora_b = HashMap::new();
+        for (name, tensor) in tensors {
+            let name = name.strip_prefix("base_model.model.").unwrap_or(&name);
+            if let Some(base) = name.strip_suffix(".lora_A.weight") {
+                lora_a.insert(format!("{base}.weight"), tensor);
+            } else if let Some(base) = name.strip_suffix(".lora_B.weight") {
+                lora_b.insert(format!("{base}.weight"), tensor);
+            } else {
+                bail!("unsupported adapter weight {name}, only lora_A/lora_B are supported");
+            }
+        }
+
+        let mut weights = HashMap::new();
+        for (name, a) in lora_a {
+            let Some(b) = lora_b.remove(&name) else {
+                bail!("adapter weight {name} has lora_A but no lora_B");
+            };
+            weights.insert(name, (a, b));
+        }
+        if let Some(name) = lora_b.keys().next() {
+            bail!("adapter weight {name} has lora_B but no lora_A");
+        }
+
+        Ok(Self { config, weights })
+    }
+
+    /// 将增量合并到基座权重, 在 F32 中计算后转回原类型, 非目标权重原样返回
+    pub fn merge(&self, name: &str, weight: &Tensor) -> Result<Tensor> {
+        let Some((a, b)) = self.weights.get(name) else {
+            return Ok(weight.clone());
+        };
+        let device = weight.device();
+        let a = a.to_device(device)?.to_dtype(DType::F32)?;
+        let b = b.to_device(device)?.to_dtype(DType::F32)?;
+        let delta = (b.matmul(&a)? * self.config.scale())?;
+        Ok((weight.to_dtype(DType::F32)? + delta)?.to_dtype(weight.dtype())?)
+    }
+
+    /// 读取基座权重并合并适配器
+    ///
+    /// 合并后的权重需常驻设备内存, 不再按需从映射的文件读取
+    pub fn var_builder<P: AsRef<Path>>(
+        &self,
+        files: &[P],
+        dtype: DType,
+        device: &Device,
+    ) -> Result<VarBuilder<'static>> {
+        let st = unsafe { MmapedSafetensors::multi(files)? };
+        let names: Vec<String> = st.tensors().into_iter().map(|(name, _)| name).collect();
+        if let Some(name) = self.weights.keys().find(|name| !names.contains(*name)) {
+            bail!("adapter targets {name}, which is not in the base model");
+        }
+
+        let tensors = names
+            .into_iter()
+            .map(|name| {
+                let tensor = self.merge(&name, &st.load(&name, device)?)?;
+                Ok((name, tensor))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(VarBuilder::from_tensors(tensors, dtype, device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Q_PROJ: &str = "model.layers.0.self_attn.q_proj";
+
+    fn adapter(target: &str) -> Result<LoraAdapter> {
+        let device = Device::Cpu;
+        // r = 1, alpha = 2, scale 为 2
+        let a = Tensor::new(&[[1f32, 0., 2.]], &device)?;
+        let b = Tensor::new(&[[1f32], [3.]], &device)?;
+        LoraAdapter::new(
+            LoraConfig {
+                r: 1,
+                lora_alpha: 2.,
+                use_rslora: false,
+            },
+            HashMap::from([
+                (format!("base_model.model.{target}.lora_A.weight"), a),
+                (format!("base_model.model.{target}.lora_B.weight"), b),
+            ]),
+        )
+    }
+
+    #[test]
+    fn test_lora_merge() -> Result<()> {
+        let device = Device::Cpu;
+        let dir = std::env::temp_dir().join(format!("lora-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("model.safetensors");
+        let base = Tensor::arange(0f32, 6., &device)?.reshape((2, 3))?;
+        let norm = Tensor::ones(3, DType::F32, &device)?;
+        candle::safetensors::save(
+            &HashMap::from([
+                (format!("{Q_PROJ}.weight"), base),
+                ("model.norm.weight".to_string(), norm),
+            ]),
+            &path,
+        )?;
+
+        let vb = adapter(Q_PROJ)?.var_builder(&[&path], DType::F32, &device)?;
+        // [[0, 1, 2], [3, 4, 5]] + 2 * [[1, 0, 2], [3, 0, 6]]
+        let merged = vb.get((2, 3), &format!("{Q_PROJ}.weight"))?;
+        assert_eq!(merged.to_vec2::<f32>()?, [[2., 1., 6.], [9., 4., 17.]]);
+        assert_eq!(vb.get(3, "model.norm.weight")?.to_vec1::<f32>()?, [1.; 3]);
+
+        // 目标权重不在基座模型中
+        let err = adapter("model.layers.1.self_attn.q_proj")?
+            .var_builder(&[&path], DType::F32, &device)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not in the base model"), "{err}");
+
+        // 缺少 lora_B
+        let a = Tensor::zeros((1, 3), DType::F32, &device)?;
+        let config = LoraConfig {
+            r: 4,
+            lora_alpha: 8.,
+            use_rslora: true,
+        };
+        assert_eq!(config.scale(), 4.);
+        assert!(
+            LoraAdapter::new(
+                config,
+                HashMap::from([(format!("{Q_PROJ}.lora_A.weight"), a)])
+            )
+            .is_err()
+        );
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,41 +1,198 @@
 use anyhow::Result;
 use candle::quantized::gguf_file::Content;
 use candle::{Device, Tensor};
-use candle_transformers::models::{quantized_llama, quantized_qwen3, qwen3};
+use candle_transformers::models::{
+    gemma2, mistral, phi3, quantized_llama, quantized_mistral, quantized_phi3, quantized_qwen2,
+    quantized_qwen3, qwen2, qwen3,
+};
+use std::any::Any;
 use std::io::{Read, Seek};
 
 pub mod config;
 pub mod hub;
+pub mod lora;
 pub mod registry;
+pub mod rope;
+pub mod sharded_qwen3;
 
 macro_rules! impl_model_traits {
-    ($($model:ty),+ $(,)?) => {
+    (@forward $arch:literal) => {
+        fn forward(
+            &mut self,
+            x: &candle::Tensor,
+            index_pos: usize,
+        ) -> anyhow::Result<candle::Tensor> {
+            self.model.forward(x, index_pos).map_err(anyhow::Error::msg)
+        }
+
+        fn arch_name(&self) -> &'static str {
+            $arch
+        }
+
+        fn num_layers(&self) -> usize {
+            self.num_layers
+        }
+    };
+    (@clear) => {
+        fn clr_kv_cache(&mut self) {
+            self.model.clear_kv_cache();
+        }
+
+        fn fork(&self) -> anyhow::Result<Box<dyn crate::model::ModelInference>> {
+            let mut model = self.clone();
+            model.model.clear_kv_cache();
+            Ok(Box::new(model))
+        }
+    };
+    // 首个 token (index_pos 为 0) 时自动重置 KV 缓存的模型, 无需手动清空
+    (@reset_on_start $($model:ty => $arch:literal),+ $(,)?) => {
         $(
-            impl crate::model::ModelInference for $model {
-                fn forward(
-                    &mut self,
-                    x: &candle::Tensor,
-                    index_pos: usize,
-                ) -> anyhow::Result<candle::Tensor> {
-                    self.forward(x, index_pos).map_err(anyhow::Error::msg)
+            impl crate::model::ModelInference for crate::model::LoadedModel<$model> {
+                impl_model_traits!(@forward $arch);
+
+                fn clr_kv_cache(&mut self) {}
+
+                fn fork(&self) -> anyhow::Result<Box<dyn crate::model::ModelInference>> {
+                    Ok(Box::new(self.clone()))
+                }
+            }
+        )+
+    };
+    // KV 缓存由拼接生成新张量、不会原地修改的模型, 复制模型即得到缓存快照
+    (@snapshot $($model:ty => $arch:literal),+ $(,)?) => {
+        $(
+            impl crate::model::ModelInference for crate::model::LoadedModel<$model> {
+                impl_model_traits!(@forward $arch);
+                impl_model_traits!(@clear);
+
+                fn supports_snapshots(&self) -> bool {
+                    true
+                }
+
+                fn save_cache(&self) -> anyhow::Result<crate::model::CacheSnapshot> {
+                    Ok(crate::model::CacheSnapshot::new(self.clone()))
                 }
 
-                fn clr_kv_cache(&mut self) {
-                    self.clear_kv_cache();
+                fn restore_cache(
+                    &mut self,
+                    snapshot: &crate::model::CacheSnapshot,
+                ) -> anyhow::Result<()> {
+                    *self = snapshot
+                        .downcast_ref::<Self>()
+                        .ok_or_else(|| anyhow!("cache snapshot belongs to another model"))?
+                        .clone();
+                    Ok(())
                 }
             }
         )+
     };
+    ($($model:ty => $arch:literal),+ $(,)?) => {
+        $(
+            impl crate::model::ModelInference for crate::model::LoadedModel<$model> {
+                impl_model_traits!(@forward $arch);
+                impl_model_traits!(@clear);
+            }
+        )+
+    };
 }
 
-pub trait ModelInference {
+/// 加载后的模型及其层数, candle 的模型类型不公开结构信息, 由加载时的配置或 GGUF 元数据提供
+#[derive(Clone)]
+pub struct LoadedModel<M> {
+    model: M,
+    num_layers: usize,
+}
+
+impl<M> LoadedModel<M> {
+    pub fn new(model: M, num_layers: usize) -> Self {
+        Self { model, num_layers }
+    }
+}
+
+/// KV 缓存快照, 由 [`ModelInference::save_cache`] 生成, 仅能恢复到同类型模型
+pub struct CacheSnapshot(Box<dyn Any + Send>);
+
+impl CacheSnapshot {
+    pub fn new<T: Any + Send>(state: T) -> Self {
+        Self(Box::new(state))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+}
+
+pub trait ModelInference: Send {
     fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor>;
 
     fn clr_kv_cache(&mut self);
+
+    /// 模型实现对应的架构名, 如 `qwen3`; llama.cpp 导出的 Mistral GGUF 按 `llama` 实现加载
+    fn arch_name(&self) -> &'static str;
+
+    /// 解码层数
+    fn num_layers(&self) -> usize;
+
+    /// 是否接受图像输入, 目前支持的架构都只处理文本
+    fn supports_images(&self) -> bool {
+        false
+    }
+
+    /// 复制一个共享权重、拥有独立 KV 缓存的模型实例
+    fn fork(&self) -> Result<Box<dyn ModelInference>> {
+        bail!("model does not support sharing weights across sessions")
+    }
+
+    /// 是否实现了 [`save_cache`](Self::save_cache) 与 [`restore_cache`](Self::restore_cache)
+    fn supports_snapshots(&self) -> bool {
+        false
+    }
+
+    /// 保存当前 KV 缓存的快照
+    fn save_cache(&self) -> Result<CacheSnapshot> {
+        bail!("model does not support kv cache snapshots")
+    }
+
+    /// 将 KV 缓存恢复到快照时的状态
+    fn restore_cache(&mut self, snapshot: &CacheSnapshot) -> Result<()> {
+        bail!("model does not support kv cache snapshots")
+    }
 }
 
 impl_model_traits!(
-    // quantized_llama::ModelWeights,
-    quantized_qwen3::ModelWeights,
-    qwen3::ModelForCausalLM
+    @snapshot
+    quantized_qwen3::ModelWeights => "qwen3",
+    qwen3::ModelForCausalLM => "qwen3",
+    sharded_qwen3::ModelForCausalLM => "qwen3",
 );
+
+impl_model_traits!(
+    qwen2::ModelForCausalLM => "qwen2",
+    gemma2::Model => "gemma",
+    mistral::Model => "mistral",
+    quantized_mistral::Model => "mistral",
+    phi3::Model => "phi3",
+);
+
+impl_model_traits!(
+    @reset_on_start
+    quantized_llama::ModelWeights => "llama",
+    quantized_phi3::ModelWeights => "phi3",
+);
+
+// quantized_qwen2 未实现 Clone, 无法在会话间共享权重
+impl ModelInference for LoadedModel<quantized_qwen2::ModelWeights> {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.model.forward(x, index_pos).map_err(anyhow::Error::msg)
+    }
+
+    fn clr_kv_cache(&mut self) {}
+
+    fn arch_name(&self) -> &'static str {
+        "qwen2"
+    }
+
+    fn num_layers(&self) -> usize {
+        self.num_layers
+    }
+}
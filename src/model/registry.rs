@@ -1,3 +1,5 @@
+use crate::error::LlmError;
+use crate::model::config::{InferenceConfig, ModelLoader};
 use crate::model::hub::{HubInfo, HubInfoRaw, ModelArch};
 use anyhow::{Error, Result};
 use config::Config;
@@ -6,14 +8,26 @@ use std::{collections::HashMap, str::FromStr};
 
 #[derive(Debug, Deserialize)]
 pub struct ModelRegistryRaw {
+    pub qwen2: Option<HashMap<String, HubInfoRaw>>,
     pub qwen3: HashMap<String, HubInfoRaw>,
     pub llama: Option<HashMap<String, HubInfoRaw>>,
+    pub gemma: Option<HashMap<String, HubInfoRaw>>,
+    pub mistral: Option<HashMap<String, HubInfoRaw>>,
+    pub phi3: Option<HashMap<String, HubInfoRaw>>,
+    #[serde(default)]
+    pub presets: HashMap<String, InferenceConfig>,
 }
 
 #[derive(Debug)]
 pub struct ModelRegistry {
+    pub qwen2: Option<HashMap<String, HubInfo>>,
     pub qwen3: HashMap<String, HubInfo>,
     pub llama: Option<HashMap<String, HubInfo>>,
+    pub gemma: Option<HashMap<String, HubInfo>>,
+    pub mistral: Option<HashMap<String, HubInfo>>,
+    pub phi3: Option<HashMap<String, HubInfo>>,
+    /// 命名的推理参数预设
+    pub presets: HashMap<String, InferenceConfig>,
 }
 
 impl ModelRegistry {
@@ -25,7 +39,76 @@ impl ModelRegistry {
             .map_err(Error::from)?;
 
         // 处理 tokenizer_repo 的自动填充并转换为最终结构
-        Ok(Self::from_raw(raw_registry))
+        let registry = Self::from_raw(raw_registry);
+        registry.validate()?;
+        Ok(registry)
+    }
+
+    /// 检查所有模型与预设, 一次列出全部问题
+    ///
+    /// 检查 `model_repo` 非空、GGUF 变体能找到分词器仓库、每个架构至多一个默认模型
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        for (arch, models) in self.archs() {
+            let mut variants: Vec<_> = models.iter().collect();
+            variants.sort_by_key(|(variant, _)| variant.as_str());
+
+            for (variant, hub_info) in &variants {
+                if hub_info.model_repo.trim().is_empty() {
+                    problems.push(format!("{arch}.{variant}: model_repo is empty"));
+                }
+                // GGUF 仓库通常不带分词器, 未找到对应的 _base 变体时回退到了 model_repo
+                if ModelLoader::is_gguf(hub_info) && hub_info.tokenizer_repo == hub_info.model_repo
+                {
+                    problems.push(format!(
+                        "{arch}.{variant}: tokenizer_repo is not set and there is no matching _base variant to inherit it from"
+                    ));
+                }
+            }
+
+            let defaults: Vec<_> = variants
+                .iter()
+                .filter(|(_, hub_info)| hub_info.default)
+                .map(|(variant, _)| format!("{arch}.{variant}"))
+                .collect();
+            if defaults.len() > 1 {
+                problems.push(format!(
+                    "{arch}: multiple default variants: {}",
+                    defaults.join(", ")
+                ));
+            }
+        }
+
+        let mut presets: Vec<_> = self.presets.iter().collect();
+        presets.sort_by_key(|(name, _)| name.as_str());
+        for (name, preset) in presets {
+            if let Err(e) = preset.validate() {
+                problems.push(format!("presets.{name}: {e}"));
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!(
+                "models.toml has {} problem(s):\n- {}",
+                problems.len(),
+                problems.join("\n- ")
+            );
+        }
+        Ok(())
+    }
+
+    /// 已配置的各架构及其模型
+    fn archs(&self) -> impl Iterator<Item = (ModelArch, &HashMap<String, HubInfo>)> {
+        [
+            (ModelArch::Qwen2, self.qwen2.as_ref()),
+            (ModelArch::Qwen3, Some(&self.qwen3)),
+            (ModelArch::Llama, self.llama.as_ref()),
+            (ModelArch::Gemma, self.gemma.as_ref()),
+            (ModelArch::Mistral, self.mistral.as_ref()),
+            (ModelArch::Phi3, self.phi3.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(arch, models)| Some((arch, models?)))
     }
 
     /// 从原始配置转换为最终配置
@@ -36,15 +119,30 @@ impl ModelRegistry {
             .map(|(k, v)| (k, HubInfo::from(v)))
             .collect();
 
-        // 处理 llama 系列
-        let llama = raw.llama.map(|mut llama_models| {
-            Self::fill_arch_tokenizer_repos(&mut llama_models);
-            llama_models.into_iter()
-                .map(|(k, v)| (k, HubInfo::from(v)))
-                .collect()
-        });
+        // 处理可选系列
+        let qwen2 = raw.qwen2.map(Self::convert_arch);
+        let llama = raw.llama.map(Self::convert_arch);
+        let gemma = raw.gemma.map(Self::convert_arch);
+        let mistral = raw.mistral.map(Self::convert_arch);
+        let phi3 = raw.phi3.map(Self::convert_arch);
 
-        Self { qwen3, llama }
+        Self {
+            qwen2,
+            qwen3,
+            llama,
+            gemma,
+            mistral,
+            phi3,
+            presets: raw.presets,
+        }
+    }
+
+    /// 填充 tokenizer_repo 并转换单个架构的模型配置
+    fn convert_arch(mut models: HashMap<String, HubInfoRaw>) -> HashMap<String, HubInfo> {
+        Self::fill_arch_tokenizer_repos(&mut models);
+        models.into_iter()
+            .map(|(k, v)| (k, HubInfo::from(v)))
+            .collect()
     }
 
     /// 为特定架构的模型填充 tokenizer_repo
@@ -57,10 +155,15 @@ impl ModelRegistry {
                 if hub_info.tokenizer_repo.is_none() {
                     hub_info.tokenizer_repo = Some(hub_info.model_repo.clone());
                 }
-                // 记录 base 模型的 tokenizer_repo 供其他变体使用
+                // 记录 base 模型的 tokenizer_repo 及其版本供其他变体使用
                 if let Some(ref tokenizer_repo) = hub_info.tokenizer_repo {
                     let base_key = variant_name.strip_suffix("_base").unwrap();
-                    base_tokenizers.insert(base_key.to_string(), tokenizer_repo.clone());
+                    let revision = hub_info.tokenizer_revision.clone().or_else(|| {
+                        let same_repo = *tokenizer_repo == hub_info.model_repo;
+                        hub_info.revision.clone().filter(|_| same_repo)
+                    });
+                    base_tokenizers
+                        .insert(base_key.to_string(), (tokenizer_repo.clone(), revision));
                 }
             }
         }
@@ -68,21 +171,30 @@ impl ModelRegistry {
         // 第二步：为非 base 模型设置 tokenizer_repo
         for (variant_name, hub_info) in models.iter_mut() {
             if !variant_name.ends_with("_base") && hub_info.tokenizer_repo.is_none() {
-                // 提取基础名称（如 "8b_q4" -> "8b"）
-                let base_name = if let Some(pos) = variant_name.rfind('_') {
-                    &variant_name[..pos]
-                } else {
-                    variant_name
-                };
-                
-                // 查找对应的 base 模型的 tokenizer_repo
-                if let Some(tokenizer_repo) = base_tokenizers.get(base_name) {
+                // 先去掉量化后缀（如 "8b_deepseek_r1_q4_k_m" -> "8b_deepseek_r1"）,
+                // 找不到时逐段缩短（"8b_deepseek" -> "8b"）
+                let base =
+                    base_names(variant_name).find_map(|base_name| base_tokenizers.get(base_name));
+                if let Some((tokenizer_repo, revision)) = base {
                     hub_info.tokenizer_repo = Some(tokenizer_repo.clone());
+                    if hub_info.tokenizer_revision.is_none() {
+                        hub_info.tokenizer_revision = revision.clone();
+                    }
                 }
             }
         }
     }
 
+    /// 获取命名的推理参数预设
+    pub fn preset(&self, name: &str) -> Result<&InferenceConfig> {
+        let preset = self
+            .presets
+            .get(name)
+            .ok_or_else(|| anyhow!("推理预设 '{}' 不存在", name))?;
+        preset.validate()?;
+        Ok(preset)
+    }
+
     /// 获取模型配置
     ///
     /// # 参数
@@ -98,33 +210,122 @@ impl ModelRegistry {
     /// let official = registry.get("qwen3.8b_full")?;  // 官方模型
     /// let default = registry.get("qwen3")?;           // 默认模型
     /// ```
-    pub fn get(&self, model_id: &str) -> Result<&HubInfo> {
+    pub fn get(&self, model_id: &str) -> Result<&HubInfo, LlmError> {
         let (arch_str, variant) = match model_id.split_once('.') {
             Some((arch, variant)) => (arch, Some(variant)),
             None => (model_id, None),
         };
 
-        let models = match ModelArch::from_str(arch_str)? {
+        let arch = ModelArch::from_str(&arch_str.to_lowercase())
+            .map_err(|_| LlmError::ArchUnsupported(arch_str.to_string()))?;
+        let not_found = || LlmError::ModelNotFound(model_id.to_string());
+
+        let models = match arch {
+            ModelArch::Qwen2 => self.qwen2.as_ref().ok_or_else(not_found)?,
             ModelArch::Qwen3 => &self.qwen3,
-            ModelArch::Llama => self
-                .llama
-                .as_ref()
-                .ok_or_else(|| anyhow!("Llama 模型未配置"))?,
-            _ => bail!("不支持的模型架构: {}", arch_str),
+            ModelArch::Llama => self.llama.as_ref().ok_or_else(not_found)?,
+            ModelArch::Gemma => self.gemma.as_ref().ok_or_else(not_found)?,
+            ModelArch::Mistral => self.mistral.as_ref().ok_or_else(not_found)?,
+            ModelArch::Phi3 => self.phi3.as_ref().ok_or_else(not_found)?,
         };
 
         match variant {
-            Some(variant) => models
-                .get(variant)
-                .ok_or_else(|| anyhow!("模型变体 '{}' 不存在", variant)),
-            None => models
-                .values()
-                .find(|config| config.default)
-                .ok_or_else(|| anyhow!("架构 '{}' 没有默认模型", arch_str)),
+            Some(variant) => Self::find_variant(models, variant)?.ok_or_else(not_found),
+            None => Self::default_variant(arch_str, models).unwrap_or_else(|| Err(not_found())),
+        }
+    }
+
+    /// 按变体名或别名查找, 不区分大小写, 完全匹配的变体名优先
+    fn find_variant<'a>(
+        models: &'a HashMap<String, HubInfo>,
+        name: &str,
+    ) -> Result<Option<&'a HubInfo>, LlmError> {
+        if let Some(hub_info) = models.get(name) {
+            return Ok(Some(hub_info));
+        }
+
+        let mut matches: Vec<_> = models
+            .iter()
+            .filter(|(variant, hub_info)| {
+                variant.eq_ignore_ascii_case(name)
+                    || hub_info
+                        .alias
+                        .iter()
+                        .any(|alias| alias.eq_ignore_ascii_case(name))
+            })
+            .collect();
+        match matches.len() {
+            0 | 1 => Ok(matches.pop().map(|(_, hub_info)| hub_info)),
+            _ => {
+                let mut variants: Vec<_> = matches
+                    .iter()
+                    .map(|(variant, _)| variant.as_str())
+                    .collect();
+                variants.sort();
+                Err(LlmError::InvalidConfig(anyhow!(
+                    "{name} matches multiple variants: {}",
+                    variants.join(", ")
+                )))
+            }
+        }
+    }
+
+    /// 架构的默认模型, 未标记 `default` 时唯一的变体即为默认
+    ///
+    /// 没有变体时返回 `None`, 有多个变体且均未标记时报错并列出可选的变体
+    fn default_variant<'a>(
+        arch: &str,
+        models: &'a HashMap<String, HubInfo>,
+    ) -> Option<Result<&'a HubInfo, LlmError>> {
+        if let Some(hub_info) = models.values().find(|hub_info| hub_info.default) {
+            return Some(Ok(hub_info));
+        }
+        match models.len() {
+            0 => None,
+            1 => models.values().next().map(Ok),
+            _ => {
+                let mut names: Vec<_> =
+                    models.keys().map(|name| format!("{arch}.{name}")).collect();
+                names.sort();
+                Some(Err(LlmError::InvalidConfig(anyhow!(
+                    "no default and multiple variants for {arch}; specify one of: {}",
+                    names.join(", ")
+                ))))
+            }
         }
     }
 }
 
+/// 变体名对应的候选基础名称, 由长到短
+fn base_names(variant: &str) -> impl Iterator<Item = &str> {
+    let segments: Vec<_> = variant.split('_').collect();
+    // 第一个量化段之后的部分都属于量化后缀
+    let len = segments
+        .iter()
+        .skip(1)
+        .position(|segment| is_quant_segment(segment))
+        .map_or(segments.len(), |pos| pos + 1);
+    let base = segments[..len].join("_").len();
+
+    std::iter::successors(Some(&variant[..base]), |&name| {
+        name.rfind('_').map(|pos| &name[..pos])
+    })
+}
+
+/// 量化标记, 如 q4、q8、iq3、f16、bf16、int4
+fn is_quant_segment(segment: &str) -> bool {
+    let segment = segment.to_lowercase();
+    let digits = segment
+        .strip_prefix("iq")
+        .or_else(|| segment.strip_prefix('q'))
+        .or_else(|| segment.strip_prefix("int"));
+    digits.is_some_and(|d| d.starts_with(|c: char| c.is_ascii_digit()))
+        || matches!(
+            segment.as_str(),
+            "f16" | "bf16" | "fp16" | "f32" | "awq" | "gptq"
+        )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,6 +358,10 @@ mod tests {
         assert_eq!(default_qwen3.model_repo, "Qwen/Qwen3-4B-Instruct-2507");
         assert!(default_qwen3.default);
 
+        // 测试获取 Qwen2 默认模型
+        let default_qwen2 = registry.get("qwen2")?;
+        assert_eq!(default_qwen2.model_repo, "Qwen/Qwen2.5-3B-Instruct");
+
         // 测试不存在的架构
         assert!(registry.get("unknown").is_err());
 
@@ -166,6 +371,181 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_model_errors() -> Result<()> {
+        let registry = ModelRegistry::new()?;
+
+        assert!(matches!(
+            registry.get("unknown.7b"),
+            Err(LlmError::ArchUnsupported(arch)) if arch == "unknown"
+        ));
+        assert!(matches!(
+            registry.get("qwen3.NonExistent"),
+            Err(LlmError::ModelNotFound(id)) if id == "qwen3.NonExistent"
+        ));
+
+        Ok(())
+    }
+
+    fn registry_from_str(toml: &str) -> Result<ModelRegistry> {
+        let raw: ModelRegistryRaw = Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()?
+            .try_deserialize()?;
+        Ok(ModelRegistry::from_raw(raw))
+    }
+
+    #[test]
+    fn test_default_variant() -> Result<()> {
+        // 唯一的变体即为默认
+        let registry = registry_from_str(
+            r#"
+            [qwen3.4b_base]
+            model_repo = "Qwen/Qwen3-4B-Instruct-2507"
+            "#,
+        )?;
+        assert_eq!(
+            registry.get("qwen3")?.model_repo,
+            "Qwen/Qwen3-4B-Instruct-2507"
+        );
+
+        // 多个变体且均未标记时列出可选的变体
+        let registry = registry_from_str(
+            r#"
+            [qwen3.8b_base]
+            model_repo = "Qwen/Qwen3-8B"
+
+            [qwen3.4b_base]
+            model_repo = "Qwen/Qwen3-4B-Instruct-2507"
+            "#,
+        )?;
+        let err = registry.get("qwen3").unwrap_err();
+        assert!(matches!(err, LlmError::InvalidConfig(_)), "{err}");
+        assert!(
+            err.to_string()
+                .contains("specify one of: qwen3.4b_base, qwen3.8b_base"),
+            "{err}"
+        );
+        assert_eq!(registry.get("qwen3.8b_base")?.model_repo, "Qwen/Qwen3-8B");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_and_alias() -> Result<()> {
+        let registry = registry_from_str(
+            r#"
+            [qwen3.4b_base]
+            model_repo = "Qwen/Qwen3-4B-Instruct-2507"
+            alias = ["latest"]
+            default = true
+
+            [qwen3.4b_q4]
+            model_repo = "byteshape/Qwen3-4B-Instruct-2507-GGUF"
+            model_file = "Qwen3-4B-Instruct-2507-Q4_K_S-3.66bpw.gguf"
+            alias = ["4b", "small"]
+
+            [qwen3.8b_base]
+            model_repo = "Qwen/Qwen3-8B"
+            alias = ["4B"]
+            "#,
+        )?;
+
+        let q4 = "byteshape/Qwen3-4B-Instruct-2507-GGUF";
+        assert_eq!(registry.get("Qwen3.4B_Q4")?.model_repo, q4);
+        assert_eq!(
+            registry.get("QWEN3")?.model_repo,
+            "Qwen/Qwen3-4B-Instruct-2507"
+        );
+        assert_eq!(registry.get("qwen3.small")?.model_repo, q4);
+        assert_eq!(
+            registry.get("qwen3.Latest")?.model_repo,
+            "Qwen/Qwen3-4B-Instruct-2507"
+        );
+
+        // 别名冲突时报错而不是任选一个
+        let err = registry.get("qwen3.4b").unwrap_err();
+        assert!(err.to_string().contains("4b_q4, 8b_base"), "{err}");
+        assert!(matches!(
+            registry.get("qwen3.tiny"),
+            Err(LlmError::ModelNotFound(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        ModelRegistry::new()?.validate()?;
+
+        let registry = registry_from_str(
+            r#"
+            [qwen3.4b_base]
+            model_repo = "Qwen/Qwen3-4B-Instruct-2507"
+            default = true
+
+            [qwen3.8b_q4]
+            model_repo = "Qwen/Qwen3-8B-GGUF"
+            model_file = "Qwen3-8B-Q4_K_M.gguf"
+            default = true
+
+            [qwen2.3b_base]
+            model_repo = ""
+
+            [presets.broken]
+            top_p = 2.0
+            "#,
+        )?;
+        let err = registry.validate().unwrap_err().to_string();
+        assert!(err.contains("4 problem(s)"), "{err}");
+        assert!(err.contains("qwen2.3b_base: model_repo is empty"), "{err}");
+        assert!(
+            err.contains("qwen3.8b_q4: tokenizer_repo is not set"),
+            "{err}"
+        );
+        assert!(
+            err.contains("qwen3: multiple default variants: qwen3.4b_base, qwen3.8b_q4"),
+            "{err}"
+        );
+        assert!(err.contains("presets.broken: top_p"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_presets() -> Result<()> {
+        let raw: ModelRegistryRaw = Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [qwen3.4b_base]
+                model_repo = "Qwen/Qwen3-4B-Instruct-2507"
+                default = true
+
+                [presets.creative]
+                temperature = 1.2
+                top_p = 0.9
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()?
+            .try_deserialize()?;
+        let registry = ModelRegistry::from_raw(raw);
+
+        let creative = registry.preset("creative")?;
+        assert_eq!(creative.temperature, 1.2);
+        assert_eq!(creative.top_p, Some(0.9));
+        // 未配置的字段使用默认值
+        assert_eq!(creative.seed, InferenceConfig::default().seed);
+
+        assert!(registry.preset("unknown").is_err());
+
+        // models.toml 中的预设
+        let registry = ModelRegistry::new()?;
+        assert_eq!(registry.preset("precise")?.temperature, 0.2);
+
+        Ok(())
+    }
+
     #[test]
     fn test_tokenizer_repo_auto_fill() -> Result<()> {
         let registry = ModelRegistry::new()?;
@@ -196,6 +576,69 @@ mod tests {
             }
         }
 
+        // 未固定版本时为 main, 继承的 tokenizer_repo 沿用 base 模型的版本
+        assert_eq!(q4_model.revision, "main");
+        assert_eq!(q4_model.tokenizer_revision, "main");
+        let registry = registry_from_str(
+            r#"
+            [qwen3.8b_base]
+            model_repo = "Qwen/Qwen3-8B"
+            revision = "b968826"
+            default = true
+
+            [qwen3.8b_q4]
+            model_repo = "Qwen/Qwen3-8B-GGUF"
+            model_file = "Qwen3-8B-Q4_K_M.gguf"
+            revision = "v1.0"
+        "#,
+        )?;
+        let q4 = registry.get("qwen3.8b_q4")?;
+        assert_eq!(q4.revision, "v1.0");
+        assert_eq!(q4.tokenizer_revision, "b968826");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_base_names() -> Result<()> {
+        let names = |variant| base_names(variant).collect::<Vec<_>>();
+        assert_eq!(names("8b_q4"), ["8b"]);
+        assert_eq!(names("8b_q4_k_m"), ["8b"]);
+        assert_eq!(
+            names("8b_deepseek_r1_q4"),
+            ["8b_deepseek_r1", "8b_deepseek", "8b"]
+        );
+        assert_eq!(names("7b_iq3_xs"), ["7b"]);
+        assert_eq!(names("4b_abliterated"), ["4b_abliterated", "4b"]);
+
+        let registry = registry_from_str(
+            r#"
+            [qwen3.8b_base]
+            model_repo = "Qwen/Qwen3-8B"
+            default = true
+
+            [qwen3.8b_deepseek_r1_q4]
+            model_repo = "unsloth/DeepSeek-R1-0528-Qwen3-8B-GGUF"
+            model_file = "DeepSeek-R1-0528-Qwen3-8B-Q4_K_M.gguf"
+
+            [qwen3.8b_deepseek_r1_base]
+            model_repo = "deepseek-ai/DeepSeek-R1-0528-Qwen3-8B"
+
+            [qwen3.8b_uncensored_q8_0]
+            model_repo = "mradermacher/Qwen3-8B-uncensored-GGUF"
+            "#,
+        )?;
+        // 优先匹配最长的基础名称
+        assert_eq!(
+            registry.get("qwen3.8b_deepseek_r1_q4")?.tokenizer_repo,
+            "deepseek-ai/DeepSeek-R1-0528-Qwen3-8B"
+        );
+        // 没有同名 base 时回退到更短的基础名称
+        assert_eq!(
+            registry.get("qwen3.8b_uncensored_q8_0")?.tokenizer_repo,
+            "Qwen/Qwen3-8B"
+        );
+
         Ok(())
     }
 }
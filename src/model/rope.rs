@@ -0,0 +1,262 @@
+//! RoPE 缩放, 将旋转位置编码拉伸到超出训练长度的上下文
+//!
+//! 字段与 config.json 中的 `rope_scaling` 一致. NTK 只需增大 `rope_theta`, 对所有架构生效;
+//! 线性内插与 YaRN 需改写各维度的频率, candle 的模型不支持, 目前仅 Qwen3 使用本仓库的实现加载
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::f64::consts::PI;
+use std::fmt;
+
+/// YaRN 在外推与内插之间过渡的旋转圈数范围, 与 transformers 的默认值一致
+const YARN_BETA_FAST: f64 = 32.;
+const YARN_BETA_SLOW: f64 = 1.;
+
+/// RoPE 缩放类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RopeType {
+    /// 位置内插: 所有频率除以 `factor`
+    Linear,
+    /// NTK-aware: 增大 `rope_theta`, 高频维度几乎不变, 低频维度被内插;
+    /// transformers 的 `dynamic` 按当前长度调整, 这里按扩展后的长度一次性计算
+    #[serde(alias = "dynamic")]
+    Ntk,
+    /// YaRN: 高频维度外推、低频维度内插, 中间线性过渡, 并放大注意力温度
+    Yarn,
+}
+
+impl fmt::Display for RopeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Linear => "linear",
+            Self::Ntk => "ntk",
+            Self::Yarn => "yarn",
+        })
+    }
+}
+
+/// RoPE 缩放配置, 可直接由 config.json 中的 `rope_scaling` 反序列化
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RopeScaling {
+    /// transformers 旧版本中为 `type`
+    #[serde(alias = "type")]
+    pub rope_type: RopeType,
+    /// 上下文扩展倍数
+    pub factor: f64,
+    /// 训练时的上下文长度, 未设置时取 config.json 中的 `max_position_embeddings`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_max_position_embeddings: Option<usize>,
+}
+
+impl RopeScaling {
+    /// 读取 config.json 中的 `rope_scaling`, 未设置或为 `default` 时为 `None`
+    ///
+    /// 不支持的类型 (如 `llama3`、`longrope`) 交给模型自身处理, 记录警告后忽略
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let value = config.get("rope_scaling").filter(|v| !v.is_null())?;
+        let rope_type = value.get("rope_type").or_else(|| value.get("type"));
+        if rope_type.and_then(|t| t.as_str()) == Some("default") {
+            return None;
+        }
+        match serde_json::from_value(value.clone()) {
+            Ok(scaling) => Some(scaling),
+            Err(e) => {
+                warn!("unsupported rope_scaling {value}, ignoring: {e}");
+                None
+            }
+        }
+    }
+
+    /// 是否需要改写各维度的频率, 否则只需修改 config.json
+    pub fn custom_rope(&self) -> bool {
+        matches!(self.rope_type, RopeType::Linear | RopeType::Yarn)
+    }
+
+    /// 将缩放写入 config.json: `max_position_embeddings` 扩大到 `factor` 倍, NTK 同时增大 `rope_theta`
+    ///
+    /// 返回补全了训练长度的配置, 供 [`inv_freq`](Self::inv_freq) 使用
+    pub fn apply_to_config(&self, config: &mut Value) -> Result<Self> {
+        if self.factor.is_nan() || self.factor < 1. {
+            bail!("rope scaling factor must be >= 1, got {}", self.factor);
+        }
+        let get = |key: &str| config.get(key).and_then(|x| x.as_u64()).map(|x| x as usize);
+        let original = match self.original_max_position_embeddings {
+            Some(original) => original,
+            None => get("max_position_embeddings")
+                .ok_or_else(|| anyhow!("max_position_embeddings not found in config.json"))?,
+        };
+
+        if self.rope_type == RopeType::Ntk {
+            let head_dim = match get("head_dim") {
+                Some(head_dim) => head_dim,
+                None => get("hidden_size")
+                    .zip(get("num_attention_heads"))
+                    .map(|(hidden, heads)| hidden / heads)
+                    .ok_or_else(|| anyhow!("head_dim not found in config.json"))?,
+            };
+            let theta = config
+                .get("rope_theta")
+                .and_then(|x| x.as_f64())
+                .unwrap_or(10000.);
+            let dim = head_dim as f64;
+            config["rope_theta"] = (theta * self.factor.powf(dim / (dim - 2.))).into();
+        }
+        config["max_position_embeddings"] = ((original as f64 * self.factor) as usize).into();
+
+        Ok(Self {
+            original_max_position_embeddings: Some(original),
+            ..*self
+        })
+    }
+
+    /// 缩放后各维度的频率, 以及 cos/sin 需乘上的注意力缩放系数
+    ///
+    /// NTK 已通过 `rope_theta` 写入配置, 这里按原频率计算
+    pub fn inv_freq(&self, head_dim: usize, rope_theta: f64) -> (Vec<f32>, f64) {
+        let dim = head_dim as f64;
+        let base: Vec<f64> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1. / rope_theta.powf(i as f64 / dim))
+            .collect();
+        let to_f32 = |freqs: Vec<f64>| freqs.into_iter().map(|f| f as f32).collect();
+
+        match self.rope_type {
+            RopeType::Ntk => (to_f32(base), 1.),
+            RopeType::Linear => (to_f32(base.iter().map(|f| f / self.factor).collect()), 1.),
+            RopeType::Yarn => {
+                let original = self.original_max_position_embeddings.unwrap_or(0) as f64;
+                // 在训练长度内旋转 `rotations` 圈的维度
+                let correction_dim = |rotations: f64| {
+                    dim * (original / (rotations * 2. * PI)).ln() / (2. * rope_theta.ln())
+                };
+                let low = correction_dim(YARN_BETA_FAST).floor().max(0.);
+                let high = correction_dim(YARN_BETA_SLOW).ceil().min(dim - 1.);
+                let high = if low == high { high + 0.001 } else { high };
+
+                let freqs = base
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| {
+                        // 0 为完全外推 (原频率), 1 为完全内插
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        f * (1. - ramp) + f / self.factor * ramp
+                    })
+                    .collect();
+                let mscale = if self.factor > 1. {
+                    0.1 * self.factor.ln() + 1.
+                } else {
+                    1.
+                };
+                (to_f32(freqs), mscale)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rope_scaling_from_config() -> Result<()> {
+        let config = json!({
+            "rope_scaling": {
+                "rope_type": "yarn",
+                "factor": 4.0,
+                "original_max_position_embeddings": 32768
+            }
+        });
+        let yarn = RopeScaling::from_config(&config).unwrap();
+        assert_eq!(yarn.rope_type, RopeType::Yarn);
+        assert_eq!(yarn.original_max_position_embeddings, Some(32768));
+
+        // 旧版本的 `type` 字段, dynamic 按 NTK 处理
+        let config = json!({"rope_scaling": {"type": "dynamic", "factor": 2.0}});
+        let ntk = RopeScaling::from_config(&config).unwrap();
+        assert_eq!(ntk.rope_type, RopeType::Ntk);
+        assert!(!ntk.custom_rope());
+
+        for rope_scaling in [
+            Value::Null,
+            json!({"rope_type": "default"}),
+            json!({"rope_type": "llama3", "factor": 8.0}),
+        ] {
+            let config = json!({ "rope_scaling": rope_scaling });
+            assert_eq!(RopeScaling::from_config(&config), None);
+        }
+        assert_eq!(RopeScaling::from_config(&json!({})), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_to_config() -> Result<()> {
+        let mut config = json!({
+            "max_position_embeddings": 4096,
+            "hidden_size": 512,
+            "num_attention_heads": 8,
+            "rope_theta": 10000.0
+        });
+        let ntk = RopeScaling {
+            rope_type: RopeType::Ntk,
+            factor: 2.,
+            original_max_position_embeddings: None,
+        };
+        let resolved = ntk.apply_to_config(&mut config)?;
+        assert_eq!(resolved.original_max_position_embeddings, Some(4096));
+        assert_eq!(config["max_position_embeddings"], 8192);
+        // head_dim 为 64, theta * 2^(64/62)
+        let theta = config["rope_theta"].as_f64().unwrap();
+        assert!(
+            (theta - 10000. * 2f64.powf(64. / 62.)).abs() < 1e-6,
+            "{theta}"
+        );
+
+        let linear = RopeScaling {
+            rope_type: RopeType::Linear,
+            factor: 0.5,
+            original_max_position_embeddings: None,
+        };
+        assert!(linear.apply_to_config(&mut config).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inv_freq() {
+        let (base, _) = RopeScaling {
+            rope_type: RopeType::Ntk,
+            factor: 4.,
+            original_max_position_embeddings: None,
+        }
+        .inv_freq(128, 1e6);
+
+        let (linear, mscale) = RopeScaling {
+            rope_type: RopeType::Linear,
+            factor: 4.,
+            original_max_position_embeddings: None,
+        }
+        .inv_freq(128, 1e6);
+        assert_eq!(mscale, 1.);
+        assert!(
+            base.iter()
+                .zip(&linear)
+                .all(|(b, l)| (b / 4. - l).abs() < 1e-9)
+        );
+
+        let (yarn, mscale) = RopeScaling {
+            rope_type: RopeType::Yarn,
+            factor: 4.,
+            original_max_position_embeddings: Some(32768),
+        }
+        .inv_freq(128, 1e6);
+        assert!((mscale - (0.1 * 4f64.ln() + 1.)).abs() < 1e-9);
+        // 高频维度保持原频率, 低频维度内插
+        assert_eq!(yarn[0], base[0]);
+        assert!((yarn[63] - base[63] / 4.).abs() < 1e-12);
+        assert!(yarn.iter().zip(&base).all(|(y, b)| y <= b && *y >= b / 4.));
+    }
+}
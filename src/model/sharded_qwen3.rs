@@ -0,0 +1,509 @@
+//! 按层切分到多个设备的 Qwen3, 用于单张显卡放不下的大模型
+//!
+//! 结构与 `candle_transformers::models::qwen3` 一致, 区别在于每个解码层可位于不同设备,
+//! 隐藏状态在层边界处搬运到下一设备. 词嵌入位于第一个设备, 最终归一化与 lm_head 位于最后一个设备.
+//!
+//! 目前仅 safetensors 格式的 Qwen3 支持多设备加载.
+//!
+//! 频率可按 [`RopeScaling`] 改写, 单设备加载需要线性内插或 YaRN 的 Qwen3 时同样使用此实现.
+
+use crate::model::rope::RopeScaling;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::kv_cache::ConcatKvCache;
+use candle_nn::{Activation, Embedding, VarBuilder};
+use candle_transformers::models::qwen3::Config;
+use candle_transformers::models::with_tracing::{Linear, RmsNorm, linear_b, linear_no_bias};
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(
+        dtype: DType,
+        cfg: &Config,
+        rope_scaling: Option<&RopeScaling>,
+        dev: &Device,
+    ) -> Result<Self> {
+        let dim = cfg.head_dim;
+        let max_seq_len = cfg.max_position_embeddings;
+        let (inv_freq, mscale) = match rope_scaling {
+            Some(scaling) => scaling.inv_freq(dim, cfg.rope_theta),
+            None => {
+                let inv_freq = (0..dim)
+                    .step_by(2)
+                    .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
+                    .collect();
+                (inv_freq, 1.)
+            }
+        };
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: (freqs.sin()? * mscale)?.to_dtype(dtype)?,
+            cos: (freqs.cos()? * mscale)?.to_dtype(dtype)?,
+        })
+    }
+
+    fn apply(&self, q: &Tensor, k: &Tensor, offset: usize) -> Result<(Tensor, Tensor)> {
+        let (_, _, seq_len, _) = q.dims4()?;
+        let cos = self.cos.narrow(0, offset, seq_len)?;
+        let sin = self.sin.narrow(0, offset, seq_len)?;
+        let q_embed = candle_nn::rotary_emb::rope(&q.contiguous()?, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            gate_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let lhs = x.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = x.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    q_norm: RmsNorm,
+    k_norm: RmsNorm,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: ConcatKvCache,
+}
+
+impl Attention {
+    fn new(cfg: &Config, rotary_emb: Arc<RotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        if cfg.use_sliding_window {
+            candle::bail!("sliding window is not supported")
+        }
+
+        let head_dim = cfg.head_dim;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let bias = cfg.attention_bias;
+
+        Ok(Self {
+            q_proj: linear_b(cfg.hidden_size, num_heads * head_dim, bias, vb.pp("q_proj"))?,
+            k_proj: linear_b(
+                cfg.hidden_size,
+                num_kv_heads * head_dim,
+                bias,
+                vb.pp("k_proj"),
+            )?,
+            v_proj: linear_b(
+                cfg.hidden_size,
+                num_kv_heads * head_dim,
+                bias,
+                vb.pp("v_proj"),
+            )?,
+            o_proj: linear_b(num_heads * head_dim, cfg.hidden_size, bias, vb.pp("o_proj"))?,
+            q_norm: RmsNorm::new(head_dim, cfg.rms_norm_eps, vb.pp("q_norm"))?,
+            k_norm: RmsNorm::new(head_dim, cfg.rms_norm_eps, vb.pp("k_norm"))?,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups: num_heads / num_kv_heads,
+            head_dim,
+            // config 中的 hidden_size 不一定与注意力输出维度一致
+            hidden_size: head_dim * num_heads,
+            rotary_emb,
+            kv_cache: ConcatKvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, attn_mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = self.q_norm.forward(&q.flatten(0, 2)?)?.reshape((
+            b,
+            self.num_heads,
+            l,
+            self.head_dim,
+        ))?;
+        let k = self.k_norm.forward(&k.flatten(0, 2)?)?.reshape((
+            b,
+            self.num_kv_heads,
+            l,
+            self.head_dim,
+        ))?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = attn_mask {
+            scores = scores.broadcast_add(m)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+
+        probs
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b, l, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    ln1: RmsNorm,
+    ln2: RmsNorm,
+    device: Device,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, rotary: Arc<RotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(cfg, rotary, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            ln1: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?,
+            ln2: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("post_attention_layernorm"),
+            )?,
+            device: vb.device().clone(),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.ln1.forward(x)?;
+        let h = self.self_attn.forward(&h, mask, offset)?;
+        let x = (x + h)?;
+        let h2 = self.ln2.forward(&x)?.apply(&self.mlp)?;
+        x + h2
+    }
+}
+
+/// 按层切分到多个设备的 Qwen3 因果语言模型
+#[derive(Debug, Clone)]
+pub struct ModelForCausalLM {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    output_device: Device,
+    dtype: DType,
+}
+
+impl ModelForCausalLM {
+    /// `vbs` 为每个设备各一个的 VarBuilder, 解码层按顺序平均分配到各设备
+    pub fn new(cfg: &Config, vbs: &[VarBuilder]) -> Result<Self> {
+        Self::with_rope_scaling(cfg, vbs, None)
+    }
+
+    /// 同 [`new`](Self::new), 按 `rope_scaling` 计算位置编码
+    ///
+    /// `cfg` 应已经过 [`RopeScaling::apply_to_config`], `max_position_embeddings` 为扩展后的长度
+    pub fn with_rope_scaling(
+        cfg: &Config,
+        vbs: &[VarBuilder],
+        rope_scaling: Option<&RopeScaling>,
+    ) -> Result<Self> {
+        let (first, last) = match vbs {
+            [first, .., last] => (first, last),
+            [only] => (only, only),
+            [] => candle::bail!("at least one device is required"),
+        };
+
+        let embed_tokens = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            first.pp("model.embed_tokens"),
+        )?;
+
+        let per_device = cfg.num_hidden_layers.div_ceil(vbs.len());
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for (i, vb) in vbs.iter().enumerate() {
+            let rotary = Arc::new(RotaryEmbedding::new(
+                vb.dtype(),
+                cfg,
+                rope_scaling,
+                vb.device(),
+            )?);
+            let vb_l = vb.pp("model.layers");
+            let end = cfg.num_hidden_layers.min((i + 1) * per_device);
+            for layer_idx in i * per_device..end {
+                layers.push(DecoderLayer::new(cfg, rotary.clone(), vb_l.pp(layer_idx))?);
+            }
+        }
+
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, last.pp("model.norm"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            // 共享词嵌入时在最后一个设备上另存一份, 避免输出时跨设备
+            let weight = last.get(
+                (cfg.vocab_size, cfg.hidden_size),
+                "model.embed_tokens.weight",
+            )?;
+            Linear::from_weights(weight, None)
+        } else {
+            linear_no_bias(cfg.hidden_size, cfg.vocab_size, last.pp("lm_head"))?
+        };
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            output_device: last.device().clone(),
+            dtype: first.dtype(),
+        })
+    }
+
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let input = input.to_device(self.embed_tokens.embeddings().device())?;
+        let mut h = self.embed_tokens.forward(&input)?;
+
+        let mut mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, h.device())?.to_dtype(self.dtype)?)
+        };
+
+        for layer in &mut self.layers {
+            // 层边界处将隐藏状态与掩码搬运到下一设备
+            if !h.device().same_device(&layer.device) {
+                h = h.to_device(&layer.device)?;
+                mask = mask.map(|m| m.to_device(&layer.device)).transpose()?;
+            }
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+
+        let h = h.to_device(&self.output_device)?;
+        self.norm
+            .forward(&h)?
+            .narrow(1, l - 1, 1)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+}
+
+fn causal_mask(b: usize, tgt: usize, offset: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<f32> = (0..tgt)
+        .flat_map(|i| {
+            (0..tgt + offset).map(move |j| {
+                if j <= i + offset {
+                    0.
+                } else {
+                    f32::NEG_INFINITY
+                }
+            })
+        })
+        .collect();
+    Tensor::from_slice(&mask, (b, 1, tgt, tgt + offset), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use candle_nn::VarMap;
+    use candle_transformers::models::qwen3;
+    use std::collections::HashMap;
+
+    fn tiny_config() -> Config {
+        Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            intermediate_size: 16,
+            num_hidden_layers: 3,
+            num_attention_heads: 2,
+            head_dim: 4,
+            attention_bias: false,
+            num_key_value_heads: 1,
+            max_position_embeddings: 32,
+            sliding_window: None,
+            max_window_layers: 3,
+            tie_word_embeddings: false,
+            rope_theta: 10000.,
+            rms_norm_eps: 1e-6,
+            use_sliding_window: false,
+            hidden_act: Activation::Silu,
+        }
+    }
+
+    /// 切分到多个设备后的输出应与单设备的 candle 实现一致
+    #[test]
+    fn test_matches_qwen3() -> Result<()> {
+        let cfg = tiny_config();
+        let varmap = VarMap::new();
+        let mut reference = qwen3::ModelForCausalLM::new(
+            &cfg,
+            VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu),
+        )?;
+
+        let tensors: HashMap<_, _> = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_tensor().clone()))
+            .collect();
+        let vbs: Vec<_> = (0..2)
+            .map(|_| VarBuilder::from_tensors(tensors.clone(), DType::F32, &Device::Cpu))
+            .collect();
+        let mut sharded = ModelForCausalLM::new(&cfg, &vbs)?;
+
+        // 预填充与单步解码均需一致
+        for (input, offset) in [(vec![1u32, 5, 7], 0), (vec![3], 3)] {
+            let input = Tensor::new(input.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+            let expected = reference.forward(&input, offset)?;
+            let actual = sharded.forward(&input, offset)?;
+
+            let diff = (expected - actual)?.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(diff < 1e-5, "diff: {diff}");
+        }
+
+        Ok(())
+    }
+
+    /// 按 YaRN 扩展后可在超出原 `max_position_embeddings` 的位置继续生成
+    #[test]
+    fn test_rope_scaling_beyond_base_max() -> Result<()> {
+        use crate::model::rope::RopeType;
+
+        let base = tiny_config();
+        let mut config = serde_json::json!({
+            "max_position_embeddings": base.max_position_embeddings,
+            "rope_scaling": {"rope_type": "yarn", "factor": 4.0},
+        });
+        let scaling = RopeScaling::from_config(&config)
+            .unwrap()
+            .apply_to_config(&mut config)?;
+        assert_eq!(scaling.rope_type, RopeType::Yarn);
+        let cfg = Config {
+            max_position_embeddings: config["max_position_embeddings"].as_u64().unwrap() as usize,
+            ..base.clone()
+        };
+        assert_eq!(cfg.max_position_embeddings, 128);
+
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut plain = ModelForCausalLM::new(&base, std::slice::from_ref(&vb))?;
+        let mut scaled = ModelForCausalLM::with_rope_scaling(&cfg, &[vb], Some(&scaling))?;
+
+        // 预填充到原上限附近, 之后逐个 token 解码越过原上限
+        let prompt: Vec<u32> = (0..30).map(|i| i % cfg.vocab_size as u32).collect();
+        let prompt = Tensor::new(prompt.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+        scaled.forward(&prompt, 0)?;
+        plain.forward(&prompt, 0)?;
+        for pos in 30..40 {
+            let input = Tensor::new(&[pos as u32 % 16], &Device::Cpu)?.unsqueeze(0)?;
+            let logits = scaled
+                .forward(&input, pos)?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            assert!(logits.iter().all(|x| x.is_finite()), "position {pos}");
+        }
+
+        // 未缩放的模型在原上限处失败
+        let input = Tensor::new(&[1u32], &Device::Cpu)?.unsqueeze(0)?;
+        assert!(plain.forward(&input, base.max_position_embeddings).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layers_split_across_devices() -> Result<()> {
+        let cfg = tiny_config();
+        let varmap = VarMap::new();
+        qwen3::ModelForCausalLM::new(
+            &cfg,
+            VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu),
+        )?;
+
+        // 无两张 CUDA 设备时跳过
+        let (Ok(d0), Ok(d1)) = (Device::new_cuda(0), Device::new_cuda(1)) else {
+            return Ok(());
+        };
+
+        let vbs: Vec<_> = [&d0, &d1]
+            .into_iter()
+            .map(|device| {
+                let tensors: HashMap<_, _> = varmap
+                    .data()
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), v.as_tensor().to_device(device)?)))
+                    .collect::<Result<_>>()?;
+                Ok(VarBuilder::from_tensors(tensors, DType::F32, device))
+            })
+            .collect::<Result<_>>()?;
+        let mut model = ModelForCausalLM::new(&cfg, &vbs)?;
+
+        // 3 层平均分配: 前 2 层位于设备 0, 最后 1 层位于设备 1
+        assert!(model.layers[1].device.same_device(&d0));
+        assert!(model.layers[2].device.same_device(&d1));
+
+        let input = Tensor::new(&[1u32, 5, 7], &d0)?.unsqueeze(0)?;
+        let logits = model.forward(&input, 0)?;
+        assert!(logits.device().same_device(&d1));
+        assert_eq!(logits.dims(), &[1, 1, cfg.vocab_size]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! OpenAI Chat Completions 兼容的请求/响应结构
+
+use crate::model::config::InferenceConfig;
+use crate::pipe::{FinishReason, GenerationStats};
+use crate::utils::chat::{Message, Role};
+use anyhow::Result;
+use futures_core::stream::Stream;
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Chat Completions 请求, 未列出的字段在反序列化时忽略
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u64>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+impl ChatCompletionRequest {
+    /// 以请求中的参数覆盖 `config`, 未指定的参数保持不变
+    pub fn apply_to(&self, config: &InferenceConfig) -> InferenceConfig {
+        InferenceConfig {
+            temperature: self.temperature.unwrap_or(config.temperature),
+            top_p: self.top_p.or(config.top_p),
+            sample_len: self.max_tokens.unwrap_or(config.sample_len),
+            seed: self.seed.unwrap_or(config.seed),
+            frequency_penalty: self.frequency_penalty.unwrap_or(config.frequency_penalty),
+            presence_penalty: self.presence_penalty.unwrap_or(config.presence_penalty),
+            ..config.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Choice {
+    pub index: usize,
+    pub message: Message,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl ChatCompletionResponse {
+    pub fn new(model: &str, content: String, stats: &GenerationStats) -> Self {
+        let (id, created) = new_id();
+        Self {
+            id,
+            object: "chat.completion".to_string(),
+            created,
+            model: model.to_string(),
+            choices: vec![Choice {
+                index: 0,
+                message: Message::new(Role::Assistant, content),
+                finish_reason: Some(finish_reason_str(&stats.stop_reason.clone().into())),
+            }],
+            usage: Usage {
+                prompt_tokens: stats.prompt_tokens,
+                completion_tokens: stats.completion_tokens,
+                total_tokens: stats.prompt_tokens + stats.completion_tokens,
+            },
+        }
+    }
+}
+
+/// 流式响应中的单个片段
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkChoice {
+    pub index: usize,
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// [`TextGeneration::complete`](crate::pipe::TextGeneration::complete) 的结果, 由请求的 `stream` 字段决定
+pub enum Completion<'a> {
+    Response(ChatCompletionResponse),
+    Stream(Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk>> + 'a>>),
+}
+
+/// 同一次流式响应的所有片段共享 id 与创建时间
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkBuilder {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl ChunkBuilder {
+    pub(crate) fn new(model: &str) -> Self {
+        let (id, created) = new_id();
+        Self {
+            id,
+            created,
+            model: model.to_string(),
+        }
+    }
+
+    pub(crate) fn chunk(
+        &self,
+        delta: Delta,
+        finish_reason: Option<&FinishReason>,
+    ) -> ChatCompletionChunk {
+        ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason: finish_reason.map(finish_reason_str),
+            }],
+        }
+    }
+}
+
+fn new_id() -> (String, u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (format!("chatcmpl-{:x}", now.as_nanos()), now.as_secs())
+}
+
+/// OpenAI 只定义了 `stop` 与 `length`, 超时的回答同样被截断, 按 `length` 输出
+fn finish_reason_str(reason: &FinishReason) -> String {
+    match reason {
+        FinishReason::Stop | FinishReason::StopSequence(_) | FinishReason::Cancelled => "stop",
+        FinishReason::Length | FinishReason::Timeout => "length",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::StopReason;
+    use serde_json::{Value, json};
+    use std::time::Duration;
+
+    #[test]
+    fn test_request_deserialize() -> Result<()> {
+        let req: ChatCompletionRequest = serde_json::from_str(
+            r#"{
+                "model": "qwen3",
+                "messages": [
+                    {"role": "system", "content": "You are a helpful assistant."},
+                    {"role": "user", "content": "Hello!"}
+                ],
+                "temperature": 0.2,
+                "max_tokens": 64,
+                "stream": true,
+                "n": 1,
+                "user": "user-1234"
+            }"#,
+        )?;
+
+        assert_eq!(req.messages.len(), 2);
+        assert_eq!(req.messages[0].role, Role::System);
+        assert!(req.stream);
+
+        let config = req.apply_to(&InferenceConfig::default());
+        assert_eq!(config.temperature, 0.2);
+        assert_eq!(config.sample_len, 64);
+        assert_eq!(config.seed, InferenceConfig::default().seed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_json() -> Result<()> {
+        let stats = GenerationStats {
+            prompt_tokens: 10,
+            completion_tokens: 3,
+            elapsed: Duration::from_millis(30),
+            stop_reason: StopReason::EosToken,
+            tool_calls: vec![],
+        };
+        let resp = ChatCompletionResponse::new("qwen3", "Hi!".to_string(), &stats);
+
+        let v: Value = serde_json::to_value(&resp)?;
+        assert_eq!(v["object"], "chat.completion");
+        assert!(v["id"].as_str().unwrap().starts_with("chatcmpl-"));
+        assert_eq!(
+            v["choices"][0],
+            json!({
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi!"},
+                "finish_reason": "stop"
+            })
+        );
+        assert_eq!(v["usage"]["total_tokens"], 13);
+
+        // 只输出 OpenAI 定义的结束原因
+        let reason = FinishReason::from(StopReason::Timeout);
+        assert_eq!(reason, FinishReason::Timeout);
+        assert_eq!(finish_reason_str(&reason), "length");
+        let reason = FinishReason::StopSequence("\n".to_string());
+        assert_eq!(finish_reason_str(&reason), "stop");
+        assert_eq!(finish_reason_str(&FinishReason::Cancelled), "stop");
+
+        let chunk = ChunkBuilder::new("qwen3").chunk(
+            Delta {
+                content: Some("Hi".to_string()),
+                ..Default::default()
+            },
+            None,
+        );
+        let v: Value = serde_json::to_value(&chunk)?;
+        assert_eq!(v["object"], "chat.completion.chunk");
+        assert_eq!(v["choices"][0]["delta"], json!({"content": "Hi"}));
+        assert_eq!(v["choices"][0]["finish_reason"], Value::Null);
+
+        Ok(())
+    }
+}
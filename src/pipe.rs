@@ -1,327 +1,4911 @@
-use crate::model::ModelInference;
-use crate::model::config::{InferenceConfig, ModelLoader};
+use crate::error::{LlmError, is_out_of_memory};
+use crate::model::config::{DecodeStrategy, InferenceConfig, ModelInfo, ModelLoader, Quantization};
 use crate::model::registry::ModelRegistry;
-use crate::utils::chat::ChatContext;
+use crate::model::{CacheSnapshot, ModelInference};
+use crate::openai::{
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, ChunkBuilder, Completion,
+    Delta,
+};
+use crate::utils::bytes::{TokenBytes, incomplete_utf8_len};
+use crate::utils::chat::{ChatContext, Content, ContentPart, PromptTemplate, Role};
+use crate::utils::gen_cache::{CachedGeneration, GenerationCache};
+use crate::utils::load::{HubClient, load_config};
+use crate::utils::memory::{MemoryStats, device_free_bytes, device_used_bytes, kv_bytes_per_token};
+use crate::utils::mirostat::Mirostat;
+use crate::utils::penalty::{
+    allow_tokens, apply_frequency_presence_penalty, apply_typical_p, suppress_tokens,
+};
+use crate::utils::special::SpecialTokenFilter;
+use crate::utils::stop::{StopMatcher, StopRegex};
+use crate::utils::token_stream::TokenOutputStream;
+use crate::utils::tools::{ToolCall, parse_tool_calls};
+use crate::utils::words::WordBuffer;
 use anyhow::{Error, Result};
 use async_stream::try_stream;
-use candle::Tensor;
-use candle_examples::token_output_stream::TokenOutputStream;
+use candle::{D, DType, Tensor};
 use candle_transformers::generation::LogitsProcessor;
 use candle_transformers::utils::apply_repeat_penalty;
 use futures_core::stream::Stream;
-use hf_hub::api::tokio::ApiBuilder;
+use futures_util::{StreamExt, pin_mut};
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fs;
-use tracing::info;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+use tokenizers::{Encoding, Tokenizer};
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+use tracing::{Instrument, Level, field, info, info_span, instrument};
 
-pub struct TextGeneration {
+/// 只加载一次、可在多个会话间共享的模型权重
+pub struct SharedModel {
     model: Box<dyn ModelInference>,
-    tos: TokenOutputStream,
-    logits_processor: LogitsProcessor,
+    tokenizer: Tokenizer,
     ctx: ChatContext,
     infer_conf: InferenceConfig,
     eos_token_id: u32,
+    /// 模型支持的最大上下文长度
+    max_context: Option<usize>,
+    /// 注册表中的模型标识符, 由组件直接构造时为空
+    model_id: Option<String>,
+    /// 生成缓存中标识所加载权重的键, 由组件直接构造时为空, 此时不使用生成缓存
+    weights_id: Option<Value>,
+    /// 模型权重占用的字节数, 由组件直接构造时为 0
+    weights_bytes: usize,
+    /// 每个 token 的 KV 缓存字节数, 缺少模型配置时为空
+    kv_bytes_per_token: Option<usize>,
+    info: ModelInfo,
+    /// 按 `cpu_threads` 创建的前向计算线程池, 各会话共用
+    cpu_pool: Option<Arc<ThreadPool>>,
 }
 
-impl TextGeneration {
-    pub async fn new(model_id: &str, config: InferenceConfig) -> Result<Self> {
-        let registry = ModelRegistry::new()?;
-        let hub_info = registry.get(model_id)?;
-        let (model, tokenizer) = ModelLoader::load(hub_info, &config.device).await?;
+impl SharedModel {
+    /// 加载模型, 按 `config` 中的 token、缓存目录、代理与环境变量创建 Hub 客户端
+    pub async fn new(model_id: &str, config: InferenceConfig) -> Result<Self, LlmError> {
+        let mut builder =
+            HubClient::builder().token(config.hf_token.as_ref().map(|t| t.expose().to_string()));
+        if let Some(cache_dir) = &config.cache_dir {
+            builder = builder.cache_dir(cache_dir);
+        }
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let hub = builder.build().map_err(LlmError::Download)?;
+        Self::with_hub(&hub, model_id, config).await
+    }
 
-        let logits_processor =
-            LogitsProcessor::new(config.seed, Some(config.temperature), config.top_p);
+    /// 使用给定的 Hub 客户端加载模型, 可共用离线模式、缓存目录等配置
+    pub async fn with_hub(
+        hub: &HubClient,
+        model_id: &str,
+        config: InferenceConfig,
+    ) -> Result<Self, LlmError> {
+        config.validate().map_err(LlmError::InvalidConfig)?;
+
+        let registry = ModelRegistry::new().map_err(LlmError::InvalidConfig)?;
+        let hub_info = registry.get(model_id)?;
+        let hub = &hub
+            .clone()
+            .with_revision(&hub_info.model_repo, &hub_info.revision)
+            .with_tokenizer_revision(&hub_info.tokenizer_repo, &hub_info.tokenizer_revision);
+        let mut hub_info = ModelLoader::resolve_model_file(hub, hub_info).await?;
+        if config.auto_quant {
+            hub_info = ModelLoader::auto_quant(hub, &hub_info, &config.device).await;
+        }
+        let (model, tokenizer) = ModelLoader::load_with_config(hub, &hub_info, &config).await?;
+        check_decode_strategy(model.as_ref(), &config).map_err(LlmError::InvalidConfig)?;
+        info!(
+            "loaded {model_id}: {} with {} layers",
+            model.arch_name(),
+            model.num_layers()
+        );
 
-        let ctx = ChatContext::from_repo(&hub_info.tokenizer_repo).await?;
+        let ctx = match &config.chat_template {
+            Some(template) => ChatContext::from_template(template),
+            None => ChatContext::from_repo(&hub.tokenizer(), &hub_info.tokenizer_repo).await,
+        }
+        .map_err(|e| LlmError::from_anyhow(e, LlmError::TokenizerLoad))?;
 
-        let pth = ApiBuilder::from_env()
-            .build()?
-            .model(hub_info.tokenizer_repo.clone())
-            .get("config.json")
-            .await?;
-        let v: Value = serde_json::from_str(&fs::read_to_string(pth)?)?;
+        let mut v = load_config(&hub.tokenizer(), &hub_info.tokenizer_repo)
+            .await
+            .map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))?;
+        // 模型上限为 RoPE 缩放扩展后的长度, GGUF 模型不应用缩放
+        if !ModelLoader::is_gguf(&hub_info) {
+            ModelLoader::apply_rope_scaling(&mut v, config.rope_scaling)
+                .map_err(|e| LlmError::from_anyhow(e, LlmError::ModelLoad))?;
+        }
         let eos_token_id = v
             .get("eos_token_id")
             .and_then(|x| x.as_u64())
-            .ok_or_else(|| anyhow!("eos_token_id not found"))? as u32;
+            .ok_or_else(|| LlmError::TokenizerLoad(anyhow!("eos_token_id not found")))?
+            as u32;
+        let model_max = v
+            .get("max_position_embeddings")
+            .and_then(|x| x.as_u64())
+            .map(|x| x as usize);
+        let max_context = resolve_max_context(model_max, config.max_context)?;
+        let info = ModelInfo {
+            id: Some(model_id.to_string()),
+            ..ModelLoader::model_info(hub, &hub_info, &v, &tokenizer).await?
+        };
+
+        let weights_bytes = ModelLoader::weights_bytes(hub, &hub_info).await?;
+        // 量化模型以 F32 计算, 完整模型以 BF16 加载
+        let kv_dtype = if ModelLoader::is_gguf(&hub_info) {
+            DType::F32
+        } else {
+            DType::BF16
+        };
 
         Ok(Self {
+            max_context,
+            model_id: Some(model_id.to_string()),
+            weights_id: Some(GenerationCache::weights_id(model_id, &hub_info)),
+            weights_bytes,
+            kv_bytes_per_token: kv_bytes_per_token(&v, kv_dtype),
+            info,
+            ..Self::from_parts(model, tokenizer, ctx, config, eos_token_id)
+        })
+    }
+
+    /// 由已加载的组件直接构造
+    pub fn from_parts(
+        model: Box<dyn ModelInference>,
+        tokenizer: Tokenizer,
+        ctx: ChatContext,
+        config: InferenceConfig,
+        eos_token_id: u32,
+    ) -> Self {
+        let cpu_pool = config
+            .build_cpu_pool()
+            .inspect_err(|e| warn!("failed to build the cpu thread pool: {e}"))
+            .ok()
+            .flatten()
+            .map(Arc::new);
+        Self {
             model,
-            tos: TokenOutputStream::new(tokenizer),
-            logits_processor,
+            info: ModelInfo::from_tokenizer(&tokenizer),
+            tokenizer,
             ctx,
+            max_context: config.max_context,
             infer_conf: config,
             eos_token_id,
-        })
+            model_id: None,
+            weights_id: None,
+            weights_bytes: 0,
+            kv_bytes_per_token: None,
+            cpu_pool,
+        }
     }
+}
 
-    /// 便利构造函数 - 使用默认配置
-    pub async fn with_default_config(model_id: &str) -> Result<Self> {
-        Self::new(model_id, InferenceConfig::default()).await
-    }
+/// 束搜索中的一条候选
+struct Beam {
+    tokens: Vec<u32>,
+    /// 累计对数概率
+    score: f32,
+    /// 下一个 token 的对数概率, 已结束的候选为 `None`
+    logprobs: Option<Vec<f32>>,
+    /// 计算 `logprobs` 后的 KV 缓存
+    cache: Option<CacheSnapshot>,
+}
 
-    /// 便利构造函数
-    pub async fn default() -> Result<Self> {
-        Self::with_default_config("qwen3").await
+impl Beam {
+    fn finished(tokens: Vec<u32>, score: f32) -> Self {
+        Self {
+            tokens,
+            score,
+            logprobs: None,
+            cache: None,
+        }
     }
+}
 
-    pub fn chat<'a>(&'a mut self, prompt: &'a str) -> impl Stream<Item = Result<String>> + 'a {
-        let mut answer = String::with_capacity(1024);
-        self.ctx.push_msg(prompt);
-        // 开始新的推理时清空 KV 缓存
-        self.model.clr_kv_cache();
+/// 对数概率最高的 `k` 个 token, 按对数概率降序
+fn top_k(logprobs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut tokens: Vec<(u32, f32)> = logprobs
+        .iter()
+        .enumerate()
+        .map(|(i, &logprob)| (i as u32, logprob))
+        .collect();
+    let cmp = |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1);
+    if k < tokens.len() {
+        tokens.select_nth_unstable_by(k, cmp);
+        tokens.truncate(k);
+    }
+    tokens.sort_by(cmp);
+    tokens
+}
 
-        try_stream!({
-            let prompt = self.ctx.render()?;
-            let mut ctx_tokens = self.str2tokens(&prompt)?;
+/// 生成的 token 及其对数概率
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub id: u32,
+    /// 单独解码该 token 得到的文本
+    pub token: String,
+    pub logprob: f32,
+    /// 对数概率最高的 `top_logprobs` 个候选, 按对数概率降序
+    pub top_logprobs: Vec<TopLogprob>,
+}
 
-            let start = std::time::Instant::now();
-            let ans_start_idx = ctx_tokens.len();
+/// [`TokenLogprob`] 中的一个候选 token
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopLogprob {
+    pub id: u32,
+    pub token: String,
+    pub logprob: f32,
+}
 
-            // 循环生成回答
-            for index in 0..self.infer_conf.sample_len {
-                let next_token = if index == 0 {
-                    self.gen_next_token(&ctx_tokens, 0, None)?
-                } else {
-                    self.gen_next_token(
-                        &ctx_tokens,
-                        ans_start_idx + index - 1,
-                        Some(ans_start_idx),
-                    )?
-                };
-                ctx_tokens.push(next_token);
+/// 以配置覆盖模型的上下文上限, 不能超过模型本身的上限
+fn resolve_max_context(
+    model_max: Option<usize>,
+    config_max: Option<usize>,
+) -> Result<Option<usize>, LlmError> {
+    match (model_max, config_max) {
+        (Some(max), Some(n)) if n > max => Err(LlmError::InvalidConfig(anyhow!(
+            "max_context {n} exceeds the model's maximum {max}"
+        ))),
+        _ => Ok(config_max.or(model_max)),
+    }
+}
 
-                if let Some(t) = self.tos.next_token(next_token)? {
-                    answer.push_str(&t);
-                    yield t;
-                }
+/// 预填充的系统提示词及其 KV 缓存快照
+struct PrefixCache {
+    system_prompt: String,
+    tokens: Vec<u32>,
+    snapshot: CacheSnapshot,
+}
 
-                if next_token == self.eos_token_id {
-                    break;
-                }
-            }
+/// 因达到 `sample_len` 而截断的回答, KV 缓存中保留着除最后一个 token 外的全部上下文
+struct Continuation {
+    ctx_tokens: Vec<u32>,
+    ans_start_idx: usize,
+    /// 完整回答, 含思考过程
+    answer: String,
+}
 
-            if let Some(t) = self.tos.decode_rest()? {
-                answer.push_str(&t);
-                yield t;
-            }
+/// 已编码的对话前缀, 以特殊 token 结尾
+#[derive(Debug, Clone)]
+struct EncodedPrefix {
+    text: String,
+    tokens: Vec<u32>,
+}
 
-            self.ctx.push_msg(&answer);
-            self.tos.clear();
+/// 取出编码结果的 token id
+///
+/// `add_special_tokens` 为 `None` 时, 若分词器添加的首个特殊 token 与模板渲染出的首个 token 相同,
+/// 说明模板已自带 BOS, 去掉重复的一个
+fn encoding_ids(encoding: &Encoding, add_special_tokens: Option<bool>) -> Vec<u32> {
+    let ids = encoding.get_ids();
+    let mask = encoding.get_special_tokens_mask();
+    let duplicated_bos = ids.len() >= 2 && ids[0] == ids[1] && mask[0] == 1 && mask[1] == 0;
 
-            info!(
-                "speed: {:.2} token/s, total tokens: {}",
-                (ctx_tokens.len() - ans_start_idx) as f64 / start.elapsed().as_secs_f64(),
-                ctx_tokens.len()
-            );
-        })
+    if add_special_tokens.is_none() && duplicated_bos {
+        ids[1..].to_vec()
+    } else {
+        ids.to_vec()
     }
+}
 
-    fn str2tokens(&mut self, string: &str) -> Result<Vec<u32>> {
-        let tokens = self
-            .tos
-            .tokenizer()
-            .encode(string, true)
-            .map_err(Error::msg)?;
-        let tokens = tokens.get_ids().to_vec();
+/// 生成结束的原因
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// 生成了 EOS token
+    EosToken,
+    /// 达到 `sample_len`
+    MaxTokens,
+    /// 命中停止序列
+    StopSequence(String),
+    /// 超出 `max_duration`
+    Timeout,
+}
 
-        Ok(tokens)
+/// 与 OpenAI `finish_reason` 对应的结束原因, 便于客户端判断是否需要继续生成
+///
+/// 比 OpenAI 的 `stop`/`length` 更细, 输出到兼容接口时再归并
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// 模型主动结束 (EOS)
+    Stop,
+    /// 达到最大生成长度
+    Length,
+    /// 命中停止序列
+    StopSequence(String),
+    /// 回答流在生成中途被丢弃
+    Cancelled,
+    /// 超出 `max_duration`
+    Timeout,
+}
+
+impl From<StopReason> for FinishReason {
+    fn from(reason: StopReason) -> Self {
+        match reason {
+            StopReason::EosToken => Self::Stop,
+            StopReason::MaxTokens => Self::Length,
+            StopReason::StopSequence(seq) => Self::StopSequence(seq),
+            StopReason::Timeout => Self::Timeout,
+        }
     }
+}
 
-    fn gen_next_token(
-        &mut self,
-        ctx_tokens: &Vec<u32>,
-        idx_pos: usize,
-        ans_start_idx: Option<usize>,
-    ) -> Result<u32> {
-        let input_arr = match ans_start_idx {
-            Some(_) => &[*ctx_tokens.last().unwrap()],
-            None => &**ctx_tokens,
-        };
+/// 一轮生成的统计信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub elapsed: Duration,
+    pub stop_reason: StopReason,
+    /// 回答中解析出的工具调用, 仅在设置了工具时解析
+    pub tool_calls: Vec<ToolCall>,
+}
 
-        let input = Tensor::new(input_arr, &self.infer_conf.device)?.unsqueeze(0)?;
+/// [`TextGeneration::dry_run`] 的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DryRun {
+    /// 渲染后的完整提示词
+    pub rendered_prompt: String,
+    pub prompt_tokens: usize,
+    /// 最多可生成的 token 数, 即 `sample_len` 与剩余上下文长度中的较小者
+    pub estimated_max_tokens: usize,
+}
 
-        // 获取模型输出并压缩维度
-        let mut logits = self
-            .model
-            .forward(&input, idx_pos)?
-            .squeeze(0)?
-            .squeeze(0)?;
+/// [`TextGeneration::benchmark`] 的结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// 首个 token 的延迟, 即预填充提示词并采样出首个 token 的耗时
+    pub ttft: Duration,
+    /// 预填充速度, token/s
+    pub prefill_tps: f64,
+    /// 首个 token 之后逐个解码的速度, token/s; 只生成一个 token 时为 0
+    pub decode_tps: f64,
+    pub total: Duration,
+}
 
-        // 非首个字符应用惩罚
-        if let Some(ans_start_idx) = ans_start_idx {
-            if self.infer_conf.repeat_penalty != 1. {
-                let ans_tokens = &ctx_tokens[ans_start_idx..];
-                let start_at = ans_tokens
-                    .len()
-                    .saturating_sub(self.infer_conf.repeat_last_n);
-                logits = apply_repeat_penalty(
-                    &logits,
-                    self.infer_conf.repeat_penalty,
-                    &ans_tokens[start_at..],
-                )?;
-            }
-        }
+/// [`TextGeneration::generate`] 的输出
+enum Output {
+    /// 助手回答的前缀
+    Prefix(String),
+    /// 新生成的 token
+    Token(u32),
+    /// 在 UTF-8 边界处解码出的文本
+    Text(String),
+}
 
-        // 采样下一个token
-        self.logits_processor.sample(&logits).map_err(Error::msg)
+/// 开启时剔除文本中的特殊 token
+fn filter_special(filter: &mut Option<SpecialTokenFilter>, text: String) -> Option<String> {
+    match filter {
+        Some(filter) => filter.push(&text),
+        None => Some(text),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::model::ModelInference;
-    use crate::pipe::TextGeneration;
-    use crate::utils::chat::ChatContext;
-    use crate::utils::{get_user_prompt, proxy::ProxyGuard};
-    use anyhow::{Error, Result};
-    use candle::Tensor;
-    use candle_transformers::generation::LogitsProcessor;
-    use candle_transformers::utils::apply_repeat_penalty;
-    use futures_util::{StreamExt, pin_mut};
-    use std::io;
-    use std::io::Write;
-    use tokenizers::Tokenizer;
-
-    fn str2tokens(string: &str, tokenizer: &Tokenizer) -> Result<Vec<u32>> {
-        let tokens = tokenizer.encode(string, true).map_err(Error::msg)?;
-        let tokens = tokens.get_ids().to_vec();
-
-        Ok(tokens)
+/// 去掉首个生成的 token 中与提示词末尾被移除部分重复的文本
+fn strip_healed(healed: &mut Option<String>, text: String) -> Option<String> {
+    let Some(prefix) = healed.take() else {
+        return Some(text);
+    };
+    if let Some(rest) = text.strip_prefix(prefix.as_str()) {
+        (!rest.is_empty()).then(|| rest.to_string())
+    } else if let Some(rest) = prefix.strip_prefix(text.as_str()) {
+        // 被拆成多段输出, 继续在下一段中去除
+        *healed = Some(rest.to_string());
+        None
+    } else {
+        Some(text)
     }
+}
 
-    fn gen_next_token(
-        ctx_tokens: &[u32],
-        idx_pos: usize,
-        model: &mut Box<dyn ModelInference>,
-        logits_processor: &mut LogitsProcessor,
-        config: &InferenceConfig,
-        ans_start_idx: Option<usize>,
-    ) -> Result<u32> {
-        let input = match ans_start_idx {
-            Some(_) => Tensor::new(&[*ctx_tokens.last().unwrap()], &config.device)?,
-            None => Tensor::new(ctx_tokens, &config.device)?,
+/// 只保留可直接输出的文本
+fn text_only<'a>(
+    stream: impl Stream<Item = Result<Output>> + 'a,
+) -> impl Stream<Item = Result<String>> + 'a {
+    stream.filter_map(|output| async move {
+        match output {
+            Ok(Output::Prefix(t) | Output::Text(t)) => Some(Ok(t)),
+            Ok(Output::Token(_)) => None,
+            Err(e) => Some(Err(e)),
         }
-        .unsqueeze(0)?;
+    })
+}
 
-        let mut logits = model.forward(&input, idx_pos)?.squeeze(0)?.squeeze(0)?;
+/// 在同步代码中阻塞驱动异步流的运行时
+enum BlockingRuntime {
+    /// 已在多线程 tokio 运行时中, 使用当前运行时
+    #[cfg(feature = "rt-multi-thread")]
+    Current(Handle),
+    /// 不在运行时中, 自建单线程运行时
+    Local(Runtime),
+}
 
-        if let Some(ans_start_idx) = ans_start_idx {
-            if config.repeat_penalty != 1. {
-                let ans_tokens = &ctx_tokens[ans_start_idx..];
-                let start_at = ans_tokens.len().saturating_sub(config.repeat_last_n);
-                logits =
-                    apply_repeat_penalty(&logits, config.repeat_penalty, &ans_tokens[start_at..])?;
+impl BlockingRuntime {
+    /// 在无法阻塞的运行时中返回错误, 而不是在 `block_on` 时 panic
+    fn new() -> Result<Self> {
+        let Ok(handle) = Handle::try_current() else {
+            return Ok(Self::Local(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?,
+            ));
+        };
+        match handle.runtime_flavor() {
+            #[cfg(feature = "rt-multi-thread")]
+            RuntimeFlavor::MultiThread => Ok(Self::Current(handle)),
+            #[cfg(not(feature = "rt-multi-thread"))]
+            RuntimeFlavor::MultiThread => {
+                bail!("blocking inside a tokio runtime needs the rt-multi-thread feature")
             }
+            flavor => bail!("cannot block inside a {flavor:?} tokio runtime, use the async stream"),
         }
+    }
 
-        // 采样下一个token
-        logits_processor.sample(&logits).map_err(Error::msg)
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        match self {
+            // 工作线程中需先让出该线程, 否则 block_on 会 panic
+            #[cfg(feature = "rt-multi-thread")]
+            Self::Current(handle) => tokio::task::block_in_place(|| handle.block_on(future)),
+            Self::Local(runtime) => runtime.block_on(future),
+        }
     }
+}
 
-    #[tokio::test]
-    async fn test_prompt() -> Result<()> {
-        // let _proxy = ProxyGuard::new(7890);
+/// 检查模型能否执行配置的解码策略, 束搜索需要模型支持 KV 缓存快照
+fn check_decode_strategy(model: &dyn ModelInference, config: &InferenceConfig) -> Result<()> {
+    if matches!(config.decode_strategy, DecodeStrategy::Beam { .. }) && !model.supports_snapshots()
+    {
+        bail!(
+            "beam search needs kv cache snapshots, which {} does not support",
+            model.arch_name()
+        );
+    }
+    Ok(())
+}
 
-        let registry = ModelRegistry::new()?;
-        let hub_info = registry.get("qwen3.4b_base")?;
+/// 编译停止正则, 无效时忽略 (已由 [`InferenceConfig::validate`] 检查)
+fn stop_regex(config: &InferenceConfig) -> Option<StopRegex> {
+    let pattern = config.stop_regex.as_deref()?;
+    StopRegex::new(pattern)
+        .inspect_err(|e| warn!("invalid stop_regex {pattern:?}: {e}"))
+        .ok()
+}
 
-        let (mut model, tokenizer) =
-            ModelLoader::load(hub_info, &candle::Device::cuda_if_available(0)?).await?;
-        let config = InferenceConfig::default();
+/// 按名称解析额外的停止 token, 不在词表中的忽略
+fn stop_tokens(tokenizer: &Tokenizer, config: &InferenceConfig) -> Vec<u32> {
+    config
+        .extra_stop_tokens
+        .iter()
+        .filter_map(|name| {
+            let id = tokenizer.token_to_id(name);
+            if id.is_none() {
+                warn!("stop token {name:?} is not in the vocab, ignoring it");
+            }
+            id
+        })
+        .collect()
+}
 
-        // 初始化模型、分词器和logits处理器
-        let mut tos = TokenOutputStream::new(tokenizer);
-        let mut logits_processor =
-            LogitsProcessor::new(config.seed, Some(config.temperature), config.top_p);
-        let mut ctx = ChatContext::from_repo(&hub_info.tokenizer_repo).await?;
+/// 将 `text` 追加到回答中并检查停止正则, 返回 `(可输出的文本, 匹配到的文本)`
+///
+/// 命中时回答截断到匹配开始处, 详见 [`StopMatcher::push`]
+fn push_answer(
+    answer: &mut String,
+    text: String,
+    stop: Option<&mut StopMatcher>,
+    enabled: bool,
+) -> (Option<String>, Option<String>) {
+    match stop {
+        Some(stop) => stop.push(answer, &text, enabled),
+        None => {
+            answer.push_str(&text);
+            (Some(text), None)
+        }
+    }
+}
 
-        let pth = ApiBuilder::from_env()
-            .build()?
-            .model(hub_info.tokenizer_repo.clone())
-            .get("config.json")
-            .await?;
-        let v: Value = serde_json::from_str(&fs::read_to_string(pth)?)?;
-        let eos_token_id = v
-            .get("eos_token_id")
-            .and_then(|x| x.as_u64())
-            .ok_or_else(|| anyhow!("eos_token_id not found"))? as u32;
+/// 输出的文本归入最近生成的 token
+fn attribute_text(token_texts: &mut [String], text: &str) {
+    if let Some(last) = token_texts.last_mut() {
+        last.push_str(text);
+    }
+}
 
-        // 初始化上下文token列表
-        let mut ctx_tokens = vec![];
+/// 按推理参数构建采样器, 随机数状态从 `seed` 开始
+///
+/// 设置温度计划时采样器的温度固定为 1, 每步由调用方按当前温度缩放 logits,
+/// 避免重建采样器重置随机数状态
+fn sampler(config: &InferenceConfig) -> LogitsProcessor {
+    let temperature = match config.temperature_schedule {
+        Some(_) => 1.,
+        None => config.temperature,
+    };
+    LogitsProcessor::new(config.seed, Some(temperature), config.top_p)
+}
 
-        let prompts = vec![
-            "我是snake，你给我记住了",
-            "还记得我是谁吗",
-            "你是谁",
-            "给我笑一笑",
-        ];
-        let mut answer = String::with_capacity(1024);
+/// 已生成的回答 token, 惩罚只作用于这一范围
+///
+/// 解码时输入的是上一步生成的 token, 位于 `idx_pos`; 首个生成的 token 位于 `ans_start_idx`,
+/// 因此提示词的最后一个 token 永远不会落入范围内
+fn answer_tokens(ctx_tokens: &[u32], ans_start_idx: usize, idx_pos: usize) -> &[u32] {
+    debug_assert_eq!(
+        idx_pos + 1,
+        ctx_tokens.len(),
+        "input token is not the last one"
+    );
+    debug_assert!(
+        (ans_start_idx..ctx_tokens.len()).contains(&idx_pos),
+        "answer starts at {ans_start_idx}, after the input token at {idx_pos}"
+    );
+    &ctx_tokens[ans_start_idx..]
+}
 
-        for prompt_str in prompts {
-            ctx.push_msg(prompt_str);
-            let prompt = ctx.render()?;
-            model.clr_kv_cache();
-            ctx_tokens = str2tokens(&prompt, tos.tokenizer())?;
+pub struct TextGeneration {
+    model: Arc<Mutex<Box<dyn ModelInference>>>,
+    /// 供阻塞线程池编码使用, 与 `tos` 共享同一份分词器
+    tokenizer: Arc<Tokenizer>,
+    tos: TokenOutputStream,
+    /// 用于统计 `tos` 中尚未输出的字节
+    token_bytes: TokenBytes,
+    /// 已送入 `tos` 但尚未输出的 token 的字节
+    undecoded: Vec<u8>,
+    logits_processor: LogitsProcessor,
+    /// 使用 Mirostat 解码时本轮的采样状态
+    mirostat: Option<Mirostat>,
+    /// 词元修复时首个 token 的候选
+    healing: Option<Vec<u32>>,
+    /// 上一轮对话提示词中可复用的编码前缀
+    encoded_prefix: Option<EncodedPrefix>,
+    /// 由 `stop_regex` 编译
+    stop_regex: Option<StopRegex>,
+    /// 由 `extra_stop_tokens` 解析, 与 EOS 一样结束回答
+    stop_tokens: Vec<u32>,
+    ctx: ChatContext,
+    infer_conf: InferenceConfig,
+    eos_token_id: u32,
+    max_context: Option<usize>,
+    model_id: Option<String>,
+    weights_id: Option<Value>,
+    prefix_cache: Option<PrefixCache>,
+    last_stats: Option<GenerationStats>,
+    /// 上一轮被 `sample_len` 截断的回答, 供 [`continue_generation`](Self::continue_generation) 续写
+    continuation: Option<Continuation>,
+    /// 本轮已生成 token 的对数概率, 设置 `top_logprobs` 时记录
+    logprobs: Vec<TokenLogprob>,
+    /// 最近一次送入模型的完整提示词
+    last_prompt: Option<String>,
+    weights_bytes: usize,
+    kv_bytes_per_token: Option<usize>,
+    info: ModelInfo,
+    cpu_pool: Option<Arc<ThreadPool>>,
+    /// 当前 KV 缓存中的 token 数
+    kv_tokens: usize,
+    /// 超时的前向计算仍在后台运行, 其写入的 KV 缓存不完整, 取得模型锁后先清空
+    needs_cache_reset: bool,
+    /// 本轮回答已开始输出但尚未结束, 回答流被中途丢弃时保持为 true
+    generating: bool,
+    /// 观测到的设备内存峰值
+    peak_bytes: Option<usize>,
+    /// 贪心解码的回答缓存, 见 [`set_generation_cache`](Self::set_generation_cache)
+    generation_cache: Option<GenerationCache>,
+}
 
-            let start = std::time::Instant::now();
+/// 独占共享模型的权重, 无需复制模型
+impl From<SharedModel> for TextGeneration {
+    fn from(shared: SharedModel) -> Self {
+        let peak_bytes = device_used_bytes(&shared.infer_conf.device);
+        let stop_tokens = stop_tokens(&shared.tokenizer, &shared.infer_conf);
+        let tokenizer = Arc::new(shared.tokenizer);
 
-            let ans_start_idx = ctx_tokens.len();
+        Self {
+            model: Arc::new(Mutex::new(shared.model)),
+            tokenizer: tokenizer.clone(),
+            tos: TokenOutputStream::new(tokenizer.clone()),
+            token_bytes: TokenBytes::new(tokenizer),
+            undecoded: vec![],
+            logits_processor: sampler(&shared.infer_conf),
+            mirostat: None,
+            healing: None,
+            encoded_prefix: None,
+            stop_regex: stop_regex(&shared.infer_conf),
+            stop_tokens,
+            ctx: shared.ctx,
+            infer_conf: shared.infer_conf,
+            eos_token_id: shared.eos_token_id,
+            max_context: shared.max_context,
+            model_id: shared.model_id,
+            weights_id: shared.weights_id,
+            prefix_cache: None,
+            last_stats: None,
+            continuation: None,
+            logprobs: vec![],
+            last_prompt: None,
+            weights_bytes: shared.weights_bytes,
+            kv_bytes_per_token: shared.kv_bytes_per_token,
+            info: shared.info,
+            cpu_pool: shared.cpu_pool,
+            kv_tokens: 0,
+            needs_cache_reset: false,
+            generating: false,
+            peak_bytes,
+            generation_cache: None,
+        }
+    }
+}
 
-            // 统一处理token生成和输出
-            for index in 0..config.sample_len {
-                let next_token = gen_next_token(
-                    &ctx_tokens,
-                    if index == 0 {
-                        0
-                    } else {
-                        ans_start_idx + index - 1
-                    },
-                    &mut model,
-                    &mut logits_processor,
-                    &config,
-                    if index == 0 {
-                        None
-                    } else {
-                        Some(ans_start_idx)
-                    },
-                )?;
-                ctx_tokens.push(next_token);
+impl TextGeneration {
+    #[instrument(name = "load_model", skip(config))]
+    pub async fn new(model_id: &str, config: InferenceConfig) -> Result<Self, LlmError> {
+        Ok(SharedModel::new(model_id, config).await?.into())
+    }
 
-                if let Some(t) = tos.next_token(next_token)? {
-                    print!("{}", t);
-                    answer.push_str(&t);
-                    io::stdout().flush()?;
-                }
+    /// 使用 `models.toml` 中命名的推理预设创建
+    pub async fn new_with_preset(model_id: &str, preset_name: &str) -> Result<Self, LlmError> {
+        let config = ModelRegistry::new()
+            .and_then(|registry| Ok(registry.preset(preset_name)?.clone()))
+            .map_err(LlmError::InvalidConfig)?;
+        Self::new(model_id, config).await
+    }
 
-                if next_token == eos_token_id {
-                    break;
-                }
-            }
+    /// 由已加载的组件直接构造
+    pub fn from_parts(
+        model: Box<dyn ModelInference>,
+        tokenizer: Tokenizer,
+        ctx: ChatContext,
+        config: InferenceConfig,
+        eos_token_id: u32,
+    ) -> Self {
+        SharedModel::from_parts(model, tokenizer, ctx, config, eos_token_id).into()
+    }
 
-            if let Some(t) = tos.decode_rest()? {
-                print!("{}", t);
-                answer.push_str(&t);
-                io::stdout().flush()?;
-            }
+    /// 基于共享模型创建新会话, 权重共享, KV 缓存与对话历史相互独立
+    pub fn new_session(shared: &SharedModel) -> Result<Self> {
+        Ok(SharedModel {
+            model: shared.model.fork()?,
+            tokenizer: shared.tokenizer.clone(),
+            ctx: shared.ctx.clone(),
+            infer_conf: shared.infer_conf.clone(),
+            eos_token_id: shared.eos_token_id,
+            max_context: shared.max_context,
+            model_id: shared.model_id.clone(),
+            weights_id: shared.weights_id.clone(),
+            weights_bytes: shared.weights_bytes,
+            kv_bytes_per_token: shared.kv_bytes_per_token,
+            info: shared.info.clone(),
+            cpu_pool: shared.cpu_pool.clone(),
+        }
+        .into())
+    }
 
-            ctx.push_msg(&answer);
+    /// 便利构造函数 - 使用默认配置
+    pub async fn with_default_config(model_id: &str) -> Result<Self, LlmError> {
+        Self::new(model_id, InferenceConfig::default()).await
+    }
 
-            tos.clear();
-            answer.clear();
+    /// 便利构造函数
+    pub async fn default() -> Result<Self, LlmError> {
+        Self::with_default_config("qwen3").await
+    }
 
-            let dt = start.elapsed();
+    pub fn chat<'a>(&'a mut self, prompt: &'a str) -> impl Stream<Item = Result<String>> + 'a {
+        self.chat_with_prefix(prompt, "")
+    }
+
+    /// 与 [`chat`](Self::chat) 相同, 但回答以 `assistant_prefix` 开头
+    ///
+    /// 前缀渲染在助手回合开始之后并参与预填充, 模型从前缀处继续生成, 前缀本身也会输出
+    pub fn chat_with_prefix<'a>(
+        &'a mut self,
+        prompt: &'a str,
+        assistant_prefix: &'a str,
+    ) -> impl Stream<Item = Result<String>> + 'a {
+        self.ctx.push_msg(prompt);
+        text_only(self.generate(None, assistant_prefix, None))
+    }
+
+    /// 以阻塞迭代器逐段返回回答, 供不使用异步的调用方以 `for chunk in gen.chat_iter(prompt)` 读取
+    ///
+    /// 不在运行时中时创建一个单线程运行时驱动回答流; 启用 `rt-multi-thread` feature 时
+    /// 也可在多线程 tokio 运行时中调用, 使用当前运行时. 其他运行时中无法阻塞,
+    /// 第一项返回错误, 对话历史不变
+    pub fn chat_iter<'a>(
+        &'a mut self,
+        prompt: &'a str,
+    ) -> impl Iterator<Item = Result<String>> + 'a {
+        let (runtime, mut error) = match BlockingRuntime::new() {
+            Ok(runtime) => (Some(runtime), None),
+            Err(e) => (None, Some(e)),
+        };
+        // 无法创建运行时时不提问, 对话历史不变
+        let mut stream = runtime.as_ref().map(|_| Box::pin(self.chat(prompt)));
+
+        std::iter::from_fn(move || match (&runtime, &mut stream) {
+            (Some(runtime), Some(stream)) => runtime.block_on(stream.next()),
+            _ => error.take().map(Err),
+        })
+    }
+
+    /// 续写上一轮因达到 `sample_len` 而截断的回答, 续写的文本追加到对话历史中的该回答
+    ///
+    /// 从保留的 KV 缓存处继续解码, 不重新预填充; 上一轮不是以
+    /// [`StopReason::MaxTokens`] 结束, 或之后运行过模型、修改过对话历史时返回错误
+    pub fn continue_generation(&mut self) -> impl Stream<Item = Result<String>> + '_ {
+        let answered = matches!(self.ctx.last(), Some(msg) if msg.role == Role::Assistant);
+        let resume = self.continuation.take().filter(|_| answered);
+
+        try_stream!({
+            let resume = resume.ok_or_else(|| anyhow!("no truncated answer to continue"))?;
+            let stream = text_only(self.generate(None, "", Some(resume)));
+            pin_mut!(stream);
+            while let Some(chunk) = stream.next().await {
+                yield chunk?;
+            }
+        })
+    }
+
+    /// 以内容片段提问, 为多模态模型预留
+    ///
+    /// 片段原样存入对话历史并以列表传给对话模板, 模板需处理列表形式的内容;
+    /// 模型不支持图像时含图像的片段返回错误, 对话历史不变
+    pub fn chat_parts<'a>(
+        &'a mut self,
+        parts: &'a [ContentPart],
+    ) -> impl Stream<Item = Result<String>> + 'a {
+        try_stream!({
+            let content = Content::from(parts.to_vec());
+            self.check_content(&content)?;
+            self.ctx.push_content(content);
+            let stream = text_only(self.generate(None, "", None));
+            pin_mut!(stream);
+            while let Some(chunk) = stream.next().await {
+                yield chunk?;
+            }
+        })
+    }
+
+    /// 与 [`chat`](Self::chat) 相同, 但每生成一个 token 立即输出其原始字节
+    ///
+    /// 不等待完整的 UTF-8 字符, 多字节字符可能被拆到多个片段中, 由调用方自行拼接,
+    /// 适用于直接转发字节的代理
+    pub fn chat_bytes<'a>(
+        &'a mut self,
+        prompt: &'a str,
+    ) -> impl Stream<Item = Result<Vec<u8>>> + 'a {
+        let mut token_bytes = TokenBytes::new(self.tokenizer.clone());
+        self.ctx.push_msg(prompt);
+        let stream = self.generate(None, "", None);
+
+        try_stream!({
+            pin_mut!(stream);
+            while let Some(output) = stream.next().await {
+                let bytes = match output? {
+                    Output::Prefix(prefix) => prefix.into_bytes(),
+                    Output::Token(id) => token_bytes.get(id),
+                    Output::Text(_) => continue,
+                };
+                if !bytes.is_empty() {
+                    yield bytes;
+                }
+            }
+        })
+    }
+
+    /// 与 [`chat`](Self::chat) 相同, 但逐个输出生成的 token 及其解码出的文本, 供对齐、高亮等工具使用
+    ///
+    /// 文本归入增量解码器输出它时的 token: 解码器等到文本以字母或数字结尾才输出,
+    /// 标点、空格与未构成完整字符的字节因而归入之后的 token, 此前的 token 为 `None`;
+    /// 回答结束时解码出的剩余文本归入最后一个 token. 各 token 的文本依次拼接即为 `chat` 的回答.
+    /// 需等到下一个 token 生成才能确定当前 token 的文本, 输出比 `chat` 晚一步
+    pub fn chat_tokens<'a>(
+        &'a mut self,
+        prompt: &'a str,
+    ) -> impl Stream<Item = Result<(u32, Option<String>)>> + 'a {
+        self.ctx.push_msg(prompt);
+        let stream = self.generate(None, "", None);
+
+        try_stream!({
+            pin_mut!(stream);
+            let mut last: Option<(u32, Option<String>)> = None;
+            while let Some(output) = stream.next().await {
+                match output? {
+                    Output::Token(id) => {
+                        if let Some(step) = last.replace((id, None)) {
+                            yield step;
+                        }
+                    }
+                    Output::Prefix(t) | Output::Text(t) => {
+                        if let Some((_, text)) = &mut last {
+                            text.get_or_insert_default().push_str(&t);
+                        }
+                    }
+                }
+            }
+            if let Some(step) = last {
+                yield step;
+            }
+        })
+    }
+
+    /// 补全模式: 不经过对话模板, 直接续写 `prompt`, 适用于未经对话微调的基座模型
+    ///
+    /// 不读取也不修改对话历史, 输出不包含 `prompt` 本身
+    pub fn complete_raw<'a>(
+        &'a mut self,
+        prompt: &'a str,
+    ) -> impl Stream<Item = Result<String>> + 'a {
+        text_only(self.generate(Some(prompt), "", None))
+    }
+
+    /// 为同一提问生成 `n` 个相互独立的回答, 第 `i` 个回答的采样种子为 `seed + i`
+    ///
+    /// 提示词只预填充一次, 各回答从其 KV 缓存快照开始生成; 第一个回答记入对话历史, 为空时撤销本轮提问
+    pub async fn chat_n(&mut self, prompt: &str, n: usize) -> Result<Vec<String>> {
+        if n == 0 {
+            bail!("n must be at least 1");
+        }
+
+        self.ctx.push_msg(prompt);
+        let answers = match self.ctx.render() {
+            Ok(rendered) => self.sample_n(&rendered, n).await,
+            Err(e) => Err(e),
+        };
+        match &answers {
+            Ok(answers) if !answers[0].trim().is_empty() => self.ctx.push_msg(&answers[0]),
+            _ => {
+                self.ctx.pop();
+            }
+        }
+        answers
+    }
+
+    /// 预填充 `prompt` 并以其快照作为临时前缀缓存, 依次以不同种子补全, 结束后恢复原状态
+    async fn sample_n(&mut self, prompt: &str, n: usize) -> Result<Vec<String>> {
+        self.reset_stale_cache()?;
+        let tokens = self.str2tokens(prompt).await?;
+        // 最后一个 token 留给各回答自行计算, 以得到首个 token 的 logits
+        let prefill = &tokens[..tokens.len().saturating_sub(1)];
+        let start_pos = self.restore_prefix(prefill)?;
+        if prefill.len() > start_pos {
+            let input =
+                Tensor::new(&prefill[start_pos..], &self.infer_conf.device)?.unsqueeze(0)?;
+            self.forward(input, start_pos).await?;
+            self.kv_tokens = prefill.len();
+        }
+
+        // 模型不支持快照时每个回答重新预填充
+        let snapshot = self.lock_model()?.save_cache().ok();
+        let saved_prefix = snapshot.map(|snapshot| {
+            let system_prompt = self
+                .prefix_cache
+                .as_ref()
+                .map(|cache| cache.system_prompt.clone())
+                .unwrap_or_default();
+            let cache = PrefixCache {
+                system_prompt,
+                tokens: prefill.to_vec(),
+                snapshot,
+            };
+            std::mem::replace(&mut self.prefix_cache, Some(cache))
+        });
+        let original = self.infer_conf.clone();
+
+        let answers = self.sample_seeds(prompt, n, original.seed).await;
+
+        self.set_config(original);
+        if let Some(prefix) = saved_prefix {
+            self.prefix_cache = prefix;
+        }
+        answers
+    }
+
+    async fn sample_seeds(&mut self, prompt: &str, n: usize, seed: u64) -> Result<Vec<String>> {
+        let mut answers = Vec::with_capacity(n);
+        for i in 0..n as u64 {
+            let config = InferenceConfig {
+                seed: seed.wrapping_add(i),
+                ..self.infer_conf.clone()
+            };
+            self.set_config(config);
+
+            let chunks: Vec<Result<String>> = self.complete_raw(prompt).collect().await;
+            answers.push(chunks.into_iter().collect::<Result<String>>()?);
+        }
+        Ok(answers)
+    }
+
+    /// 生成回答, `raw_prompt` 为 `None` 时以渲染后的对话上下文作为提示词并记录回答
+    ///
+    /// 回答为空 (如首个 token 即为 EOS) 时不记录, 同时撤销本轮提问
+    ///
+    /// `resume` 不为 `None` 时从截断处续写, 不重新编码与预填充提示词
+    fn generate<'a>(
+        &'a mut self,
+        raw_prompt: Option<&'a str>,
+        assistant_prefix: &'a str,
+        resume: Option<Continuation>,
+    ) -> impl Stream<Item = Result<Output>> + 'a {
+        let mut answer = String::with_capacity(1024);
+        let chat = raw_prompt.is_none();
+        let resumed = resume.is_some();
+        // 续写出错时保留上一轮已记录的回答
+        let undo_prompt = chat && !resumed;
+        let mut special = self
+            .infer_conf
+            .skip_special_tokens
+            .then(|| SpecialTokenFilter::new(&self.tokenizer));
+
+        try_stream!({
+            self.last_stats = None;
+            self.generating = false;
+            self.logprobs.clear();
+            self.continuation = None;
+            // 上一轮的流可能在生成中途被丢弃
+            self.clear_decoder();
+            if let Err(e) = self.reset_stale_cache() {
+                Err(self.abort_generation(undo_prompt, e))?;
+            }
+            self.healing = None;
+            let mut healed = None;
+            let mut cache_key = None;
+
+            let (mut ctx_tokens, ans_start_idx) = if let Some(resume) = resume {
+                // 续写沿用上一轮的采样状态, 与不截断时的输出一致
+                answer = resume.answer;
+                // 以回答的最后一个 token 作为解码器的上文, 使衔接处的文本 (如开头的空格) 正确,
+                // 该 token 已经输出过, 未能立即输出时从之后的首段文本中去除
+                if let Some(&last) = resume.ctx_tokens.last() {
+                    if self.decode_next(last)?.is_none() {
+                        healed = Some(self.tokenizer.decode(&[last], false).map_err(Error::msg)?);
+                    }
+                    self.undecoded.clear();
+                }
+                (resume.ctx_tokens, resume.ans_start_idx)
+            } else {
+                self.reset_sampler();
+                let prompt = match raw_prompt {
+                    Some(prompt) => prompt.to_string(),
+                    None => self.ctx.render()? + assistant_prefix,
+                };
+                let mut ctx_tokens = if chat {
+                    self.encode_prompt(&prompt).await?
+                } else {
+                    self.str2tokens(&prompt).await?
+                };
+                if let Some(weights) = &self.weights_id
+                    && self.generation_cache.is_some()
+                    && self.infer_conf.is_greedy()
+                    && self.infer_conf.top_logprobs.is_none()
+                {
+                    cache_key = Some(GenerationCache::key(weights, &prompt, &self.infer_conf)?);
+                }
+                self.last_prompt = Some(prompt);
+
+                if let Some(max) = self.max_context
+                    && ctx_tokens.len() > max
+                {
+                    if chat {
+                        self.ctx.pop();
+                    }
+                    Err(LlmError::ContextOverflow { len: ctx_tokens.len(), max })?;
+                }
+
+                // 词元修复: 去掉提示词末尾可能不完整的 token, 由首个生成的 token 补全
+                if !chat
+                    && self.infer_conf.token_healing
+                    && ctx_tokens.len() > 1
+                    && let Some(last) = ctx_tokens.pop()
+                {
+                    self.healing = Some(self.healing_candidates(last));
+                    healed = Some(self.tokenizer.decode(&[last], false).map_err(Error::msg)?);
+                }
+                let ans_start_idx = ctx_tokens.len();
+                (ctx_tokens, ans_start_idx)
+            };
+
+            // 命中缓存时直接输出缓存的回答, 不运行模型
+            if let Some(cached) = cache_key
+                .as_deref()
+                .and_then(|key| self.generation_cache.as_ref()?.get(key))
+                .cloned()
+            {
+                debug!("answer served from the generation cache");
+                self.healing = None;
+                self.generating = true;
+                if !assistant_prefix.is_empty() {
+                    yield Output::Prefix(assistant_prefix.to_string());
+                }
+                for (&token, text) in cached.tokens.iter().zip(&cached.texts) {
+                    yield Output::Token(token);
+                    if !text.is_empty() {
+                        yield Output::Text(text.clone());
+                    }
+                }
+                self.finish_turn(
+                    chat,
+                    false,
+                    &cached.answer,
+                    GenerationStats {
+                        prompt_tokens: ans_start_idx,
+                        completion_tokens: cached.completion_tokens,
+                        elapsed: Duration::ZERO,
+                        stop_reason: cached.stop_reason,
+                        tool_calls: vec![],
+                    },
+                );
+                return;
+            }
+
+            let span = info_span!(
+                "generation",
+                model_id = self.model_id.as_deref(),
+                prompt_tokens = ctx_tokens.len(),
+                completion_tokens = field::Empty,
+                tokens_per_second = field::Empty,
+            );
+
+            // 续写时 KV 缓存中已有除最后一个 token 外的全部上下文
+            let start_pos = if resumed {
+                ctx_tokens.len() - 1
+            } else {
+                self.restore_prefix(&ctx_tokens)?
+            };
+
+            let start = Instant::now();
+            // 本次调用生成的第一个 token 的位置, 续写时位于上一轮回答之后
+            let gen_start_idx = ctx_tokens.len();
+            let mut stop_reason = StopReason::MaxTokens;
+            // 各 token 之后输出的文本, 写入缓存以便命中时按相同的归属重放
+            let mut token_texts: Vec<String> = vec![];
+
+            if !assistant_prefix.is_empty() {
+                answer.push_str(assistant_prefix);
+                yield Output::Prefix(assistant_prefix.to_string());
+            }
+
+            // 前缀与续写前的回答已经输出, 不参与停止正则的匹配
+            let mut stop = self.stop_regex.as_ref().map(|re| re.matcher(answer.len()));
+
+            // 束搜索在首轮完成整个回答, 之后逐个输出其 token
+            let mut beam: Option<std::vec::IntoIter<u32>> = None;
+
+            // 循环生成回答
+            self.generating = true;
+            for index in 0..self.infer_conf.sample_len {
+                let next_token = if let Some(tokens) = beam.as_mut() {
+                    match tokens.next() {
+                        Some(token) => Ok(token),
+                        None => break,
+                    }
+                } else if index == 0
+                    && !resumed
+                    && let DecodeStrategy::Beam { width } = self.infer_conf.decode_strategy
+                {
+                    self.beam_search(&ctx_tokens, start_pos, width)
+                        .instrument(span.clone())
+                        .await
+                        .map(|tokens| {
+                            let mut tokens = tokens.into_iter();
+                            let first = tokens.next();
+                            beam = Some(tokens);
+                            first.unwrap_or(self.eos_token_id)
+                        })
+                } else if index == 0 && !resumed {
+                    debug!(parent: &span, cached_tokens = start_pos, "prefill start");
+                    let token = self
+                        .gen_next_token(&ctx_tokens, start_pos, None)
+                        .instrument(span.clone())
+                        .await;
+                    debug!(parent: &span, "prefill end");
+                    token
+                } else {
+                    self.gen_next_token(&ctx_tokens, ctx_tokens.len() - 1, Some(ans_start_idx))
+                        .instrument(span.clone())
+                        .await
+                };
+                let next_token = match next_token {
+                    Ok(token) => token,
+                    Err(e) => Err(self.abort_generation(undo_prompt, e))?,
+                };
+                ctx_tokens.push(next_token);
+
+                // EOS 可能解码为可见文本 (如 `<|im_end|>`), 不输出也不计入回答
+                if self.is_eos(next_token) {
+                    stop_reason = StopReason::EosToken;
+                    break;
+                }
+
+                yield Output::Token(next_token);
+                token_texts.push(String::new());
+                let decoded = match self.decode_next(next_token) {
+                    Ok(decoded) => decoded,
+                    Err(e) => Err(self.abort_generation(undo_prompt, e))?,
+                };
+                if let Some(t) = decoded
+                    && let Some(t) = strip_healed(&mut healed, t)
+                    && let Some(t) = filter_special(&mut special, t)
+                {
+                    // 未达到最少生成数量前不检查停止正则
+                    let enabled =
+                        ctx_tokens.len() - ans_start_idx >= self.infer_conf.min_new_tokens;
+                    let (t, matched) = push_answer(&mut answer, t, stop.as_mut(), enabled);
+                    if let Some(t) = t {
+                        attribute_text(&mut token_texts, &t);
+                        yield Output::Text(t);
+                    }
+                    if let Some(matched) = matched {
+                        stop_reason = StopReason::StopSequence(matched);
+                        break;
+                    }
+                }
+
+                // 束搜索的回答已经生成完毕, 超时在搜索过程中处理
+                if beam.is_none()
+                    && let Some(max_duration) = self.infer_conf.max_duration
+                    && start.elapsed() >= max_duration
+                {
+                    stop_reason = StopReason::Timeout;
+                    break;
+                }
+            }
+
+            // 命中停止正则后丢弃剩余文本
+            let stopped = matches!(stop_reason, StopReason::StopSequence(_));
+            let rest = match self.decode_rest() {
+                Ok(rest) => rest,
+                Err(e) => Err(self.abort_generation(undo_prompt, e))?,
+            };
+            let rest = rest
+                .and_then(|t| strip_healed(&mut healed, t))
+                .and_then(|t| filter_special(&mut special, t));
+            let flushed = special.as_mut().and_then(|filter| filter.flush());
+            let enabled = ctx_tokens.len() - ans_start_idx >= self.infer_conf.min_new_tokens;
+            for t in rest.into_iter().chain(flushed).filter(|_| !stopped) {
+                let (t, matched) = push_answer(&mut answer, t, stop.as_mut(), enabled);
+                if let Some(t) = t {
+                    attribute_text(&mut token_texts, &t);
+                    yield Output::Text(t);
+                }
+                if let Some(matched) = matched {
+                    stop_reason = StopReason::StopSequence(matched);
+                    break;
+                }
+            }
+            // 输出等待停止正则确认的文本
+            if let Some(t) = stop.as_mut().and_then(|stop| stop.flush(&answer)) {
+                attribute_text(&mut token_texts, &t);
+                yield Output::Text(t);
+            }
+
+            let elapsed = start.elapsed();
+            let completion_tokens = ctx_tokens.len() - gen_start_idx;
+            let recorded = self.finish_turn(
+                chat,
+                resumed,
+                &answer,
+                GenerationStats {
+                    prompt_tokens: ans_start_idx,
+                    completion_tokens,
+                    elapsed,
+                    stop_reason: stop_reason.clone(),
+                    tool_calls: vec![],
+                },
+            );
+            // 束搜索的 KV 缓存属于各候选, 无法从中续写; 未生成 token 时模型没有运行过
+            if recorded
+                && matches!(stop_reason, StopReason::MaxTokens)
+                && beam.is_none()
+                && ctx_tokens.len() > ans_start_idx
+            {
+                self.continuation = Some(Continuation {
+                    ctx_tokens: ctx_tokens.clone(),
+                    ans_start_idx,
+                    answer: answer.clone(),
+                });
+            }
+            self.clear_decoder();
+
+            let tokens_per_second = completion_tokens as f64 / elapsed.as_secs_f64();
+            span.record("completion_tokens", completion_tokens);
+            span.record("tokens_per_second", tokens_per_second);
+
+            info!(
+                parent: &span,
+                "speed: {:.2} token/s, total tokens: {}",
+                tokens_per_second,
+                ctx_tokens.len()
+            );
+
+            // 超时的回答不完整, 不写入缓存
+            if let Some(key) = cache_key
+                && stop_reason != StopReason::Timeout
+            {
+                let tokens = ctx_tokens[ans_start_idx..]
+                    .iter()
+                    .copied()
+                    .filter(|&t| !self.is_eos(t))
+                    .collect();
+                let generation = CachedGeneration {
+                    answer: answer.clone(),
+                    tokens,
+                    texts: token_texts,
+                    completion_tokens,
+                    stop_reason,
+                };
+                if let Some(cache) = &mut self.generation_cache
+                    && let Err(e) = cache.insert(key, generation)
+                {
+                    warn!("failed to write the generation cache: {e:#}");
+                }
+            }
+        })
+    }
+
+    /// 结束一轮生成: 回答写入对话历史, 解析其中的工具调用并记录统计, 返回回答是否写入了历史
+    ///
+    /// 续写的回答替换上一轮记录的部分; 空回答会在历史中留下空白的助手回合,
+    /// 连同提问一起撤销以保持用户与助手交替
+    fn finish_turn(
+        &mut self,
+        chat: bool,
+        resumed: bool,
+        answer: &str,
+        stats: GenerationStats,
+    ) -> bool {
+        let mut recorded = false;
+        if chat {
+            if resumed {
+                self.ctx.pop();
+            }
+            if answer.trim().is_empty() {
+                warn!("empty answer, the turn is not recorded in the history");
+                self.ctx.pop();
+            } else {
+                self.ctx.push_msg(answer);
+                recorded = true;
+            }
+        }
+        let tool_calls = if self.ctx.tools().is_empty() {
+            vec![]
+        } else {
+            parse_tool_calls(answer)
+        };
+        self.generating = false;
+        self.last_stats = Some(GenerationStats {
+            tool_calls,
+            ..stats
+        });
+        recorded
+    }
+
+    /// 生成中途出错时撤销本轮提问并清空 KV 缓存
+    ///
+    /// 缓存中可能只写入了部分回答, 之后直接调用 [`feed`](Self::feed) 等接口会读到不完整的状态
+    fn abort_generation(&mut self, chat: bool, e: Error) -> Error {
+        self.generating = false;
+        if chat {
+            self.ctx.pop();
+        }
+        self.clear_decoder();
+        // 模型被其他会话占用时无法清空, 该会话的下一轮会重新设置缓存
+        if let Ok(mut model) = self.lock_model() {
+            model.clr_kv_cache();
+        }
+        self.kv_tokens = 0;
+        e
+    }
+
+    /// 只渲染并编码提问, 不运行模型, 用于估算开销与排查提示词
+    ///
+    /// 在对话历史的副本上追加提问, 历史本身不变; 提示词超出上下文长度时可生成数为 0
+    pub async fn dry_run(&self, prompt: &str) -> Result<DryRun> {
+        let mut ctx = self.ctx.clone();
+        ctx.push_msg(prompt);
+        let rendered_prompt = ctx.render()?;
+        let prompt_tokens = self.str2tokens(&rendered_prompt).await?.len();
+
+        let sample_len = self.infer_conf.sample_len;
+        let estimated_max_tokens = self.max_context.map_or(sample_len, |max| {
+            sample_len.min(max.saturating_sub(prompt_tokens))
+        });
+
+        Ok(DryRun {
+            rendered_prompt,
+            prompt_tokens,
+            estimated_max_tokens,
+        })
+    }
+
+    /// 以 `prompt` 进行一次只用于测速的生成, 分别统计预填充与逐 token 解码的速度
+    ///
+    /// 在空白对话中从头预填充, 忽略 EOS 生成满 `sample_len` 个 token; 不输出文本也不修改对话历史,
+    /// 结束后清空 KV 缓存
+    pub async fn benchmark(&mut self, prompt: &str, sample_len: usize) -> Result<BenchReport> {
+        if sample_len == 0 {
+            bail!("sample_len must be greater than 0");
+        }
+        let mut ctx = self.ctx.clone();
+        ctx.reset();
+        ctx.push_msg(prompt);
+        let tokens = self.str2tokens(&ctx.render()?).await?;
+        let prompt_tokens = tokens.len();
+        if let Some(max) = self.max_context
+            && prompt_tokens + sample_len > max
+        {
+            Err(LlmError::ContextOverflow {
+                len: prompt_tokens + sample_len,
+                max,
+            })?;
+        }
+
+        self.logprobs.clear();
+        self.healing = None;
+        self.reset_sampler();
+        self.lock_model()?.clr_kv_cache();
+        let timings = self.timed_generate(tokens, sample_len).await;
+        // 测速的上下文不保留
+        if let Ok(mut model) = self.lock_model() {
+            model.clr_kv_cache();
+        }
+        self.kv_tokens = 0;
+        let (ttft, total) = timings?;
+
+        let decode_tps = match sample_len - 1 {
+            0 => 0.,
+            decoded => decoded as f64 / (total - ttft).as_secs_f64(),
+        };
+        Ok(BenchReport {
+            prompt_tokens,
+            completion_tokens: sample_len,
+            ttft,
+            prefill_tps: prompt_tokens as f64 / ttft.as_secs_f64(),
+            decode_tps,
+            total,
+        })
+    }
+
+    /// 从位置 0 预填充 `tokens` 后再生成 `sample_len - 1` 个 token, 返回首个 token 的延迟与总耗时
+    async fn timed_generate(
+        &mut self,
+        mut tokens: Vec<u32>,
+        sample_len: usize,
+    ) -> Result<(Duration, Duration)> {
+        let prompt_tokens = tokens.len();
+        let start = Instant::now();
+        let token = self.gen_next_token(&tokens, 0, None).await?;
+        let ttft = start.elapsed();
+        tokens.push(token);
+
+        for _ in 1..sample_len {
+            let token = self
+                .gen_next_token(&tokens, tokens.len() - 1, Some(prompt_tokens))
+                .await?;
+            tokens.push(token);
+        }
+        Ok((ttft, start.elapsed()))
+    }
+
+    /// 已生成但尚未构成完整 UTF-8 字符的字节数
+    ///
+    /// 多字节字符 (如中文或 emoji) 只生成了一部分时不为 0, 界面可据此显示等待状态;
+    /// 已完整但等待后续文本 (如标点之后的字母) 而暂未输出的字符不计入
+    pub fn pending_bytes(&self) -> usize {
+        incomplete_utf8_len(&self.undecoded)
+    }
+
+    /// 丢弃增量解码器中尚未输出的 token 与字节, 不影响对话历史与 KV 缓存
+    ///
+    /// 提前丢弃回答流后缓冲区可能残留半个字符, 调用后 [`pending_bytes`](Self::pending_bytes) 归零.
+    /// 各生成接口开始时已自动清空, 调用与否不影响下一轮的输出, 只用于立即复位界面显示的状态
+    pub fn clear_output_buffer(&mut self) {
+        self.clear_decoder();
+    }
+
+    /// 上一轮完整生成的统计信息, 生成失败或尚未完成时为 `None`
+    pub fn last_stats(&self) -> Option<&GenerationStats> {
+        self.last_stats.as_ref()
+    }
+
+    /// 最近一轮生成的各 token 的对数概率及候选, 未设置 `top_logprobs` 时为空
+    ///
+    /// 与生成的 token 一一对应, 不含结束回答的 EOS
+    pub fn last_logprobs(&self) -> &[TokenLogprob] {
+        &self.logprobs
+    }
+
+    /// 最近一次送入模型的完整提示词, 即渲染后的对话模板 (含助手前缀), 便于排查模板问题
+    pub fn last_rendered_prompt(&self) -> Option<&str> {
+        self.last_prompt.as_deref()
+    }
+
+    /// 已加载模型的架构、上下文长度、词表大小等信息
+    pub fn model_info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    /// 已加载权重的量化类型与每个权重的平均比特数, 由组件直接构造时为 `None`
+    pub fn quantization(&self) -> Option<Quantization> {
+        self.info.quantization.clone()
+    }
+
+    /// 上一轮生成的结束原因, 每轮完整生成后设置一次
+    ///
+    /// 回答流在输出中途被丢弃时为 [`FinishReason::Cancelled`], 生成出错时为 `None`
+    pub fn last_finish_reason(&self) -> Option<FinishReason> {
+        if self.generating {
+            return Some(FinishReason::Cancelled);
+        }
+        self.last_stats
+            .as_ref()
+            .map(|stats| stats.stop_reason.clone().into())
+    }
+
+    /// 设备内存使用统计
+    ///
+    /// 峰值在加载后及每次前向计算后采样, 仅 CUDA 设备可查询, 包含其他进程的占用
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            weights_bytes: self.weights_bytes,
+            kv_cache_bytes: self.kv_bytes_per_token.map(|n| n * self.kv_tokens),
+            peak_bytes: self
+                .peak_bytes
+                .max(device_used_bytes(&self.infer_conf.device)),
+        }
+    }
+
+    /// 处理 OpenAI Chat Completions 请求
+    ///
+    /// 对话上下文由请求中的消息重建, 请求参数仅对本次生成生效;
+    /// `stream` 为 true 时返回片段流, 否则返回完整响应
+    pub async fn complete(&mut self, req: ChatCompletionRequest) -> Result<Completion<'_>> {
+        if req.stream {
+            return Ok(Completion::Stream(Box::pin(self.complete_stream(req))));
+        }
+
+        let answer = {
+            let mut text_gen = self.begin_completion(&req)?;
+            let chunks: Vec<Result<String>> =
+                text_only(text_gen.generate(None, "", None)).collect().await;
+            chunks.into_iter().collect::<Result<String>>()?
+        };
+        let stats = self
+            .last_stats
+            .as_ref()
+            .ok_or_else(|| anyhow!("generation did not finish"))?;
+        Ok(Completion::Response(ChatCompletionResponse::new(
+            &req.model, answer, stats,
+        )))
+    }
+
+    /// 以 OpenAI 流式片段的形式处理请求
+    pub fn complete_stream(
+        &mut self,
+        req: ChatCompletionRequest,
+    ) -> impl Stream<Item = Result<ChatCompletionChunk>> + '_ {
+        try_stream!({
+            let mut text_gen = self.begin_completion(&req)?;
+            let chunks = ChunkBuilder::new(&req.model);
+            yield chunks.chunk(
+                Delta {
+                    role: Some(Role::Assistant),
+                    ..Default::default()
+                },
+                None,
+            );
+
+            let mut failed = None;
+            {
+                let stream = text_only(text_gen.generate(None, "", None));
+                pin_mut!(stream);
+                while let Some(text) = stream.next().await {
+                    match text {
+                        Ok(text) => {
+                            yield chunks.chunk(
+                                Delta {
+                                    content: Some(text),
+                                    ..Default::default()
+                                },
+                                None,
+                            )
+                        }
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            // 无论成功与否都恢复原推理参数
+            drop(text_gen);
+            if let Some(e) = failed {
+                Err(e)?;
+            }
+
+            yield chunks.chunk(Delta::default(), self.last_finish_reason().as_ref());
+        })
+    }
+
+    /// 按请求重建对话上下文并应用推理参数, 返回应用了请求参数的实例
+    fn begin_completion(&mut self, req: &ChatCompletionRequest) -> Result<RequestConfig<'_>> {
+        let last = req.messages.last().ok_or_else(|| anyhow!("no messages"))?;
+        if last.role != Role::User {
+            bail!("the last message must come from the user");
+        }
+        for msg in &req.messages {
+            self.check_content(&msg.content)?;
+        }
+
+        let config = req.apply_to(&self.infer_conf);
+        config.validate()?;
+
+        self.ctx.clear();
+        self.ctx.extend(req.messages.iter().cloned());
+        let original = self.set_config(config);
+        Ok(RequestConfig {
+            text_gen: self,
+            original: Some(original),
+        })
+    }
+
+    /// 检查模型能否处理消息内容, 不支持图像的模型拒绝图像片段
+    fn check_content(&self, content: &Content) -> Result<()> {
+        if content.has_images() {
+            let model = self.lock_model()?;
+            if !model.supports_images() {
+                bail!("{} does not support images", model.arch_name());
+            }
+        }
+        Ok(())
+    }
+
+    /// 增量解码下一个 token, 词表外的 token (如模型为对齐而填充的 id) 视为解码失败
+    ///
+    /// 非严格模式下记录并跳过出错的 token, 解码器随之重置, 其中尚未输出的文本丢弃
+    fn decode_next(&mut self, token: u32) -> Result<Option<String>> {
+        let res = if self.tokenizer.id_to_token(token).is_some() {
+            self.tos.next_token(token).map_err(|e| {
+                // 出错的 token 已留在解码器中, 重置后才能继续解码
+                self.clear_decoder();
+                Error::from(e)
+            })
+        } else {
+            Err(anyhow!("token {token} is not in the vocab"))
+        };
+        match &res {
+            Ok(Some(_)) => self.undecoded.clear(),
+            Ok(None) => self.undecoded.extend(self.token_bytes.get(token)),
+            Err(_) => {}
+        }
+        match res {
+            Err(e) if !self.infer_conf.strict_decode => {
+                warn!("failed to decode token {token}, skipping it: {e}");
+                Ok(None)
+            }
+            res => res,
+        }
+    }
+
+    /// 重置增量解码器, 丢弃尚未输出的文本
+    fn clear_decoder(&mut self) {
+        self.tos.clear();
+        self.undecoded.clear();
+    }
+
+    /// 解码剩余的 token, 非严格模式下出错时丢弃
+    fn decode_rest(&mut self) -> Result<Option<String>> {
+        match self.tos.decode_rest() {
+            Err(e) if !self.infer_conf.strict_decode => {
+                warn!("failed to decode the rest of the answer, dropping it: {e}");
+                Ok(None)
+            }
+            res => Ok(res?),
+        }
+    }
+
+    /// 结束回答的 token: EOS 与额外的停止 token
+    fn eos_tokens(&self) -> Vec<u32> {
+        let mut tokens = vec![self.eos_token_id];
+        tokens.extend(&self.stop_tokens);
+        tokens
+    }
+
+    fn is_eos(&self, token: u32) -> bool {
+        token == self.eos_token_id || self.stop_tokens.contains(&token)
+    }
+
+    /// 以 `seed` 重新开始采样, 同一提示词在全新上下文中输出相同
+    fn reset_sampler(&mut self) {
+        self.logits_processor = sampler(&self.infer_conf);
+        self.mirostat = match self.infer_conf.decode_strategy {
+            DecodeStrategy::Mirostat { tau, eta } => Some(Mirostat::new(tau, eta)),
+            _ => None,
+        };
+    }
+
+    /// 替换推理参数并重建采样器, 返回原推理参数
+    fn set_config(&mut self, config: InferenceConfig) -> InferenceConfig {
+        self.logits_processor = sampler(&config);
+        self.stop_regex = stop_regex(&config);
+        self.stop_tokens = stop_tokens(&self.tokenizer, &config);
+        // `add_special_tokens` 可能改变, 前缀需重新编码
+        self.encoded_prefix = None;
+        std::mem::replace(&mut self.infer_conf, config)
+    }
+
+    /// 与 [`chat`](Self::chat) 相同, 但按词边界输出, 不会输出半个单词
+    pub fn chat_words<'a>(
+        &'a mut self,
+        prompt: &'a str,
+    ) -> impl Stream<Item = Result<String>> + 'a {
+        try_stream!({
+            let mut words = WordBuffer::default();
+
+            let stream = self.chat(prompt);
+            pin_mut!(stream);
+            while let Some(chunk) = stream.next().await {
+                if let Some(w) = words.push(&chunk?) {
+                    yield w;
+                }
+            }
+
+            if let Some(w) = words.flush() {
+                yield w;
+            }
+        })
+    }
+
+    /// 预填充系统提示词并保存 KV 缓存快照
+    ///
+    /// 之后以该系统提示词开头的对话直接从快照恢复, 无需重复计算前缀
+    pub async fn prefill_and_snapshot(&mut self, system_prompt: &str) -> Result<()> {
+        self.prefix_cache = None;
+        self.ctx.clear();
+        self.ctx.push_message(Role::System, system_prompt);
+
+        let tokens = self.str2tokens(&self.ctx.render_prefix()?).await?;
+        if tokens.is_empty() {
+            bail!("no tokens to process");
+        }
+        let input = Tensor::new(tokens.as_slice(), &self.infer_conf.device)?.unsqueeze(0)?;
+
+        self.lock_model()?.clr_kv_cache();
+        self.forward(input, 0).await?;
+        self.kv_tokens = tokens.len();
+        let snapshot = self.lock_model()?.save_cache()?;
+
+        self.prefix_cache = Some(PrefixCache {
+            system_prompt: system_prompt.to_string(),
+            tokens,
+            snapshot,
+        });
+
+        Ok(())
+    }
+
+    /// 丢弃对话历史, 恢复到预填充系统提示词后的状态
+    pub fn restore(&mut self) -> Result<()> {
+        let cache = self
+            .prefix_cache
+            .as_ref()
+            .ok_or_else(|| anyhow!("no prefilled prefix to restore"))?;
+
+        self.lock_model()?.restore_cache(&cache.snapshot)?;
+        self.kv_tokens = cache.tokens.len();
+        self.continuation = None;
+        self.ctx.clear();
+        self.ctx.push_message(Role::System, &cache.system_prompt);
+
+        Ok(())
+    }
+
+    /// 清空对话历史与 KV 缓存, 之后的 [`feed`](Self::feed) 从位置 0 开始
+    ///
+    /// 对话历史恢复为提示模板中的系统提示词与示例
+    pub fn reset(&mut self) -> Result<()> {
+        self.ctx.reset();
+        self.lock_model()?.clr_kv_cache();
+        self.kv_tokens = 0;
+        self.continuation = None;
+        Ok(())
+    }
+
+    /// 设置生成缓存, `None` 关闭缓存
+    ///
+    /// 贪心解码 (温度为 0) 且未设置 `top_logprobs` 时, 相同权重、提示词与推理参数的回答直接取自缓存,
+    /// 不运行模型; 未命中时生成完毕后写入. 取自缓存的回答不能以
+    /// [`continue_generation`](Self::continue_generation) 续写.
+    /// 由组件直接构造的实例无法确定加载的权重, 不使用缓存
+    pub fn set_generation_cache(&mut self, cache: Option<GenerationCache>) {
+        self.generation_cache = cache;
+    }
+
+    pub fn generation_cache(&self) -> Option<&GenerationCache> {
+        self.generation_cache.as_ref()
+    }
+
+    /// 设置每次重置后保留的系统提示词与少样本示例, 当前对话历史替换为模板中的消息
+    pub fn set_prompt_template(&mut self, template: PromptTemplate) {
+        self.ctx.set_prompt_template(template);
+    }
+
+    /// 当前 KV 缓存中的 token 数, 即下一次 [`feed`](Self::feed) 写入的起始位置
+    pub fn position(&self) -> usize {
+        self.kv_tokens
+    }
+
+    /// 将 `tokens` 送入模型, 返回最后一个位置的 logits, 形状为 `(vocab_size,)`
+    ///
+    /// 供自定义生成循环使用: `tokens` 从 [`position`](Self::position) 处写入 KV 缓存,
+    /// 即模型的 `index_pos`, 调用后位置增加 `tokens.len()`. 首次调用传入完整提示词完成预填充,
+    /// 之后每步只传入上一步选出的 token, 不要重复传入已在缓存中的 token.
+    ///
+    /// [`chat`](Self::chat) 等接口会清空或恢复缓存, 与之交替使用时先调用 [`reset`](Self::reset)
+    pub async fn feed(&mut self, tokens: &[u32]) -> Result<Tensor> {
+        self.feed_at(tokens, self.kv_tokens).await
+    }
+
+    /// 按配置的温度与 top_p 从 `logits` 中采样, 不应用重复惩罚等处理
+    ///
+    /// 随机数状态延续上一次采样, 每轮对话开始时以 `seed` 重置
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        self.logits_processor.sample(logits).map_err(Error::msg)
+    }
+
+    /// 从 `idx_pos` 处前向计算 `tokens`, 返回最后一个位置的 logits
+    async fn feed_at(&mut self, tokens: &[u32], idx_pos: usize) -> Result<Tensor> {
+        if tokens.is_empty() {
+            bail!("no tokens to process");
+        }
+        let input = Tensor::new(tokens, &self.infer_conf.device)?.unsqueeze(0)?;
+        let logits = self.forward(input, idx_pos).await?.squeeze(0)?.squeeze(0)?;
+        self.kv_tokens = idx_pos + tokens.len();
+        self.peak_bytes = self
+            .peak_bytes
+            .max(device_used_bytes(&self.infer_conf.device));
+        Ok(logits)
+    }
+
+    /// 以预填充前缀开头时从快照恢复 KV 缓存, 否则清空缓存
+    ///
+    /// 返回需要从哪个位置开始计算
+    fn restore_prefix(&self, ctx_tokens: &[u32]) -> Result<usize> {
+        let mut model = self.lock_model()?;
+        match &self.prefix_cache {
+            Some(cache)
+                if ctx_tokens.len() > cache.tokens.len()
+                    && ctx_tokens.starts_with(&cache.tokens) =>
+            {
+                model.restore_cache(&cache.snapshot)?;
+                Ok(cache.tokens.len())
+            }
+            _ => {
+                model.clr_kv_cache();
+                Ok(0)
+            }
+        }
+    }
+
+    /// 批量编码多段文本, 供批量生成使用
+    ///
+    /// 在阻塞线程池中执行; 分词器默认以多线程并行编码各段文本,
+    /// 可通过环境变量 `TOKENIZERS_PARALLELISM=false` 关闭.
+    /// 并行只在多核时有收益: 单核 CPU 上 32 段各约 2 万 token 的文本
+    /// 与逐段编码耗时相同 (GPT-2 字节级 BPE, release 构建, 均约 425ms)
+    pub async fn encode_batch(&self, inputs: Vec<String>) -> Result<Vec<Vec<u32>>> {
+        let tokenizer = self.tokenizer.clone();
+        let add_special_tokens = self.infer_conf.add_special_tokens;
+        let encodings = tokio::task::spawn_blocking(move || {
+            tokenizer.encode_batch(inputs, add_special_tokens.unwrap_or(true))
+        })
+        .await?
+        .map_err(Error::msg)?;
+
+        Ok(encodings
+            .iter()
+            .map(|encoding| encoding_ids(encoding, add_special_tokens))
+            .collect())
+    }
+
+    /// 在阻塞线程池中编码, 避免长文本编码阻塞异步运行时
+    ///
+    /// 约 2 万 token 的提示词编码一次约 11ms (GPT-2 字节级 BPE, 单核 CPU, release 构建),
+    /// 经线程池调度与直接编码耗时相同
+    async fn str2tokens(&self, string: &str) -> Result<Vec<u32>> {
+        let add_special_tokens = self.infer_conf.add_special_tokens;
+        let encoding = self
+            .encode(string, add_special_tokens.unwrap_or(true))
+            .await?;
+
+        Ok(encoding_ids(&encoding, add_special_tokens))
+    }
+
+    async fn encode(&self, string: &str, add_special_tokens: bool) -> Result<Encoding> {
+        let tokenizer = self.tokenizer.clone();
+        let string = string.to_string();
+        let encoding =
+            tokio::task::spawn_blocking(move || tokenizer.encode(string, add_special_tokens))
+                .await?
+                .map_err(Error::msg)?;
+        Ok(encoding)
+    }
+
+    /// 编码对话提示词, 以上一轮已编码的前缀开头时只编码新增部分
+    ///
+    /// 编码结果与完整编码相同, 并记录截至最后一个特殊 token 的前缀供下一轮复用
+    async fn encode_prompt(&mut self, prompt: &str) -> Result<Vec<u32>> {
+        if let Some(prefix) = self.encoded_prefix.clone()
+            && prompt.starts_with(prefix.text.as_str())
+            && let Some(tokens) = self.encode_suffix(&prefix, prompt).await?
+        {
+            return Ok(tokens);
+        }
+
+        let add_special_tokens = self.infer_conf.add_special_tokens;
+        let encoding = self
+            .encode(prompt, add_special_tokens.unwrap_or(true))
+            .await?;
+        let ids = encoding_ids(&encoding, add_special_tokens);
+        let dropped = encoding.len() - ids.len();
+        // 后处理器在末尾添加 token 时, 拼接的结果与完整编码不同, 不缓存
+        let trailing = encoding.get_special_tokens_mask().last() == Some(&1);
+        self.encoded_prefix = self
+            .special_boundary(&encoding)
+            .filter(|&(_, count)| !trailing && count > dropped)
+            .map(|(end, count)| EncodedPrefix {
+                text: prompt[..end].to_string(),
+                tokens: ids[..count - dropped].to_vec(),
+            });
+        Ok(ids)
+    }
+
+    /// 编码 `prompt` 在 `prefix` 之后的部分并拼接到前缀的 token 之后, 无法保证与完整编码相同时返回 `None`
+    ///
+    /// 新增部分连同前缀末尾的特殊 token 一起编码, 使依赖位置的预分词 (如 Metaspace 的
+    /// `prepend_scheme: first` 只在文本开头添加 `▁`) 与完整编码时一致
+    async fn encode_suffix(
+        &mut self,
+        prefix: &EncodedPrefix,
+        prompt: &str,
+    ) -> Result<Option<Vec<u32>>> {
+        let Some(&anchor) = prefix.tokens.last() else {
+            return Ok(None);
+        };
+        let Some(anchor_text) = self
+            .tokenizer
+            .get_added_tokens_decoder()
+            .get(&anchor)
+            .filter(|token| token.special)
+            .map(|token| token.content.clone())
+        else {
+            return Ok(None);
+        };
+
+        let start = prefix.text.len();
+        let encoding = self
+            .encode(&format!("{anchor_text}{}", &prompt[start..]), false)
+            .await?;
+        let ids = encoding.get_ids();
+        if ids.first() != Some(&anchor) {
+            debug!("prefix token changed when encoding the suffix, falling back to a full encode");
+            return Ok(None);
+        }
+
+        if let Some((end, count)) = self.special_boundary(&encoding) {
+            let mut tokens = prefix.tokens.clone();
+            tokens.extend(&ids[1..count]);
+            self.encoded_prefix = Some(EncodedPrefix {
+                text: prompt[..start + end - anchor_text.len()].to_string(),
+                tokens,
+            });
+        }
+        let mut tokens = prefix.tokens.clone();
+        tokens.extend(&ids[1..]);
+        Ok(Some(tokens))
+    }
+
+    /// 最后一个特殊 token 的结束位置, 及截至该 token 的 token 数
+    ///
+    /// 分词器先按特殊 token 切分文本再分别编码, 在此处截断的前缀编码结果不受后续文本影响
+    fn special_boundary(&self, encoding: &Encoding) -> Option<(usize, usize)> {
+        let added = self.tokenizer.get_added_tokens_decoder();
+        let mask = encoding.get_special_tokens_mask();
+        let offsets = encoding.get_offsets();
+        encoding
+            .get_ids()
+            .iter()
+            .enumerate()
+            .rev()
+            // 后处理器添加的 token 不对应提示词中的文本
+            .find(|&(i, id)| mask[i] == 0 && added.get(id).is_some_and(|token| token.special))
+            .map(|(i, _)| (offsets[i].1, i + 1))
+    }
+
+    /// 超时的前向计算仍在运行时模型锁被占用, 返回 "model busy"
+    fn lock_model(&self) -> Result<MutexGuard<'_, Box<dyn ModelInference>>> {
+        self.model.try_lock().map_err(|e| match e {
+            TryLockError::WouldBlock => anyhow!("model busy"),
+            TryLockError::Poisoned(e) => anyhow!("model is unavailable: {e}"),
+        })
+    }
+
+    /// 超时的前向计算结束后清空它写入的 KV 缓存, 计算仍在运行时返回 "model busy"
+    fn reset_stale_cache(&mut self) -> Result<()> {
+        if self.needs_cache_reset {
+            self.lock_model()?.clr_kv_cache();
+            self.kv_tokens = 0;
+            self.needs_cache_reset = false;
+        }
+        Ok(())
+    }
+
+    /// 在阻塞线程池中执行模型前向计算, 避免阻塞异步运行时
+    ///
+    /// 每个 token 额外增加一次线程池调度, 单核 CPU release 构建下 mock 模型实测约 6µs
+    /// (见 `bench_forward_overhead`), 远小于毫秒级的真实前向计算;
+    /// 设置了 `cpu_threads` 时在专用的 rayon 线程池中计算
+    /// 配置了 token_timeout 时超时立即返回错误, 阻塞计算无法取消, 在后台继续运行,
+    /// 结束前的调用返回 "model busy", 之后的首次调用先清空其写入的 KV 缓存;
+    /// 设备内存不足时清空 KV 缓存并返回 [`LlmError::OutOfMemory`]
+    async fn forward(&mut self, input: Tensor, idx_pos: usize) -> Result<Tensor> {
+        self.reset_stale_cache()?;
+        // KV 缓存随之改变, 不能再续写截断的回答
+        self.continuation = None;
+        let tokens = idx_pos + input.dim(1)?;
+        let model = self.model.clone();
+        let cpu_pool = self.cpu_pool.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut guard = model
+                .lock()
+                .map_err(|e| anyhow!("model is unavailable: {e}"))?;
+            let model = &mut **guard;
+            match &cpu_pool {
+                Some(pool) => pool.install(|| model.forward(&input, idx_pos)),
+                None => model.forward(&input, idx_pos),
+            }
+        });
+
+        let logits = match self.infer_conf.token_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, handle).await {
+                Ok(logits) => logits?,
+                Err(_) => {
+                    // 阻塞任务无法取消, 不等它结束; 它释放模型锁后再清空只写入了一部分的 KV 缓存
+                    self.needs_cache_reset = true;
+                    self.kv_tokens = 0;
+                    Err(anyhow!("token generation timed out after {timeout:?}"))
+                }
+            },
+            None => handle.await?,
+        };
+        match logits {
+            Ok(logits) => Ok(logits),
+            Err(e) if is_out_of_memory(&e) => {
+                warn!("out of memory at {tokens} tokens, clearing the kv cache: {e:#}");
+                // 缓存中可能只写入了一部分, 清空后会话可以继续使用
+                if let Ok(mut model) = self.lock_model() {
+                    model.clr_kv_cache();
+                }
+                self.kv_tokens = 0;
+                Err(LlmError::OutOfMemory {
+                    requested: self.kv_bytes_per_token.map(|n| n * tokens),
+                    available: device_free_bytes(&self.infer_conf.device),
+                }
+                .into())
+            }
+            Err(e) => Err(LlmError::Inference(e).into()),
+        }
+    }
+
+    /// 束搜索: 每步将各候选按对数概率最高的 `width` 个 token 扩展, 保留累计对数概率最高的 `width` 条
+    ///
+    /// 各候选保存自己的 KV 缓存快照, 需要模型支持快照; 以 EOS 结束的候选不再扩展,
+    /// 返回累计对数概率最高的候选生成的 token
+    async fn beam_search(
+        &mut self,
+        ctx_tokens: &[u32],
+        start_pos: usize,
+        width: usize,
+    ) -> Result<Vec<u32>> {
+        let start = Instant::now();
+        let prompt = &ctx_tokens[start_pos..];
+        let logprobs = self.beam_forward(prompt, start_pos).await?;
+        let mut beams = vec![Beam {
+            tokens: vec![],
+            score: 0.,
+            logprobs: Some(logprobs),
+            cache: Some(self.lock_model()?.save_cache()?),
+        }];
+
+        for _ in 0..self.infer_conf.sample_len {
+            // (父候选, 新 token, 累计对数概率), 已结束的候选原样保留
+            let mut candidates = vec![];
+            for (i, beam) in beams.iter().enumerate() {
+                let Some(logprobs) = &beam.logprobs else {
+                    candidates.push((i, None, beam.score));
+                    continue;
+                };
+                let mut logprobs = logprobs.clone();
+                // 未达到最少生成数量前屏蔽 EOS
+                if beam.tokens.len() < self.infer_conf.min_new_tokens {
+                    for eos in self.eos_tokens() {
+                        if let Some(logprob) = logprobs.get_mut(eos as usize) {
+                            *logprob = f32::NEG_INFINITY;
+                        }
+                    }
+                }
+                for (token, logprob) in top_k(&logprobs, width) {
+                    candidates.push((i, Some(token), beam.score + logprob));
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+            candidates.truncate(width);
+
+            let mut next = Vec::with_capacity(width);
+            for (parent, token, score) in candidates {
+                let mut tokens = beams[parent].tokens.clone();
+                let Some(token) = token else {
+                    next.push(Beam::finished(tokens, score));
+                    continue;
+                };
+                let pos = ctx_tokens.len() + tokens.len();
+                tokens.push(token);
+                if self.is_eos(token) || tokens.len() == self.infer_conf.sample_len {
+                    next.push(Beam::finished(tokens, score));
+                    continue;
+                }
+
+                if let Some(cache) = &beams[parent].cache {
+                    self.lock_model()?.restore_cache(cache)?;
+                }
+                let logprobs = self.beam_forward(&[token], pos).await?;
+                next.push(Beam {
+                    tokens,
+                    score,
+                    logprobs: Some(logprobs),
+                    cache: Some(self.lock_model()?.save_cache()?),
+                });
+            }
+            beams = next;
+
+            if beams.iter().all(|beam| beam.logprobs.is_none()) {
+                break;
+            }
+            if let Some(max_duration) = self.infer_conf.max_duration
+                && start.elapsed() >= max_duration
+            {
+                break;
+            }
+        }
+
+        let best = beams
+            .into_iter()
+            .max_by(|a, b| a.score.total_cmp(&b.score))
+            .ok_or_else(|| anyhow!("no beam left"))?;
+        Ok(best.tokens)
+    }
+
+    /// 前向计算 `tokens`, 返回下一个 token 的对数概率
+    async fn beam_forward(&mut self, tokens: &[u32], idx_pos: usize) -> Result<Vec<f32>> {
+        let logits = self.feed_at(tokens, idx_pos).await?;
+        let logprobs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+        Ok(logprobs.to_vec1()?)
+    }
+
+    /// 词表中以 `token` 开头的全部 token, 包括其自身
+    fn healing_candidates(&self, token: u32) -> Vec<u32> {
+        let Some(prefix) = self.tokenizer.id_to_token(token) else {
+            return vec![token];
+        };
+        self.tokenizer
+            .get_vocab(true)
+            .into_iter()
+            .filter(|(piece, _)| piece.starts_with(&prefix))
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    async fn gen_next_token(
+        &mut self,
+        ctx_tokens: &Vec<u32>,
+        idx_pos: usize,
+        ans_start_idx: Option<usize>,
+    ) -> Result<u32> {
+        let input_arr = match ans_start_idx {
+            Some(_) => ctx_tokens.last().map(std::slice::from_ref),
+            None => ctx_tokens.get(idx_pos..).filter(|x| !x.is_empty()),
+        }
+        .ok_or_else(|| anyhow!("no tokens to process"))?;
+
+        let input = Tensor::new(input_arr, &self.infer_conf.device)?.unsqueeze(0)?;
+
+        // 获取模型输出并压缩维度
+        let mut logits = self.forward(input, idx_pos).await?.squeeze(0)?.squeeze(0)?;
+        self.kv_tokens = idx_pos + input_arr.len();
+        self.peak_bytes = self
+            .peak_bytes
+            .max(device_used_bytes(&self.infer_conf.device));
+
+        // 非首个字符应用惩罚
+        let ans_tokens = ans_start_idx.map(|idx| answer_tokens(ctx_tokens, idx, idx_pos));
+        if let Some(ans_tokens) = ans_tokens {
+            if self.infer_conf.applies_repeat_penalty() {
+                let start_at = ans_tokens
+                    .len()
+                    .saturating_sub(self.infer_conf.repeat_last_n);
+                logits = apply_repeat_penalty(
+                    &logits,
+                    self.infer_conf.repeat_penalty,
+                    &ans_tokens[start_at..],
+                )?;
+            }
+
+            if self.infer_conf.frequency_penalty != 0. || self.infer_conf.presence_penalty != 0. {
+                logits = apply_frequency_presence_penalty(
+                    &logits,
+                    self.infer_conf.frequency_penalty,
+                    self.infer_conf.presence_penalty,
+                    ans_tokens,
+                )?;
+            }
+        }
+
+        // 未达到最少生成数量前屏蔽 EOS
+        let generated = ans_tokens.map_or(0, <[u32]>::len);
+        if generated < self.infer_conf.min_new_tokens {
+            logits = suppress_tokens(&logits, &self.eos_tokens())?;
+        }
+
+        // 词元修复时首个 token 只能补全被去掉的部分
+        if ans_start_idx.is_none()
+            && let Some(allowed) = self.healing.take()
+        {
+            logits = allow_tokens(&logits, &allowed)?;
+        }
+
+        let temperature = self.infer_conf.temperature_at(generated);
+
+        // 贪心解码时不过滤
+        if let Some(typical_p) = self.infer_conf.typical_p
+            && temperature > 0.
+        {
+            logits = apply_typical_p(&logits, typical_p, temperature)?;
+        }
+
+        if let Some(mirostat) = &mut self.mirostat {
+            logits = mirostat.truncate(&logits, temperature)?;
+        }
+
+        // 未开启 trace 级别时不计算, 也不解码
+        let trace = enabled!(Level::TRACE);
+        let logprobs = if self.infer_conf.top_logprobs.is_some() || trace {
+            Some(
+                candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?
+                    .to_vec1::<f32>()?,
+            )
+        } else {
+            None
+        };
+
+        // 采样下一个token
+        let token = match self.infer_conf.temperature_schedule {
+            Some(_) if temperature <= 0. => logits.argmax(0)?.to_scalar::<u32>()?,
+            Some(_) => self
+                .logits_processor
+                .sample(&(logits / temperature)?)
+                .map_err(Error::msg)?,
+            None => self.logits_processor.sample(&logits).map_err(Error::msg)?,
+        };
+        if let Some(mirostat) = &mut self.mirostat {
+            mirostat.update(token);
+        }
+        if trace {
+            let text = self.tokenizer.decode(&[token], false).map_err(Error::msg)?;
+            let logprob = logprobs
+                .as_ref()
+                .and_then(|l| l.get(token as usize))
+                .copied();
+            trace!(token, text, logprob, index_pos = idx_pos, "sampled token");
+        }
+        if let (Some(logprobs), Some(k)) = (logprobs, self.infer_conf.top_logprobs)
+            && !self.is_eos(token)
+        {
+            let logprob = self.token_logprob(token, &logprobs, k)?;
+            self.logprobs.push(logprob);
+        }
+        Ok(token)
+    }
+
+    /// 由采样前的对数概率构造 `token` 及其前 `k` 个候选的记录
+    fn token_logprob(&self, token: u32, logprobs: &[f32], k: usize) -> Result<TokenLogprob> {
+        let decode = |id: u32| self.tokenizer.decode(&[id], false).map_err(Error::msg);
+        let top_logprobs = top_k(logprobs, k)
+            .into_iter()
+            .map(|(id, logprob)| {
+                Ok(TopLogprob {
+                    id,
+                    token: decode(id)?,
+                    logprob,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(TokenLogprob {
+            id: token,
+            token: decode(token)?,
+            logprob: logprobs
+                .get(token as usize)
+                .copied()
+                .ok_or_else(|| anyhow!("token {token} is out of the vocab"))?,
+            top_logprobs,
+        })
+    }
+}
+
+/// 应用了请求参数的实例, 离开作用域时恢复原推理参数
+///
+/// 流式请求的流可能在生成中途被丢弃, 由析构恢复才不会把请求参数留给之后的对话
+struct RequestConfig<'a> {
+    text_gen: &'a mut TextGeneration,
+    original: Option<InferenceConfig>,
+}
+
+impl Deref for RequestConfig<'_> {
+    type Target = TextGeneration;
+
+    fn deref(&self) -> &TextGeneration {
+        self.text_gen
+    }
+}
+
+impl DerefMut for RequestConfig<'_> {
+    fn deref_mut(&mut self) -> &mut TextGeneration {
+        self.text_gen
+    }
+}
+
+impl Drop for RequestConfig<'_> {
+    fn drop(&mut self) {
+        if let Some(original) = self.original.take() {
+            self.text_gen.set_config(original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::TextGenerationHandle;
+    use crate::model::ModelInference;
+    use crate::model::config::TemperatureSchedule;
+    use crate::pipe::TextGeneration;
+    use crate::utils::chat::{ChatContext, Message};
+    use crate::utils::{get_user_prompt, proxy::ProxyGuard};
+    use anyhow::{Error, Result};
+    use candle::Tensor;
+    use candle::{DType, Device};
+    use candle_transformers::generation::LogitsProcessor;
+    use candle_transformers::utils::apply_repeat_penalty;
+    use futures_util::{StreamExt, pin_mut};
+    use std::collections::HashMap;
+    use std::io;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokenizers::decoders::byte_fallback::ByteFallback;
+    use tokenizers::decoders::fuse::Fuse;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::metaspace::{Metaspace, PrependScheme};
+    use tokenizers::processors::template::TemplateProcessing;
+    use tokenizers::{AddedToken, Tokenizer};
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    fn str2tokens(string: &str, tokenizer: &Tokenizer) -> Result<Vec<u32>> {
+        let tokens = tokenizer.encode(string, true).map_err(Error::msg)?;
+        let tokens = tokens.get_ids().to_vec();
+
+        Ok(tokens)
+    }
+
+    fn gen_next_token(
+        ctx_tokens: &[u32],
+        idx_pos: usize,
+        model: &mut Box<dyn ModelInference>,
+        logits_processor: &mut LogitsProcessor,
+        config: &InferenceConfig,
+        ans_start_idx: Option<usize>,
+    ) -> Result<u32> {
+        let input = match ans_start_idx {
+            Some(_) => Tensor::new(&[*ctx_tokens.last().unwrap()], &config.device)?,
+            None => Tensor::new(ctx_tokens, &config.device)?,
+        }
+        .unsqueeze(0)?;
+
+        let mut logits = model.forward(&input, idx_pos)?.squeeze(0)?.squeeze(0)?;
+
+        if let Some(ans_start_idx) = ans_start_idx {
+            if config.repeat_penalty != 1. {
+                let ans_tokens = &ctx_tokens[ans_start_idx..];
+                let start_at = ans_tokens.len().saturating_sub(config.repeat_last_n);
+                logits =
+                    apply_repeat_penalty(&logits, config.repeat_penalty, &ans_tokens[start_at..])?;
+            }
+        }
+
+        // 采样下一个token
+        logits_processor.sample(&logits).map_err(Error::msg)
+    }
+
+    #[tokio::test]
+    async fn test_prompt() -> Result<()> {
+        // let _proxy = ProxyGuard::new(7890);
+
+        let registry = ModelRegistry::new()?;
+        let hub_info = registry.get("qwen3.4b_base")?;
+        let hub = HubClient::from_env()?;
+
+        let (mut model, tokenizer) =
+            ModelLoader::load(&hub, hub_info, &candle::Device::cuda_if_available(0)?).await?;
+        let config = InferenceConfig::default();
+
+        // 初始化模型、分词器和logits处理器
+        let mut tos = TokenOutputStream::new(Arc::new(tokenizer));
+        let mut logits_processor =
+            LogitsProcessor::new(config.seed, Some(config.temperature), config.top_p);
+        let mut ctx = ChatContext::from_repo(&hub, &hub_info.tokenizer_repo).await?;
+
+        let pth = hub.get(&hub_info.tokenizer_repo, "config.json").await?;
+        let v: Value = serde_json::from_str(&fs::read_to_string(pth)?)?;
+        let eos_token_id = v
+            .get("eos_token_id")
+            .and_then(|x| x.as_u64())
+            .ok_or_else(|| anyhow!("eos_token_id not found"))? as u32;
+
+        // 初始化上下文token列表
+        let mut ctx_tokens = vec![];
+
+        let prompts = vec![
+            "我是snake，你给我记住了",
+            "还记得我是谁吗",
+            "你是谁",
+            "给我笑一笑",
+        ];
+        let mut answer = String::with_capacity(1024);
+
+        for prompt_str in prompts {
+            ctx.push_msg(prompt_str);
+            let prompt = ctx.render()?;
+            model.clr_kv_cache();
+            ctx_tokens = str2tokens(&prompt, tos.tokenizer())?;
+
+            let start = std::time::Instant::now();
+
+            let ans_start_idx = ctx_tokens.len();
+
+            // 统一处理token生成和输出
+            for index in 0..config.sample_len {
+                let next_token = gen_next_token(
+                    &ctx_tokens,
+                    if index == 0 {
+                        0
+                    } else {
+                        ans_start_idx + index - 1
+                    },
+                    &mut model,
+                    &mut logits_processor,
+                    &config,
+                    if index == 0 {
+                        None
+                    } else {
+                        Some(ans_start_idx)
+                    },
+                )?;
+                ctx_tokens.push(next_token);
+
+                if let Some(t) = tos.next_token(next_token)? {
+                    print!("{}", t);
+                    answer.push_str(&t);
+                    io::stdout().flush()?;
+                }
+
+                if next_token == eos_token_id {
+                    break;
+                }
+            }
+
+            if let Some(t) = tos.decode_rest()? {
+                print!("{}", t);
+                answer.push_str(&t);
+                io::stdout().flush()?;
+            }
+
+            ctx.push_msg(&answer);
+
+            tos.clear();
+            answer.clear();
+
+            let dt = start.elapsed();
+
+            println!(
+                "\n\nspeed: {:.2} token/s",
+                (ctx_tokens.len() - ans_start_idx) as f64 / dt.as_secs_f64(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// 测试用模型, 每次前向计算休眠指定时间
+    /// 以缓存中 token 之和对 3 取模作为最可能的下一个 token, 从不输出 `<eos>`
+    #[derive(Clone)]
+    struct MockModel {
+        delay: Duration,
+        cache: Vec<u32>,
+    }
+
+    impl ModelInference for MockModel {
+        fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+            std::thread::sleep(self.delay);
+
+            self.cache.truncate(index_pos);
+            self.cache.extend(x.squeeze(0)?.to_vec1::<u32>()?);
+
+            let next = self.cache.iter().sum::<u32>() % 3;
+            let logits: Vec<f32> = (0..4).map(|i| if i == next { 1. } else { 0. }).collect();
+            Ok(Tensor::from_vec(logits, (1, 4), &Device::Cpu)?)
+        }
+
+        fn clr_kv_cache(&mut self) {
+            self.cache.clear();
+        }
+
+        fn arch_name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn num_layers(&self) -> usize {
+            1
+        }
+
+        fn fork(&self) -> Result<Box<dyn ModelInference>> {
+            let mut model = self.clone();
+            model.cache.clear();
+            Ok(Box::new(model))
+        }
+
+        fn supports_snapshots(&self) -> bool {
+            true
+        }
+
+        fn save_cache(&self) -> Result<CacheSnapshot> {
+            Ok(CacheSnapshot::new(self.cache.clone()))
+        }
+
+        fn restore_cache(&mut self, snapshot: &CacheSnapshot) -> Result<()> {
+            self.cache = snapshot.downcast_ref::<Vec<u32>>().unwrap().clone();
+            Ok(())
+        }
+    }
+
+    /// 按脚本依次输出 token 的测试模型, 脚本结束后输出 `eos`
+    #[derive(Clone)]
+    struct ScriptedModel {
+        script: Vec<u32>,
+        eos: u32,
+        step: usize,
+    }
+
+    impl ModelInference for ScriptedModel {
+        fn forward(&mut self, _x: &Tensor, index_pos: usize) -> Result<Tensor> {
+            if index_pos == 0 {
+                self.step = 0;
+            }
+            let next = self.script.get(self.step).copied().unwrap_or(self.eos);
+            self.step += 1;
+
+            let vocab_size = self.eos as usize + 1;
+            let logits: Vec<f32> = (0..vocab_size)
+                .map(|i| if i as u32 == next { 1. } else { 0. })
+                .collect();
+            Ok(Tensor::from_vec(logits, (1, vocab_size), &Device::Cpu)?)
+        }
+
+        fn clr_kv_cache(&mut self) {
+            self.step = 0;
+        }
+
+        fn arch_name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn num_layers(&self) -> usize {
+            1
+        }
+
+        fn fork(&self) -> Result<Box<dyn ModelInference>> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    /// 由本次输入的 token 直接算出 logits 的无状态测试模型
+    struct FnModel<const N: usize>(fn(&[u32]) -> [f32; N]);
+
+    impl<const N: usize> ModelInference for FnModel<N> {
+        fn forward(&mut self, x: &Tensor, _index_pos: usize) -> Result<Tensor> {
+            let logits = (self.0)(&x.squeeze(0)?.to_vec1::<u32>()?);
+            Ok(Tensor::new(&logits, &Device::Cpu)?.unsqueeze(0)?)
+        }
+
+        fn clr_kv_cache(&mut self) {}
+
+        fn arch_name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn num_layers(&self) -> usize {
+            1
+        }
+    }
+
+    /// 按脚本输出 token 的会话, 分词器为 [`mock_tokenizer`]
+    fn scripted_text_gen(
+        script: Vec<u32>,
+        eos: u32,
+        config: InferenceConfig,
+    ) -> Result<TextGeneration> {
+        scripted_text_gen_with(mock_tokenizer()?, script, eos, config)
+    }
+
+    /// 使用指定分词器的 [`scripted_text_gen`], 脚本中的 token 需在词表内才能解码
+    fn scripted_text_gen_with(
+        tokenizer: Tokenizer,
+        script: Vec<u32>,
+        eos: u32,
+        config: InferenceConfig,
+    ) -> Result<TextGeneration> {
+        Ok(TextGeneration::from_parts(
+            Box::new(ScriptedModel {
+                script,
+                eos,
+                step: 0,
+            }),
+            tokenizer,
+            mock_ctx()?,
+            InferenceConfig {
+                device: Device::Cpu,
+                ..config
+            },
+            eos,
+        ))
+    }
+
+    fn mock_text_gen(delay: Duration, config: InferenceConfig) -> Result<TextGeneration> {
+        Ok(mock_shared(delay, config)?.into())
+    }
+
+    /// 构造一个无需网络的 mock 共享模型, 词表为 `<unk> a b <eos>`
+    fn mock_shared(delay: Duration, config: InferenceConfig) -> Result<SharedModel> {
+        Ok(SharedModel::from_parts(
+            Box::new(MockModel {
+                delay,
+                cache: vec![],
+            }),
+            mock_tokenizer()?,
+            mock_ctx()?,
+            InferenceConfig {
+                device: Device::Cpu,
+                ..config
+            },
+            3,
+        ))
+    }
+
+    /// 词表为 `<unk> a b <eos>` 的分词器, `<eos>` 不是特殊 token, 解码为可见文本
+    fn mock_tokenizer() -> Result<Tokenizer> {
+        let vocab = [("<unk>", 0), ("a", 1), ("b", 2), ("<eos>", 3)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        Ok(tokenizer)
+    }
+
+    fn mock_ctx() -> Result<ChatContext> {
+        ChatContext::from_template("{% for m in messages %}{{ m.content }} {% endfor %}")
+    }
+
+    /// 完整消费一次对话的输出
+    async fn chat_to_string(text_gen: &mut TextGeneration, prompt: &str) -> Result<String> {
+        let stream = text_gen.chat(prompt);
+        pin_mut!(stream);
+
+        let mut answer = String::new();
+        while let Some(r) = stream.next().await {
+            answer.push_str(&r?);
+        }
+
+        Ok(answer)
+    }
+
+    /// 记录所有 span 字段的测试 layer
+    #[derive(Clone, Default)]
+    struct SpanCapture(Arc<Mutex<HashMap<String, HashMap<String, String>>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanCapture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let name = ctx.span(id).unwrap().name().to_string();
+            let mut spans = self.0.lock().unwrap();
+            attrs.record(&mut FieldVisitor(spans.entry(name).or_default()));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let name = ctx.span(id).unwrap().name().to_string();
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut FieldVisitor(spans.entry(name).or_default()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generation_span() -> Result<()> {
+        let capture = SpanCapture::default();
+        let _guard = tracing_subscriber::registry()
+            .with(capture.clone())
+            .set_default();
+
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        text_gen.model_id = Some("mock".to_string());
+
+        chat_to_string(&mut text_gen, "a b").await?;
+
+        let spans = capture.0.lock().unwrap();
+        let generation = &spans["generation"];
+        assert_eq!(generation["model_id"], "\"mock\"");
+        assert_eq!(generation["prompt_tokens"], "2");
+        assert!(generation["completion_tokens"].parse::<usize>()? > 0);
+        assert!(generation.contains_key("tokens_per_second"));
+
+        Ok(())
+    }
+
+    /// 记录 trace 级别事件字段的测试 layer
+    #[derive(Clone, Default)]
+    struct TraceCapture(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: Subscriber> Layer<S> for TraceCapture {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            if *event.metadata().level() == Level::TRACE {
+                let mut fields = HashMap::new();
+                event.record(&mut FieldVisitor(&mut fields));
+                self.0.lock().unwrap().push(fields);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_sampled_tokens() -> Result<()> {
+        let capture = TraceCapture::default();
+        let _guard = tracing_subscriber::registry()
+            .with(capture.clone())
+            .set_default();
+
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        chat_to_string(&mut text_gen, "a b").await?;
+
+        let events: Vec<_> = capture
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|fields| fields.get("message").is_some_and(|m| m == "sampled token"))
+            .cloned()
+            .collect();
+        let stats = text_gen.last_stats().unwrap();
+        assert_eq!(events.len(), stats.completion_tokens);
+
+        // 预填充后依次解码, 位置逐个递增
+        let positions: Vec<usize> = events
+            .iter()
+            .map(|fields| fields["index_pos"].parse())
+            .collect::<Result<_, _>>()?;
+        assert_eq!(positions[0], 0);
+        assert!(positions.windows(2).all(|w| w[1] > w[0]));
+        for fields in &events {
+            let token: u32 = fields["token"].parse()?;
+            let text = text_gen
+                .tokenizer
+                .decode(&[token], false)
+                .map_err(Error::msg)?;
+            assert_eq!(fields["text"], format!("{text:?}"));
+            assert!(fields["logprob"].parse::<f32>()? <= 0.);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_duration() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 100,
+            max_duration: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::from_millis(20), config)?;
+
+        let answer = chat_to_string(&mut text_gen, "a b").await?;
+
+        let stats = text_gen.last_stats().unwrap();
+        assert_eq!(stats.stop_reason, StopReason::Timeout);
+        assert!(stats.completion_tokens > 0 && stats.completion_tokens < 100);
+        assert_eq!(text_gen.last_finish_reason(), Some(FinishReason::Timeout));
+        // 已生成的部分回答保留在对话中
+        assert!(!answer.is_empty());
+        assert_eq!(text_gen.ctx.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finish_reason_eos() -> Result<()> {
+        let config = InferenceConfig {
+            temperature: 0.,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        // mock 模型对 "a" 依次生成 1, 2
+        text_gen.eos_token_id = 2;
+
+        assert!(text_gen.last_finish_reason().is_none());
+        chat_to_string(&mut text_gen, "a").await?;
+
+        assert_eq!(text_gen.last_finish_reason(), Some(FinishReason::Stop));
+        assert_eq!(text_gen.last_stats().unwrap().completion_tokens, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extra_stop_tokens() -> Result<()> {
+        let config = InferenceConfig::builder()
+            .temperature(0.)
+            .repeat_penalty(1.)
+            .extra_stop_tokens(["b", "<|im_end|>"])
+            .build()?;
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        // 不在词表中的名称被忽略
+        assert_eq!(text_gen.stop_tokens, [2]);
+
+        // mock 模型对 "a" 依次生成 1, 2, 按名称添加的 b 与 EOS 一样结束回答且不输出
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, "a");
+        assert_eq!(text_gen.last_finish_reason(), Some(FinishReason::Stop));
+        assert_eq!(text_gen.last_stats().unwrap().completion_tokens, 2);
+
+        Ok(())
+    }
+
+    /// 在 `fail_at` 位置前向计算以 `error` 出错一次的 [`MockModel`]
+    struct FlakyModel {
+        inner: MockModel,
+        fail_at: usize,
+        error: &'static str,
+        failed: bool,
+    }
+
+    impl FlakyModel {
+        fn new(fail_at: usize, error: &'static str) -> Self {
+            Self {
+                inner: MockModel {
+                    delay: Duration::ZERO,
+                    cache: vec![],
+                },
+                fail_at,
+                error,
+                failed: false,
+            }
+        }
+    }
+
+    impl ModelInference for FlakyModel {
+        fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+            if index_pos == self.fail_at && !self.failed {
+                self.failed = true;
+                bail!("{}", self.error);
+            }
+            self.inner.forward(x, index_pos)
+        }
+
+        fn clr_kv_cache(&mut self) {
+            self.inner.clr_kv_cache();
+        }
+
+        fn arch_name(&self) -> &'static str {
+            self.inner.arch_name()
+        }
+
+        fn num_layers(&self) -> usize {
+            self.inner.num_layers()
+        }
+    }
+
+    /// 以字节回退 token 逐字节生成 "a😀" 的实例
+    fn byte_fallback_text_gen() -> Result<TextGeneration> {
+        // 😀 = F0 9F 98 80, 以字节回退 token 逐字节生成
+        let tokens = [
+            "<unk>", "a", "<0xF0>", "<0x9F>", "<0x98>", "<0x80>", "<eos>",
+        ];
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.with_decoder(Some(ByteFallback::new()));
+
+        scripted_text_gen_with(
+            tokenizer,
+            vec![1, 2, 3, 4, 5],
+            6,
+            InferenceConfig {
+                temperature: 0.,
+                repeat_penalty: 1.,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pending_bytes() -> Result<()> {
+        let mut text_gen = byte_fallback_text_gen()?;
+
+        // 收到第 4 个 token 时前 3 个已解码, 😀 的前两个字节尚未输出
+        {
+            let stream = text_gen.chat_bytes("a");
+            pin_mut!(stream);
+            for _ in 0..4 {
+                stream.next().await.unwrap()?;
+            }
+        }
+        assert_eq!(text_gen.pending_bytes(), 2);
+
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, "a😀");
+        assert_eq!(text_gen.pending_bytes(), 0);
+
+        // 😀 生成完整后虽因不是字母或数字暂未输出, 也不再计入
+        text_gen.clear_output_buffer();
+        let pending: Vec<_> = (1..=5)
+            .map(|token| {
+                text_gen.decode_next(token)?;
+                Ok(text_gen.pending_bytes())
+            })
+            .collect::<Result<_>>()?;
+        assert_eq!(pending, [0, 1, 2, 3, 0]);
+        assert_eq!(text_gen.tos.decode_rest()?.as_deref(), Some("😀"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear_output_buffer() -> Result<()> {
+        let mut text_gen = byte_fallback_text_gen()?;
+
+        // 在 😀 只生成了两个字节时丢弃回答流
+        {
+            let stream = text_gen.chat_bytes("a");
+            pin_mut!(stream);
+            for _ in 0..4 {
+                stream.next().await.unwrap()?;
+            }
+        }
+        assert_eq!(text_gen.pending_bytes(), 2);
+        let history = text_gen.ctx.len();
+
+        text_gen.clear_output_buffer();
+        assert_eq!(text_gen.pending_bytes(), 0);
+        assert_eq!(text_gen.tos.decode_rest()?, None);
+        // 只清空解码器, 对话历史不变
+        assert_eq!(text_gen.ctx.len(), history);
+
+        // 下一轮的输出不含上一轮残留的字节
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, "a😀");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear_kv_cache_on_error() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 4,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        let mut fresh = mock_text_gen(Duration::ZERO, config.clone())?;
+        let expected = chat_to_string(&mut fresh, "a").await?;
+
+        let mut text_gen = TextGeneration::from_parts(
+            Box::new(FlakyModel::new(2, "device lost")),
+            mock_tokenizer()?,
+            mock_ctx()?,
+            InferenceConfig {
+                device: Device::Cpu,
+                ..config
+            },
+            3,
+        );
+
+        // 生成一个 token 后出错
+        let chunks: Vec<_> = text_gen.chat("a").collect().await;
+        assert!(chunks[0].is_ok());
+        let err = chunks.last().unwrap().as_ref().unwrap_err();
+        assert!(err.to_string().contains("device lost"), "{err}");
+
+        // 缓存已清空, 之后从位置 0 开始
+        assert_eq!(text_gen.position(), 0);
+        let logits = text_gen.feed(&[1]).await?;
+        assert_eq!(logits.argmax(0)?.to_scalar::<u32>()?, 1);
+
+        // 出错的提问已撤销, 重新提问与全新会话的输出相同
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_parts() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 3,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        // 模板按片段列表渲染, 与纯文本提问的提示词相同
+        let template = "{% for m in messages %}{% if m.content is string %}{{ m.content }}\
+                        {% else %}{% for p in m.content %}{{ p.text }}{% endfor %}{% endif %} {% endfor %}";
+        let mut fresh = mock_text_gen(Duration::ZERO, config.clone())?;
+        fresh.ctx = fresh.ctx.clone().with_template(template)?;
+        let expected = chat_to_string(&mut fresh, "a").await?;
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        text_gen.ctx = text_gen.ctx.clone().with_template(template)?;
+
+        // 图像片段报错, 不影响对话历史
+        let parts = [ContentPart::ImagePlaceholder, ContentPart::text("a")];
+        let chunks: Vec<_> = text_gen.chat_parts(&parts).collect().await;
+        let err = chunks.into_iter().collect::<Result<String>>().unwrap_err();
+        assert!(err.to_string().contains("does not support images"), "{err}");
+        assert!(text_gen.ctx.is_empty());
+
+        // 片段原样存入对话历史
+        let parts = [ContentPart::text("a")];
+        let chunks: Vec<_> = text_gen.chat_parts(&parts).collect().await;
+        assert_eq!(chunks.into_iter().collect::<Result<String>>()?, expected);
+        assert_eq!(text_gen.ctx[0].content, Content::Parts(parts.to_vec()));
+        assert_eq!(
+            text_gen.last_rendered_prompt(),
+            fresh.last_rendered_prompt()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handle() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 3,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        let mut local = mock_text_gen(Duration::ZERO, config.clone())?;
+        let expected = [
+            chat_to_string(&mut local, "a").await?,
+            chat_to_string(&mut local, "b").await?,
+        ];
+
+        // 后台线程中的对话与本地一致, 第二轮延续第一轮的历史
+        let handle = mock_text_gen(Duration::ZERO, config)?.into_handle();
+        for (prompt, expected) in ["a", "b"].into_iter().zip(expected) {
+            let chunks: Vec<_> = handle.chat(prompt).collect().await;
+            assert_eq!(chunks.into_iter().collect::<Result<String>>()?, expected);
+        }
+        handle.shutdown().await?;
+
+        // 加载失败时每个请求都返回错误
+        let handle =
+            TextGenerationHandle::spawn(|| async { Err(LlmError::ModelNotFound("x".into())) });
+        let chunks: Vec<_> = handle.chat("a").collect().await;
+        let err = chunks.into_iter().collect::<Result<String>>().unwrap_err();
+        assert!(err.to_string().contains("failed to load"), "{err}");
+        handle.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_top_logprobs() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 3,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            top_logprobs: Some(2),
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        chat_to_string(&mut text_gen, "a").await?;
+
+        let logprobs = text_gen.last_logprobs();
+        assert_eq!(
+            logprobs.len(),
+            text_gen.last_stats().unwrap().completion_tokens
+        );
+        // mock 模型的 logits 只有选中的 token 为 1, 其余 3 个为 0
+        let expected = 1. - (1f32.exp() + 3.).ln();
+        for logprob in logprobs {
+            assert_eq!(logprob.top_logprobs.len(), 2);
+            assert!((logprob.logprob - expected).abs() < 1e-5, "{logprob:?}");
+            // 贪心解码选中的是概率最高的 token
+            let top = &logprob.top_logprobs[0];
+            assert_eq!((top.id, top.logprob), (logprob.id, logprob.logprob));
+            assert_eq!(top.token, logprob.token);
+            assert!(logprob.top_logprobs[1].logprob < logprob.logprob);
+        }
+
+        // 未设置时不记录
+        let mut text_gen = mock_text_gen(
+            Duration::ZERO,
+            InferenceConfig {
+                top_logprobs: None,
+                ..config
+            },
+        )?;
+        chat_to_string(&mut text_gen, "a").await?;
+        assert!(text_gen.last_logprobs().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_answer_not_recorded() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 0,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, "");
+        assert!(text_gen.ctx.messages.is_empty());
+        assert_eq!(text_gen.chat_n("a", 2).await?, ["", ""]);
+        assert!(text_gen.ctx.messages.is_empty());
+
+        // 之后的提问仍从用户回合开始
+        text_gen.set_config(InferenceConfig {
+            sample_len: 3,
+            ..text_gen.infer_conf.clone()
+        });
+        let answer = chat_to_string(&mut text_gen, "a").await?;
+        let messages = &text_gen.ctx.messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, Role::User);
+        assert_eq!(messages[1], Message::new(Role::Assistant, answer));
+
+        // 模型第一个 token 就输出 EOS, 同样不记录空的助手回合
+        let mut text_gen = scripted_text_gen(vec![], 3, InferenceConfig::default())?;
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, "");
+        assert!(text_gen.ctx.messages.is_empty());
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::EosToken
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_continue_generation() -> Result<()> {
+        // 从位置 0 重新预填充时脚本会从头开始
+        let mut text_gen = scripted_text_gen(
+            vec![1, 2, 1, 2, 2, 1],
+            3,
+            InferenceConfig {
+                sample_len: 3,
+                temperature: 0.,
+                repeat_penalty: 1.,
+                ..Default::default()
+            },
+        )?;
+
+        // 没有被截断的回答
+        let chunks: Vec<_> = text_gen.continue_generation().collect().await;
+        assert!(chunks[0].is_err());
+
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, "a b a");
+        let prompt_tokens = text_gen.last_stats().unwrap().prompt_tokens;
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::MaxTokens
+        );
+
+        let chunks: Vec<_> = text_gen.continue_generation().collect().await;
+        let continued = chunks.into_iter().collect::<Result<String>>()?;
+        // 衔接处保留分隔的空格
+        assert_eq!(continued, " b b a");
+        let stats = text_gen.last_stats().unwrap();
+        assert_eq!(stats.prompt_tokens, prompt_tokens);
+        assert_eq!(stats.completion_tokens, 3);
+        assert_eq!(text_gen.position(), prompt_tokens + 5);
+        assert_eq!(text_gen.ctx.messages.len(), 2);
+        assert_eq!(
+            text_gen.ctx.messages[1],
+            Message::new(Role::Assistant, "a b a b b a")
+        );
+
+        // 脚本结束后以 EOS 结束, 回答不变且不能再续写
+        let chunks: Vec<_> = text_gen.continue_generation().collect().await;
+        assert_eq!(chunks.into_iter().collect::<Result<String>>()?, "");
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::EosToken
+        );
+        assert_eq!(text_gen.ctx.messages[1].content, "a b a b b a");
+        let chunks: Vec<_> = text_gen.continue_generation().collect().await;
+        assert!(chunks[0].is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generation_cache() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// 统计前向计算次数的 [`MockModel`]
+        struct CountingModel {
+            inner: MockModel,
+            calls: Arc<AtomicUsize>,
+        }
+
+        impl ModelInference for CountingModel {
+            fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                self.inner.forward(x, index_pos)
+            }
+
+            fn clr_kv_cache(&mut self) {
+                self.inner.clr_kv_cache();
+            }
+
+            fn arch_name(&self) -> &'static str {
+                self.inner.arch_name()
+            }
+
+            fn num_layers(&self) -> usize {
+                self.inner.num_layers()
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("pipe-gen-cache-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let model = CountingModel {
+            inner: MockModel {
+                delay: Duration::ZERO,
+                cache: vec![],
+            },
+            calls: calls.clone(),
+        };
+        let config = InferenceConfig {
+            sample_len: 5,
+            temperature: 0.,
+            device: Device::Cpu,
+            ..Default::default()
+        };
+        let mut text_gen =
+            TextGeneration::from_parts(Box::new(model), mock_tokenizer()?, mock_ctx()?, config, 3);
+        text_gen.set_generation_cache(Some(GenerationCache::open(&path)?));
+
+        // 由组件直接构造时无法确定加载的权重, 不使用缓存
+        chat_to_string(&mut text_gen, "a b").await?;
+        assert!(text_gen.generation_cache().unwrap().is_empty());
+
+        text_gen.reset()?;
+        calls.store(0, Ordering::SeqCst);
+        text_gen.weights_id = Some(serde_json::json!({ "model_id": "mock" }));
+        let first = chat_to_string(&mut text_gen, "a b").await?;
+        let generated = calls.load(Ordering::SeqCst);
+        assert!(generated > 0);
+        assert_eq!(text_gen.generation_cache().unwrap().len(), 1);
+
+        // 相同的贪心提问直接取自缓存, 模型没有运行
+        text_gen.reset()?;
+        let second = chat_to_string(&mut text_gen, "a b").await?;
+        assert_eq!(second, first);
+        assert_eq!(calls.load(Ordering::SeqCst), generated);
+        assert_eq!(text_gen.ctx.last().unwrap().content, first);
+        let stats = text_gen.last_stats().unwrap();
+        assert_eq!(stats.completion_tokens, 5);
+        assert_eq!(stats.stop_reason, StopReason::MaxTokens);
+
+        // 缓存同时保存回答的 token, 命中时按 token 输出的接口也能得到完整回答
+        text_gen.reset()?;
+        let mut steps = vec![];
+        {
+            let stream = text_gen.chat_tokens("a b");
+            pin_mut!(stream);
+            while let Some(step) = stream.next().await {
+                steps.push(step?);
+            }
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), generated);
+        assert_eq!(steps.len(), 5);
+        let text: String = steps.iter().filter_map(|(_, t)| t.as_deref()).collect();
+        assert_eq!(text, first);
+
+        // 采样生成不查询也不写入缓存
+        text_gen.reset()?;
+        text_gen.infer_conf.temperature = 0.8;
+        chat_to_string(&mut text_gen, "a b").await?;
+        assert!(calls.load(Ordering::SeqCst) > generated);
+        assert_eq!(text_gen.generation_cache().unwrap().len(), 1);
+
+        // 命中时各 token 的文本与实际生成时的归属相同
+        text_gen.reset()?;
+        text_gen.infer_conf.temperature = 0.;
+        let cache = text_gen.generation_cache.take();
+        let mut generated_steps = vec![];
+        {
+            let stream = text_gen.chat_tokens("a b");
+            pin_mut!(stream);
+            while let Some(step) = stream.next().await {
+                generated_steps.push(step?);
+            }
+        }
+        assert_eq!(steps, generated_steps);
+        text_gen.set_generation_cache(cache);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_iter() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        let expected = runtime.block_on(chat_to_string(&mut text_gen, "a b"))?;
+        assert!(!expected.is_empty());
+
+        // 不在运行时中, 自建运行时
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        let answer = text_gen.chat_iter("a b").collect::<Result<String>>()?;
+        assert_eq!(answer, expected);
+        assert_eq!(text_gen.ctx.last().unwrap().content, expected);
+
+        // 多线程运行时中使用当前运行时
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        let answer =
+            runtime.block_on(async { text_gen.chat_iter("a b").collect::<Result<String>>() });
+        if cfg!(feature = "rt-multi-thread") {
+            assert_eq!(answer?, expected);
+        } else {
+            assert!(answer.is_err());
+        }
+
+        // 单线程运行时中返回错误而不是 panic
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        let answer =
+            runtime.block_on(async { text_gen.chat_iter("a b").collect::<Result<String>>() });
+        assert!(answer.is_err());
+        assert!(text_gen.ctx.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_benchmark() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::from_millis(2), InferenceConfig::default())?;
+        chat_to_string(&mut text_gen, "a").await?;
+        let history = text_gen.ctx.messages.clone();
+
+        let report = text_gen.benchmark("a b", 4).await?;
+        assert_eq!(report.prompt_tokens, 2);
+        assert_eq!(report.completion_tokens, 4);
+        assert!(report.ttft > Duration::ZERO);
+        assert!(report.prefill_tps > 0.);
+        assert!(report.decode_tps > 0.);
+        assert!(report.ttft <= report.total);
+        // 对话历史不变, KV 缓存已清空
+        assert_eq!(text_gen.ctx.messages, history);
+        assert_eq!(text_gen.position(), 0);
+
+        assert_eq!(text_gen.benchmark("a", 1).await?.decode_tps, 0.);
+        assert!(text_gen.benchmark("a", 0).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handle_interrupted() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 50,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        let handle = mock_text_gen(Duration::from_millis(20), config.clone())?.into_handle();
+
+        // 生成途中驱逐模型, 回答流以 Interrupted 结束
+        let mut stream = Box::pin(handle.chat("a"));
+        stream.next().await.unwrap()?;
+        let queued = handle.chat("b");
+        handle.evict().await?;
+        let rest: Vec<_> = stream.collect().await;
+        assert!(rest.len() < 49, "generation was not interrupted");
+        // 排队中的请求同样被中断
+        for chunks in [rest, queued.collect().await] {
+            let err = chunks.into_iter().last().unwrap().unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(LlmError::Interrupted)),
+                "{err}"
+            );
+        }
+
+        // 重新加载后的请求使用新模型, 对话历史从头开始
+        let config = InferenceConfig {
+            sample_len: 3,
+            ..config
+        };
+        let expected =
+            chat_to_string(&mut mock_text_gen(Duration::ZERO, config.clone())?, "a").await?;
+        let handle = mock_text_gen(Duration::from_millis(20), config.clone())?.into_handle();
+        let mut stream = Box::pin(handle.chat("b"));
+        stream.next().await.unwrap()?;
+        handle.reload(move || async move {
+            mock_text_gen(Duration::ZERO, config).map_err(LlmError::Other)
+        })?;
+        let err = stream.collect::<Vec<_>>().await.pop().unwrap().unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(LlmError::Interrupted)),
+            "{err}"
+        );
+        let chunks: Vec<_> = handle.chat("a").collect().await;
+        assert_eq!(chunks.into_iter().collect::<Result<String>>()?, expected);
+        handle.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_out_of_memory() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 4,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            device: Device::Cpu,
+            ..Default::default()
+        };
+        let mut text_gen = TextGeneration::from_parts(
+            Box::new(FlakyModel::new(
+                2,
+                "DriverError(CUDA_ERROR_OUT_OF_MEMORY, \"out of memory\")",
+            )),
+            mock_tokenizer()?,
+            mock_ctx()?,
+            config.clone(),
+            3,
+        );
+
+        let chunks: Vec<_> = text_gen.chat("a").collect().await;
+        let err = chunks.into_iter().collect::<Result<String>>().unwrap_err();
+        let err = LlmError::from_anyhow(err, LlmError::Inference);
+        assert!(matches!(err, LlmError::OutOfMemory { .. }), "{err}");
+        assert!(err.to_string().contains("max_context"), "{err}");
+
+        // 缓存已清空, 会话可以继续使用
+        assert_eq!(text_gen.position(), 0);
+        let mut fresh = mock_text_gen(Duration::ZERO, config)?;
+        assert_eq!(
+            chat_to_string(&mut text_gen, "a").await?,
+            chat_to_string(&mut fresh, "a").await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_temperature_schedule() -> Result<()> {
+        let complete = |temperature_schedule| {
+            let config = InferenceConfig {
+                sample_len: 6,
+                temperature: 1.,
+                temperature_schedule,
+                repeat_penalty: 1.,
+                ..Default::default()
+            };
+            async move {
+                let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+                chat_to_string(&mut text_gen, "a").await
+            }
+        };
+        let greedy = chat_to_string(
+            &mut mock_text_gen(
+                Duration::ZERO,
+                InferenceConfig {
+                    sample_len: 6,
+                    temperature: 0.,
+                    repeat_penalty: 1.,
+                    ..Default::default()
+                },
+            )?,
+            "a",
+        )
+        .await?;
+
+        // 计划温度覆盖 `temperature`, 为 0 时贪心解码
+        let schedule = TemperatureSchedule::Constant(0.);
+        assert_eq!(complete(Some(schedule)).await?, greedy);
+        // 极低温度下按缩放后的 logits 采样, 结果同样确定
+        let schedule = TemperatureSchedule::Linear {
+            start: 1e-3,
+            end: 0.,
+        };
+        assert_eq!(complete(Some(schedule)).await?, greedy);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_undecodable_token() -> Result<()> {
+        // 模型输出的 5 不在词表 `<unk> a b <eos>` 中
+        let complete = |strict_decode| {
+            let mut text_gen = scripted_text_gen(
+                vec![1, 5, 2],
+                6,
+                InferenceConfig {
+                    temperature: 0.,
+                    repeat_penalty: 1.,
+                    strict_decode,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            async move {
+                let chunks: Vec<_> = text_gen.complete_raw("a").collect().await;
+                chunks.into_iter().collect::<Result<String>>()
+            }
+        };
+
+        // 跳过无法解码的 token, 继续生成
+        assert_eq!(complete(false).await?, "a b");
+        let err = complete(true).await.unwrap_err();
+        assert!(err.to_string().contains("not in the vocab"), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        chat_to_string(&mut text_gen, "a").await?;
+        let history = text_gen.ctx.messages.clone();
+
+        let dry_run = text_gen.dry_run("b a").await?;
+        assert!(dry_run.rendered_prompt.ends_with(" b a "), "{dry_run:?}");
+        assert_eq!(
+            dry_run.prompt_tokens,
+            text_gen.str2tokens(&dry_run.rendered_prompt).await?.len()
+        );
+        assert_eq!(dry_run.estimated_max_tokens, 5);
+        // 对话历史不变
+        assert_eq!(text_gen.ctx.messages, history);
+
+        // 受剩余上下文长度限制
+        text_gen.max_context = Some(dry_run.prompt_tokens + 2);
+        assert_eq!(text_gen.dry_run("b a").await?.estimated_max_tokens, 2);
+        text_gen.max_context = Some(1);
+        assert_eq!(text_gen.dry_run("b a").await?.estimated_max_tokens, 0);
+
+        Ok(())
+    }
+
+    /// 记录前向计算所在 rayon 线程池线程数的 [`MockModel`]
+    struct PoolSizeModel {
+        inner: MockModel,
+        threads: Arc<AtomicUsize>,
+    }
+
+    impl ModelInference for PoolSizeModel {
+        fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+            self.threads
+                .store(rayon::current_num_threads(), Ordering::SeqCst);
+            self.inner.forward(x, index_pos)
+        }
+
+        fn clr_kv_cache(&mut self) {
+            self.inner.clr_kv_cache();
+        }
+
+        fn arch_name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn num_layers(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cpu_threads() -> Result<()> {
+        let config = InferenceConfig {
+            temperature: 0.,
+            repeat_penalty: 1.,
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        let expected = chat_to_string(&mut text_gen, "a").await?;
+
+        let env = std::env::var("RAYON_NUM_THREADS").ok();
+        let threads = Arc::new(AtomicUsize::new(0));
+        let mut text_gen = TextGeneration::from_parts(
+            Box::new(PoolSizeModel {
+                inner: MockModel {
+                    delay: Duration::ZERO,
+                    cache: vec![],
+                },
+                threads: threads.clone(),
+            }),
+            mock_tokenizer()?,
+            mock_ctx()?,
+            InferenceConfig {
+                cpu_threads: Some(1),
+                device: Device::Cpu,
+                ..config
+            },
+            3,
+        );
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, expected);
+
+        // 前向计算在专用线程池中执行, 不修改环境变量
+        assert_eq!(threads.load(Ordering::SeqCst), 1);
+        assert_eq!(std::env::var("RAYON_NUM_THREADS").ok(), env);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finish_reason_length() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        chat_to_string(&mut text_gen, "a b").await?;
+
+        assert_eq!(text_gen.last_finish_reason(), Some(FinishReason::Length));
+        assert_eq!(text_gen.last_stats().unwrap().completion_tokens, 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finish_reason_cancelled() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        // 输出第一段后丢弃回答流
+        {
+            let stream = text_gen.chat("a b");
+            pin_mut!(stream);
+            stream.next().await.unwrap()?;
+        }
+        assert_eq!(text_gen.last_finish_reason(), Some(FinishReason::Cancelled));
+        assert!(text_gen.last_stats().is_none());
+
+        // 下一轮完整生成后恢复为实际的结束原因
+        chat_to_string(&mut text_gen, "a b").await?;
+        assert_eq!(text_gen.last_finish_reason(), Some(FinishReason::Length));
+
+        Ok(())
+    }
+
+    fn completion_request(stream: bool) -> Result<ChatCompletionRequest> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "model": "mock",
+            "messages": [
+                {"role": "system", "content": "a"},
+                {"role": "user", "content": "b"}
+            ],
+            "max_tokens": 3,
+            "temperature": 0.0,
+            "stream": stream
+        }))?)
+    }
+
+    #[tokio::test]
+    async fn test_complete() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+
+        let Completion::Response(resp) = text_gen.complete(completion_request(false)?).await?
+        else {
+            bail!("expected a full response");
+        };
+
+        let v = serde_json::to_value(&resp)?;
+        assert_eq!(v["model"], "mock");
+        assert_eq!(v["choices"][0]["message"]["role"], "assistant");
+        assert_eq!(v["choices"][0]["finish_reason"], "length");
+        assert_eq!(v["usage"]["completion_tokens"], 3);
+
+        // 请求参数不影响后续生成
+        assert_eq!(text_gen.infer_conf.sample_len, 1000);
+        // 对话上下文由请求重建
+        assert_eq!(text_gen.ctx.len(), 3);
+        assert_eq!(text_gen.ctx[0].role, Role::System);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_complete_stream() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+
+        let chunks: Vec<_> = {
+            let Completion::Stream(stream) = text_gen.complete(completion_request(true)?).await?
+            else {
+                bail!("expected a chunk stream");
+            };
+            stream.collect().await
+        };
+        let chunks = chunks.into_iter().collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(chunks[0].choices[0].delta.role, Some(Role::Assistant));
+        assert!(chunks.iter().all(|c| c.id == chunks[0].id));
+        let last = chunks.last().unwrap();
+        assert_eq!(last.choices[0].finish_reason.as_deref(), Some("length"));
+        assert_eq!(text_gen.infer_conf.sample_len, 1000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_complete_stream_dropped() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+
+        // 客户端在生成中途断开, 流被丢弃
+        {
+            let stream = text_gen.complete_stream(completion_request(true)?);
+            pin_mut!(stream);
+            stream.next().await.unwrap()?;
+            stream.next().await.unwrap()?;
+        }
+        // 丢弃时立即恢复原推理参数
+        assert_eq!(text_gen.infer_conf.sample_len, 1000);
+        assert_eq!(text_gen.infer_conf.temperature, 0.8);
+
+        let Completion::Response(resp) = text_gen.complete(completion_request(false)?).await?
+        else {
+            bail!("expected a full response");
+        };
+        assert_eq!(resp.usage.completion_tokens, 3);
+        assert_eq!(text_gen.infer_conf.sample_len, 1000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_assistant_prefix() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 3,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        let answer = {
+            let stream = text_gen.chat_with_prefix("b", "a a");
+            pin_mut!(stream);
+
+            assert_eq!(stream.next().await.unwrap()?, "a a");
+            let mut answer = "a a".to_string();
+            while let Some(r) = stream.next().await {
+                answer.push_str(&r?);
+            }
+            answer
+        };
+
+        assert!(answer.starts_with("a a"));
+        assert_eq!(text_gen.ctx.last().unwrap().content, answer);
+
+        // 前缀参与预填充, 计入提示词而非生成的 token
+        let stats = text_gen.last_stats().unwrap();
+        assert_eq!(stats.prompt_tokens, 3);
+        assert_eq!(stats.completion_tokens, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_context_overflow() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+        text_gen.max_context = Some(3);
+
+        let err = chat_to_string(&mut text_gen, "a b a b").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LlmError::ContextOverflow { len: 4, max: 3 })
+        ));
+        assert!(text_gen.ctx.is_empty());
+
+        // 未超出上限时正常生成
+        chat_to_string(&mut text_gen, "a").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_context_override() -> Result<()> {
+        let config = InferenceConfig {
+            max_context: Some(3),
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        let err = chat_to_string(&mut text_gen, "a b a b").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LlmError::ContextOverflow { len: 4, max: 3 })
+        ));
+
+        assert_eq!(resolve_max_context(Some(8), Some(4))?, Some(4));
+        assert_eq!(resolve_max_context(Some(8), None)?, Some(8));
+        assert_eq!(resolve_max_context(None, Some(4))?, Some(4));
+        assert!(matches!(
+            resolve_max_context(Some(8), Some(16)),
+            Err(LlmError::InvalidConfig(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token_timeout() -> Result<()> {
+        let config = InferenceConfig {
+            token_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::from_millis(200), config)?;
+
+        // 超时立即返回, 不等待仍在运行的前向计算
+        let start = Instant::now();
+        {
+            let stream = text_gen.chat("a b");
+            pin_mut!(stream);
+
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert!(err.to_string().contains("timed out"));
+            assert!(matches!(err.downcast_ref(), Some(LlmError::Inference(_))));
+            assert!(stream.next().await.is_none());
+        }
+        assert!(start.elapsed() < Duration::from_millis(150));
+
+        // 超时后撤销本轮提问
+        assert!(text_gen.ctx.is_empty());
+        assert_eq!(text_gen.position(), 0);
+
+        // 计算结束前模型锁被占用, 下一轮返回 busy 且不改变对话历史
+        text_gen.infer_conf.token_timeout = None;
+        text_gen.infer_conf.sample_len = 1;
+        let err = chat_to_string(&mut text_gen, "a b").await.unwrap_err();
+        assert!(err.to_string().contains("model busy"));
+        assert!(text_gen.ctx.is_empty());
+
+        // 计算结束后其写入的 KV 缓存不完整, 下一轮先清空再正常生成
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let cache = text_gen.lock_model()?.save_cache()?;
+        assert!(!cache.downcast_ref::<Vec<u32>>().unwrap().is_empty());
+        assert!(!chat_to_string(&mut text_gen, "a b").await?.is_empty());
+        assert!(!text_gen.needs_cache_reset);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward_not_blocking_runtime() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            min_new_tokens: 5,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::from_millis(50), config)?;
+        let done = AtomicBool::new(false);
+
+        let generate = async {
+            let stream = text_gen.chat("a b");
+            pin_mut!(stream);
+            while let Some(r) = stream.next().await {
+                r?;
+            }
+            done.store(true, Ordering::Relaxed);
+            Ok::<_, Error>(())
+        };
+
+        // 前向计算期间同一运行时上的其他任务应持续推进
+        let ticker = async {
+            let mut ticks = 0;
+            while !done.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                ticks += 1;
+            }
+            ticks
+        };
+
+        let (r, ticks) = tokio::join!(generate, ticker);
+        r?;
+        assert!(ticks > 20, "ticker only ran {ticks} times");
+
+        Ok(())
+    }
+
+    /// 对比直接调用模型与经阻塞线程池调度的前向计算耗时, 即 [`TextGeneration::forward`] 每个 token 的额外开销
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_forward_overhead() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+        let input = Tensor::new(&[1u32], &Device::Cpu)?.unsqueeze(0)?;
+        let n = 20000;
+
+        let start = Instant::now();
+        for i in 0..n {
+            text_gen.lock_model()?.forward(&input, i % 512)?;
+        }
+        let direct = start.elapsed() / n as u32;
+
+        let start = Instant::now();
+        for i in 0..n {
+            text_gen.forward(input.clone(), i % 512).await?;
+        }
+        let pooled = start.elapsed() / n as u32;
+
+        let overhead = pooled.saturating_sub(direct);
+        assert!(
+            overhead < Duration::from_micros(100),
+            "direct: {direct:?}, spawn_blocking: {pooled:?}, overhead: {overhead:?}"
+        );
+
+        Ok(())
+    }
+
+    /// 对比长提示词的各种编码方式耗时, 需要联网下载分词器
+    ///
+    /// 线程池调度不应明显增加耗时, 单核 CPU 上 `encode_batch` 也不应慢于逐段编码
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_encode_long_prompt() -> Result<()> {
+        let registry = ModelRegistry::new()?;
+        let hub_info = registry.get("qwen3.4b_base")?;
+        let hub = HubClient::from_env()?;
+        let tokenizer =
+            crate::utils::load::load_tokenizer(&hub, &hub_info.tokenizer_repo, None).await?;
+        let text_gen = TextGeneration::from_parts(
+            Box::new(MockModel {
+                delay: Duration::ZERO,
+                cache: vec![],
+            }),
+            tokenizer.clone(),
+            mock_ctx()?,
+            InferenceConfig::default(),
+            3,
+        );
+
+        let long =
+            "The quick brown fox jumps over the lazy dog. 敏捷的棕色狐狸跳过了懒狗。".repeat(500);
+        let docs: Vec<String> = (0..32).map(|_| long.clone()).collect();
+
+        let start = Instant::now();
+        let expected = str2tokens(&long, &tokenizer)?;
+        let direct = start.elapsed();
+
+        let start = Instant::now();
+        assert_eq!(text_gen.str2tokens(&long).await?, expected);
+        let blocking = start.elapsed();
+
+        let start = Instant::now();
+        for doc in &docs {
+            str2tokens(doc, &tokenizer)?;
+        }
+        let sequential = start.elapsed();
+
+        let start = Instant::now();
+        let batch = text_gen.encode_batch(docs).await?;
+        let batched = start.elapsed();
+        assert!(batch.iter().all(|tokens| *tokens == expected));
+
+        assert!(
+            blocking <= direct.mul_f64(1.5) + Duration::from_millis(1),
+            "{} tokens, encode: {direct:?}, blocking pool: {blocking:?}",
+            expected.len()
+        );
+        assert!(
+            batched <= sequential.mul_f64(1.2),
+            "32 docs sequential: {sequential:?}, encode_batch: {batched:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_long_prompt() -> Result<()> {
+        let text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+        let tokenizer = &*text_gen.tokenizer;
+
+        let long = "a b c ".repeat(5000);
+        let expected = str2tokens(&long, tokenizer)?;
+        assert_eq!(expected.len(), 15000);
+        assert_eq!(text_gen.str2tokens(&long).await?, expected);
+
+        // 并行批量编码与逐条编码结果一致
+        let inputs: Vec<String> = (0..16).map(|i| "a b ".repeat(i * 100)).collect();
+        let batch = text_gen.encode_batch(inputs.clone()).await?;
+        for (input, tokens) in inputs.iter().zip(batch) {
+            assert_eq!(tokens, str2tokens(input, tokenizer)?);
+        }
+
+        Ok(())
+    }
 
-            println!(
-                "\n\nspeed: {:.2} token/s",
-                (ctx_tokens.len() - ans_start_idx) as f64 / dt.as_secs_f64(),
+    #[tokio::test]
+    async fn test_add_special_tokens() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+
+        // 以 `<eos>` 充当 BOS, 由分词器在开头添加
+        let mut tokenizer = text_gen.tokenizer.as_ref().clone();
+        tokenizer.add_special_tokens(&[AddedToken::from("<eos>", true)]);
+        tokenizer.with_post_processor(Some(
+            TemplateProcessing::builder()
+                .try_single("<eos> $A")
+                .map_err(Error::msg)?
+                .special_tokens(vec![("<eos>", 3)])
+                .build()?,
+        ));
+        text_gen.tokenizer = Arc::new(tokenizer);
+
+        let cases = [
+            (Some(true), "a b", vec![3, 1, 2]),
+            (Some(false), "a b", vec![1, 2]),
+            (None, "a b", vec![3, 1, 2]),
+            // 模板已自带 BOS
+            (Some(true), "<eos> a b", vec![3, 3, 1, 2]),
+            (Some(false), "<eos> a b", vec![3, 1, 2]),
+            (None, "<eos> a b", vec![3, 1, 2]),
+        ];
+        for (add_special_tokens, prompt, expected) in cases {
+            text_gen.infer_conf.add_special_tokens = add_special_tokens;
+            assert_eq!(
+                text_gen.str2tokens(prompt).await?,
+                expected,
+                "{add_special_tokens:?} {prompt:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_complete_raw() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 4,
+            temperature: 0.,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        let stream = text_gen.complete_raw("a b");
+        pin_mut!(stream);
+        let mut answer = String::new();
+        while let Some(r) = stream.next().await {
+            answer.push_str(&r?);
+        }
+
+        // 不经过对话模板, 也不记录对话历史
+        assert!(!answer.is_empty());
+        assert!(text_gen.ctx.is_empty());
+        assert_eq!(text_gen.last_stats().unwrap().prompt_tokens, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_bytes() -> Result<()> {
+        // 😀 = F0 9F 98 80, 以 SentencePiece 字节回退 token 逐字节生成
+        let tokens = [
+            "<unk>", "a", "<0xF0>", "<0x9F>", "<0x98>", "<0x80>", "<eos>",
+        ];
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.add_special_tokens(&[AddedToken::from("<eos>", true)]);
+
+        let mut text_gen = scripted_text_gen_with(
+            tokenizer,
+            vec![1, 2, 3, 4, 5],
+            6,
+            InferenceConfig {
+                temperature: 0.,
+                ..Default::default()
+            },
+        )?;
+
+        let stream = text_gen.chat_bytes("a");
+        pin_mut!(stream);
+        let mut chunks = vec![];
+        while let Some(r) = stream.next().await {
+            chunks.push(r?);
+        }
+
+        // 每个 token 立即输出, 不等待完整的字符
+        assert_eq!(chunks.len(), 5);
+        let partial = chunks.iter().filter(|c| std::str::from_utf8(c).is_err());
+        assert_eq!(partial.count(), 4);
+        assert_eq!(String::from_utf8(chunks.concat())?, "a😀");
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::EosToken
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_tokens() -> Result<()> {
+        async fn chat_tokens(
+            text_gen: &mut TextGeneration,
+            prompt: &str,
+        ) -> Result<Vec<(u32, Option<String>)>> {
+            let stream = text_gen.chat_tokens(prompt);
+            pin_mut!(stream);
+            let mut steps = vec![];
+            while let Some(r) = stream.next().await {
+                steps.push(r?);
+            }
+            Ok(steps)
+        }
+        let scripted = |script| -> Result<TextGeneration> {
+            scripted_text_gen(
+                script,
+                3,
+                InferenceConfig {
+                    temperature: 0.,
+                    repeat_penalty: 1.,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let expected = chat_to_string(&mut scripted(vec![1, 2, 2, 1])?, "a").await?;
+        let mut text_gen = scripted(vec![1, 2, 2, 1])?;
+        let steps = chat_tokens(&mut text_gen, "a").await?;
+        let ids: Vec<u32> = steps.iter().map(|(id, _)| *id).collect();
+        let text: String = steps.iter().filter_map(|(_, t)| t.as_deref()).collect();
+        assert_eq!(text, expected);
+        let encoding = text_gen.tokenizer.encode(text, false).map_err(Error::msg)?;
+        assert_eq!(ids, encoding.get_ids());
+        assert_eq!(text_gen.ctx.last().unwrap().content, expected);
+
+        // 未构成完整字符的 token 没有文本, 字符在最后一个字节处输出
+        let mut text_gen = byte_fallback_text_gen()?;
+        let steps = chat_tokens(&mut text_gen, "a").await?;
+        assert_eq!(
+            steps,
+            [
+                (1, Some("a".to_string())),
+                (2, None),
+                (3, None),
+                (4, None),
+                (5, Some("😀".to_string())),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_eos_not_in_answer() -> Result<()> {
+        let mut text_gen = scripted_text_gen(
+            vec![1, 2],
+            3,
+            InferenceConfig {
+                temperature: 0.,
+                ..Default::default()
+            },
+        )?;
+
+        let answer = chat_to_string(&mut text_gen, "a").await?;
+        assert!(!answer.contains("<eos>"), "{answer:?}");
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::EosToken
+        );
+
+        // 存入历史的助手回答同样不含 EOS
+        let stored = &text_gen.ctx.last().unwrap().content;
+        assert_eq!(stored, &answer);
+        assert!(!stored.text().contains("<eos>"), "{stored:?}");
+
+        let stream = text_gen.chat_bytes("b");
+        pin_mut!(stream);
+        let mut bytes = vec![];
+        while let Some(r) = stream.next().await {
+            bytes.extend(r?);
+        }
+        assert!(!String::from_utf8(bytes)?.contains("<eos>"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_special_tokens() -> Result<()> {
+        // `<|im_start|>` 被拆成三个普通 token 生成, 按 id 无法过滤
+        let vocab = ["<unk>", "a", "b", "<|", "im_start", "|>", "<eos>"]
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.with_decoder(Some(Fuse::new()));
+        tokenizer.add_special_tokens(&[AddedToken::from("<|im_start|>", true)]);
+
+        let text_gen = |skip_special_tokens| {
+            scripted_text_gen_with(
+                tokenizer.clone(),
+                vec![1, 3, 4, 5, 2],
+                6,
+                InferenceConfig {
+                    temperature: 0.,
+                    skip_special_tokens,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let mut filtered = text_gen(true);
+        let answer = chat_to_string(&mut filtered, "a").await?;
+        assert_eq!(answer, "ab");
+        assert_eq!(filtered.ctx.last().unwrap().content, "ab");
+
+        let answer = chat_to_string(&mut text_gen(false), "a").await?;
+        assert_eq!(answer, "a<|im_start|>b");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seed_reproducible() -> Result<()> {
+        let text_gen = || -> Result<TextGeneration> {
+            scripted_text_gen(
+                vec![1; 64],
+                3,
+                InferenceConfig {
+                    temperature: 1.,
+                    min_new_tokens: 8,
+                    sample_len: 32,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let first = chat_to_string(&mut text_gen()?, "a").await?;
+        let second = chat_to_string(&mut text_gen()?, "a").await?;
+        assert!(!first.is_empty());
+        assert_eq!(first.as_bytes(), second.as_bytes());
+
+        // 同一实例中采样器在每轮开始时重置
+        let mut text_gen = text_gen()?;
+        chat_to_string(&mut text_gen, "a").await?;
+        text_gen.ctx.clear();
+        let again = chat_to_string(&mut text_gen, "a").await?;
+        assert_eq!(first.as_bytes(), again.as_bytes());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_n() -> Result<()> {
+        let config = InferenceConfig {
+            temperature: 1.,
+            min_new_tokens: 8,
+            sample_len: 16,
+            ..Default::default()
+        };
+
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        let answers = text_gen.chat_n("a b", 3).await?;
+        assert_eq!(answers.len(), 3);
+        assert!(
+            answers.iter().any(|answer| answer != &answers[0]),
+            "{answers:?}"
+        );
+
+        // 第一个回答记入历史, 推理参数恢复原值
+        assert_eq!(text_gen.ctx.len(), 2);
+        assert_eq!(text_gen.ctx.last().unwrap().content, answers[0]);
+        assert_eq!(text_gen.infer_conf.seed, config.seed);
+
+        // 从快照恢复后的第一个回答与直接生成相同
+        let mut fresh = mock_text_gen(Duration::ZERO, config)?;
+        assert_eq!(chat_to_string(&mut fresh, "a b").await?, answers[0]);
+
+        assert!(text_gen.chat_n("a", 0).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_beam_search() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 10,
+            temperature: 0.,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config.clone())?;
+        let greedy = chat_to_string(&mut text_gen, "a").await?;
+
+        // 宽度为 1 的束搜索等价于贪心解码
+        let beam = |width| InferenceConfig {
+            decode_strategy: DecodeStrategy::Beam { width },
+            ..config.clone()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, beam(1))?;
+        assert_eq!(chat_to_string(&mut text_gen, "a").await?, greedy);
+        assert_eq!(text_gen.last_stats().unwrap().completion_tokens, 10);
+
+        let mut text_gen = mock_text_gen(Duration::ZERO, beam(3))?;
+        let answer = chat_to_string(&mut text_gen, "a").await?;
+        assert_eq!(text_gen.ctx.last().unwrap().content, answer);
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::MaxTokens
+        );
+
+        assert_eq!(top_k(&[0.1, 0.5, 0.2, 0.4], 2), [(1, 0.5), (3, 0.4)]);
+
+        // 不支持快照的模型在加载时拒绝束搜索
+        let scripted = ScriptedModel {
+            script: vec![],
+            eos: 3,
+            step: 0,
+        };
+        assert!(check_decode_strategy(&scripted, &beam(2)).is_err());
+        assert!(check_decode_strategy(&scripted, &config).is_ok());
+        let mock = MockModel {
+            delay: Duration::ZERO,
+            cache: vec![],
+        };
+        assert!(check_decode_strategy(&mock, &beam(2)).is_ok());
+
+        Ok(())
+    }
+
+    /// 按输入的最后一个 token 给出固定 logits, 词表见 [`test_token_healing`]
+    ///
+    /// the -> city 最可能, capital 次之; cap -> city; 其余 -> <eos>
+    fn healing_logits(x: &[u32]) -> [f32; 7] {
+        match x.last() {
+            Some(1) => [0., 0., 0., 1., 0., 2., 0.],
+            Some(2) => [0., 0., 0., 0., 0., 2., 0.],
+            _ => [0., 0., 0., 0., 0., 0., 1.],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_healing() -> Result<()> {
+        let vocab = ["<unk>", "the", "cap", "capital", "ital", "city", "<eos>"]
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.with_decoder(Some(Fuse::new()));
+
+        let complete = |token_healing| {
+            let mut text_gen = TextGeneration::from_parts(
+                Box::new(FnModel(healing_logits)),
+                tokenizer.clone(),
+                mock_ctx().unwrap(),
+                InferenceConfig {
+                    temperature: 0.,
+                    repeat_penalty: 1.,
+                    token_healing,
+                    device: Device::Cpu,
+                    ..Default::default()
+                },
+                6,
+            );
+            async move {
+                let chunks: Vec<_> = text_gen.complete_raw("the cap").collect().await;
+                chunks.into_iter().collect::<Result<String>>()
+            }
+        };
+
+        // 提示词以不完整的单词结尾, 直接续写得到 "the cap" + "city"
+        assert_eq!(complete(false).await?, "city");
+        // 去掉 "cap" 后首个 token 只能以其开头, 补全为 "capital" 且不重复输出 "cap"
+        assert_eq!(complete(true).await?, "ital");
+
+        Ok(())
+    }
+
+    /// 预填充后输出 `a`; 输入 `a` 时最可能输出 `b`, 其次 `<eos>`; 其余输出 `<eos>`
+    fn penalty_logits(x: &[u32]) -> [f32; 4] {
+        match x {
+            [_, _, ..] => [0., 1., 0., 0.],
+            [1] => [0., 0., 2., 1.],
+            _ => [0., 0., 0., 1.],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeat_penalty_window() -> Result<()> {
+        // 首个解码步输入的是首个生成的 token
+        assert_eq!(answer_tokens(&[2, 2, 1], 2, 2), [1]);
+        assert_eq!(answer_tokens(&[2, 2, 1, 2], 2, 3), [1, 2]);
+
+        // 提示词以 b 结尾, 惩罚范围误含提示词最后一个 token 时 b 会被压到 <eos> 之下
+        let mut text_gen = TextGeneration::from_parts(
+            Box::new(FnModel(penalty_logits)),
+            mock_tokenizer()?,
+            mock_ctx()?,
+            InferenceConfig {
+                temperature: 0.,
+                repeat_penalty: 10.,
+                sample_len: 2,
+                device: Device::Cpu,
+                ..Default::default()
+            },
+            3,
+        );
+        let chunks: Vec<_> = text_gen.complete_raw("a b").collect().await;
+        assert_eq!(chunks.into_iter().collect::<Result<String>>()?, "a b");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repeat_penalty_disabled() -> Result<()> {
+        let complete = |repeat_penalty_enabled| {
+            let config = InferenceConfig {
+                temperature: 0.,
+                repeat_penalty: 10.,
+                repeat_penalty_enabled,
+                sample_len: 3,
+                device: Device::Cpu,
+                ..Default::default()
+            };
+            assert_eq!(config.applies_repeat_penalty(), repeat_penalty_enabled);
+            // 总是最可能输出 `a`, 其次 `<eos>`
+            let mut text_gen = TextGeneration::from_parts(
+                Box::new(FnModel(|_| [0., 2., 0., 1.])),
+                mock_tokenizer().unwrap(),
+                mock_ctx().unwrap(),
+                config,
+                3,
             );
+            async move {
+                let chunks: Vec<_> = text_gen.complete_raw("b").collect().await;
+                chunks.into_iter().collect::<Result<String>>()
+            }
+        };
+
+        // 惩罚后 a 低于 <eos>, 只生成一个 a
+        assert_eq!(complete(true).await?, "a");
+        // 关闭后即使 repeat_penalty 不为 1 也不惩罚, 一直生成到 sample_len
+        assert_eq!(complete(false).await?, "a a a");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encoded_prefix() -> Result<()> {
+        let mut tokenizer = mock_tokenizer()?;
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<|im_start|>", true),
+            AddedToken::from("<|im_end|>", true),
+        ]);
+        let ctx = ChatContext::from_template(
+            "{% for m in messages %}<|im_start|>{{ m.content }}<|im_end|> {% endfor %}<|im_start|>",
+        )?;
+        let mut text_gen = TextGeneration::from_parts(
+            Box::new(MockModel {
+                delay: Duration::ZERO,
+                cache: vec![],
+            }),
+            tokenizer,
+            ctx,
+            InferenceConfig {
+                sample_len: 3,
+                temperature: 0.,
+                device: Device::Cpu,
+                ..Default::default()
+            },
+            3,
+        );
+
+        chat_to_string(&mut text_gen, "a").await?;
+        chat_to_string(&mut text_gen, "b").await?;
+
+        // 缓存的前缀与完整编码相同
+        let prefix = text_gen.encoded_prefix.clone().unwrap();
+        let prompt = text_gen.last_rendered_prompt().unwrap();
+        assert!(prompt.starts_with(&prefix.text));
+        assert_eq!(prefix.tokens, text_gen.str2tokens(&prefix.text).await?);
+
+        // 新一轮只编码新增部分, 结果与完整编码相同
+        text_gen.ctx.push_msg("a b");
+        let prompt = text_gen.ctx.render()?;
+        assert!(prompt.len() > prefix.text.len());
+        let expected = text_gen.str2tokens(&prompt).await?;
+        assert_eq!(text_gen.encode_prompt(&prompt).await?, expected);
+        assert_eq!(text_gen.encoded_prefix.as_ref().unwrap().text, prompt);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encoded_prefix_metaspace() -> Result<()> {
+        // prepend_scheme 为 first 时只在整段文本开头添加 ▁, 单独编码新增部分会多出 ▁
+        let vocab = [
+            ("<unk>", 0),
+            ("a", 1),
+            ("▁a", 2),
+            ("b", 3),
+            ("▁b", 4),
+            ("<s>", 5),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Metaspace::new('▁', PrependScheme::First, true)));
+        tokenizer.add_special_tokens(&[AddedToken::from("<s>", true)]);
+        let mut text_gen = TextGeneration::from_parts(
+            Box::new(MockModel {
+                delay: Duration::ZERO,
+                cache: vec![],
+            }),
+            tokenizer,
+            mock_ctx()?,
+            InferenceConfig {
+                device: Device::Cpu,
+                ..Default::default()
+            },
+            3,
+        );
+
+        assert_eq!(text_gen.encode_prompt("<s>a b<s>").await?, [5, 1, 4, 5]);
+        assert_eq!(text_gen.encoded_prefix.as_ref().unwrap().text, "<s>a b<s>");
+
+        let prompt = "<s>a b<s>b a";
+        let expected = text_gen.str2tokens(prompt).await?;
+        assert_eq!(expected, [5, 1, 4, 5, 3, 2]);
+        assert_eq!(text_gen.encode_prompt(prompt).await?, expected);
+
+        // 前缀不以特殊 token 结尾时无法拼接, 退回完整编码
+        text_gen.encoded_prefix = Some(EncodedPrefix {
+            text: "<s>a".to_string(),
+            tokens: vec![5, 1],
+        });
+        assert_eq!(text_gen.encode_prompt(prompt).await?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prompt_template() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+        let template = PromptTemplate::new("classify")
+            .example("a", "b")
+            .example("b", "a");
+        text_gen.set_prompt_template(template.clone());
+        assert_eq!(text_gen.ctx.messages, template.messages());
+
+        // 示例之后是用户的提问
+        chat_to_string(&mut text_gen, "a b").await?;
+        assert_eq!(text_gen.ctx.len(), 7);
+        assert_eq!(text_gen.ctx[5], Message::new(Role::User, "a b"));
+        let prompt = text_gen.last_rendered_prompt().unwrap();
+        assert_eq!(prompt, "classify a b b a a b ");
+
+        // 重置后只保留系统提示词与示例
+        text_gen.reset()?;
+        assert_eq!(text_gen.ctx.messages, template.messages());
+        assert_eq!(text_gen.ctx[0].role, Role::System);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_feed_and_sample() -> Result<()> {
+        let config = InferenceConfig {
+            temperature: 0.,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        // 仅用 feed 与 sample 实现贪心解码
+        async fn greedy(text_gen: &mut TextGeneration) -> Result<Vec<u32>> {
+            text_gen.reset()?;
+            let mut logits = text_gen.feed(&[1]).await?;
+            let mut tokens = vec![];
+            for _ in 0..5 {
+                let token = text_gen.sample(&logits)?;
+                tokens.push(token);
+                logits = text_gen.feed(&[token]).await?;
+            }
+            Ok(tokens)
+        }
+
+        // MockModel 以缓存中 token 之和对 3 取模作为下一个 token
+        let tokens = greedy(&mut text_gen).await?;
+        assert_eq!(tokens, [1, 2, 1, 2, 1]);
+        assert_eq!(text_gen.position(), 6);
+        assert!(text_gen.feed(&[]).await.is_err());
+
+        // 重置后从位置 0 重新开始
+        assert_eq!(greedy(&mut text_gen).await?, tokens);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_regex() -> Result<()> {
+        let vocab = ["<unk>", "1.", " x", "\n2.", " y", "<eos>"]
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.with_decoder(Some(Fuse::new()));
+
+        let config = InferenceConfig {
+            temperature: 0.,
+            sample_len: 6,
+            stop_regex: Some(r"\n\d+\.".to_string()),
+            ..Default::default()
+        };
+        let mut text_gen =
+            scripted_text_gen_with(tokenizer.clone(), vec![1, 2, 3, 4, 1, 2], 5, config.clone())?;
+
+        // 在第二个列表项开始处停止, 输出与记录的回答一致
+        let answer = chat_to_string(&mut text_gen, "a").await?;
+        assert_eq!(answer, "1. x");
+        assert_eq!(text_gen.ctx.last().unwrap().content, answer);
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::StopSequence("\n2.".to_string())
+        );
+        assert_eq!(
+            text_gen.last_finish_reason(),
+            Some(FinishReason::StopSequence("\n2.".to_string()))
+        );
+
+        // 第二个列表项在第 4 个 token 处输出, 未达到最少生成数量时不停止
+        let mut text_gen = scripted_text_gen_with(
+            tokenizer,
+            vec![1, 2, 3, 4, 1, 2],
+            5,
+            InferenceConfig {
+                min_new_tokens: 5,
+                ..config
+            },
+        )?;
+        let answer = chat_to_string(&mut text_gen, "a").await?;
+        assert_eq!(answer, "1. x\n2. y1. x");
+        assert_eq!(
+            text_gen.last_stats().unwrap().stop_reason,
+            StopReason::MaxTokens
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_base_model_complete_raw() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 20,
+            temperature: 0.,
+            ..Default::default()
+        };
+        let mut text_gen = TextGeneration::new("qwen3.4b_base", config).await?;
+
+        let stream = text_gen.complete_raw("The capital of France is");
+        pin_mut!(stream);
+        let mut answer = String::new();
+        while let Some(r) = stream.next().await {
+            answer.push_str(&r?);
+        }
+
+        // 续写原文, 而不是开启新的对话回合
+        assert!(answer.contains("Paris"), "{answer}");
+        assert!(!answer.contains("<|im_start|>"), "{answer}");
+        assert!(text_gen.ctx.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_last_rendered_prompt() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 2,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+        text_gen.ctx = text_gen
+            .ctx
+            .clone()
+            .with_template("{% for m in messages %}{{ m.role }}: {{ m.content }} {% endfor %}")?;
+        assert_eq!(text_gen.last_rendered_prompt(), None);
+
+        text_gen.ctx.push_message(Role::System, "a");
+        chat_to_string(&mut text_gen, "b").await?;
+
+        let prompt = text_gen.last_rendered_prompt().unwrap();
+        assert!(prompt.contains("system: a"));
+        assert!(prompt.contains("user: b"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_model_sessions() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 3,
+            ..Default::default()
+        };
+        let shared = mock_shared(Duration::ZERO, config)?;
+        let mut session_a = TextGeneration::new_session(&shared)?;
+        let mut session_b = TextGeneration::new_session(&shared)?;
+
+        chat_to_string(&mut session_a, "a").await?;
+        chat_to_string(&mut session_a, "b").await?;
+        chat_to_string(&mut session_b, "b a").await?;
+
+        // 两个会话的对话历史互不影响
+        assert_eq!(session_a.ctx.len(), 4);
+        assert_eq!(session_a.ctx[0].content, "a");
+        assert_eq!(session_b.ctx.len(), 2);
+        assert_eq!(session_b.ctx[0].content, "b a");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefix_snapshot() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            temperature: 0.,
+            ..Default::default()
+        };
+        let mut text_gen = mock_text_gen(Duration::ZERO, config)?;
+
+        text_gen.prefill_and_snapshot("a b").await?;
+        let first = chat_to_string(&mut text_gen, "b").await?;
+
+        text_gen.restore()?;
+        assert_eq!(text_gen.ctx.len(), 1);
+        let second = chat_to_string(&mut text_gen, "b").await?;
+
+        assert_eq!(first, second);
+        assert!(!first.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_prompt() -> Result<()> {
+        let mut text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+
+        for prompt in ["", "   "] {
+            let err = chat_to_string(&mut text_gen, prompt).await.unwrap_err();
+            assert!(err.to_string().contains("no tokens to process"));
+        }
+        assert!(text_gen.prefill_and_snapshot(" ").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_min_new_tokens() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 20,
+            min_new_tokens: 10,
+            temperature: 0.,
+            ..Default::default()
+        };
+
+        // 脚本在第 3 步输出 EOS, 生成满 10 个 token 前被抑制
+        let mut text_gen = scripted_text_gen(vec![1, 2], 3, config.clone())?;
+        chat_to_string(&mut text_gen, "a").await?;
+        let stats = text_gen.last_stats().unwrap();
+        assert!(stats.completion_tokens >= 10, "{stats:?}");
+        assert_eq!(stats.stop_reason, StopReason::EosToken);
+
+        // 停止序列同样在生成满 10 个 token 后才生效
+        let config = InferenceConfig {
+            stop_regex: Some("b".to_string()),
+            ..config
+        };
+        let mut text_gen = scripted_text_gen([1, 2].repeat(8), 3, config)?;
+        chat_to_string(&mut text_gen, "a").await?;
+        let stats = text_gen.last_stats().unwrap();
+        assert!(stats.completion_tokens >= 10, "{stats:?}");
+        assert_eq!(stats.stop_reason, StopReason::StopSequence("b".to_string()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_model_info() -> Result<()> {
+        let text_gen = mock_text_gen(Duration::ZERO, InferenceConfig::default())?;
+        let info = text_gen.model_info();
+        assert_eq!(info.vocab_size, 4);
+        assert_eq!(info.arch, None);
+
+        let text_gen = TextGeneration::new("qwen3.4b_q4", InferenceConfig::default()).await?;
+        let info = text_gen.model_info();
+        assert_eq!(info.id.as_deref(), Some("qwen3.4b_q4"));
+        assert_eq!(info.arch.as_deref(), Some("qwen3"));
+        assert!(info.vocab_size > 0);
+        assert!(info.context_length.is_some());
+        assert_eq!(info.dtype, Some(DType::F32));
+
+        // Q4_K_S 文件, 约 3.66 bpw
+        let quant = text_gen.quantization().unwrap();
+        assert!(quant.dtype.starts_with("Q4"), "{quant:?}");
+        assert!(quant.is_quantized());
+        assert!((3. ..6.).contains(&quant.bits_per_weight), "{quant:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_stats() -> Result<()> {
+        let config = InferenceConfig {
+            sample_len: 5,
+            ..Default::default()
+        };
+        let mut text_gen = TextGeneration::new("qwen3", config).await?;
+
+        let stats = text_gen.memory_stats();
+        assert!(stats.weights_bytes > 0);
+        assert_eq!(stats.kv_cache_bytes, Some(0));
+
+        let stream = text_gen.chat("hello");
+        pin_mut!(stream);
+        while let Some(r) = stream.next().await {
+            r?;
+        }
+
+        let stats = text_gen.memory_stats();
+        assert!(stats.kv_cache_bytes.unwrap() > 0);
+        if text_gen.infer_conf.device.is_cuda() {
+            assert!(stats.peak_bytes.unwrap() >= stats.weights_bytes);
         }
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_gemma_generate() -> Result<()> {
+        let mut text_gen = TextGeneration::with_default_config("gemma").await?;
+
+        let stream = text_gen.chat("hello");
+        pin_mut!(stream);
+
+        assert!(!stream.next().await.unwrap()?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mistral_generate() -> Result<()> {
+        let mut text_gen = TextGeneration::with_default_config("mistral.7b_q4").await?;
+
+        let stream = text_gen.chat("hello");
+        pin_mut!(stream);
+
+        assert!(!stream.next().await.unwrap()?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_phi3_generate() -> Result<()> {
+        let mut text_gen = TextGeneration::with_default_config("phi3.mini_q4").await?;
+
+        let stream = text_gen.chat("hello");
+        pin_mut!(stream);
+
+        assert!(!stream.next().await.unwrap()?.is_empty());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_pipeline() -> Result<()> {
         tracing_subscriber::fmt::init();
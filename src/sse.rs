@@ -0,0 +1,103 @@
+//! 将 OpenAI 流式片段编码为 Server-Sent Events
+
+use crate::openai::{ChatCompletionChunk, ChatCompletionRequest};
+use crate::pipe::TextGeneration;
+use anyhow::Result;
+use async_stream::try_stream;
+use futures_core::stream::Stream;
+use futures_util::{StreamExt, pin_mut};
+use serde::Serialize;
+
+/// 流结束标记, 与 OpenAI 一致
+pub const DONE: &str = "data: [DONE]\n\n";
+
+/// 单个 SSE 数据帧: `data: {json}\n\n`
+pub fn sse_frame(data: &impl Serialize) -> Result<String> {
+    Ok(format!("data: {}\n\n", serde_json::to_string(data)?))
+}
+
+/// 处理流式 Chat Completions 请求, 输出 SSE 数据帧, 以 `data: [DONE]` 结束
+///
+/// 客户端断开时丢弃该流即可, 生成在下一个 token 前停止, 下次请求时恢复推理参数
+pub fn chat_completion_stream(
+    text_gen: &mut TextGeneration,
+    req: ChatCompletionRequest,
+) -> impl Stream<Item = Result<String>> + '_ {
+    sse_stream(text_gen.complete_stream(req))
+}
+
+/// 将片段流编码为 SSE 数据帧, 出错时不输出结束标记
+pub fn sse_stream<'a>(
+    chunks: impl Stream<Item = Result<ChatCompletionChunk>> + 'a,
+) -> impl Stream<Item = Result<String>> + 'a {
+    try_stream!({
+        pin_mut!(chunks);
+        while let Some(chunk) = chunks.next().await {
+            yield sse_frame(&chunk?)?;
+        }
+        yield DONE.to_string();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::{ChunkBuilder, Delta};
+    use crate::pipe::FinishReason;
+    use crate::utils::chat::Role;
+    use futures_util::stream;
+    use serde_json::{Value, json};
+
+    #[tokio::test]
+    async fn test_sse_stream() -> Result<()> {
+        let builder = ChunkBuilder::new("qwen3");
+        let chunks = vec![
+            Ok(builder.chunk(
+                Delta {
+                    role: Some(Role::Assistant),
+                    ..Default::default()
+                },
+                None,
+            )),
+            Ok(builder.chunk(
+                Delta {
+                    content: Some("Hi".to_string()),
+                    ..Default::default()
+                },
+                None,
+            )),
+            Ok(builder.chunk(Delta::default(), Some(&FinishReason::Stop))),
+        ];
+
+        let frames = sse_stream(stream::iter(chunks))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(frames.len(), 4);
+        for frame in &frames {
+            assert!(
+                frame.starts_with("data: ") && frame.ends_with("\n\n"),
+                "{frame:?}"
+            );
+        }
+        let data: Value = serde_json::from_str(&frames[1]["data: ".len()..])?;
+        assert_eq!(data["object"], "chat.completion.chunk");
+        assert_eq!(data["choices"][0]["delta"], json!({"content": "Hi"}));
+        let data: Value = serde_json::from_str(&frames[2]["data: ".len()..])?;
+        assert_eq!(data["choices"][0]["finish_reason"], "stop");
+        assert_eq!(frames.last().unwrap(), DONE);
+
+        // 出错时不输出结束标记
+        let chunks = vec![
+            Ok(builder.chunk(Delta::default(), None)),
+            Err(anyhow!("oom")),
+        ];
+        let frames: Vec<_> = sse_stream(stream::iter(chunks)).collect().await;
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].is_err());
+
+        Ok(())
+    }
+}
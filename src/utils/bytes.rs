@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+use tokenizers::decoders::DecoderWrapper;
+
+/// 将 token 还原为原始字节, 不等待完整的 UTF-8 字符
+///
+/// 支持 GPT-2 风格的 byte-level 词表与 SentencePiece 的 `<0xHH>` 字节回退, 特殊 token 输出为空
+pub struct TokenBytes {
+    tokenizer: Arc<Tokenizer>,
+    byte_level: bool,
+    /// byte-level 词表中的字符到原始字节
+    unicode_bytes: HashMap<char, u8>,
+    cache: HashMap<u32, Vec<u8>>,
+}
+
+impl TokenBytes {
+    pub fn new(tokenizer: Arc<Tokenizer>) -> Self {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        Self {
+            tokenizer,
+            byte_level,
+            unicode_bytes: unicode_bytes(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// token 对应的原始字节
+    pub fn get(&mut self, id: u32) -> Vec<u8> {
+        if let Some(bytes) = self.cache.get(&id) {
+            return bytes.clone();
+        }
+
+        let bytes = match self.tokenizer.id_to_token(id) {
+            None => vec![],
+            Some(token) if self.is_special(&token) => vec![],
+            Some(token) => self.token_bytes(&token),
+        };
+        self.cache.insert(id, bytes.clone());
+        bytes
+    }
+
+    fn is_special(&self, token: &str) -> bool {
+        self.tokenizer
+            .get_added_vocabulary()
+            .is_special_token(token)
+    }
+
+    fn token_bytes(&self, token: &str) -> Vec<u8> {
+        if let Some(byte) = token
+            .strip_prefix("<0x")
+            .and_then(|hex| hex.strip_suffix('>'))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            return vec![byte];
+        }
+
+        if self.byte_level {
+            token
+                .chars()
+                .filter_map(|c| self.unicode_bytes.get(&c).copied())
+                .collect()
+        } else {
+            // SentencePiece 以 `▁` 表示空格
+            token.replace('▁', " ").into_bytes()
+        }
+    }
+}
+
+/// `bytes` 末尾不完整的 UTF-8 字符的字节数, 以完整字符结尾时为 0
+pub fn incomplete_utf8_len(bytes: &[u8]) -> usize {
+    for (i, &b) in bytes.iter().rev().take(4).enumerate() {
+        // 跳过后续字节, 找到最后一个字符的首字节
+        if b & 0xC0 == 0x80 {
+            continue;
+        }
+        let len = match b {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if len > i + 1 { i + 1 } else { 0 };
+    }
+    0
+}
+
+/// GPT-2 byte-level 编码的逆映射: 可打印字节映射为自身, 其余字节依次映射到 256 之后的字符
+fn unicode_bytes() -> HashMap<char, u8> {
+    let mut map = HashMap::new();
+    let mut n = 0;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let c = if printable {
+            char::from(b)
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+        map.insert(c, b);
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::decoders::byte_level::ByteLevel;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    fn tokenizer(vocab: &[&str]) -> Tokenizer {
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn test_token_bytes() {
+        // SentencePiece 字节回退, 😀 = F0 9F 98 80
+        let tokenizer = tokenizer(&["<unk>", "▁hi", "<0xF0>", "<0x9F>", "<0x98>", "<0x80>"]);
+        let mut bytes = TokenBytes::new(Arc::new(tokenizer));
+        let out: Vec<u8> = (1..6).flat_map(|id| bytes.get(id)).collect();
+        assert_eq!(String::from_utf8(out).unwrap(), " hi😀");
+
+        // byte-level 词表: `Ġ` 为空格, `ðŁĺĢ` 为 😀 的四个字节
+        let mut tokenizer = tokenizer(&["<unk>", "Ġhi", "ðŁ", "ĺĢ"]);
+        tokenizer.with_decoder(Some(ByteLevel::default()));
+        let mut bytes = TokenBytes::new(Arc::new(tokenizer));
+        assert_eq!(bytes.get(1), b" hi");
+        assert_eq!(bytes.get(2), [0xF0, 0x9F]);
+        assert_eq!(bytes.get(3), [0x98, 0x80]);
+        assert!(bytes.get(100).is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_utf8_len() {
+        let emoji = "😀".as_bytes();
+        assert_eq!(incomplete_utf8_len(b""), 0);
+        assert_eq!(incomplete_utf8_len(b"a,"), 0);
+        assert_eq!(incomplete_utf8_len(emoji), 0);
+        for n in 1..4 {
+            assert_eq!(incomplete_utf8_len(&[b"a", &emoji[..n]].concat()), n);
+        }
+        // 孤立的后续字节不属于未完成的字符
+        assert_eq!(incomplete_utf8_len(&emoji[1..]), 0);
+    }
+}
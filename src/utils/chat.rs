@@ -1,34 +1,54 @@
+use crate::utils::load::HubClient;
 use anyhow::{Error, Result, bail};
 use derive_new::new;
-use hf_hub::api::tokio::{Api, ApiBuilder};
 use minijinja::{Environment, Template};
 use minijinja_contrib::pycompat;
-use serde::Serialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::BufReader;
 use std::ops::{Deref, DerefMut};
 use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Environment存在生命周期标注，放置全局避免在ChatContext中处理生命周期问题
 static TEMPLATE_ENV: LazyLock<Environment> = LazyLock::new(|| {
     let mut env = Environment::new();
     env.set_unknown_method_callback(pycompat::unknown_method_callback);
+    // transformers 渲染模板时提供的函数, 如 Llama-3 在系统提示中写入当前日期
+    env.add_function("strftime_now", |format: &str| strftime(format, unix_now()));
     env
 });
 
-pub async fn load_template(tokenizer_repo: &str) -> Result<Value> {
-    let pth = ApiBuilder::from_env()
-        .build()?
-        .model(tokenizer_repo.to_string())
-        .get("tokenizer_config.json")
-        .await?;
+/// 没有对话模板时使用的纯文本模板, 每条消息内容占一行
+const PLAIN_TEMPLATE: &str = "{% for m in messages %}{{ m.content }}\n{% endfor %}";
+
+pub async fn load_template(hub: &HubClient, tokenizer_repo: &str) -> Result<Value> {
+    let pth = hub.get(tokenizer_repo, "tokenizer_config.json").await?;
     let file = File::open(pth)?;
     let mut json: Value = serde_json::from_reader(BufReader::new(file))?;
     Ok(json["chat_template"].take())
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq)]
+/// 渲染单轮对话的提示词, 无需自行管理 [`ChatContext`]
+///
+/// 适用于只需要提示词文本的场景, 如交给其他推理服务
+pub async fn render_chat_prompt(
+    hub: &HubClient,
+    tokenizer_repo: &str,
+    system: Option<&str>,
+    user: &str,
+) -> Result<String> {
+    let mut ctx = ChatContext::from_repo(hub, tokenizer_repo).await?;
+    if let Some(system) = system {
+        ctx.push_message(Role::System, system);
+    }
+    ctx.push_message(Role::User, user);
+    ctx.render()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
     System,
@@ -36,11 +56,141 @@ pub enum Role {
     Assistant,
 }
 
-#[derive(Debug, Clone, Serialize, new, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, new, PartialEq)]
 pub struct Message {
     pub role: Role,
     #[new(into)]
-    pub content: String,
+    pub content: Content,
+}
+
+/// 消息内容, 纯文本或 OpenAI 格式的内容片段数组
+///
+/// 渲染时纯文本为字符串, 片段为 `{"type": ...}` 对象的列表, 与 transformers 传给多模态对话模板的结构一致
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    /// 文本内容, 各文本片段以换行分隔, 图像片段忽略
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(text) => Cow::Borrowed(text),
+            Self::Parts(parts) => Cow::Owned(
+                parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImagePlaceholder => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    /// 是否包含图像片段
+    pub fn has_images(&self) -> bool {
+        match self {
+            Self::Text(_) => false,
+            Self::Parts(parts) => parts
+                .iter()
+                .any(|part| matches!(part, ContentPart::ImagePlaceholder)),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<&String> for Content {
+    fn from(text: &String) -> Self {
+        Self::Text(text.clone())
+    }
+}
+
+impl From<Vec<ContentPart>> for Content {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        Self::Parts(parts)
+    }
+}
+
+impl PartialEq<&str> for Content {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, Self::Text(text) if text == other)
+    }
+}
+
+impl PartialEq<String> for Content {
+    fn eq(&self, other: &String) -> bool {
+        matches!(self, Self::Text(text) if text == other)
+    }
+}
+
+/// 消息内容片段, 与 OpenAI 及 transformers 多模态对话模板的 `{"type": ...}` 格式一致
+///
+/// 为视觉模型预留, 图像片段只能交给支持图像的模型
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    /// 图像占位, 图像数据本身暂不读取
+    #[serde(rename = "image", alias = "image_url")]
+    ImagePlaceholder,
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text { text: text.into() }
+    }
+}
+
+/// 固定的系统提示词与少样本示例, 对话重置后保留, 之后只需变化最后的用户输入
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    pub system: Option<String>,
+    /// 示例对话, 每项为 (用户输入, 助手回答)
+    pub examples: Vec<(String, String)>,
+}
+
+impl PromptTemplate {
+    pub fn new(system: impl Into<String>) -> Self {
+        Self {
+            system: Some(system.into()),
+            examples: vec![],
+        }
+    }
+
+    /// 追加一轮示例对话
+    pub fn example(mut self, user: impl Into<String>, assistant: impl Into<String>) -> Self {
+        self.examples.push((user.into(), assistant.into()));
+        self
+    }
+
+    /// 展开为对话开头的消息
+    pub fn messages(&self) -> Vec<Message> {
+        let system = self.system.iter().map(|s| Message::new(Role::System, s));
+        let examples = self.examples.iter().flat_map(|(user, assistant)| {
+            [
+                Message::new(Role::User, user),
+                Message::new(Role::Assistant, assistant),
+            ]
+        });
+        system.chain(examples).collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -49,6 +199,15 @@ pub struct ChatContext {
     add_generation_prompt: bool,
     // qwen3特有
     pub enable_thinking: bool,
+    /// 可供模型调用的工具 (函数) 定义, 渲染为模板的 `tools` 变量
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    /// 额外的模板变量, 渲染时覆盖同名的默认变量
+    #[serde(skip_serializing)]
+    template_vars: Map<String, Value>,
+    /// 重置对话时保留的开头消息
+    #[serde(skip_serializing)]
+    prompt_template: Option<PromptTemplate>,
     #[serde(skip_serializing)]
     template: Template<'static, 'static>,
 }
@@ -69,13 +228,16 @@ impl DerefMut for ChatContext {
 
 impl ChatContext {
     /// 从tokenizer repo创建ChatContext
-    pub async fn from_repo(tokenizer_repo: &str) -> Result<Self> {
-        let template_str = load_template(&tokenizer_repo)
-            .await?
-            .as_str()
-            .unwrap()
-            .to_string();
-        Self::from_template(&template_str)
+    ///
+    /// 基座模型可能没有对话模板, 此时回退到按行拼接消息内容的纯文本模板
+    pub async fn from_repo(hub: &HubClient, tokenizer_repo: &str) -> Result<Self> {
+        match load_template(hub, tokenizer_repo).await?.as_str() {
+            Some(template_str) => Self::from_template(template_str),
+            None => {
+                warn!("{tokenizer_repo} has no chat template, falling back to plain text");
+                Self::from_template(PLAIN_TEMPLATE)
+            }
+        }
     }
 
     /// 从模板字符串创建ChatContext
@@ -84,15 +246,32 @@ impl ChatContext {
             messages: vec![],
             add_generation_prompt: true,
             enable_thinking: false,
+            tools: vec![],
+            template_vars: Map::new(),
+            prompt_template: None,
             template: TEMPLATE_ENV
                 .template_from_str(Box::leak(template_str.to_string().into_boxed_str()))?,
         })
     }
 
+    /// 替换对话模板, 保留已有消息, 用于修正仓库中缺失或有误的模板
+    pub fn with_template(self, template_str: &str) -> Result<Self> {
+        Ok(Self {
+            template: Self::from_template(template_str)?.template,
+            ..self
+        })
+    }
+
     /// 添加消息到对话上下文中
     /// 发送消息角色根据上一条消息自动切换
     /// User->Assistant->User->...
     pub fn push_msg(&mut self, content: &str) {
+        // 带思考过程只取回答
+        self.push_content(content.split("</think>").last().unwrap());
+    }
+
+    /// 与 [`push_msg`](Self::push_msg) 相同, 内容可以是片段数组
+    pub fn push_content(&mut self, content: impl Into<Content>) {
         let role = match self.messages.last() {
             None => Role::User,
             Some(msg) => match msg.role {
@@ -100,11 +279,7 @@ impl ChatContext {
                 _ => Role::User,
             },
         };
-        self.messages.push(Message::new(
-            role,
-            // 带思考过程只取回答
-            content.split("</think>").last().unwrap(),
-        ));
+        self.messages.push(Message::new(role, content));
     }
 
     /// 手动添加指定角色的消息
@@ -112,23 +287,158 @@ impl ChatContext {
         self.messages.push(Message::new(role, content));
     }
 
-    /// 渲染为模板字符串
+    /// 设置提示模板, 对话历史替换为模板中的系统提示词与示例
+    pub fn set_prompt_template(&mut self, template: PromptTemplate) {
+        self.prompt_template = Some(template);
+        self.reset();
+    }
+
+    /// 清空对话历史, 保留提示模板中的消息
+    pub fn reset(&mut self) {
+        self.messages = self
+            .prompt_template
+            .as_ref()
+            .map_or_else(Vec::new, PromptTemplate::messages);
+    }
+
+    /// 设置可供模型调用的工具, 每项为一个工具的 JSON schema, 传入空列表则清除
+    pub fn set_tools(&mut self, tools: Vec<Value>) {
+        self.tools = tools;
+    }
+
+    /// 当前设置的工具定义
+    pub fn tools(&self) -> &[Value] {
+        &self.tools
+    }
+
+    /// 设置模板变量, 如 `date_string`、`bos_token`, 渲染时传给模板
+    pub fn set_template_var(&mut self, key: &str, value: impl Into<Value>) {
+        self.template_vars.insert(key.to_string(), value.into());
+    }
+
+    /// 渲染为模板字符串, 末尾追加生成提示
     pub fn render(&self) -> Result<String> {
+        self.render_with(true)
+    }
+
+    /// 渲染为模板字符串, 不追加生成提示, 可作为后续对话的前缀
+    pub fn render_prefix(&self) -> Result<String> {
+        self.render_with(false)
+    }
+
+    /// 渲染为模板字符串, 由 `add_generation_prompt` 决定是否追加助手回合的生成提示
+    ///
+    /// 对已有的助手回答打分时不应追加生成提示
+    pub fn render_with(&self, add_generation_prompt: bool) -> Result<String> {
         if self.messages.is_empty() {
             bail!("no messages");
         }
-        let ctx = serde_json::to_value(self)?;
+        let mut ctx = serde_json::to_value(self)?;
+        ctx["add_generation_prompt"] = add_generation_prompt.into();
+        // 与 Llama-3 模板中的 date_string 格式一致
+        ctx["date_string"] = strftime("%d %b %Y", unix_now()).into();
+        for (key, value) in &self.template_vars {
+            ctx[key] = value.clone();
+        }
         self.template.render(&ctx).map_err(Error::msg)
     }
 }
 
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// 按 C `strftime` 格式化 UTC 时间戳, 支持常用的日期与时间占位符, 其余原样输出
+fn strftime(format: &str, secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let secs = secs.rem_euclid(86400);
+    let (hour, minute, second) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    // 公历日期, 见 http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let yday = (doy + if leap { 60 } else { 59 }) % (365 + i64::from(leap)) + 1;
+    // 1970-01-01 为星期四
+    let weekday = (days + 4).rem_euclid(7) as usize;
+    let month_name = MONTHS[month as usize - 1];
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out += &year.to_string(),
+            Some('y') => out += &format!("{:02}", year.rem_euclid(100)),
+            Some('m') => out += &format!("{month:02}"),
+            Some('d') => out += &format!("{day:02}"),
+            Some('e') => out += &format!("{day:>2}"),
+            Some('j') => out += &format!("{yday:03}"),
+            Some('H') => out += &format!("{hour:02}"),
+            Some('I') => out += &format!("{:02}", (hour + 11) % 12 + 1),
+            Some('p') => out += if hour < 12 { "AM" } else { "PM" },
+            Some('M') => out += &format!("{minute:02}"),
+            Some('S') => out += &format!("{second:02}"),
+            Some('b') => out += &month_name[..3],
+            Some('B') => out += month_name,
+            Some('a') => out += &WEEKDAYS[weekday][..3],
+            Some('A') => out += WEEKDAYS[weekday],
+            Some('%') => out.push('%'),
+            Some(c) => {
+                out.push('%');
+                out.push(c);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
     async fn test_push_msg() -> Result<()> {
-        let mut ctx = ChatContext::from_repo("Qwen/Qwen3-4B-Instruct-2507").await?;
+        let hub = HubClient::from_env()?;
+        let mut ctx = ChatContext::from_repo(&hub, "Qwen/Qwen3-4B-Instruct-2507").await?;
         ctx.push_msg("hello");
         ctx.push_msg("hi");
         ctx.push_msg("how are you");
@@ -146,7 +456,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_manual_push() -> Result<()> {
-        let mut ctx = ChatContext::from_repo("Qwen/Qwen3-4B-Instruct-2507").await?;
+        let hub = HubClient::from_env()?;
+        let mut ctx = ChatContext::from_repo(&hub, "Qwen/Qwen3-4B-Instruct-2507").await?;
         ctx.push_message(Role::System, "You are a helpful assistant");
         ctx.push_message(Role::User, "hello");
         ctx.push_message(Role::Assistant, "hi there!");
@@ -160,7 +471,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_from_repo() -> Result<()> {
-        let mut ctx = ChatContext::from_repo("Qwen/Qwen3-4B-Instruct-2507").await?;
+        let hub = HubClient::from_env()?;
+        let mut ctx = ChatContext::from_repo(&hub, "Qwen/Qwen3-4B-Instruct-2507").await?;
         ctx.push_msg("hello");
         ctx.push_msg("hi");
 
@@ -169,6 +481,24 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_render_chat_prompt() -> Result<()> {
+        let hub = HubClient::from_env()?;
+        let prompt = render_chat_prompt(
+            &hub,
+            "Qwen/Qwen3-4B-Instruct-2507",
+            Some("You are a pirate"),
+            "where is the treasure?",
+        )
+        .await?;
+
+        assert!(prompt.contains("You are a pirate"), "{prompt}");
+        assert!(prompt.contains("where is the treasure?"), "{prompt}");
+        // 以助手回合的生成提示结尾
+        assert!(prompt.ends_with("<|im_start|>assistant\n"), "{prompt}");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_from_template() -> Result<()> {
         let template_str = r#"
@@ -197,9 +527,205 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_render_with() -> Result<()> {
+        let template_str = r#"
+{%- for message in messages %}
+<|{{ message.role }}|>{{ message.content }}<|end|>
+{%- endfor %}
+{%- if add_generation_prompt %}
+<|assistant|>
+{%- endif %}"#;
+
+        let mut ctx = ChatContext::from_template(template_str)?;
+        ctx.push_msg("hello");
+        ctx.push_msg("hi");
+
+        let with_prompt = ctx.render_with(true)?;
+        let without_prompt = ctx.render_with(false)?;
+        assert_eq!(with_prompt, ctx.render()?);
+        assert_eq!(without_prompt, ctx.render_prefix()?);
+
+        // 两者仅相差生成提示后缀
+        assert_eq!(
+            with_prompt.strip_prefix(&without_prompt),
+            Some("\n<|assistant|>")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tools() -> Result<()> {
+        // 与 Qwen3 模板中工具部分的结构一致
+        let template_str = r#"
+{%- if tools %}
+<|im_start|>system
+# Tools
+<tools>
+{%- for tool in tools %}
+{{ tool | tojson }}
+{%- endfor %}
+</tools><|im_end|>
+{%- endif %}
+{%- for message in messages %}
+<|im_start|>{{ message.role }}
+{{ message.content }}<|im_end|>
+{%- endfor %}"#;
+
+        let mut ctx = ChatContext::from_template(template_str)?;
+        ctx.push_msg("what's the weather in Paris?");
+        assert!(!ctx.render()?.contains("<tools>"));
+
+        let tool = serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "Get the current weather",
+                "parameters": {
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}},
+                    "required": ["city"]
+                }
+            }
+        });
+        ctx.set_tools(vec![tool.clone()]);
+
+        let prompt = ctx.render()?;
+        let tools_section = prompt
+            .split_once("<tools>")
+            .and_then(|(_, rest)| rest.split_once("</tools>"))
+            .map(|(section, _)| section)
+            .ok_or_else(|| anyhow!("tools section not found"))?;
+        assert_eq!(serde_json::from_str::<Value>(tools_section.trim())?, tool);
+        // 工具定义位于对话消息之前
+        assert!(prompt.find("</tools>") < prompt.find("what's the weather"));
+
+        ctx.set_tools(vec![]);
+        assert!(!ctx.render()?.contains("<tools>"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_template() -> Result<()> {
+        let mut ctx = ChatContext::from_template("{{ messages | length }}")?;
+        ctx.push_msg("hello");
+        ctx.push_msg("hi");
+
+        let ctx = ctx.with_template(
+            "{% for m in messages %}{{ m.role }}: {{ m.content }}\n{% endfor %}\
+             {% if add_generation_prompt %}assistant: {% endif %}",
+        )?;
+        assert_eq!(ctx.render()?, "user: hello\nassistant: hi\nassistant: ");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_parts() -> Result<()> {
+        let parts = vec![
+            ContentPart::ImagePlaceholder,
+            ContentPart::text("look"),
+            ContentPart::text("here"),
+        ];
+        let content = Content::from(parts.clone());
+        assert_eq!(content.text(), "look\nhere");
+        assert!(content.has_images());
+        assert!(!Content::from("look").has_images());
+
+        // OpenAI 格式的内容片段数组原样保留
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [{"type": "image_url", "image_url": {"url": "a.png"}}, {"type": "text", "text": "hi"}],
+        }))?;
+        assert_eq!(
+            msg.content,
+            Content::Parts(vec![ContentPart::ImagePlaceholder, ContentPart::text("hi")])
+        );
+
+        // 片段以列表传给模板, 纯文本仍为字符串
+        let mut ctx = ChatContext::from_template(
+            "{% for m in messages %}{% if m.content is string %}{{ m.content }}\
+             {% else %}{% for p in m.content %}<{{ p.type }}>{{ p.text }}{% endfor %}{% endif %}\n\
+             {% endfor %}",
+        )?;
+        ctx.push_content(parts);
+        ctx.push_msg("ok");
+        assert_eq!(ctx.render()?, "<image><text>look<text>here\nok\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strftime() {
+        assert_eq!(strftime("%d %b %Y", 0), "01 Jan 1970");
+        // 2024-07-26 13:05:09, 星期五
+        let secs = 1721952000 + 13 * 3600 + 5 * 60 + 9;
+        assert_eq!(strftime("%d %b %Y", secs), "26 Jul 2024");
+        assert_eq!(
+            strftime("%A %B %e %y %H:%M:%S %I%p %j 100%%", secs),
+            "Friday July 26 24 13:05:09 01PM 208 100%"
+        );
+        assert_eq!(strftime("%Y-%m-%d %a", 951782400), "2000-02-29 Tue");
+        assert_eq!(strftime("%j", 978220800), "366");
+    }
+
+    #[test]
+    fn test_template_vars() -> Result<()> {
+        // 与 Llama-3.1 模板中日期部分的结构一致
+        let template_str = r#"
+{%- if not date_string is defined %}
+    {%- if strftime_now is defined %}
+        {%- set date_string = strftime_now("%d %b %Y") %}
+    {%- else %}
+        {%- set date_string = "26 Jul 2024" %}
+    {%- endif %}
+{%- endif %}
+Today Date: {{ date_string }}
+Now: {{ strftime_now("%Y-%m-%d") }}
+{%- for message in messages %}
+{{ message.content }}
+{%- endfor %}"#;
+
+        let mut ctx = ChatContext::from_template(template_str)?;
+        ctx.push_msg("hello");
+
+        let prompt = ctx.render()?;
+        let date = regex::Regex::new(r"Today Date: (\d{2}) ([A-Z][a-z]{2}) (\d{4})")?
+            .captures(&prompt)
+            .ok_or_else(|| anyhow!("no date in {prompt:?}"))?;
+        assert!(MONTHS.iter().any(|m| m.starts_with(&date[2])), "{prompt}");
+        assert!(date[3].parse::<i32>()? >= 2024, "{prompt}");
+        assert!(regex::Regex::new(r"Now: \d{4}-\d{2}-\d{2}\n")?.is_match(&prompt));
+
+        // 自定义变量覆盖默认值
+        ctx.set_template_var("date_string", "01 Jan 2030");
+        assert!(ctx.render()?.contains("Today Date: 01 Jan 2030\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_phi3_template() -> Result<()> {
+        let hub = HubClient::from_env()?;
+        let mut ctx = ChatContext::from_repo(&hub, "microsoft/Phi-3-mini-4k-instruct").await?;
+        ctx.push_msg("hello");
+        ctx.push_msg("hi");
+        ctx.push_msg("how are you");
+
+        let prompt = ctx.render()?;
+        assert!(prompt.contains("<|user|>\nhello<|end|>"));
+        assert!(prompt.contains("<|assistant|>\nhi<|end|>"));
+        assert!(prompt.ends_with("<|assistant|>\n"));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_thinking_content() -> Result<()> {
-        let mut ctx = ChatContext::from_repo("Qwen/Qwen3-4B-Instruct-2507").await?;
+        let hub = HubClient::from_env()?;
+        let mut ctx = ChatContext::from_repo(&hub, "Qwen/Qwen3-4B-Instruct-2507").await?;
         ctx.push_msg("hello");
         ctx.push_msg("<think>let me think about this</think>hi there!");
 
@@ -0,0 +1,211 @@
+//! 按提示词缓存确定性生成的完整回答, 重复评测相同提示词时直接返回而不运行模型
+//!
+//! 键为 (加载的权重, 渲染后的提示词, 影响输出的推理参数) 的规范 JSON, 缓存以 JSON 保存在本地文件
+
+use crate::model::config::InferenceConfig;
+use crate::model::hub::HubInfo;
+use crate::pipe::StopReason;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// 不影响生成结果的推理参数, 不参与计算键
+const IGNORED_PARAMS: [&str; 7] = [
+    "top_logprobs",
+    "token_timeout",
+    "max_duration",
+    "cpu_threads",
+    "hf_token",
+    "cache_dir",
+    "proxy",
+];
+
+/// 缓存的一次生成
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedGeneration {
+    /// 完整回答, 含助手回答前缀
+    pub answer: String,
+    /// 回答的 token, 不含 EOS
+    pub tokens: Vec<u32>,
+    /// 各 token 之后输出的文本, 与 `tokens` 一一对应
+    pub texts: Vec<String>,
+    pub completion_tokens: usize,
+    pub stop_reason: StopReason,
+}
+
+/// 保存在本地文件中的生成缓存, 由 [`TextGeneration`](crate::pipe::TextGeneration) 在生成前查询、生成后写入
+///
+/// 只缓存贪心解码的回答, 采样生成每次结果不同, 不查询也不写入
+#[derive(Debug)]
+pub struct GenerationCache {
+    path: PathBuf,
+    entries: HashMap<String, CachedGeneration>,
+}
+
+impl GenerationCache {
+    /// 打开缓存文件, 文件不存在时为空缓存, 首次写入时创建
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => Err(e)?,
+        };
+        Ok(Self { path, entries })
+    }
+
+    /// 由加载的权重 (见 [`weights_id`](Self::weights_id))、渲染后的提示词与推理参数计算键
+    ///
+    /// 键为这些内容的规范 JSON, 对象按字段名排序; 按完整内容比较, 不会因哈希碰撞取到其他提示词的回答
+    pub fn key(weights: &Value, prompt: &str, config: &InferenceConfig) -> Result<String> {
+        let mut params = serde_json::to_value(config)?;
+        if let Value::Object(params) = &mut params {
+            for name in IGNORED_PARAMS {
+                params.remove(name);
+            }
+        }
+        let key = json!({
+            "weights": weights,
+            "prompt": prompt,
+            "params": params,
+        });
+        Ok(serde_json::to_string(&key)?)
+    }
+
+    /// 标识加载的权重: 模型标识符、仓库与固定的版本、权重文件、LoRA 适配器与分词器
+    ///
+    /// 同一标识符换用其他版本、量化文件或适配器时不会取到之前的回答
+    pub fn weights_id(model_id: &str, hub_info: &HubInfo) -> Value {
+        json!({
+            "model_id": model_id,
+            "model_repo": hub_info.model_repo,
+            "revision": hub_info.revision,
+            "model_file": hub_info.model_file,
+            "adapter_repo": hub_info.adapter_repo,
+            "tokenizer_repo": hub_info.tokenizer_repo,
+            "tokenizer_revision": hub_info.tokenizer_revision,
+            "tokenizer_file": hub_info.tokenizer_file,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&CachedGeneration> {
+        self.entries.get(key)
+    }
+
+    /// 写入一条缓存并保存到文件
+    pub fn insert(&mut self, key: String, generation: CachedGeneration) -> Result<()> {
+        self.entries.insert(key, generation);
+        // 先写入临时文件再替换, 中途退出不会留下损坏的缓存文件
+        let tmp = self.path.with_extension("tmp");
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&tmp, serde_json::to_vec(&self.entries)?)?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_cache() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gen-cache-{}", std::process::id()));
+        let path = dir.join("cache.json");
+
+        let config = InferenceConfig {
+            temperature: 0.,
+            device: candle::Device::Cpu,
+            ..Default::default()
+        };
+        let hub_info = HubInfo {
+            model_repo: "Mock/Model-GGUF".to_string(),
+            model_file: "model-Q4_K_M.gguf".to_string(),
+            model_file_pattern: None,
+            tokenizer_repo: "Mock/Model".to_string(),
+            revision: "main".to_string(),
+            tokenizer_revision: "main".to_string(),
+            tokenizer_file: None,
+            adapter_repo: None,
+            alias: vec![],
+            default: false,
+        };
+        let mock = GenerationCache::weights_id("mock", &hub_info);
+        let key = GenerationCache::key(&mock, "a b", &config)?;
+        assert_eq!(key, GenerationCache::key(&mock, "a b", &config)?);
+        assert_ne!(key, GenerationCache::key(&mock, "a", &config)?);
+        // 同一标识符的其他版本、量化文件或适配器不共用缓存
+        for other in [
+            GenerationCache::weights_id("other", &hub_info),
+            GenerationCache::weights_id(
+                "mock",
+                &HubInfo {
+                    revision: "v1.0".to_string(),
+                    ..hub_info.clone()
+                },
+            ),
+            GenerationCache::weights_id(
+                "mock",
+                &HubInfo {
+                    model_file: "model-Q8_0.gguf".to_string(),
+                    ..hub_info.clone()
+                },
+            ),
+            GenerationCache::weights_id(
+                "mock",
+                &HubInfo {
+                    adapter_repo: Some("Mock/Adapter".to_string()),
+                    ..hub_info.clone()
+                },
+            ),
+        ] {
+            assert_ne!(key, GenerationCache::key(&other, "a b", &config)?);
+        }
+        // 影响输出的参数参与计算键, 超时等参数不参与
+        let longer = InferenceConfig {
+            sample_len: config.sample_len + 1,
+            ..config.clone()
+        };
+        assert_ne!(key, GenerationCache::key(&mock, "a b", &longer)?);
+        let proxied = InferenceConfig {
+            proxy: Some("http://127.0.0.1:7890".to_string()),
+            ..config.clone()
+        };
+        assert_eq!(key, GenerationCache::key(&mock, "a b", &proxied)?);
+
+        let mut cache = GenerationCache::open(&path)?;
+        assert!(cache.is_empty());
+        let generation = CachedGeneration {
+            answer: "b a".to_string(),
+            tokens: vec![2, 1],
+            texts: vec!["b".to_string(), " a".to_string()],
+            completion_tokens: 2,
+            stop_reason: StopReason::EosToken,
+        };
+        cache.insert(key.clone(), generation.clone())?;
+
+        // 重新打开后仍可读取
+        let cache = GenerationCache::open(&path)?;
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&key), Some(&generation));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,48 +1,43 @@
-use anyhow::{Error, Result};
+use crate::error::LlmError;
+use crate::utils::sentencepiece;
+use crate::utils::{Secret, format_size};
+use anyhow::{Context, Error, Result, bail};
 use candle::quantized::gguf_file::Content;
 use candle_transformers::generation::{LogitsProcessor, Sampling};
 use futures_util::future::try_join_all;
-use hf_hub::api::tokio::ApiBuilder;
-use hf_hub::{Cache, Repo, api::tokio::Api};
+use hf_hub::{Cache, Repo, RepoType};
+use indicatif::{ProgressBar, ProgressStyle};
 use regex::Regex;
-use std::{fs::File, path::PathBuf, process::Command};
-use tokenizers::{FromPretrainedParameters, Tokenizer};
+use reqwest::header::{AUTHORIZATION, CONTENT_RANGE, HeaderMap, HeaderValue, LOCATION, RANGE};
+use reqwest::{Client, Proxy, StatusCode, redirect};
+use serde_json::Value;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tokenizers::Tokenizer;
+use tokio::io::AsyncWriteExt;
 
 /// 从指定仓库下载GGUF模型文件,支持下载分片模型文件,会自动检测并合并分片
 ///
 /// # 参数
+/// * `hub` - Hub 客户端
 /// * `repo` - 模型仓库名
 /// * `filename` - 模型文件名(不带后缀)
-pub async fn download_gguf(repo: &str, filename: &str) -> Result<PathBuf> {
-    if let Some(path) = Cache::default().model(repo.to_string()).get(filename) {
-        Ok(path)
+pub async fn download_gguf(hub: &HubClient, repo: &str, filename: &str) -> Result<PathBuf> {
+    let split_paths = download_gguf_files(hub, repo, filename).await?;
+    if let [path] = split_paths.as_slice() {
+        Ok(path.clone())
     } else {
-        let repo = ApiBuilder::from_env().build()?.model(repo.to_string());
-
-        // 获取不带后缀的文件名前缀用于分片检测
         let filename_prefix = filename.strip_suffix(".gguf").unwrap_or(filename);
-
-        // 模型可能分片, 收集前缀为 filename_prefix 的文件
-        let split_filenames: Vec<_> = repo
-            .info()
-            .await?
-            .siblings
-            .into_iter()
-            .map(|sibling| sibling.rfilename)
-            .filter(|s| s.starts_with(filename_prefix))
-            .collect();
-
-        // 如果没有分片，直接下载完整文件
-        if split_filenames.len() == 1 {
-            return Ok(repo.get(filename).await?);
-        }
-
-        // 下载分片文件
-        let split_paths = try_join_all(split_filenames.iter().map(|f| repo.get(f))).await?;
-
         let download_dir = split_paths[0].parent().unwrap();
 
-        let merge_path = download_dir.join(format!("{filename_prefix}*"));
+        let merge_path = download_dir.join(format!("{filename_prefix}-*-of-*.gguf"));
 
         let output = Command::new("gguf-utils")
             .arg("merge")
@@ -65,69 +60,1051 @@ pub async fn download_gguf(repo: &str, filename: &str) -> Result<PathBuf> {
         let new_path = download_dir.join(filename);
         std::fs::rename(merged_path, &new_path)?;
 
+        if hub.cleanup_shards {
+            remove_shards(&new_path, &split_paths)?;
+        }
+
         Ok(new_path)
     }
 }
 
-pub fn load_tokenizer(repo: &str) -> Result<Tokenizer> {
-    let mut params = FromPretrainedParameters::default();
-    params.token = std::env::var("HF_TOKEN").ok();
+/// 下载 GGUF 模型文件, 分片模型返回按序号排列的全部分片, 不合并
+///
+/// 文件、之前合并出的文件或完整的一组分片已缓存时不访问 Hub
+pub async fn download_gguf_files(
+    hub: &HubClient,
+    repo: &str,
+    filename: &str,
+) -> Result<Vec<PathBuf>> {
+    if let Some(paths) = cached_gguf_files(hub, repo, filename) {
+        return Ok(paths);
+    }
+    hub.ensure_online(repo, filename)?;
+
+    // 获取不带后缀的文件名前缀用于分片检测
+    let filename_prefix = filename.strip_suffix(".gguf").unwrap_or(filename);
+
+    // 模型可能分片, 收集 `{filename_prefix}-00001-of-0000N.gguf` 形式的文件
+    let siblings = hub.repo_files(repo).await?;
+    let split_filenames = gguf_shards(&siblings, filename_prefix)?;
+
+    // 如果没有分片，直接下载完整文件
+    if split_filenames.is_empty() {
+        return Ok(vec![hub.download(repo, filename).await?]);
+    }
+    try_join_all(split_filenames.iter().map(|f| hub.download(repo, f))).await
+}
+
+/// 缓存中的 GGUF 文件、之前合并出的文件或完整的一组分片
+fn cached_gguf_files(hub: &HubClient, repo: &str, filename: &str) -> Option<Vec<PathBuf>> {
+    if let Some(path) = hub
+        .cached(repo, filename)
+        .or_else(|| merged_gguf(hub, repo, filename))
+    {
+        return Some(vec![path]);
+    }
+    let filename_prefix = filename.strip_suffix(".gguf").unwrap_or(filename);
+    cached_gguf_shards(hub, repo, filename_prefix)
+}
+
+/// 读取 GGUF 文件或一组分片的文件头, 未缓存时以 Range 请求只下载文件头, 不下载权重
+///
+/// 返回的 [`Content`] 只用于查看元数据与张量信息, 分片中张量的偏移量未按拼接后的位置调整
+pub async fn read_gguf_header(hub: &HubClient, repo: &str, filename: &str) -> Result<Content> {
+    if let Some(paths) = cached_gguf_files(hub, repo, filename) {
+        return Ok(read_gguf(&paths)?.0);
+    }
+    hub.ensure_online(repo, filename)?;
+
+    // 多数模型不分片, 先直接读取, 文件不存在时再查找分片
+    let err = match hub.fetch_gguf_header(repo, filename).await {
+        Err(e) if status_code(&e) == Some(404) => e,
+        res => return res,
+    };
+    let siblings = hub.repo_files(repo).await?;
+    let filename_prefix = filename.strip_suffix(".gguf").unwrap_or(filename);
+    let shards = gguf_shards(&siblings, filename_prefix)?;
+    if shards.is_empty() {
+        return Err(err.context(format!("{filename} not found in {repo}")));
+    }
+
+    let mut merged = None;
+    for ct in try_join_all(shards.iter().map(|f| hub.fetch_gguf_header(repo, f))).await? {
+        merge_gguf(&mut merged, ct)?;
+    }
+    merged.ok_or_else(|| anyhow!("no GGUF file to read"))
+}
+
+/// 将一个分片的张量并入 `merged`, 元数据取自第一个分片
+fn merge_gguf(merged: &mut Option<Content>, ct: Content) -> Result<()> {
+    match merged {
+        None => *merged = Some(ct),
+        Some(merged) => {
+            for (name, info) in ct.tensor_infos {
+                if merged.tensor_infos.insert(name.clone(), info).is_some() {
+                    bail!("tensor {name} appears in multiple GGUF shards");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 读取 GGUF 文件或一组分片, 分片的张量合并到一个 [`Content`] 中, 元数据取自第一个分片
+///
+/// 返回的读取器将各文件首尾拼接, 合并后的张量偏移量相对于拼接后的开头,
+/// 可直接传给 `ModelWeights::from_gguf` 等接口, 无需先合并分片
+pub fn read_gguf(paths: &[PathBuf]) -> Result<(Content, GgufReader)> {
+    let mut reader = GgufReader::open(paths)?;
+    let mut merged: Option<Content> = None;
+    for ((start, _, file), path) in reader.files.iter_mut().zip(paths) {
+        let mut ct = Content::read(file).with_context(|| format!("failed to read {path:?}"))?;
+        for info in ct.tensor_infos.values_mut() {
+            info.offset += *start + ct.tensor_data_offset;
+        }
+        ct.tensor_data_offset = 0;
+        merge_gguf(&mut merged, ct)?;
+    }
+    let ct = merged.ok_or_else(|| anyhow!("no GGUF file to read"))?;
+    Ok((ct, reader))
+}
+
+/// 将多个文件首尾拼接为一个可寻址的读取器, 见 [`read_gguf`]
+pub struct GgufReader {
+    /// 各文件的起始位置、长度与文件
+    files: Vec<(u64, u64, File)>,
+    pos: u64,
+    len: u64,
+}
+
+impl GgufReader {
+    fn open(paths: &[PathBuf]) -> Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut len = 0;
+        for path in paths {
+            let file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+            let file_len = file.metadata()?.len();
+            files.push((len, file_len, file));
+            len += file_len;
+        }
+        Ok(Self { files, pos: 0, len })
+    }
+}
+
+impl Read for GgufReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let pos = self.pos;
+        let Some((start, len, file)) = self
+            .files
+            .iter_mut()
+            .find(|(start, len, _)| (*start..*start + *len).contains(&pos))
+        else {
+            return Ok(0);
+        };
+        let offset = pos - *start;
+        file.seek(SeekFrom::Start(offset))?;
+        let max = buf.len().min((*len - offset) as usize);
+        let n = file.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for GgufReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// 仓库中各文件的字节数
+async fn repo_file_sizes(hub: &HubClient, repo: &str) -> Result<BTreeMap<String, u64>> {
+    hub.ensure_online(repo, "the file list")?;
+
+    let info = hub.repo_info(repo, true).await?;
+    Ok(siblings(&info, repo)?
+        .iter()
+        .filter_map(|sibling| {
+            let name = sibling["rfilename"].as_str()?;
+            Some((name.to_string(), sibling["size"].as_u64()?))
+        })
+        .collect())
+}
+
+/// 仓库中可选的 GGUF 文件及其字节数, 分片按合并后的文件名累加
+pub async fn gguf_candidates(hub: &HubClient, repo: &str) -> Result<Vec<(String, usize)>> {
+    let shard = Regex::new(r"-\d{5}-of-\d{5}\.gguf$")?;
+    let mut sizes = BTreeMap::new();
+    for (name, size) in repo_file_sizes(hub, repo).await? {
+        // 多模态投影等辅助文件不是模型权重
+        if !name.ends_with(".gguf") || name.contains("mmproj") {
+            continue;
+        }
+        *sizes
+            .entry(shard.replace(&name, ".gguf").into_owned())
+            .or_insert(0) += size as usize;
+    }
+    Ok(sizes.into_iter().collect())
+}
+
+/// 下载 GGUF 文件前按需下载的大小询问确认回调, 文件或合并后的分片已缓存时不询问
+pub async fn confirm_gguf_download(hub: &HubClient, repo: &str, filename: &str) -> Result<()> {
+    if !hub.needs_confirm()
+        || hub
+            .cached(repo, filename)
+            .or_else(|| merged_gguf(hub, repo, filename))
+            .is_some()
+    {
+        return Ok(());
+    }
+
+    let sizes = repo_file_sizes(hub, repo).await?;
+    let names: Vec<_> = sizes.keys().cloned().collect();
+    let filename_prefix = filename.strip_suffix(".gguf").unwrap_or(filename);
+    let mut files = gguf_shards(&names, filename_prefix)?;
+    if files.is_empty() {
+        files.push(filename.to_string());
+    }
+    hub.confirm(repo, &files, &sizes)
+}
+
+/// 下载 safetensors 权重前按需下载的大小询问确认回调, 单文件不存在时按 index.json 统计分片
+pub async fn confirm_safetensors_download(
+    hub: &HubClient,
+    repo: &str,
+    filename: &str,
+) -> Result<()> {
+    if !hub.needs_confirm() {
+        return Ok(());
+    }
+
+    let sizes = repo_file_sizes(hub, repo).await?;
+    let files = if sizes.contains_key(filename) {
+        vec![filename.to_string()]
+    } else {
+        safetensors_shards(&hub.get(repo, SAFETENSORS_INDEX).await?)?
+    };
+    hub.confirm(repo, &files, &sizes)
+}
+
+/// 在仓库文件中解析 GGUF 文件名通配符, 返回唯一匹配的文件名
+pub async fn resolve_gguf_pattern(hub: &HubClient, repo: &str, pattern: &str) -> Result<String> {
+    hub.ensure_online(repo, pattern)?;
+    let siblings = hub.repo_files(repo).await?;
+    match_gguf_pattern(&siblings, pattern)
+        .with_context(|| format!("failed to resolve {pattern} in {repo}"))
+}
+
+/// 通配符支持 `*` 与 `?`, 分片按合并后的文件名计, 只匹配到同一模型的多个分片不算歧义
+fn match_gguf_pattern(siblings: &[String], pattern: &str) -> Result<String> {
+    let re = Regex::new(&format!(
+        "^{}$",
+        regex::escape(pattern)
+            .replace(r"\*", "[^/]*")
+            .replace(r"\?", "[^/]")
+    ))?;
+    let shard = Regex::new(r"-\d{5}-of-\d{5}\.gguf$")?;
+
+    let mut matches: Vec<_> = siblings
+        .iter()
+        .filter(|s| s.ends_with(".gguf") && re.is_match(s))
+        .map(|s| shard.replace(s, ".gguf").into_owned())
+        .collect();
+    matches.sort();
+    matches.dedup();
+
+    match matches.as_slice() {
+        [] => bail!("no GGUF file matches {pattern}"),
+        [file] => Ok(file.clone()),
+        _ => bail!("{pattern} matches multiple files: {}", matches.join(", ")),
+    }
+}
+
+/// 之前合并分片得到的文件, 只在固定版本的快照目录中查找, 不使用其他版本的文件
+fn merged_gguf(hub: &HubClient, repo: &str, filename: &str) -> Option<PathBuf> {
+    let path = hub.snapshot_dir(repo)?.join(filename);
+    path.is_file().then_some(path)
+}
+
+/// 缓存中完整的一组分片, 按序号排列且位于固定版本的快照目录
+fn cached_gguf_shards(hub: &HubClient, repo: &str, filename_prefix: &str) -> Option<Vec<PathBuf>> {
+    let dir = hub.snapshot_dir(repo)?;
+    let names: Vec<_> = std::fs::read_dir(&dir)
+        .ok()?
+        .flatten()
+        .filter_map(|file| file.file_name().into_string().ok())
+        .collect();
+    let shards = gguf_shards(&names, filename_prefix).ok()?;
+    // `-00001-of-00003.gguf` 中的分片总数
+    let count: usize = shards
+        .first()?
+        .rsplit_once("-of-")?
+        .1
+        .strip_suffix(".gguf")?
+        .parse()
+        .ok()?;
+    (shards.len() == count).then(|| shards.iter().map(|name| dir.join(name)).collect())
+}
+
+/// 确认合并后的文件可以读取, 再删除分片及其在缓存中指向的 blob
+fn remove_shards(merged: &Path, shards: &[PathBuf]) -> Result<()> {
+    Content::read(&mut File::open(merged)?)
+        .with_context(|| format!("merged file {merged:?} is unreadable, keeping the shards"))?;
+
+    for shard in shards {
+        // 缓存快照中的文件是指向 blobs 的符号链接
+        if shard.is_symlink() {
+            std::fs::remove_file(std::fs::canonicalize(shard)?)?;
+        }
+        std::fs::remove_file(shard)?;
+    }
+    Ok(())
+}
+
+/// 仓库文件中属于 `filename_prefix` 的 GGUF 分片, 按分片序号排序
+///
+/// 只匹配 `{filename_prefix}-00001-of-00003.gguf` 这类规范命名, 避免前缀相同的其他量化文件混入
+fn gguf_shards(siblings: &[String], filename_prefix: &str) -> Result<Vec<String>> {
+    let re = Regex::new(&format!(
+        r"^{}-\d{{5}}-of-\d{{5}}\.gguf$",
+        regex::escape(filename_prefix)
+    ))?;
+
+    let mut shards: Vec<_> = siblings
+        .iter()
+        .filter(|s| re.is_match(s))
+        .cloned()
+        .collect();
+    shards.sort();
+    Ok(shards)
+}
+
+/// 未指定版本时使用的分支
+pub const DEFAULT_REVISION: &str = "main";
+
+/// 仓库的用途, 同一仓库的权重与分词器可以固定不同的版本
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RepoRole {
+    #[default]
+    Model,
+    Tokenizer,
+}
+
+/// 读取 GGUF 文件头时首次请求的字节数, 通常足以包含内嵌的词表
+const GGUF_HEADER_BYTES: usize = 8 << 20;
+
+/// HuggingFace Hub 客户端, 集中管理 token、缓存目录、代理、进度条与离线模式
+///
+/// 构造一次后传给各个加载函数, 所有 Hub 访问共用同一份配置;
+/// 下载的文件按 hf-hub 的缓存布局 (`blobs`、`snapshots`、`refs`) 保存, 与 Python 端共用缓存
+#[derive(Clone)]
+pub struct HubClient {
+    /// 跟随所有重定向, 用于下载文件与读取仓库信息
+    client: Client,
+    /// 只跟随同一主机内的重定向, 用于读取文件元数据, 指向 CDN 的重定向留给 `client`
+    metadata_client: Client,
+    endpoint: String,
+    cache: Cache,
+    token: Option<Secret>,
+    progress: bool,
+    offline: bool,
+    cleanup_shards: bool,
+    retries: usize,
+    confirm_download: Option<ConfirmDownload>,
+    /// 按用途固定了版本的仓库, 其余仓库使用 [`DEFAULT_REVISION`]
+    revisions: HashMap<(String, RepoRole), String>,
+    /// 查找固定版本时使用的用途, 见 [`tokenizer`](Self::tokenizer)
+    role: RepoRole,
+}
+
+/// 下载确认回调, 参数为需要下载的字节数
+#[derive(Clone)]
+struct ConfirmDownload(Arc<dyn Fn(u64) -> bool + Send + Sync>);
+
+impl fmt::Debug for ConfirmDownload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfirmDownload")
+    }
+}
+
+/// 不输出 HTTP 客户端, 其默认请求头中带有 token
+impl fmt::Debug for HubClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HubClient")
+            .field("endpoint", &self.endpoint)
+            .field("cache", &self.cache)
+            .field("token", &self.token)
+            .field("progress", &self.progress)
+            .field("offline", &self.offline)
+            .field("cleanup_shards", &self.cleanup_shards)
+            .field("retries", &self.retries)
+            .field("confirm_download", &self.confirm_download)
+            .field("revisions", &self.revisions)
+            .field("role", &self.role)
+            .finish_non_exhaustive()
+    }
+}
 
-    Tokenizer::from_pretrained(repo, Some(params)).map_err(Error::msg)
+/// 下载文件时从 Hub 响应头得到的元数据
+struct FileMetadata {
+    commit: String,
+    etag: String,
+    size: u64,
 }
 
-/// ApiRepo 的扩展 trait，提供 safetensors 加载功能
-pub trait ApiRepoExt {
-    /// 从 HuggingFace Hub 加载 safetensors 模型文件
+impl HubClient {
+    /// 按环境变量 (`HF_ENDPOINT`、`HF_HOME`、`HF_TOKEN` 等) 创建
+    pub fn from_env() -> Result<Self> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> HubClientBuilder {
+        HubClientBuilder::default()
+    }
+
+    /// 固定 `repo` 中模型权重的版本 (分支、标签或 commit), 之后该仓库的下载与缓存查找都使用该版本
+    pub fn with_revision(self, repo: &str, revision: &str) -> Self {
+        self.with_role_revision(repo, RepoRole::Model, revision)
+    }
+
+    /// 固定 `repo` 中分词器文件的版本, 只对 [`tokenizer`](Self::tokenizer) 返回的客户端生效
+    pub fn with_tokenizer_revision(self, repo: &str, revision: &str) -> Self {
+        self.with_role_revision(repo, RepoRole::Tokenizer, revision)
+    }
+
+    fn with_role_revision(mut self, repo: &str, role: RepoRole, revision: &str) -> Self {
+        self.revisions
+            .insert((repo.to_string(), role), revision.to_string());
+        self
+    }
+
+    /// 按分词器的固定版本访问仓库的客户端, 用于下载分词器、对话模板与 config.json
+    pub fn tokenizer(&self) -> Self {
+        Self {
+            role: RepoRole::Tokenizer,
+            ..self.clone()
+        }
+    }
+
+    /// `repo` 使用的版本
+    pub fn revision(&self, repo: &str) -> &str {
+        self.revisions
+            .get(&(repo.to_string(), self.role))
+            .map_or(DEFAULT_REVISION, String::as_str)
+    }
+
+    /// `repo` 固定的版本在缓存中的快照目录, 版本未缓存时为 `None`
+    ///
+    /// 由 `refs/{revision}` 得到 commit, 没有该引用时版本本身视为 commit
+    fn snapshot_dir(&self, repo: &str) -> Option<PathBuf> {
+        let repo_dir = self
+            .cache_dir()
+            .join(Repo::model(repo.to_string()).folder_name());
+        let revision = self.revision(repo);
+        let commit = std::fs::read_to_string(repo_dir.join("refs").join(revision))
+            .map_or_else(|_| revision.to_string(), |commit| commit.trim().to_string());
+        let dir = repo_dir.join("snapshots").join(commit);
+        dir.is_dir().then_some(dir)
+    }
+
+    fn repo_ref(&self, repo: &str) -> Repo {
+        Repo::with_revision(
+            repo.to_string(),
+            RepoType::Model,
+            self.revision(repo).to_string(),
+        )
+    }
+
+    /// 访问 Hub 使用的 token, 显式设置的优先于 `HF_TOKEN` 环境变量
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_ref().map(Secret::expose)
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        self.cache.path()
+    }
+
+    /// 本地缓存中的文件
+    pub fn cached(&self, repo: &str, filename: &str) -> Option<PathBuf> {
+        self.cache.repo(self.repo_ref(repo)).get(filename)
+    }
+
+    /// 从仓库获取文件, 优先使用缓存, 离线模式下只读取缓存
+    ///
+    /// 失败时按原因 (鉴权、不存在、网络) 给出可操作的错误信息
+    pub async fn get(&self, repo: &str, filename: &str) -> Result<PathBuf> {
+        if let Some(path) = self.cached(repo, filename) {
+            return Ok(path);
+        }
+        self.ensure_online(repo, filename)?;
+
+        self.download(repo, filename).await.map_err(|e| {
+            // 未登录时访问私有或不存在的仓库同样返回 401
+            let msg = match status_code(&e) {
+                Some(401 | 403) => format!(
+                    "failed to fetch {filename} for {repo}; this repo may be gated or private \
+                     — set HF_TOKEN"
+                ),
+                Some(404) => format!("{filename} not found in {repo}; check the repo name"),
+                _ => format!(
+                    "failed to fetch {filename} for {repo}; check the network or HF_ENDPOINT"
+                ),
+            };
+            e.context(msg)
+        })
+    }
+
+    /// 下载文件, 传输中断时重试
     ///
-    /// 根据 model.safetensors.index.json 文件加载所有分片的 safetensors 文件
-    fn get_safetensors(&self) -> impl std::future::Future<Output = Result<Vec<PathBuf>>> + Send;
-}
-
-impl ApiRepoExt for hf_hub::api::tokio::ApiRepo {
-    async fn get_safetensors(&self) -> Result<Vec<PathBuf>> {
-        let json_file = "model.safetensors.index.json";
-        // 自行下载 index.json 文件 
-        // todo Header content-range is missing
-        let json_path = self.get(json_file).await?;
-        let json_file_handle = std::fs::File::open(json_path)?;
-        let json: serde_json::Value = serde_json::from_reader(&json_file_handle)?;
-
-        // 提取 weight_map
-        let weight_map = match json.get("weight_map") {
-            None => anyhow::bail!("no weight map in {json_file}"),
-            Some(serde_json::Value::Object(map)) => map,
-            Some(_) => anyhow::bail!("weight map in {json_file} is not a map"),
+    /// 下载中的数据写入 blobs 下的 `.part` 文件, 重试时从已完成的部分继续
+    async fn download(&self, repo: &str, filename: &str) -> Result<PathBuf> {
+        let mut attempt = 0;
+        loop {
+            match self.try_download(repo, filename).await {
+                Err(e) if attempt < self.retries && is_interrupted(&e) => {
+                    attempt += 1;
+                    let retries = self.retries;
+                    warn!("download of {filename} interrupted ({e}), retrying {attempt}/{retries}");
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn try_download(&self, repo: &str, filename: &str) -> Result<PathBuf> {
+        let url = self.file_url(repo, filename);
+        let metadata = self.file_metadata(&url).await?;
+
+        let folder = self.cache.path().join(self.repo_ref(repo).folder_name());
+        let blob = folder.join("blobs").join(&metadata.etag);
+        if !blob.is_file() {
+            self.download_blob(&url, &blob, metadata.size, filename)
+                .await?;
+        }
+
+        let cache_repo = self.cache.repo(self.repo_ref(repo));
+        let pointer = cache_repo.pointer_path(&metadata.commit).join(filename);
+        if let Some(dir) = pointer.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if !pointer.exists() {
+            link_blob(&blob, &pointer)?;
+        }
+        cache_repo.create_ref(&metadata.commit)?;
+        Ok(pointer)
+    }
+
+    /// `repo` 中文件的下载地址
+    fn file_url(&self, repo: &str, filename: &str) -> String {
+        let revision = self.revision(repo).replace('/', "%2F");
+        format!("{}/{repo}/resolve/{revision}/{filename}", self.endpoint)
+    }
+
+    /// 只请求第一个字节, 从响应头读取文件所在的 commit、etag 与大小
+    async fn file_metadata(&self, url: &str) -> Result<FileMetadata> {
+        let response = self
+            .metadata_client
+            .get(url)
+            .header(RANGE, "bytes=0-0")
+            .send()
+            .await?
+            .error_for_status()?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.replace('"', ""))
         };
+        let commit = header("x-repo-commit")
+            .ok_or_else(|| anyhow!("missing x-repo-commit header from {url}"))?;
+        let etag = header("x-linked-etag")
+            .or_else(|| header("etag"))
+            .ok_or_else(|| anyhow!("missing etag header from {url}"))?;
 
-        // 收集所有唯一的 safetensors 文件名
-        let safetensors_files: Vec<String> = weight_map
-            .values()
-            .filter_map(|v| v.as_str().map(|s| s.to_string()))
-            .collect::<std::collections::HashSet<_>>()
-            .into_iter()
-            .collect();
+        // LFS 文件重定向到 CDN, 由 CDN 返回文件大小
+        let location = header(LOCATION.as_str()).filter(|_| response.status().is_redirection());
+        let response = match location {
+            Some(location) => self
+                .client
+                .get(location)
+                .header(RANGE, "bytes=0-0")
+                .send()
+                .await?
+                .error_for_status()?,
+            None => response,
+        };
+        let size = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.rsplit_once('/')?.1.parse().ok())
+            .ok_or_else(|| anyhow!("missing content-range header from {url}"))?;
+
+        Ok(FileMetadata { commit, etag, size })
+    }
+
+    /// 下载到 blob 旁的 `.part` 文件, 已下载的部分以 Range 请求跳过, 完成后改名为 blob
+    ///
+    /// 传输完成后长度仍不符的部分文件 (损坏或属于其他文件) 删除后从头重试一次, 仍不符时删除并报错
+    async fn download_blob(&self, url: &str, blob: &Path, size: u64, filename: &str) -> Result<()> {
+        if let Some(dir) = blob.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut part = blob.as_os_str().to_owned();
+        part.push(".part");
+        let part = PathBuf::from(part);
+
+        let mut len = self.download_part(url, &part, size, filename).await?;
+        if len != size {
+            warn!("downloaded {len} of {size} bytes of {filename}, retrying from scratch");
+            tokio::fs::remove_file(&part).await?;
+            len = self.download_part(url, &part, size, filename).await?;
+        }
+        if len != size {
+            tokio::fs::remove_file(&part).await?;
+            bail!("downloaded {len} of {size} bytes of {filename}");
+        }
+        tokio::fs::rename(&part, blob).await?;
+        Ok(())
+    }
+
+    /// 从 `part` 已有的长度续传, 返回传输结束后的长度
+    ///
+    /// 比完整文件还长的部分文件不可能续传, 清空后从头下载
+    async fn download_part(
+        &self,
+        url: &str,
+        part: &Path,
+        size: u64,
+        filename: &str,
+    ) -> Result<u64> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(part)
+            .await?;
+        let mut start = file.metadata().await?.len();
+        if start > size {
+            warn!("partial download of {filename} is larger than the file, restarting");
+            file.set_len(0).await?;
+            start = 0;
+        }
+        if start < size {
+            let mut response = self
+                .client
+                .get(url)
+                .header(RANGE, format!("bytes={start}-"))
+                .send()
+                .await?
+                .error_for_status()?;
+            // 服务端忽略 Range 时从头写入
+            if start > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+                file.set_len(0).await?;
+                start = 0;
+            }
+
+            let bar = self.progress.then(|| progress_bar(filename, size, start));
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+                if let Some(bar) = &bar {
+                    bar.inc(chunk.len() as u64);
+                }
+            }
+            file.flush().await?;
+            if let Some(bar) = bar {
+                bar.finish();
+            }
+        }
 
-        // 并发下载所有文件
-        let download_futures: Vec<_> = safetensors_files
+        Ok(file.metadata().await?.len())
+    }
+
+    /// 以 Range 请求读取文件开头, 不够解析出完整的文件头时加倍读取
+    async fn fetch_gguf_header(&self, repo: &str, filename: &str) -> Result<Content> {
+        let url = self.file_url(repo, filename);
+        let mut buf = Vec::new();
+        let mut len = GGUF_HEADER_BYTES;
+        loop {
+            let start = buf.len();
+            let response = self
+                .client
+                .get(&url)
+                .header(RANGE, format!("bytes={start}-{}", len - 1))
+                .send()
+                .await?
+                .error_for_status()?;
+            // 服务端忽略 Range 时返回整个文件
+            let ranged = response.status() == StatusCode::PARTIAL_CONTENT;
+            let body = response.bytes().await?;
+            if !ranged {
+                buf.clear();
+            }
+            let complete = !ranged || body.len() < len - start;
+            buf.extend_from_slice(&body);
+
+            match Content::read(&mut std::io::Cursor::new(&buf)) {
+                Ok(ct) => return Ok(ct),
+                Err(candle::Error::Io(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof && !complete =>
+                {
+                    len *= 2;
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("failed to read the header of {filename}"));
+                }
+            }
+        }
+    }
+
+    /// 仓库信息, `blobs` 为 true 时包含各文件的大小
+    async fn repo_info(&self, repo: &str, blobs: bool) -> Result<Value> {
+        let revision = self.revision(repo).replace('/', "%2F");
+        let url = format!("{}/api/models/{repo}/revision/{revision}", self.endpoint);
+        let mut request = self.client.get(url);
+        if blobs {
+            request = request.query(&[("blobs", "true")]);
+        }
+        let body = request.send().await?.error_for_status()?.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// 仓库中的所有文件名
+    async fn repo_files(&self, repo: &str) -> Result<Vec<String>> {
+        let info = self.repo_info(repo, false).await?;
+        Ok(siblings(&info, repo)?
             .iter()
-            .map(|filename| self.get(filename))
-            .collect();
+            .filter_map(|sibling| Some(sibling["rfilename"].as_str()?.to_string()))
+            .collect())
+    }
+
+    /// 获取 safetensors 权重, 按 model.safetensors.index.json 下载所有分片
+    pub async fn get_safetensors(&self, repo: &str) -> Result<Vec<PathBuf>> {
+        let index = self.get(repo, SAFETENSORS_INDEX).await?;
+        let filenames = safetensors_shards(&index)?;
+        try_join_all(filenames.iter().map(|filename| self.get(repo, filename))).await
+    }
+
+    /// 设置了确认回调且可以下载时才需要统计下载大小
+    fn needs_confirm(&self) -> bool {
+        self.confirm_download.is_some() && !self.offline
+    }
+
+    /// 统计 `files` 中未缓存文件的大小并询问确认回调, 回调拒绝时返回 [`LlmError::Cancelled`]
+    fn confirm(&self, repo: &str, files: &[String], sizes: &BTreeMap<String, u64>) -> Result<()> {
+        let Some(confirm) = &self.confirm_download else {
+            return Ok(());
+        };
+        let bytes: u64 = files
+            .iter()
+            .filter(|file| self.cached(repo, file).is_none())
+            .filter_map(|file| sizes.get(file.as_str()))
+            .sum();
+        if bytes > 0 && !(confirm.0)(bytes) {
+            info!(
+                "download of {} from {repo} cancelled",
+                format_size(bytes as usize)
+            );
+            return Err(LlmError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// 离线模式下拒绝访问网络
+    fn ensure_online(&self, repo: &str, filename: &str) -> Result<()> {
+        if self.offline {
+            bail!("{filename} for {repo} is not cached and offline mode is enabled");
+        }
+        Ok(())
+    }
+}
+
+/// [`HubClient`] 构建器, 未设置的项从环境变量读取
+#[derive(Debug, Clone)]
+pub struct HubClientBuilder {
+    token: Option<Secret>,
+    cache_dir: Option<PathBuf>,
+    endpoint: Option<String>,
+    proxy: Option<String>,
+    progress: bool,
+    offline: bool,
+    cleanup_shards: bool,
+    retries: usize,
+    confirm_download: Option<ConfirmDownload>,
+}
+
+impl Default for HubClientBuilder {
+    fn default() -> Self {
+        Self {
+            token: None,
+            cache_dir: None,
+            endpoint: None,
+            proxy: None,
+            progress: true,
+            offline: false,
+            cleanup_shards: true,
+            retries: 3,
+            confirm_download: None,
+        }
+    }
+}
+
+impl HubClientBuilder {
+    /// 显式的 token, 优先于环境变量与 HF 缓存目录中保存的 token
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.token = token.map(Secret::from);
+        self
+    }
+
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// 下载使用的代理, 优先于 `HTTPS_PROXY`/`HTTP_PROXY` 环境变量
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// 合并 GGUF 分片后删除分片文件, 默认开启
+    pub fn cleanup_shards(mut self, cleanup_shards: bool) -> Self {
+        self.cleanup_shards = cleanup_shards;
+        self
+    }
+
+    /// 下载因网络中断失败时的重试次数, 默认 3 次
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// 下载模型权重前的确认回调, 参数为需要下载的字节数 (已缓存的文件不计入)
+    ///
+    /// 返回 false 时放弃加载并返回 [`LlmError::Cancelled`],
+    /// 可用于在命令行中提示 "将下载 8.2 GB, 是否继续?"
+    pub fn confirm_download(
+        mut self,
+        confirm: impl Fn(u64) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.confirm_download = Some(ConfirmDownload(Arc::new(confirm)));
+        self
+    }
+
+    pub fn build(self) -> Result<HubClient> {
+        let cache = match self.cache_dir {
+            Some(dir) => Cache::new(dir),
+            None => Cache::from_env(),
+        };
+        let token = self
+            .token
+            .or_else(|| std::env::var("HF_TOKEN").ok().map(Secret::from))
+            .or_else(|| cache.token().map(Secret::from));
+        let endpoint = self
+            .endpoint
+            .or_else(|| std::env::var("HF_ENDPOINT").ok())
+            .unwrap_or_else(|| "https://huggingface.co".to_string());
+
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose()))
+                .context("HF_TOKEN is not a valid header value")?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let proxy = self
+            .proxy
+            .as_deref()
+            .map(Proxy::all)
+            .transpose()
+            .context("invalid proxy url")?;
+        let client = |redirect: redirect::Policy| {
+            let mut builder = Client::builder()
+                .user_agent(concat!(
+                    env!("CARGO_PKG_NAME"),
+                    "/",
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .default_headers(headers.clone())
+                .redirect(redirect);
+            // 显式的代理排在环境变量中的代理之前, 优先匹配
+            if let Some(proxy) = &proxy {
+                builder = builder.proxy(proxy.clone());
+            }
+            builder
+                .build()
+                .context("failed to create the HuggingFace Hub client")
+        };
+        // 改名的仓库以相对地址重定向到新名称, 跟随; 指向其他主机的重定向停下
+        let same_host = redirect::Policy::custom(|attempt| {
+            let same_host = attempt
+                .previous()
+                .last()
+                .is_some_and(|prev| prev.host_str() == attempt.url().host_str());
+            if attempt.previous().len() > 10 {
+                attempt.error("too many redirects")
+            } else if same_host {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        });
+
+        Ok(HubClient {
+            client: client(redirect::Policy::default())?,
+            metadata_client: client(same_host)?,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            cache,
+            token,
+            progress: self.progress,
+            offline: self.offline,
+            cleanup_shards: self.cleanup_shards,
+            retries: self.retries,
+            confirm_download: self.confirm_download,
+            revisions: HashMap::new(),
+            role: RepoRole::Model,
+        })
+    }
+}
 
-        let paths = try_join_all(download_futures).await?;
+/// 请求失败时的 HTTP 状态码
+fn status_code(err: &Error) -> Option<u16> {
+    err.downcast_ref::<reqwest::Error>()?
+        .status()
+        .map(|status| status.as_u16())
+}
+
+/// 连接或传输中断导致的失败, 服务端返回的错误状态不属于此类
+fn is_interrupted(err: &Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.status().is_none() && !e.is_builder())
+}
 
-        Ok(paths)
+/// 快照中的文件以符号链接指向 blob, 无法创建符号链接时复制
+fn link_blob(blob: &Path, pointer: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(blob, pointer);
+    #[cfg(windows)]
+    let linked = std::os::windows::fs::symlink_file(blob, pointer);
+    linked.or_else(|_| std::fs::copy(blob, pointer).map(|_| ()))
+}
+
+fn progress_bar(filename: &str, size: u64, start: u64) -> ProgressBar {
+    let bar = ProgressBar::new(size).with_position(start);
+    if let Ok(style) = ProgressStyle::with_template(
+        "{msg} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} {bytes_per_sec} ({eta})",
+    ) {
+        bar.set_style(style.progress_chars("=>-"));
     }
+    bar.with_message(filename.to_string())
+}
+
+/// 仓库信息中的文件列表
+fn siblings<'a>(info: &'a Value, repo: &str) -> Result<&'a Vec<Value>> {
+    info["siblings"]
+        .as_array()
+        .ok_or_else(|| anyhow!("siblings not found in the info of {repo}"))
+}
+
+/// 从指定仓库读取 config.json
+pub async fn load_config(hub: &HubClient, repo: &str) -> Result<Value> {
+    let pth = hub.get(repo, "config.json").await?;
+    Ok(serde_json::from_reader(BufReader::new(File::open(pth)?))?)
+}
+
+/// 从指定仓库加载分词器, `.model` 文件按 SentencePiece 模型转换
+///
+/// 未指定 `filename` 时依次尝试 tokenizer.json 与 tokenizer.model
+pub async fn load_tokenizer(
+    hub: &HubClient,
+    repo: &str,
+    filename: Option<&str>,
+) -> Result<Tokenizer> {
+    let pth = match filename {
+        Some(filename) => hub.get(repo, filename).await?,
+        None => match hub.get(repo, "tokenizer.json").await {
+            Ok(pth) => pth,
+            Err(e) => hub.get(repo, "tokenizer.model").await.map_err(|_| {
+                e.context(format!(
+                    "neither tokenizer.json nor tokenizer.model found in {repo}; \
+                     set tokenizer_file in models.toml"
+                ))
+            })?,
+        },
+    };
+
+    if pth.extension().is_some_and(|ext| ext == "model") {
+        sentencepiece::load_sentencepiece(&std::fs::read(pth)?)
+    } else {
+        Tokenizer::from_file(pth).map_err(|e| LlmError::TokenizerLoad(Error::msg(e)).into())
+    }
+}
+
+const SAFETENSORS_INDEX: &str = "model.safetensors.index.json";
+
+/// index.json 中 weight_map 引用的所有分片文件名
+fn safetensors_shards(json_path: &Path) -> Result<Vec<String>> {
+    let json_file = SAFETENSORS_INDEX;
+    let json_file_handle = std::fs::File::open(json_path)?;
+    let json: serde_json::Value = serde_json::from_reader(&json_file_handle)?;
+
+    // 提取 weight_map
+    let weight_map = match json.get("weight_map") {
+        None => anyhow::bail!("no weight map in {json_file}"),
+        Some(serde_json::Value::Object(map)) => map,
+        Some(_) => anyhow::bail!("weight map in {json_file} is not a map"),
+    };
+
+    // 收集所有唯一的 safetensors 文件名
+    Ok(weight_map
+        .values()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .collect())
 }
 
 mod tests {
     use super::*;
     use crate::model::registry::ModelRegistry;
-    use crate::utils::{log_tensor_size, proxy::ProxyGuard};
+    use crate::utils::log_tensor_size;
+    use crate::utils::test_hub::{requested_range, serve, write_partial, write_response};
     use candle_transformers::models::flux::model;
     use candle_transformers::models::hiera;
+    use hf_hub::api::tokio::ApiBuilder;
     use serde_json::Value;
-    use std::io::BufReader;
+    use std::io::Write;
+    use std::sync::mpsc::{self, Receiver};
 
     #[tokio::test]
     async fn test_load_gguf() -> Result<()> {
@@ -139,18 +1116,17 @@ mod tests {
 
         let hub_info = registry.get(model_id).unwrap();
 
-        let model_path = download_gguf(&hub_info.model_repo, &hub_info.model_file).await?;
+        let hub = HubClient::from_env()?;
 
-        let mut file = File::open(&model_path)?;
+        let model_path = download_gguf(&hub, &hub_info.model_repo, &hub_info.model_file).await?;
 
-        let api = ApiBuilder::from_env().build()?;
+        let mut file = File::open(&model_path)?;
 
         // 构建模型
         let ct = Content::read(&mut file)?;
 
-        let pth = api
-            .model(hub_info.tokenizer_repo.clone())
-            .get("tokenizer_config.json")
+        let pth = hub
+            .get(&hub_info.tokenizer_repo, "tokenizer_config.json")
             .await?;
 
         let file = File::open(pth)?;
@@ -162,13 +1138,71 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_match_gguf_pattern() -> Result<()> {
+        let siblings = [
+            "Qwen3-4B-Q4_K_M-00001-of-00002.gguf",
+            "Qwen3-4B-Q4_K_M-00002-of-00002.gguf",
+            "Qwen3-4B-Q8_0.gguf",
+            "Qwen3-4B-Q8_0.gguf.sha256",
+            "mmproj-Qwen3-4B-F16.gguf",
+            "README.md",
+        ]
+        .map(String::from);
+
+        // 分片解析为合并后的文件名
+        assert_eq!(
+            match_gguf_pattern(&siblings, "Qwen3-4B-Q4_K_M*.gguf")?,
+            "Qwen3-4B-Q4_K_M.gguf"
+        );
+        assert_eq!(
+            match_gguf_pattern(&siblings, "Qwen3-4B-Q?_0*")?,
+            "Qwen3-4B-Q8_0.gguf"
+        );
+        // 点号按字面匹配
+        assert!(match_gguf_pattern(&siblings, "Qwen3-4B-Q8_0xgguf").is_err());
+
+        let err = match_gguf_pattern(&siblings, "Qwen3-4B-*.gguf").unwrap_err();
+        assert!(err.to_string().contains("matches multiple files"), "{err}");
+        assert!(match_gguf_pattern(&siblings, "*Q2_K*").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_shards() -> Result<()> {
+        let siblings = [
+            "Qwen3-4B-Q4.gguf",
+            "Qwen3-4B-Q4-00002-of-00002.gguf",
+            "Qwen3-4B-Q4-00001-of-00002.gguf",
+            "Qwen3-4B-Q4_K_M-extra.gguf",
+            "Qwen3-4B-Q4_0-00001-of-00001.gguf",
+            "Qwen3-4B-Q4-00001-of-00002.gguf.sha256",
+            "sub/Qwen3-4B-Q4-00001-of-00002.gguf",
+            "README.md",
+        ]
+        .map(String::from);
+
+        assert_eq!(
+            gguf_shards(&siblings, "Qwen3-4B-Q4")?,
+            [
+                "Qwen3-4B-Q4-00001-of-00002.gguf",
+                "Qwen3-4B-Q4-00002-of-00002.gguf"
+            ]
+        );
+        // 未分片的模型不应匹配到近似文件名
+        assert!(gguf_shards(&siblings, "Qwen3-4B-Q4_K_M")?.is_empty());
+        // 前缀中的正则元字符按字面匹配
+        assert!(gguf_shards(&siblings, "Qwen3-4B.Q4")?.is_empty());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_hub_load_safetensors() -> Result<()> {
         // 测试加载分片的 safetensors 模型
-        let api = ApiBuilder::from_env().build()?;
-        let repo = api.model("Qwen/Qwen3-8B".to_string());
-
-        let paths = repo.get_safetensors().await?;
+        let hub = HubClient::from_env()?;
+        let paths = hub.get_safetensors("Qwen/Qwen3-8B").await?;
 
         println!("加载了 {} 个 safetensors 文件:", paths.len());
         for path in &paths {
@@ -196,6 +1230,556 @@ mod tests {
         Ok(())
     }
 
+    /// 启动一个对所有请求都返回 `status` 的本地 Hub, 每个请求的请求头通过通道发出
+    fn mock_hub(status: &'static str) -> Result<(HubClientBuilder, Receiver<Vec<String>>)> {
+        let (tx, rx) = mpsc::channel();
+        let endpoint = serve(move |headers, stream| {
+            let _ = tx.send(headers.to_vec());
+            write_response(stream, status, b"");
+        })?;
+
+        let builder = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(std::env::temp_dir().join("candle-llm-chat-mock-hub"))
+            .progress(false);
+        Ok((builder, rx))
+    }
+
+    #[tokio::test]
+    async fn test_explicit_hf_token() -> Result<()> {
+        let (builder, requests) = mock_hub("404 Not Found")?;
+        let hub = builder.token(Some("hf_explicit".to_string())).build()?;
+        assert_eq!(hub.token(), Some("hf_explicit"));
+        assert!(!format!("{hub:?}").contains("hf_explicit"));
+        let _ = hub.get("Qwen/NoSuchRepo", "config.json").await;
+
+        let headers = requests.recv()?;
+        assert!(
+            headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case("authorization: Bearer hf_explicit")),
+            "{headers:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hub_revision() -> Result<()> {
+        let (builder, requests) = mock_hub("404 Not Found")?;
+        let hub = builder
+            .build()?
+            .with_revision("Qwen/MockRepo", "b968826")
+            .with_tokenizer_revision("Qwen/MockRepo", "v1.0");
+        assert_eq!(hub.revision("Qwen/MockRepo"), "b968826");
+        assert_eq!(hub.revision("Qwen/OtherRepo"), DEFAULT_REVISION);
+        // 同一仓库的分词器固定为另一版本, 互不覆盖
+        assert_eq!(hub.tokenizer().revision("Qwen/MockRepo"), "v1.0");
+        assert_eq!(hub.tokenizer().revision("Qwen/OtherRepo"), DEFAULT_REVISION);
+
+        // 下载固定版本的文件
+        let _ = hub.get("Qwen/MockRepo", "config.json").await;
+        let headers = requests.recv()?;
+        assert!(
+            headers[0].contains("/Qwen/MockRepo/resolve/b968826/config.json"),
+            "{headers:?}"
+        );
+
+        // 缓存中 main 与固定的版本指向不同的快照
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-revision");
+        let cache = Cache::new(cache_dir.clone());
+        for (revision, commit, eos) in [("main", "0000000", 1), ("v1.0", "1111111", 2)] {
+            let repo = cache.repo(Repo::with_revision(
+                "Qwen/MockRepo".to_string(),
+                RepoType::Model,
+                revision.to_string(),
+            ));
+            repo.create_ref(commit)?;
+            let snapshot = repo.pointer_path(commit);
+            std::fs::create_dir_all(&snapshot)?;
+            std::fs::write(
+                snapshot.join("config.json"),
+                format!(r#"{{"eos_token_id": {eos}}}"#),
+            )?;
+        }
+
+        let hub = HubClient::builder()
+            .cache_dir(cache_dir)
+            .offline(true)
+            .build()?;
+        assert_eq!(load_config(&hub, "Qwen/MockRepo").await?["eos_token_id"], 1);
+        let hub = hub.with_revision("Qwen/MockRepo", "v1.0");
+        assert_eq!(load_config(&hub, "Qwen/MockRepo").await?["eos_token_id"], 2);
+        // 未缓存的版本在离线模式下不可用
+        let hub = hub.with_revision("Qwen/MockRepo", "v2.0");
+        assert!(load_config(&hub, "Qwen/MockRepo").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hub_client_errors() -> Result<()> {
+        let hub = mock_hub("403 Forbidden")?.0.build()?;
+        let err = hub
+            .get("meta-llama/Llama-3.1-8B", "config.json")
+            .await
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("meta-llama/Llama-3.1-8B"), "{msg}");
+        assert!(msg.contains("gated"), "{msg}");
+        assert!(msg.contains("HF_TOKEN"), "{msg}");
+        // 仍归类为下载失败
+        assert!(matches!(
+            LlmError::from_anyhow(err, LlmError::ModelLoad),
+            LlmError::Download(_)
+        ));
+
+        let hub = mock_hub("404 Not Found")?.0.build()?;
+        let err = hub.get("Qwen/NoSuchRepo", "config.json").await.unwrap_err();
+        assert!(err.to_string().contains("not found"), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hub_client_proxy() -> Result<()> {
+        let https_proxy = std::env::var("HTTPS_PROXY").ok();
+
+        // 本地 Hub 充当代理, 经代理的请求行为绝对 URL
+        let (mock, requests) = mock_hub("404 Not Found")?;
+        let proxy = mock.endpoint.clone().unwrap();
+        let hub = mock.endpoint("http://hub.invalid").proxy(proxy).build()?;
+        let _ = hub.get("Qwen/NoSuchRepo", "config.json").await;
+
+        let headers = requests.recv()?;
+        assert!(
+            headers[0].starts_with("GET http://hub.invalid/Qwen/NoSuchRepo/"),
+            "{headers:?}"
+        );
+        // 代理只作用于该客户端, 不修改进程的环境变量
+        assert_eq!(std::env::var("HTTPS_PROXY").ok(), https_proxy);
+
+        Ok(())
+    }
+
+    /// 启动一个按 Range 请求返回 `body` 的本地 Hub, 模拟文件下载
+    ///
+    /// 前 `interrupted` 个数据请求只返回一半内容后断开连接, 每个请求的范围通过通道发出
+    fn mock_file_hub(
+        body: &'static [u8],
+        mut interrupted: usize,
+    ) -> Result<(String, Receiver<(usize, usize)>)> {
+        let (tx, rx) = mpsc::channel();
+        let endpoint = serve(move |headers, stream| {
+            let (start, end) = requested_range(headers, body.len());
+            let _ = tx.send((start, end));
+            let mut chunk = &body[start..=end];
+            write_partial(stream, (start, end), chunk.len(), body.len());
+            // 元数据请求只取第一个字节
+            if (start, end) != (0, 0) && interrupted > 0 {
+                interrupted -= 1;
+                chunk = &chunk[..chunk.len() / 2];
+            }
+            let _ = stream.write_all(chunk);
+        })?;
+        Ok((endpoint, rx))
+    }
+
+    /// 启动一个只提供 `files` 中文件的本地 Hub, 其余文件返回 404
+    ///
+    /// 仓库信息列出 `files` 及其大小, 每个文件请求的文件名与范围通过通道发出
+    fn mock_repo_hub(
+        files: Vec<(&'static str, Vec<u8>)>,
+    ) -> Result<(String, Receiver<(String, (usize, usize))>)> {
+        let (tx, rx) = mpsc::channel();
+        let endpoint = serve(move |headers, stream| {
+            if headers[0].contains("/api/models/") {
+                let siblings: Vec<_> = files
+                    .iter()
+                    .map(
+                        |(name, body)| serde_json::json!({ "rfilename": name, "size": body.len() }),
+                    )
+                    .collect();
+                let info = serde_json::json!({ "siblings": siblings }).to_string();
+                write_response(stream, "200 OK", info.as_bytes());
+                return;
+            }
+            let file = files
+                .iter()
+                .find(|(name, _)| headers[0].contains(&format!("/resolve/main/{name} ")));
+            let Some((name, body)) = file else {
+                write_response(stream, "404 Not Found", b"");
+                return;
+            };
+            let (start, end) = requested_range(headers, body.len());
+            let _ = tx.send((name.to_string(), (start, end)));
+            write_partial(stream, (start, end), end + 1 - start, body.len());
+            let _ = stream.write_all(&body[start..=end]);
+        })?;
+        Ok((endpoint, rx))
+    }
+
+    #[tokio::test]
+    async fn test_load_sentencepiece_tokenizer() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-sentencepiece");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let model = sentencepiece::encode_model(
+            &[
+                ("<unk>", 0., 2),
+                ("<s>", 0., 3),
+                ("</s>", 0., 3),
+                ("▁hi", -1., 1),
+            ],
+            false,
+        );
+
+        // 仓库中只有 tokenizer.model
+        let hub = HubClient::builder()
+            .endpoint(mock_repo_hub(vec![("tokenizer.model", model)])?.0)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .build()?;
+        let tokenizer = load_tokenizer(&hub, "Mock/SentencePiece", None).await?;
+        assert_eq!(tokenizer.token_to_id("▁hi"), Some(3));
+        assert_eq!(tokenizer.encode("hi", false).unwrap().get_ids(), [3]);
+
+        // 指定的文件名
+        let json = tokenizer.to_string(false).unwrap();
+        let hub = HubClient::builder()
+            .endpoint(mock_repo_hub(vec![("tok.json", json.into())])?.0)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .build()?;
+        let tokenizer = load_tokenizer(&hub, "Mock/CustomName", Some("tok.json")).await?;
+        assert_eq!(tokenizer.token_to_id("▁hi"), Some(3));
+
+        // 两种文件都没有
+        let err = load_tokenizer(&hub, "Mock/Empty", None).await.unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.contains("tokenizer.json"), "{msg}");
+        assert!(msg.contains("tokenizer.model"), "{msg}");
+        assert!(msg.contains("Mock/Empty"), "{msg}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_dir() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-cache-dir");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+
+        let hub = HubClient::builder()
+            .endpoint(mock_file_hub(br#"{"eos_token_id": 2}"#, 0)?.0)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .build()?;
+        assert_eq!(hub.cache_dir(), cache_dir);
+
+        // 下载的文件落在指定的缓存目录中, 之后直接命中缓存
+        let pth = hub.get("Qwen/MockRepo", "config.json").await?;
+        assert!(pth.starts_with(&cache_dir), "{pth:?}");
+        assert_eq!(hub.cached("Qwen/MockRepo", "config.json"), Some(pth));
+        assert_eq!(load_config(&hub, "Qwen/MockRepo").await?["eos_token_id"], 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_download() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-resume");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let body = br#"{"eos_token_id": 2, "max_position_embeddings": 40960}"#;
+
+        // 第一次传输中途断开, 重试后下载完成
+        let (endpoint, ranges) = mock_file_hub(body, 1)?;
+        let hub = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .build()?;
+        let pth = hub.get("Qwen/MockRepo", "config.json").await?;
+        assert_eq!(std::fs::read(&pth)?, body);
+
+        // 重试从中断处继续, 不重新下载已完成的一半
+        let data_requests: Vec<_> = ranges.try_iter().filter(|range| *range != (0, 0)).collect();
+        let end = body.len() - 1;
+        assert_eq!(data_requests, [(0, end), (body.len() / 2, end)]);
+
+        // 不重试时中断直接报错
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let (endpoint, _) = mock_file_hub(body, 1)?;
+        let hub = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .retries(0)
+            .build()?;
+        assert!(hub.get("Qwen/MockRepo", "config.json").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_partial_download() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join(format!("stale-part-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let body = br#"{"eos_token_id": 2}"#;
+
+        // 上次留下的部分文件比完整文件还长, 无法续传
+        let blobs = cache_dir.join("models--Qwen--MockRepo").join("blobs");
+        std::fs::create_dir_all(&blobs)?;
+        let part = blobs.join("mock-etag.part");
+        std::fs::write(&part, vec![b'x'; body.len() * 2])?;
+
+        let (endpoint, ranges) = mock_file_hub(body, 0)?;
+        let hub = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .retries(0)
+            .build()?;
+        let pth = hub.get("Qwen/MockRepo", "config.json").await?;
+        assert_eq!(std::fs::read(&pth)?, body);
+        assert!(!part.exists());
+
+        // 清空后从头下载
+        let data_requests: Vec<_> = ranges.try_iter().filter(|range| *range != (0, 0)).collect();
+        assert_eq!(data_requests, [(0, body.len() - 1)]);
+
+        std::fs::remove_dir_all(cache_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_gguf_merged() -> Result<()> {
+        // 上次运行合并出的文件, 位于 refs/v1.0 指向的快照中
+        let (mock, requests) = mock_hub("404 Not Found")?;
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-merged-gguf");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let repo = Cache::new(cache_dir.clone()).repo(Repo::with_revision(
+            "Qwen/MockRepo-GGUF".to_string(),
+            RepoType::Model,
+            "v1.0".to_string(),
+        ));
+        repo.create_ref("1111111")?;
+        let snapshot = repo.pointer_path("1111111");
+        std::fs::create_dir_all(&snapshot)?;
+        std::fs::write(snapshot.join("Qwen3-4B-Q4.gguf"), "GGUF")?;
+
+        let hub = mock
+            .cache_dir(&cache_dir)
+            .build()?
+            .with_revision("Qwen/MockRepo-GGUF", "v1.0");
+        let pth = download_gguf(&hub, "Qwen/MockRepo-GGUF", "Qwen3-4B-Q4.gguf").await?;
+        assert_eq!(pth, snapshot.join("Qwen3-4B-Q4.gguf"));
+        // 没有访问 Hub, 也就不会下载或合并分片
+        assert!(requests.try_recv().is_err());
+
+        // 其他版本快照中的文件不使用
+        let hub = hub.with_revision("Qwen/MockRepo-GGUF", "main");
+        assert!(merged_gguf(&hub, "Qwen/MockRepo-GGUF", "Qwen3-4B-Q4.gguf").is_none());
+        assert!(
+            download_gguf(&hub, "Qwen/MockRepo-GGUF", "Qwen3-4B-Q4.gguf")
+                .await
+                .is_err()
+        );
+        assert!(requests.try_recv().is_ok());
+
+        std::fs::remove_dir_all(cache_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_gguf_shards() -> Result<()> {
+        use candle::quantized::{GgmlDType, QTensor, gguf_file};
+        use candle::{Device, Tensor};
+
+        let (mock, requests) = mock_hub("404 Not Found")?;
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-gguf-shards");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let repo = Cache::new(cache_dir.clone()).model("Qwen/MockRepo-GGUF".to_string());
+        repo.create_ref("0000000")?;
+        let snapshot = repo.pointer_path("0000000");
+        std::fs::create_dir_all(&snapshot)?;
+
+        // 元数据只在第一个分片中, 两个分片各自保存一部分张量
+        let device = Device::Cpu;
+        let norm = QTensor::quantize(&Tensor::new(&[1f32, 2., 3.], &device)?, GgmlDType::F32)?;
+        let embed = Tensor::arange(0f32, 8., &device)?.reshape((2, 4))?;
+        let embed = QTensor::quantize(&embed, GgmlDType::F32)?;
+        let arch = gguf_file::Value::String("qwen3".to_string());
+        let shards = [
+            (
+                "m-00001-of-00002.gguf",
+                vec![("general.architecture", &arch)],
+                vec![("output_norm.weight", &norm)],
+            ),
+            (
+                "m-00002-of-00002.gguf",
+                vec![],
+                vec![("token_embd.weight", &embed)],
+            ),
+        ];
+        for (name, metadata, tensors) in &shards {
+            let mut file = File::create(snapshot.join(name))?;
+            gguf_file::write(&mut file, metadata, tensors)?;
+        }
+
+        // 完整的一组分片已缓存, 不访问 Hub 也不合并
+        let hub = mock.cache_dir(&cache_dir).build()?;
+        let paths = download_gguf_files(&hub, "Qwen/MockRepo-GGUF", "m.gguf").await?;
+        assert_eq!(paths.len(), 2);
+        assert!(requests.try_recv().is_err());
+        assert!(!snapshot.join("m.gguf").exists());
+
+        let (ct, mut reader) = read_gguf(&paths)?;
+        assert!(ct.metadata.contains_key("general.architecture"));
+        assert_eq!(ct.tensor_infos.len(), 2);
+        let norm = ct.tensor(&mut reader, "output_norm.weight", &device)?;
+        assert_eq!(norm.dequantize(&device)?.to_vec1::<f32>()?, [1., 2., 3.]);
+        let embed = ct.tensor(&mut reader, "token_embd.weight", &device)?;
+        assert_eq!(
+            embed.dequantize(&device)?.to_vec2::<f32>()?,
+            [[0., 1., 2., 3.], [4., 5., 6., 7.]]
+        );
+
+        // 同名张量出现在多个分片中
+        assert!(read_gguf(&[paths[0].clone(), paths[0].clone()]).is_err());
+
+        // 缺少分片时不视为已缓存
+        std::fs::remove_file(&paths[1])?;
+        assert!(cached_gguf_shards(&hub, "Qwen/MockRepo-GGUF", "m").is_none());
+
+        std::fs::remove_dir_all(cache_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_gguf_header() -> Result<()> {
+        use candle::quantized::{GgmlDType, QTensor, gguf_file};
+        use candle::{DType, Device, Tensor};
+
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-gguf-header");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+
+        let device = Device::Cpu;
+        let arch = gguf_file::Value::String("qwen3".to_string());
+        let gguf = |arch: Option<&gguf_file::Value>, name: &str, len: usize| -> Result<Vec<u8>> {
+            let weight = Tensor::zeros(len, DType::F32, &device)?;
+            let weight = QTensor::quantize(&weight, GgmlDType::F32)?;
+            let metadata: Vec<_> = arch
+                .map(|v| ("general.architecture", v))
+                .into_iter()
+                .collect();
+            let mut buf = std::io::Cursor::new(Vec::new());
+            gguf_file::write(&mut buf, &metadata, &[(name, &weight)])?;
+            Ok(buf.into_inner())
+        };
+        // 单文件的权重大于首次请求的字节数
+        let single = gguf(Some(&arch), "output_norm.weight", 3 << 20)?;
+        let single_len = single.len();
+        let (endpoint, requests) = mock_repo_hub(vec![
+            ("m.gguf", single),
+            (
+                "s-00001-of-00002.gguf",
+                gguf(Some(&arch), "output_norm.weight", 4)?,
+            ),
+            ("s-00002-of-00002.gguf", gguf(None, "token_embd.weight", 4)?),
+        ])?;
+        let hub = HubClient::builder()
+            .endpoint(endpoint)
+            .cache_dir(&cache_dir)
+            .progress(false)
+            .build()?;
+
+        // 只请求文件开头, 不下载到缓存
+        let ct = read_gguf_header(&hub, "Mock/Header-GGUF", "m.gguf").await?;
+        assert!(ct.metadata.contains_key("general.architecture"));
+        assert_eq!(ct.tensor_infos.len(), 1);
+        let (name, (start, end)) = requests.recv()?;
+        assert_eq!((name.as_str(), start), ("m.gguf", 0));
+        assert!(end + 1 < single_len, "{end} of {single_len}");
+        assert!(requests.try_recv().is_err());
+        assert!(hub.cached("Mock/Header-GGUF", "m.gguf").is_none());
+
+        // 文件不存在时按仓库中的分片读取
+        let ct = read_gguf_header(&hub, "Mock/Header-GGUF", "s.gguf").await?;
+        assert!(ct.metadata.contains_key("general.architecture"));
+        assert_eq!(ct.tensor_infos.len(), 2);
+
+        assert!(
+            read_gguf_header(&hub, "Mock/Header-GGUF", "x.gguf")
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_shards() -> Result<()> {
+        let dir = std::env::temp_dir().join("candle-llm-chat-remove-shards");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let shards: Vec<_> = ["m-00001-of-00002.gguf", "m-00002-of-00002.gguf"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        for shard in &shards {
+            std::fs::write(shard, "shard")?;
+        }
+
+        // 合并结果无法读取时保留分片
+        let merged = dir.join("m.gguf");
+        std::fs::write(&merged, "not a gguf file")?;
+        assert!(remove_shards(&merged, &shards).is_err());
+        assert!(shards.iter().all(|shard| shard.exists()));
+
+        // 空的 GGUF: magic、版本 3、张量数与元数据数均为 0
+        let mut gguf = b"GGUF".to_vec();
+        gguf.extend(3u32.to_le_bytes());
+        gguf.extend(0u64.to_le_bytes());
+        gguf.extend(0u64.to_le_bytes());
+        std::fs::write(&merged, gguf)?;
+        remove_shards(&merged, &shards)?;
+        assert!(shards.iter().all(|shard| !shard.exists()));
+        assert!(merged.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hub_client_offline() -> Result<()> {
+        // 预先在缓存目录中放入两个文件
+        let cache_dir = std::env::temp_dir().join("candle-llm-chat-offline-hub");
+        let repo = Cache::new(cache_dir.clone()).model("Qwen/MockRepo".to_string());
+        repo.create_ref("0000000")?;
+        let snapshot = repo.pointer_path("0000000");
+        std::fs::create_dir_all(&snapshot)?;
+        std::fs::write(snapshot.join("config.json"), r#"{"eos_token_id": 2}"#)?;
+        std::fs::write(snapshot.join("tokenizer_config.json"), "{}")?;
+
+        // 同一个客户端完成两次获取, 离线模式下不访问网络
+        let hub = HubClient::builder()
+            .cache_dir(cache_dir)
+            .offline(true)
+            .build()?;
+        assert!(hub.is_offline());
+
+        let config = load_config(&hub, "Qwen/MockRepo").await?;
+        assert_eq!(config["eos_token_id"], 2);
+        let pth = hub.get("Qwen/MockRepo", "tokenizer_config.json").await?;
+        assert!(pth.ends_with("tokenizer_config.json"));
+
+        let err = hub
+            .get("Qwen/MockRepo", "model.safetensors")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("offline"), "{err}");
+
+        Ok(())
+    }
+
     #[test]
     fn test_chat_template_parsing() -> Result<()> {
         // 测试 chat template 解析逻辑，不需要网络请求
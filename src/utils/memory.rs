@@ -0,0 +1,315 @@
+use anyhow::Result;
+use candle::quantized::GgmlDType;
+use candle::quantized::gguf_file::Content;
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device};
+use serde_json::Value;
+use std::path::Path;
+
+/// 设备内存使用统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// 模型权重占用的字节数
+    pub weights_bytes: usize,
+    /// 按模型维度估算的当前 KV 缓存字节数, 缺少模型配置时为 `None`
+    pub kv_cache_bytes: Option<usize>,
+    /// 观测到的设备内存峰值, 仅 CUDA 设备可查询
+    pub peak_bytes: Option<usize>,
+}
+
+/// 自动选择量化文件时为 KV 缓存与激活值预留的可用内存比例
+const QUANT_HEADROOM: f64 = 0.2;
+
+/// 设备当前已使用的内存, 包含其他进程的占用, 仅 CUDA 设备可查询
+pub fn device_used_bytes(device: &Device) -> Option<usize> {
+    device_mem_info(device).map(|(free, total)| total - free)
+}
+
+/// 设备当前可用的内存, 仅 CUDA 设备可查询
+pub fn device_free_bytes(device: &Device) -> Option<usize> {
+    device_mem_info(device).map(|(free, _)| free)
+}
+
+/// 设备的 (可用, 总) 内存
+fn device_mem_info(device: &Device) -> Option<(usize, usize)> {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(cuda) => {
+            use candle::cuda_backend::cudarc::driver::result::mem_get_info;
+
+            cuda.cuda_stream().context().bind_to_thread().ok()?;
+            mem_get_info().ok()
+        }
+        _ => None,
+    }
+}
+
+/// 从 (文件名, 字节数) 中选出放得下的最大文件, 可用内存的 20% 预留给 KV 缓存与激活值
+pub fn select_quant(candidates: &[(String, usize)], free_bytes: usize) -> Option<&str> {
+    let budget = (free_bytes as f64 * (1. - QUANT_HEADROOM)) as usize;
+    candidates
+        .iter()
+        .filter(|(_, bytes)| *bytes <= budget)
+        .max_by_key(|(_, bytes)| *bytes)
+        .map(|(name, _)| name.as_str())
+}
+
+/// 以 `dtype` 加载 safetensors 权重后占用的字节数
+pub fn safetensors_bytes<P: AsRef<Path>>(paths: &[P], dtype: DType) -> Result<usize> {
+    let st = unsafe { MmapedSafetensors::multi(paths)? };
+    Ok(st
+        .tensors()
+        .iter()
+        .map(|(_, view)| view.shape().iter().product::<usize>() * dtype.size_in_bytes())
+        .sum())
+}
+
+/// GGUF 文件中单个张量的信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorInfo {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub dtype: GgmlDType,
+    /// 量化后占用的字节数
+    pub bytes: usize,
+}
+
+/// GGUF 文件中各张量的形状、量化类型与大小, 按大小降序排列
+pub fn gguf_tensor_report(ct: &Content) -> Vec<TensorInfo> {
+    let mut report: Vec<_> = ct
+        .tensor_infos
+        .iter()
+        .map(|(name, tensor)| TensorInfo {
+            name: name.clone(),
+            shape: tensor.shape.dims().to_vec(),
+            dtype: tensor.ggml_dtype,
+            bytes: tensor.shape.elem_count() * tensor.ggml_dtype.type_size()
+                / tensor.ggml_dtype.block_size(),
+        })
+        .collect();
+    report.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+    report
+}
+
+/// 张量的总字节数
+pub fn total_bytes(tensors: &[TensorInfo]) -> usize {
+    tensors.iter().map(|tensor| tensor.bytes).sum()
+}
+
+/// GGUF 文件中量化张量的总字节数
+pub fn gguf_bytes(ct: &Content) -> usize {
+    total_bytes(&gguf_tensor_report(ct))
+}
+
+/// 根据 config.json 中的模型维度计算每个 token 的 KV 缓存字节数
+pub fn kv_bytes_per_token(config: &Value, dtype: DType) -> Option<usize> {
+    KvCacheDims::from_config(config).map(|dims| dims.bytes(1, dtype) as usize)
+}
+
+/// 上下文长度为 `context_len` 时 KV 缓存占用的字节数, K 与 V 各一份
+pub fn estimate_kv_cache_bytes(
+    num_layers: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    context_len: usize,
+    dtype: DType,
+) -> u64 {
+    let per_token = 2 * num_layers * num_kv_heads * head_dim * dtype.size_in_bytes();
+    per_token as u64 * context_len as u64
+}
+
+/// 估算 KV 缓存需要的模型维度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvCacheDims {
+    pub num_layers: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+}
+
+impl KvCacheDims {
+    /// 从 config.json 读取, 缺少 `head_dim` 时由 `hidden_size` 推出, 缺少 KV 头数时与注意力头数相同
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let get = |key: &str| config.get(key).and_then(|x| x.as_u64()).map(|x| x as usize);
+        Self::new(
+            get("num_hidden_layers")?,
+            get("num_attention_heads")?,
+            get("num_key_value_heads"),
+            get("head_dim"),
+            get("hidden_size"),
+        )
+    }
+
+    /// 从 GGUF 元数据 `{architecture}.block_count`、`{architecture}.attention.*` 等读取
+    pub fn from_gguf(ct: &Content) -> Option<Self> {
+        let arch = ct.metadata.get("general.architecture")?.to_string().ok()?;
+        let get = |key: &str| {
+            let v = ct.metadata.get(&format!("{arch}.{key}"))?;
+            v.to_u32()
+                .map(|x| x as usize)
+                .or_else(|_| v.to_u64().map(|x| x as usize))
+                .ok()
+        };
+        Self::new(
+            get("block_count")?,
+            get("attention.head_count")?,
+            get("attention.head_count_kv"),
+            get("attention.key_length"),
+            get("embedding_length"),
+        )
+    }
+
+    fn new(
+        num_layers: usize,
+        num_heads: usize,
+        num_kv_heads: Option<usize>,
+        head_dim: Option<usize>,
+        hidden_size: Option<usize>,
+    ) -> Option<Self> {
+        let head_dim = match head_dim {
+            Some(head_dim) => head_dim,
+            None => hidden_size? / num_heads,
+        };
+        Some(Self {
+            num_layers,
+            num_kv_heads: num_kv_heads.unwrap_or(num_heads),
+            head_dim,
+        })
+    }
+
+    /// 见 [`estimate_kv_cache_bytes`]
+    pub fn bytes(&self, context_len: usize, dtype: DType) -> u64 {
+        estimate_kv_cache_bytes(
+            self.num_layers,
+            self.num_kv_heads,
+            self.head_dim,
+            context_len,
+            dtype,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Tensor;
+    use candle::quantized::{QTensor, gguf_file};
+    use serde_json::json;
+
+    #[test]
+    fn test_kv_bytes_per_token() {
+        // Qwen3-4B: 36 层, 8 个 KV 头, head_dim 128
+        let config = json!({
+            "num_hidden_layers": 36,
+            "num_attention_heads": 32,
+            "num_key_value_heads": 8,
+            "head_dim": 128,
+            "hidden_size": 2560
+        });
+        assert_eq!(
+            kv_bytes_per_token(&config, DType::BF16),
+            Some(2 * 36 * 8 * 128 * 2)
+        );
+
+        // 缺少 head_dim 时由 hidden_size 推出, 缺少 KV 头数时与注意力头数相同
+        let config = json!({
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "hidden_size": 64
+        });
+        assert_eq!(kv_bytes_per_token(&config, DType::F32), Some(2 * 2 * 4 * 16 * 4));
+
+        assert_eq!(kv_bytes_per_token(&json!({}), DType::F32), None);
+        assert_eq!(device_used_bytes(&Device::Cpu), None);
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn test_device_mem_info_cuda() -> Result<()> {
+        // 无 CUDA 设备时跳过
+        let Ok(device) = Device::new_cuda(0) else {
+            return Ok(());
+        };
+
+        let (free, total) = device_mem_info(&device).unwrap();
+        assert!(free > 0 && free <= total);
+        // 其他进程可能同时分配显存, 只检查范围
+        assert!(device_free_bytes(&device).unwrap() <= total);
+        assert!(device_used_bytes(&device).unwrap() <= total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_kv_cache_bytes() {
+        // Qwen3-4B 的 32K 上下文: 2 * 36 * 8 * 128 * 32768 * 2 字节, 约 4.5 GiB
+        assert_eq!(
+            estimate_kv_cache_bytes(36, 8, 128, 32768, DType::BF16),
+            4_831_838_208
+        );
+        assert_eq!(estimate_kv_cache_bytes(36, 8, 128, 0, DType::BF16), 0);
+
+        let dims = KvCacheDims {
+            num_layers: 2,
+            num_kv_heads: 4,
+            head_dim: 16,
+        };
+        assert_eq!(dims.bytes(10, DType::F32), 2 * 2 * 4 * 16 * 10 * 4);
+    }
+
+    #[test]
+    fn test_select_quant() {
+        const GB: usize = 1_000_000_000;
+        let candidates = [
+            ("Qwen3-8B-Q4_K_M.gguf", 5 * GB),
+            ("Qwen3-8B-Q8_0.gguf", 8 * GB + GB / 2),
+            ("Qwen3-8B-BF16.gguf", 16 * GB),
+            ("Qwen3-8B-Q5_K_M.gguf", 6 * GB),
+        ]
+        .map(|(name, bytes)| (name.to_string(), bytes));
+        let select = |free_bytes| select_quant(&candidates, free_bytes);
+
+        assert_eq!(select(24 * GB), Some("Qwen3-8B-BF16.gguf"));
+        assert_eq!(select(12 * GB), Some("Qwen3-8B-Q8_0.gguf"));
+        // 8.5GB 放得进 10GB, 但不足以预留 KV 缓存
+        assert_eq!(select(10 * GB), Some("Qwen3-8B-Q5_K_M.gguf"));
+        assert_eq!(select(7 * GB), Some("Qwen3-8B-Q4_K_M.gguf"));
+        assert_eq!(select(4 * GB), None);
+        assert_eq!(select_quant(&[], 24 * GB), None);
+    }
+
+    #[test]
+    fn test_gguf_tensor_report() -> Result<()> {
+        let weight = Tensor::zeros((64, 256), DType::F32, &Device::Cpu)?;
+        let embed = QTensor::quantize(&weight, GgmlDType::Q8_0)?;
+        let attn = QTensor::quantize(&weight, GgmlDType::Q4K)?;
+        let norm = QTensor::quantize(&weight.narrow(0, 0, 1)?, GgmlDType::F32)?;
+        let tensors = [
+            ("blk.0.attn_q.weight", &attn),
+            ("output_norm.weight", &norm),
+            ("token_embd.weight", &embed),
+        ];
+
+        let pth = std::env::temp_dir().join("candle-llm-chat-tensor-report.gguf");
+        gguf_file::write(&mut std::fs::File::create(&pth)?, &[], &tensors)?;
+        let ct = Content::read(&mut std::fs::File::open(&pth)?)?;
+
+        let report = gguf_tensor_report(&ct);
+        assert_eq!(report.len(), 3);
+        assert_eq!(report[0].name, "token_embd.weight");
+        assert_eq!(report[0].shape, [64, 256]);
+        assert_eq!(report[0].dtype, GgmlDType::Q8_0);
+        // Q8_0 每 32 个元素 34 字节
+        assert_eq!(report[0].bytes, 64 * 256 / 32 * 34);
+
+        // 文件中除张量数据外只有文件头与对齐填充
+        let file_size = std::fs::metadata(&pth)?.len() as usize;
+        let total = total_bytes(&report);
+        assert_eq!(total, gguf_bytes(&ct));
+        assert!(
+            total <= file_size && file_size - total < 1024,
+            "{total} vs {file_size}"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use candle::{DType, Tensor};
+
+/// Mirostat v2 采样状态
+///
+/// 每步屏蔽惊奇度 `-log2 p` 超过 `mu` 的 token, 再按采样到的 token 在截断分布中的惊奇度
+/// 与目标 `tau` 之差更新 `mu`, 使输出的平均惊奇度稳定在 `tau` 附近
+#[derive(Debug, Clone)]
+pub struct Mirostat {
+    tau: f64,
+    eta: f64,
+    mu: f64,
+    /// 上一次截断后重新归一化的概率
+    probs: Vec<f64>,
+}
+
+impl Mirostat {
+    pub fn new(tau: f64, eta: f64) -> Self {
+        Self {
+            tau,
+            eta,
+            mu: 2. * tau,
+            probs: vec![],
+        }
+    }
+
+    pub fn mu(&self) -> f64 {
+        self.mu
+    }
+
+    /// 屏蔽惊奇度超过 `mu` 的 token, 至少保留概率最高的一个
+    ///
+    /// 概率按 `temperature` 缩放后的分布计算, 贪心解码时按 1 计算
+    pub fn truncate(&mut self, logits: &Tensor, temperature: f64) -> Result<Tensor> {
+        let device = logits.device();
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+
+        let temperature = if temperature > 0. { temperature } else { 1. };
+        let scaled: Vec<f64> = logits.iter().map(|&l| l as f64 / temperature).collect();
+        let max = scaled.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let exp: Vec<f64> = scaled.iter().map(|l| (l - max).exp()).collect();
+        let sum: f64 = exp.iter().sum();
+
+        let mut order: Vec<usize> = (0..logits.len()).collect();
+        order.sort_by(|&a, &b| exp[b].total_cmp(&exp[a]));
+
+        self.probs = vec![0.; logits.len()];
+        let mut kept = 0.;
+        for (rank, i) in order.into_iter().enumerate() {
+            let p = exp[i] / sum;
+            if rank == 0 || -p.log2() <= self.mu {
+                self.probs[i] = p;
+                kept += p;
+            } else {
+                logits[i] = f32::NEG_INFINITY;
+            }
+        }
+        self.probs.iter_mut().for_each(|p| *p /= kept);
+
+        let logits_len = logits.len();
+        Ok(Tensor::from_vec(logits, logits_len, device)?)
+    }
+
+    /// 按采样到的 token 更新 `mu`, 返回其在截断分布中的惊奇度
+    pub fn update(&mut self, token: u32) -> f64 {
+        let p = self.probs.get(token as usize).copied().unwrap_or_default();
+        if p <= 0. {
+            return f64::INFINITY;
+        }
+
+        let surprise = -p.log2();
+        self.mu -= self.eta * (surprise - self.tau);
+        surprise
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+    use candle_transformers::generation::LogitsProcessor;
+
+    #[test]
+    fn test_mirostat_converges() -> Result<()> {
+        // Zipf 分布的 1000 个 token, 熵远高于目标惊奇度
+        let logits: Vec<f32> = (1..=1000).map(|rank| -(rank as f32).ln()).collect();
+        let logits = Tensor::new(logits.as_slice(), &Device::Cpu)?;
+
+        let (tau, eta) = (3., 0.1);
+        let mut mirostat = Mirostat::new(tau, eta);
+        let mut sampler = LogitsProcessor::new(42, Some(1.), None);
+
+        let steps = 1000;
+        let mut surprises = vec![];
+        for _ in 0..steps {
+            let truncated = mirostat.truncate(&logits, 1.)?;
+            let token = sampler.sample(&truncated)?;
+            surprises.push(mirostat.update(token));
+        }
+
+        // 平均惊奇度收敛到 tau, mu 稳定在截断生效的范围内
+        let mean = surprises.iter().sum::<f64>() / steps as f64;
+        assert!((mean - tau).abs() < 0.3, "mean surprise {mean}");
+        let recent = &surprises[steps - 200..];
+        let mean = recent.iter().sum::<f64>() / recent.len() as f64;
+        assert!((mean - tau).abs() < 0.5, "recent surprise {mean}");
+        let mu = mirostat.mu();
+        assert!(mu >= tau && mu < 4. * tau, "mu {mu}");
+
+        Ok(())
+    }
+}
@@ -1,10 +1,25 @@
+pub mod bytes;
 pub mod chat;
+pub mod gen_cache;
 pub mod load;
+pub mod memory;
+pub mod mirostat;
+pub mod penalty;
 pub mod proxy;
+pub mod sentencepiece;
+pub mod special;
+pub mod stop;
+#[cfg(test)]
+pub mod test_hub;
+pub mod token_stream;
+pub mod tools;
+pub mod words;
 
+use crate::utils::memory::{gguf_tensor_report, total_bytes};
 use candle::quantized::gguf_file::Content;
+use serde::Deserialize;
 use std::io::BufRead;
-use std::{env, io};
+use std::{env, fmt, io};
 use tracing::info;
 
 pub fn format_size(size_in_bytes: usize) -> String {
@@ -19,6 +34,29 @@ pub fn format_size(size_in_bytes: usize) -> String {
     }
 }
 
+/// 访问令牌等敏感字符串, `Debug` 输出中不显示内容
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
 pub fn get_user_prompt() -> String {
     println!("请输入您的问题:");
     let stdin = io::stdin();
@@ -34,18 +72,19 @@ pub fn get_user_prompt() -> String {
     line
 }
 
-/// 计算并记录 GGUF 文件中张量的总大小信息
+/// 计算并记录 GGUF 文件中张量的总大小信息, 各张量的明细在 debug 级别输出
 pub fn log_tensor_size(ct: &Content) {
-    let mut total_size_in_bytes = 0;
-    for (_, tensor) in ct.tensor_infos.iter() {
-        let elem_count = tensor.shape.elem_count();
-        total_size_in_bytes +=
-            elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+    let report = gguf_tensor_report(ct);
+    for tensor in &report {
+        debug!(
+            "{}: {:?} {:?} ({})",
+            tensor.name,
+            tensor.shape,
+            tensor.dtype,
+            format_size(tensor.bytes)
+        );
     }
-    let formatted_size = format_size(total_size_in_bytes);
-    info!(
-        "loaded {:?} tensors ({})",
-        ct.tensor_infos.len(),
-        &formatted_size
-    );
+
+    let formatted_size = format_size(total_bytes(&report));
+    info!("loaded {:?} tensors ({})", report.len(), &formatted_size);
 }
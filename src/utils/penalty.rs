@@ -0,0 +1,174 @@
+use anyhow::Result;
+use candle::{DType, Tensor};
+use std::collections::HashMap;
+
+/// OpenAI 风格的加性惩罚
+///
+/// 根据 `context` 中各 token 的出现次数修改 logits:
+/// `logit -= presence_penalty + frequency_penalty * count`
+pub fn apply_frequency_presence_penalty(
+    logits: &Tensor,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    context: &[u32],
+) -> Result<Tensor> {
+    let device = logits.device();
+    let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for token_id in context {
+        *counts.entry(*token_id).or_default() += 1;
+    }
+
+    for (token_id, count) in counts {
+        if let Some(logit) = logits.get_mut(token_id as usize) {
+            *logit -= presence_penalty + frequency_penalty * count as f32;
+        }
+    }
+
+    let logits_len = logits.len();
+    Ok(Tensor::from_vec(logits, logits_len, device)?)
+}
+
+/// 屏蔽指定 token, 将其 logits 置为 `-inf` 使其无法被采样
+pub fn suppress_tokens(logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+    let device = logits.device();
+    let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+
+    for token_id in tokens {
+        if let Some(logit) = logits.get_mut(*token_id as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+
+    let logits_len = logits.len();
+    Ok(Tensor::from_vec(logits, logits_len, device)?)
+}
+
+/// 只保留指定 token, 其余 logits 置为 `-inf`
+pub fn allow_tokens(logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+    let device = logits.device();
+    let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+
+    let mut allowed = vec![f32::NEG_INFINITY; logits.len()];
+    for token_id in tokens {
+        if let Some(&logit) = logits.get(*token_id as usize) {
+            allowed[*token_id as usize] = logit;
+        }
+    }
+
+    let logits_len = allowed.len();
+    Ok(Tensor::from_vec(allowed, logits_len, device)?)
+}
+
+/// 局部典型采样 (typical sampling) 过滤
+///
+/// 按 token 信息量 `-log p` 与分布熵之差的绝对值升序排列, 保留累计概率达到 `typical_p` 的最小集合,
+/// 其余 logits 置为 `-inf`; 概率按 `temperature` 缩放后的分布计算
+pub fn apply_typical_p(logits: &Tensor, typical_p: f64, temperature: f64) -> Result<Tensor> {
+    let device = logits.device();
+    let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+
+    let scaled: Vec<f64> = logits.iter().map(|&l| l as f64 / temperature).collect();
+    let max = scaled.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = max + scaled.iter().map(|l| (l - max).exp()).sum::<f64>().ln();
+    let logprobs: Vec<f64> = scaled.iter().map(|l| l - log_sum).collect();
+    let entropy = -logprobs
+        .iter()
+        .filter(|lp| lp.is_finite())
+        .map(|lp| lp.exp() * lp)
+        .sum::<f64>();
+
+    let shifted = |i: usize| (-logprobs[i] - entropy).abs();
+    let mut order: Vec<usize> = (0..logits.len()).collect();
+    order.sort_by(|&a, &b| shifted(a).total_cmp(&shifted(b)));
+
+    // 至少保留一个 token
+    let mut mass = 0.;
+    for i in order {
+        if mass >= typical_p {
+            logits[i] = f32::NEG_INFINITY;
+        }
+        mass += logprobs[i].exp();
+    }
+
+    let logits_len = logits.len();
+    Ok(Tensor::from_vec(logits, logits_len, device)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    #[test]
+    fn test_frequency_penalty() -> Result<()> {
+        let logits = Tensor::zeros(4, DType::F32, &Device::Cpu)?;
+
+        // token 1 出现 3 次, token 2 出现 1 次, token 3 未出现
+        let logits = apply_frequency_presence_penalty(&logits, 0.5, 0., &[1, 1, 2, 1])?;
+        let logits = logits.to_vec1::<f32>()?;
+
+        assert_eq!(logits, vec![0., -1.5, -0.5, 0.]);
+        assert!(logits[1] < logits[2] && logits[2] < logits[3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_presence_penalty() -> Result<()> {
+        let logits = Tensor::zeros(4, DType::F32, &Device::Cpu)?;
+
+        // presence_penalty 与出现次数无关
+        let logits = apply_frequency_presence_penalty(&logits, 0., 0.5, &[1, 1, 2])?;
+
+        assert_eq!(logits.to_vec1::<f32>()?, vec![0., -0.5, -0.5, 0.]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_suppress_tokens() -> Result<()> {
+        let logits = Tensor::ones(4, DType::F32, &Device::Cpu)?;
+
+        let logits = suppress_tokens(&logits, &[2, 10])?;
+
+        assert_eq!(
+            logits.to_vec1::<f32>()?,
+            vec![1., 1., f32::NEG_INFINITY, 1.]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allow_tokens() -> Result<()> {
+        let logits = Tensor::ones(4, DType::F32, &Device::Cpu)?;
+
+        let logits = allow_tokens(&logits, &[1, 3, 10])?;
+
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(logits.to_vec1::<f32>()?, vec![inf, 1., inf, 1.]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_typical_p() -> Result<()> {
+        // 熵约为 1.14, 与之最接近的依次为 token 1 (-ln 0.3 ≈ 1.20) 与 token 0 (-ln 0.5 ≈ 0.69)
+        let probs = [0.5f32, 0.3, 0.15, 0.05];
+        let logits = Tensor::new(probs.map(f32::ln).as_slice(), &Device::Cpu)?;
+        let kept = |typical_p| -> Result<Vec<usize>> {
+            let logits = apply_typical_p(&logits, typical_p, 1.)?.to_vec1::<f32>()?;
+            let kept = (0..logits.len()).filter(|&i| logits[i].is_finite());
+            Ok(kept.collect())
+        };
+
+        assert_eq!(kept(0.5)?, [0, 1]);
+        // 最可能的 token 也可能被过滤
+        assert_eq!(kept(0.2)?, [1]);
+        assert_eq!(kept(1.)?, [0, 1, 2, 3]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! 将 SentencePiece 的 `tokenizer.model` 转换为 tokenizers 的 [`Tokenizer`]
+//!
+//! 只解析转换需要的 protobuf 字段, 生成与 transformers 中 `LlamaConverter` 一致的配置:
+//! 空格替换为 `▁` 并在开头补 `▁`, 解码时还原空格并合并字节回退 token
+
+use crate::error::LlmError;
+use anyhow::{Result, bail};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+/// SentencePiece 中的 piece 类型, 对应 `SentencePiece.Type`
+const UNKNOWN: u64 = 2;
+const CONTROL: u64 = 3;
+const USER_DEFINED: u64 = 4;
+
+/// `TrainerSpec.ModelType`
+const BPE: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: u64,
+}
+
+/// protobuf 字段值, 只区分转换用到的几种编码
+enum Field<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+/// 读取 SentencePiece 模型文件并转换
+pub fn load_sentencepiece(bytes: &[u8]) -> Result<Tokenizer> {
+    let json = tokenizer_json(bytes)?;
+    Tokenizer::from_bytes(serde_json::to_vec(&json)?)
+        .map_err(|e| LlmError::TokenizerLoad(anyhow::Error::msg(e)).into())
+}
+
+/// 生成等价的 tokenizer.json
+fn tokenizer_json(bytes: &[u8]) -> Result<Value> {
+    let mut pieces = vec![];
+    let mut model_type = 1;
+    let mut byte_fallback = false;
+    let mut add_dummy_prefix = true;
+
+    for field in fields(bytes)? {
+        match field {
+            (1, Field::Bytes(buf)) => pieces.push(piece(buf)?),
+            (2, Field::Bytes(buf)) => {
+                for field in fields(buf)? {
+                    match field {
+                        (3, Field::Varint(v)) => model_type = v,
+                        (35, Field::Varint(v)) => byte_fallback = v != 0,
+                        _ => {}
+                    }
+                }
+            }
+            (3, Field::Bytes(buf)) => {
+                for field in fields(buf)? {
+                    if let (3, Field::Varint(v)) = field {
+                        add_dummy_prefix = v != 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if pieces.is_empty() {
+        bail!("no pieces in the SentencePiece model");
+    }
+
+    let unk = pieces.iter().position(|p| p.kind == UNKNOWN);
+    let model = if model_type == BPE {
+        let vocab: HashMap<&str, usize> = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, p)| (p.piece.as_str(), id))
+            .collect();
+        json!({
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": unk.map(|id| &pieces[id].piece),
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": true,
+            "byte_fallback": byte_fallback,
+            "vocab": vocab,
+            "merges": merges(&pieces, &vocab),
+        })
+    } else {
+        let vocab: Vec<_> = pieces.iter().map(|p| json!([p.piece, p.score])).collect();
+        json!({
+            "type": "Unigram",
+            "unk_id": unk,
+            "vocab": vocab,
+            "byte_fallback": byte_fallback,
+        })
+    };
+
+    // 控制 token 与用户定义的 token 不参与切分
+    let added_tokens: Vec<_> = pieces
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| matches!(p.kind, UNKNOWN | CONTROL | USER_DEFINED))
+        .map(|(id, p)| {
+            json!({
+                "id": id,
+                "content": p.piece,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": p.kind != USER_DEFINED,
+            })
+        })
+        .collect();
+
+    let mut normalizers = vec![json!({
+        "type": "Replace", "pattern": {"String": " "}, "content": "▁"
+    })];
+    let mut decoders = vec![
+        json!({"type": "Replace", "pattern": {"String": "▁"}, "content": " "}),
+        json!({"type": "ByteFallback"}),
+        json!({"type": "Fuse"}),
+    ];
+    if add_dummy_prefix {
+        normalizers.insert(0, json!({"type": "Prepend", "prepend": "▁"}));
+        decoders.push(json!({"type": "Strip", "content": " ", "start": 1, "stop": 0}));
+    }
+
+    Ok(json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": {"type": "Sequence", "normalizers": normalizers},
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": {"type": "Sequence", "decoders": decoders},
+        "model": model,
+    }))
+}
+
+fn piece(buf: &[u8]) -> Result<Piece> {
+    let mut piece = Piece {
+        piece: String::new(),
+        score: 0.,
+        kind: 1,
+    };
+    for field in fields(buf)? {
+        match field {
+            (1, Field::Bytes(s)) => piece.piece = String::from_utf8(s.to_vec())?,
+            (2, Field::Fixed32(v)) => piece.score = f32::from_bits(v),
+            (3, Field::Varint(v)) => piece.kind = v,
+            _ => {}
+        }
+    }
+    Ok(piece)
+}
+
+/// BPE 合并规则: 词表中每个 piece 所有能拆成两个已有 piece 的切分, 按合并结果的 id 排序
+fn merges(pieces: &[Piece], vocab: &HashMap<&str, usize>) -> Vec<String> {
+    let mut merges = vec![];
+    for (id, p) in pieces.iter().enumerate() {
+        let s = p.piece.as_str();
+        for (i, _) in s.char_indices().skip(1) {
+            let (left, right) = s.split_at(i);
+            if let (Some(&l), Some(&r)) = (vocab.get(left), vocab.get(right)) {
+                merges.push(((id, l, r), format!("{left} {right}")));
+            }
+        }
+    }
+    merges.sort();
+    merges.into_iter().map(|(_, merge)| merge).collect()
+}
+
+/// 解析一条 protobuf 消息的所有字段
+fn fields(mut buf: &[u8]) -> Result<Vec<(u32, Field<'_>)>> {
+    let mut fields = vec![];
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let field = match key & 7 {
+            0 => Field::Varint(varint(&mut buf)?),
+            1 => {
+                take(&mut buf, 8)?;
+                continue;
+            }
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                Field::Bytes(take(&mut buf, len)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(take(&mut buf, 4)?.try_into()?)),
+            wire => bail!("unsupported protobuf wire type {wire} in the SentencePiece model"),
+        };
+        fields.push(((key >> 3) as u32, field));
+    }
+    Ok(fields)
+}
+
+fn varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let data = *buf;
+        let [byte, ref rest @ ..] = *data else {
+            bail!("truncated SentencePiece model");
+        };
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid varint in the SentencePiece model")
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!("truncated SentencePiece model");
+    }
+    let data = *buf;
+    let (head, rest) = data.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+/// 按 sentencepiece_model.proto 编码一个模型, `pieces` 为 (piece, score, type)
+#[cfg(test)]
+pub(crate) fn encode_model(pieces: &[(&str, f32, u64)], bpe: bool) -> Vec<u8> {
+    fn varint(buf: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+    }
+    fn bytes(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+        varint(buf, field << 3 | 2);
+        varint(buf, data.len() as u64);
+        buf.extend_from_slice(data);
+    }
+
+    let mut model = vec![];
+    for &(piece, score, kind) in pieces {
+        let mut msg = vec![];
+        bytes(&mut msg, 1, piece.as_bytes());
+        varint(&mut msg, 2 << 3 | 5);
+        msg.extend_from_slice(&score.to_le_bytes());
+        varint(&mut msg, 3 << 3);
+        varint(&mut msg, kind);
+        bytes(&mut model, 1, &msg);
+    }
+
+    let mut trainer = vec![];
+    varint(&mut trainer, 3 << 3);
+    varint(&mut trainer, if bpe { BPE } else { 1 });
+    varint(&mut trainer, 35 << 3);
+    varint(&mut trainer, 1);
+    bytes(&mut model, 2, &trainer);
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BYTE: u64 = 6;
+
+    fn ids(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+        tokenizer.encode(text, false).unwrap().get_ids().to_vec()
+    }
+
+    #[test]
+    fn test_sentencepiece_unigram() -> Result<()> {
+        let model = encode_model(
+            &[
+                ("<unk>", 0., UNKNOWN),
+                ("<s>", 0., CONTROL),
+                ("</s>", 0., CONTROL),
+                ("▁hello", -1., 1),
+                ("▁world", -1., 1),
+                ("▁", -3., 1),
+                ("h", -5., 1),
+                ("<0x21>", 0., BYTE),
+            ],
+            false,
+        );
+        let tokenizer = load_sentencepiece(&model)?;
+
+        assert_eq!(ids(&tokenizer, "hello world"), [3, 4]);
+        // 词表外的字符回退为字节
+        assert_eq!(ids(&tokenizer, "hello!"), [3, 7]);
+        assert_eq!(ids(&tokenizer, "</s>"), [2]);
+        assert_eq!(tokenizer.decode(&[3, 4, 7], true).unwrap(), "hello world!");
+        assert_eq!(tokenizer.token_to_id("<s>"), Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sentencepiece_bpe() -> Result<()> {
+        let model = encode_model(
+            &[
+                ("<unk>", 0., UNKNOWN),
+                ("<s>", 0., CONTROL),
+                ("</s>", 0., CONTROL),
+                ("<0x21>", 0., BYTE),
+                ("▁", -1., 1),
+                ("h", -2., 1),
+                ("i", -3., 1),
+                ("▁h", -4., 1),
+                ("hi", -5., 1),
+                ("▁hi", -6., 1),
+            ],
+            true,
+        );
+        let tokenizer = load_sentencepiece(&model)?;
+
+        assert_eq!(ids(&tokenizer, "hi"), [9]);
+        assert_eq!(ids(&tokenizer, "hi hi!"), [9, 9, 3]);
+        assert_eq!(tokenizer.decode(&[9, 9, 3], true).unwrap(), "hi hi!");
+
+        // 截断的文件
+        assert!(load_sentencepiece(&model[..model.len() - 1]).is_err());
+
+        Ok(())
+    }
+}
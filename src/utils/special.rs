@@ -0,0 +1,94 @@
+use tokenizers::Tokenizer;
+
+/// 从流式输出中剔除特殊 token 的文本, 如被拆成普通 token 生成的 `<|im_start|>`
+///
+/// 片段末尾可能是特殊 token 的开头时暂不输出, 等待后续片段确认
+#[derive(Debug, Default)]
+pub struct SpecialTokenFilter {
+    tokens: Vec<String>,
+    buf: String,
+}
+
+impl SpecialTokenFilter {
+    /// 使用分词器 added_tokens 中标记为特殊的 token
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let tokens = tokenizer
+            .get_added_tokens_decoder()
+            .values()
+            .filter(|token| token.special && !token.content.is_empty())
+            .map(|token| token.content.clone())
+            .collect();
+        Self::from_tokens(tokens)
+    }
+
+    pub fn from_tokens(tokens: Vec<String>) -> Self {
+        Self {
+            tokens,
+            buf: String::new(),
+        }
+    }
+
+    /// 追加一段输出, 返回剔除特殊 token 后可以确定的内容
+    pub fn push(&mut self, chunk: &str) -> Option<String> {
+        self.buf.push_str(chunk);
+        for token in &self.tokens {
+            if self.buf.contains(token.as_str()) {
+                self.buf = self.buf.replace(token.as_str(), "");
+            }
+        }
+
+        // 保留可能是特殊 token 开头的最长后缀
+        let hold = self
+            .buf
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.buf[i..];
+                self.tokens.iter().any(|token| token.starts_with(suffix))
+            })
+            .unwrap_or(self.buf.len());
+
+        let rest = self.buf.split_off(hold);
+        let out = std::mem::replace(&mut self.buf, rest);
+        (!out.is_empty()).then_some(out)
+    }
+
+    /// 取出剩余的全部内容, 未补全的特殊 token 开头原样输出
+    pub fn flush(&mut self) -> Option<String> {
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buf))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_special_token_filter() {
+        let mut filter = SpecialTokenFilter::from_tokens(vec![
+            "<|im_start|>".to_string(),
+            "<|im_end|>".to_string(),
+        ]);
+
+        assert_eq!(filter.push("Hello<|im_end|>"), Some("Hello".to_string()));
+
+        // 跨片段的特殊 token
+        assert_eq!(filter.push(" world<|im"), Some(" world".to_string()));
+        assert_eq!(
+            filter.push("_start|>assistant"),
+            Some("assistant".to_string())
+        );
+
+        // 看起来像开头但最终不是特殊 token
+        assert_eq!(filter.push("a <"), Some("a ".to_string()));
+        assert_eq!(filter.push("b>"), Some("<b>".to_string()));
+
+        assert_eq!(filter.push("你好<|"), Some("你好".to_string()));
+        assert_eq!(filter.flush(), Some("<|".to_string()));
+        assert_eq!(filter.flush(), None);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Result;
+use regex::Regex;
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::{Anchored, Input};
+
+/// 为等待匹配最多暂缓输出的字节数, 更早的文本即使之后组成匹配也已输出, 不再参与匹配
+const MAX_HOLD: usize = 256;
+
+/// 编译后的停止正则
+#[derive(Debug, Clone)]
+pub struct StopRegex {
+    regex: Regex,
+    /// 判断一段文本能否成为匹配的开头, 锚定在起点搜索
+    dfa: DFA,
+}
+
+impl StopRegex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let dfa = DFA::builder()
+            // 非 ASCII 文本上的 `\b` 使 DFA 放弃判断, 按可能匹配处理
+            .configure(DFA::config().unicode_word_boundary(true))
+            .build(pattern)?;
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+            dfa,
+        })
+    }
+
+    /// 开始匹配一次生成的回答, `answer` 中已有的 `start` 字节视为已输出, 不参与匹配
+    pub fn matcher(&self, start: usize) -> StopMatcher {
+        StopMatcher {
+            cache: self.dfa.create_cache(),
+            stop: self.clone(),
+            emitted: start,
+            scan_from: start,
+        }
+    }
+}
+
+/// 在流式生成的回答上匹配停止正则
+///
+/// 回答末尾可能是匹配开头的部分暂不输出, 确定不会匹配后再输出;
+/// 每次只从尚未排除的位置开始查找, 不重复扫描整个回答
+#[derive(Debug)]
+pub struct StopMatcher {
+    stop: StopRegex,
+    cache: Cache,
+    /// 已输出的回答长度
+    emitted: usize,
+    /// 匹配可能开始的最早位置
+    scan_from: usize,
+}
+
+impl StopMatcher {
+    /// 将 `text` 追加到回答中, 返回 `(可以输出的文本, 匹配到的文本)`
+    ///
+    /// `enabled` 为 false 时 (如未达到最少生成数量) 不检查停止正则, 文本全部输出且不参与之后的匹配;
+    /// 命中时回答截断到匹配开始处
+    pub fn push(
+        &mut self,
+        answer: &mut String,
+        text: &str,
+        enabled: bool,
+    ) -> (Option<String>, Option<String>) {
+        answer.push_str(text);
+        if !enabled {
+            self.scan_from = answer.len();
+            return (self.flush(answer), None);
+        }
+
+        if let Some(m) = self.stop.regex.find_at(answer, self.scan_from) {
+            let matched = m.as_str().to_string();
+            answer.truncate(m.start());
+            return (self.flush(answer), Some(matched));
+        }
+
+        // 跳过不可能成为匹配开头的位置, 之后的文本暂不输出
+        let mut start = self.scan_from.max(answer.len().saturating_sub(MAX_HOLD));
+        while start < answer.len()
+            && !(answer.is_char_boundary(start) && self.may_match_at(answer, start))
+        {
+            start += 1;
+        }
+        self.scan_from = start;
+
+        let text = (start > self.emitted).then(|| answer[self.emitted..start].to_string());
+        self.emitted = self.emitted.max(start);
+        (text, None)
+    }
+
+    /// 取出暂缓输出的全部文本
+    pub fn flush(&mut self, answer: &str) -> Option<String> {
+        let text = (answer.len() > self.emitted).then(|| answer[self.emitted..].to_string());
+        self.emitted = self.emitted.max(answer.len());
+        text
+    }
+
+    /// 从 `start` 开始的文本是否可能是匹配的开头, DFA 无法判断时按可能处理
+    fn may_match_at(&mut self, answer: &str, start: usize) -> bool {
+        let (dfa, cache) = (&self.stop.dfa, &mut self.cache);
+        let input = Input::new(answer).range(start..).anchored(Anchored::Yes);
+        let Ok(mut state) = dfa.start_state_forward(cache, &input) else {
+            return true;
+        };
+        for &byte in &answer.as_bytes()[start..] {
+            if state.is_dead() {
+                return false;
+            }
+            if state.is_quit() {
+                return true;
+            }
+            state = match dfa.next_state(cache, state, byte) {
+                Ok(state) => state,
+                Err(_) => return true,
+            };
+        }
+        !state.is_dead()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 逐段推入, 返回各段的输出与匹配
+    fn push_all(pattern: &str, chunks: &[&str], enabled_from: usize) -> (String, Vec<String>) {
+        let mut matcher = StopRegex::new(pattern).unwrap().matcher(0);
+        let mut answer = String::new();
+        let mut outputs = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            let (text, matched) = matcher.push(&mut answer, chunk, i >= enabled_from);
+            outputs.extend(text);
+            if let Some(matched) = matched {
+                outputs.push(format!("<{matched}>"));
+                return (answer, outputs);
+            }
+        }
+        outputs.extend(matcher.flush(&answer));
+        (answer, outputs)
+    }
+
+    #[test]
+    fn test_stop_matcher() {
+        // 跨片段的匹配: 可能是开头的 `\n` 暂不输出, 命中后不会出现在输出中
+        let (answer, outputs) = push_all(r"\n\d+\.", &["1. x", "\n", "2", ".", " y"], 0);
+        assert_eq!(answer, "1. x");
+        assert_eq!(outputs, ["1. x", "<\n2.>"]);
+
+        // 看起来像开头但最终不匹配
+        let (answer, outputs) = push_all(r"\n\d+\.", &["a\n", "1", "b"], 0);
+        assert_eq!(answer, "a\n1b");
+        assert_eq!(outputs, ["a", "\n1b"]);
+
+        // 未启用前的文本不参与匹配
+        let (answer, outputs) = push_all("ab", &["a", "b", "ab"], 1);
+        assert_eq!(answer, "ab");
+        assert_eq!(outputs, ["a", "b", "<ab>"]);
+
+        // 结束时输出暂缓的文本
+        let (answer, outputs) = push_all("abc", &["x", "ab"], 0);
+        assert_eq!(answer, "xab");
+        assert_eq!(outputs, ["x", "ab"]);
+    }
+
+    #[test]
+    fn test_stop_matcher_bounded_hold() {
+        // 暂缓输出的文本不超过 MAX_HOLD, 之后只扫描新增部分
+        let mut matcher = StopRegex::new(r"<[^>]*>").unwrap().matcher(0);
+        let mut answer = String::new();
+        let (text, _) = matcher.push(&mut answer, "<", true);
+        assert_eq!(text, None);
+        let (text, matched) = matcher.push(&mut answer, &"x".repeat(MAX_HOLD), true);
+        assert_eq!(text.map(|t| t.len()), Some(MAX_HOLD + 1));
+        assert_eq!(matched, None);
+        assert_eq!(matcher.scan_from, answer.len());
+        // 超出范围的开头不再参与匹配
+        let (_, matched) = matcher.push(&mut answer, ">", true);
+        assert_eq!(matched, None);
+
+        // 非 ASCII 文本
+        let (answer, outputs) = push_all(r"\bend\b", &["你好", " end", "!"], 0);
+        assert_eq!(answer, "你好 ");
+        assert_eq!(outputs, ["你好 ", "<end>"]);
+    }
+}
@@ -0,0 +1,68 @@
+//! 测试用的本地 Hub, 各测试只需提供按请求头写回响应的函数
+
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// 启动一个本地 HTTP 服务, 返回其地址
+///
+/// 每个连接读完请求头后交给 `respond` 写回响应, 请求头的第一行为请求行
+pub fn serve(
+    mut respond: impl FnMut(&[String], &mut TcpStream) + Send + 'static,
+) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let headers: Vec<String> = BufReader::new(&stream)
+                .lines()
+                .map_while(|line| line.ok().filter(|line| !line.is_empty()))
+                .collect();
+            if !headers.is_empty() {
+                respond(&headers, &mut stream);
+            }
+        }
+    });
+    Ok(endpoint)
+}
+
+/// 写回完整的响应
+pub fn write_response(stream: &mut impl Write, status: &str, body: &[u8]) {
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    let _ = stream.write_all(body);
+}
+
+/// 写回 Range 请求的响应头, 内容由调用方写入
+pub fn write_partial(stream: &mut impl Write, range: (usize, usize), chunk: usize, len: usize) {
+    let (start, end) = range;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 206 Partial Content\r\n\
+         Content-Length: {chunk}\r\n\
+         Content-Range: bytes {start}-{end}/{len}\r\n\
+         ETag: \"mock-etag\"\r\n\
+         X-Repo-Commit: 0000000\r\n\
+         Connection: close\r\n\r\n"
+    );
+}
+
+/// 请求头中的 Range, 没有时为整个文件, 省略结尾时到文件末尾
+pub fn requested_range(headers: &[String], len: usize) -> (usize, usize) {
+    let (start, end) = headers
+        .iter()
+        .find_map(|h| {
+            h.to_ascii_lowercase()
+                .strip_prefix("range: bytes=")?
+                .split_once('-')
+                .map(|(a, b)| (a.parse().ok(), b.parse().ok()))
+        })
+        .and_then(|(a, b)| Some((a?, b.unwrap_or(len - 1))))
+        .unwrap_or((0, len - 1));
+    (start, end.min(len - 1))
+}
@@ -0,0 +1,77 @@
+//! 增量解码生成的 token, 与 candle-examples 的 `TokenOutputStream` 行为相同,
+//! 但分词器以 `Arc` 共享, 无需为解码另存一份
+
+use anyhow::{Error, Result};
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// 逐个接收 token, 在文本以字母或数字结尾时输出新增的部分
+///
+/// 解码的窗口从上一次输出处开始, 使依赖上文的解码 (如 SentencePiece 的空格) 保持正确
+pub struct TokenOutputStream {
+    tokenizer: Arc<Tokenizer>,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenOutputStream {
+    pub fn new(tokenizer: Arc<Tokenizer>) -> Self {
+        Self {
+            tokenizer,
+            tokens: vec![],
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer.decode(tokens, true).map_err(Error::msg)
+    }
+
+    /// 上一次输出时窗口内的文本
+    fn prev_text(&self) -> Result<String> {
+        if self.tokens.is_empty() {
+            Ok(String::new())
+        } else {
+            self.decode(&self.tokens[self.prev_index..self.current_index])
+        }
+    }
+
+    /// 送入一个 token, 返回可以输出的新文本
+    pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+        let prev_text = self.prev_text()?;
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() && text.chars().last().is_some_and(char::is_alphanumeric) {
+            let (_, new) = text.split_at(prev_text.len());
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(new.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// 解码尚未输出的文本, 不改变状态
+    pub fn decode_rest(&self) -> Result<Option<String>> {
+        let prev_text = self.prev_text()?;
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() {
+            let (_, new) = text.split_at(prev_text.len());
+            Ok(Some(new.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.prev_index = 0;
+        self.current_index = 0;
+    }
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const TOOL_CALL_START: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+
+/// 模型输出的一次工具 (函数) 调用
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+/// 从模型输出中提取 `<tool_call>...</tool_call>` 包裹的 JSON 工具调用
+///
+/// 按出现顺序返回; 无法解析的调用记录警告后跳过, 未闭合的最后一个调用解析到文本末尾
+pub fn parse_tool_calls(text: &str) -> Vec<ToolCall> {
+    let mut calls = vec![];
+
+    let mut rest = text;
+    while let Some(start) = rest.find(TOOL_CALL_START) {
+        rest = &rest[start + TOOL_CALL_START.len()..];
+        let (body, next) = rest.split_once(TOOL_CALL_END).unwrap_or((rest, ""));
+        rest = next;
+
+        match serde_json::from_str::<ToolCall>(body.trim()) {
+            Ok(call) => calls.push(call),
+            Err(e) => warn!("skipping malformed tool call {:?}: {e}", body.trim()),
+        }
+    }
+
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tool_calls() {
+        // Qwen3 一次输出多个工具调用
+        let text = r#"<think>
+The user wants the weather in two cities.
+</think>
+
+<tool_call>
+{"name": "get_weather", "arguments": {"city": "Paris"}}
+</tool_call>
+<tool_call>
+{"name": "get_weather", "arguments": {"city": "Tokyo", "unit": "celsius"}}
+</tool_call>"#;
+        assert_eq!(
+            parse_tool_calls(text),
+            vec![
+                ToolCall {
+                    name: "get_weather".to_string(),
+                    arguments: json!({"city": "Paris"}),
+                },
+                ToolCall {
+                    name: "get_weather".to_string(),
+                    arguments: json!({"city": "Tokyo", "unit": "celsius"}),
+                },
+            ]
+        );
+
+        // 畸形的调用被跳过, 不影响其余调用
+        let text = r#"<tool_call>
+{"name": "get_time", "arguments": {"tz": }
+</tool_call>
+<tool_call>
+{"arguments": {}}
+</tool_call>
+<tool_call>
+{"name": "get_time"}
+</tool_call>"#;
+        assert_eq!(
+            parse_tool_calls(text),
+            vec![ToolCall {
+                name: "get_time".to_string(),
+                arguments: Value::Null,
+            }]
+        );
+
+        // 生成被截断时最后一个调用没有结束标签
+        let text = "<tool_call>\n{\"name\": \"search\", \"arguments\": {\"q\": \"rust\"}}\n";
+        assert_eq!(parse_tool_calls(text)[0].name, "search");
+
+        assert!(parse_tool_calls("The weather in Paris is sunny.").is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+/// 按词边界缓冲流式输出, 保证不会输出半个单词
+///
+/// 连续的字母数字视为一个单词, 空白、标点以及中日韩文字均视为边界
+#[derive(Debug, Default)]
+pub struct WordBuffer {
+    buf: String,
+}
+
+impl WordBuffer {
+    /// 追加一段输出, 返回截至最后一个边界的完整内容
+    pub fn push(&mut self, chunk: &str) -> Option<String> {
+        self.buf.push_str(chunk);
+
+        let end = self
+            .buf
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !is_word_char(*c))
+            .map(|(i, c)| i + c.len_utf8())?;
+
+        let rest = self.buf.split_off(end);
+        Some(std::mem::replace(&mut self.buf, rest))
+    }
+
+    /// 取出剩余的全部内容
+    pub fn flush(&mut self) -> Option<String> {
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buf))
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() && !is_cjk(c)
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'   // 平假名、片假名
+        | '\u{3400}'..='\u{4DBF}' // 扩展 A
+        | '\u{4E00}'..='\u{9FFF}' // 基本汉字
+        | '\u{AC00}'..='\u{D7AF}' // 韩文音节
+        | '\u{F900}'..='\u{FAFF}' // 兼容汉字
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_buffer() {
+        let mut words = WordBuffer::default();
+        let mut chunks = vec![];
+
+        for fragment in ["Hel", "lo wor", "ld! 你", "好", "ca", "fé"] {
+            chunks.extend(words.push(fragment));
+        }
+        let rest = words.flush();
+
+        assert_eq!(chunks, vec!["Hello ", "world! 你", "好"]);
+        assert_eq!(rest.as_deref(), Some("café"));
+
+        // 除最后一段外, 每段都在边界处结束
+        for chunk in &chunks {
+            assert!(!is_word_char(chunk.chars().last().unwrap()));
+        }
+        assert!(words.flush().is_none());
+    }
+}